@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS receipts (
+  order_id char(36) primary key not null,
+  buyer_pubkey char(64) not null,
+  event_id char(64) not null,
+  created_at integer not null
+);
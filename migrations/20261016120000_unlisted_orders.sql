@@ -0,0 +1,3 @@
+CREATE TABLE IF NOT EXISTS unlisted_orders (
+  order_id char(36) primary key not null
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS order_take_pow (
+  order_id char(36) primary key not null,
+  pow integer not null
+);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS dispute_solvers (
+  order_id char(36) not null,
+  solver_pubkey char(64) not null,
+  vote varchar(10),
+  voted_at integer,
+  primary key (order_id, solver_pubkey)
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS fiat_confirmations (
+  order_id char(36) primary key not null,
+  seller_confirmed_at integer not null
+);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS dispute_evidence (
+  id integer primary key autoincrement,
+  dispute_id char(36) not null,
+  sender_pubkey char(64) not null,
+  content text not null,
+  created_at integer not null
+);
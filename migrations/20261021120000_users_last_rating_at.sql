@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN last_rating_at integer not null default 0;
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS quarantined_orders (
+  order_id char(36) primary key not null,
+  reason text not null,
+  created_at integer not null
+);
@@ -0,0 +1,20 @@
+ALTER TABLE disputes ADD COLUMN resolved_at integer;
+CREATE TABLE IF NOT EXISTS disputes_archive (
+  id char(36) primary key not null,
+  order_id char(36) not null,
+  status varchar(10) not null,
+  solver_pubkey char(64),
+  created_at integer not null,
+  taken_at integer default 0,
+  buyer_token integer not null,
+  seller_token integer not null,
+  resolved_at integer not null,
+  closed_at integer not null
+);
+CREATE TABLE IF NOT EXISTS dispute_evidence_archive (
+  id integer primary key,
+  dispute_id char(36) not null,
+  sender_pubkey char(64) not null,
+  content text not null,
+  created_at integer not null
+);
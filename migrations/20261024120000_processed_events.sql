@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS processed_events (
+  event_id char(64) primary key not null,
+  processed_at integer not null
+);
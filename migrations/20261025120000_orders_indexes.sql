@@ -0,0 +1,6 @@
+CREATE INDEX IF NOT EXISTS orders_status ON orders (status);
+CREATE INDEX IF NOT EXISTS orders_fiat_code ON orders (fiat_code);
+CREATE INDEX IF NOT EXISTS orders_created_at ON orders (created_at);
+CREATE INDEX IF NOT EXISTS orders_creator_pubkey ON orders (creator_pubkey);
+CREATE INDEX IF NOT EXISTS orders_buyer_pubkey ON orders (buyer_pubkey);
+CREATE INDEX IF NOT EXISTS orders_seller_pubkey ON orders (seller_pubkey);
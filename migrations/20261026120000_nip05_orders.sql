@@ -0,0 +1,3 @@
+CREATE TABLE IF NOT EXISTS nip05_orders (
+  order_id char(36) primary key not null
+);
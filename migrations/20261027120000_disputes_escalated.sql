@@ -0,0 +1 @@
+ALTER TABLE disputes ADD COLUMN escalated_at integer;
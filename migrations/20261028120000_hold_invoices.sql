@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS hold_invoices (
+  order_id char(36) primary key not null,
+  payment_request text not null,
+  expires_at integer not null,
+  reminded_at integer
+);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS order_ratings (
+  order_id char(36) not null,
+  rater_pubkey char(64) not null,
+  created_at integer not null,
+  primary key (order_id, rater_pubkey)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS pending_settlements (
+  order_id char(36) primary key not null,
+  admin_pubkey char(64) not null,
+  request_id integer,
+  settle_at integer not null
+);
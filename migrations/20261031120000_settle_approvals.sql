@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS settle_approvals (
+  order_id char(36) not null,
+  approver_pubkey char(64) not null,
+  approved_at integer not null,
+  primary key (order_id, approver_pubkey)
+);
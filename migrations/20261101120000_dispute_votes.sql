@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS dispute_votes (
+  dispute_id char(36) not null,
+  solver_pubkey char(64) not null,
+  vote varchar(10) not null,
+  voted_at integer not null,
+  primary key (dispute_id, solver_pubkey)
+);
+ALTER TABLE dispute_solvers DROP COLUMN vote;
+ALTER TABLE dispute_solvers DROP COLUMN voted_at;
+DROP TABLE IF EXISTS settle_approvals;
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS payment_retries (
+  order_id char(36) primary key not null,
+  next_retry_at integer not null
+);
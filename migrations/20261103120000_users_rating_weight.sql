@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN rating_weight real not null default 0;
+UPDATE users SET rating_weight = total_reviews;
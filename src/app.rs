@@ -5,12 +5,21 @@
 pub mod add_invoice; // Handles invoice creation
 pub mod admin_add_solver; // Admin functionality to add dispute solvers
 pub mod admin_cancel; // Admin order cancellation
+pub mod admin_get_order; // Admin order inspection
 pub mod admin_settle; // Admin dispute settlement
 pub mod admin_take_dispute; // Admin dispute handling
 pub mod cancel; // User order cancellation
+pub mod command; // Requests sent as a rumor command tag
+pub mod confirm_receipt; // Buyer receipt confirmation
 pub mod dispute; // User dispute handling
+pub mod dispute_escalation; // Stuck disputes escalation
+pub mod dispute_evidence; // Dispute evidence from the parties
+pub mod extend_order; // Order expiration extension
 pub mod fiat_sent; // Fiat payment confirmation
 pub mod order; // Order creation and management
+pub mod order_interest; // Taker interest notifications
+pub mod pay_invoice; // Buyer invoice payment
+pub mod quarantine; // Orders held for review
 pub mod rate_user; // User reputation system
 pub mod release; // Release of held funds
 pub mod take_buy; // Taking buy orders
@@ -20,34 +29,77 @@ pub mod take_sell; // Taking sell orders
 use crate::app::add_invoice::add_invoice_action;
 use crate::app::admin_add_solver::admin_add_solver_action;
 use crate::app::admin_cancel::admin_cancel_action;
-use crate::app::admin_settle::admin_settle_action;
-use crate::app::admin_take_dispute::admin_take_dispute_action;
+use crate::app::admin_get_order::admin_get_order_action;
+use crate::app::admin_settle::{admin_abort_settle_action, admin_settle_action};
+use crate::app::admin_take_dispute::{admin_reassign_dispute_action, admin_take_dispute_action};
 use crate::app::cancel::cancel_action;
+use crate::app::command::{get_command, Command};
+use crate::app::confirm_receipt::confirm_receipt_action;
 use crate::app::dispute::dispute_action;
+use crate::app::extend_order::extend_order_action;
 use crate::app::fiat_sent::fiat_sent_action;
 use crate::app::order::order_action;
+use crate::app::order_interest::order_interest_action;
+use crate::app::pay_invoice::pay_invoice_action;
+use crate::app::quarantine::{approve_order_action, reject_order_action};
 use crate::app::rate_user::update_user_reputation_action;
 use crate::app::release::release_action;
 use crate::app::take_buy::take_buy_action;
 use crate::app::take_sell::take_sell_action;
 use crate::db::update_user_trade_index;
+use crate::error::MostroError;
 // Core functionality imports
+use crate::cli::settings::Mostro;
 use crate::db::add_new_user;
+use crate::db::find_order_take_pow;
 use crate::db::is_user_present;
-use crate::lightning::LndConnector;
-use crate::util::send_cant_do_msg;
+use crate::db::{is_event_processed, mark_event_processed};
+use crate::lightning::backend::LightningBackend;
+use crate::lightning::is_lnd_available;
+use crate::pow::{self, AdaptivePow};
+use crate::shutdown;
+use crate::util::{get_bitcoin_price, get_required_pow, send_cant_do_msg};
 use crate::Settings;
 
 // External dependencies
 use anyhow::Result;
 use mostro_core::message::{Action, CantDoReason, Message};
+use mostro_core::order::Order;
 use mostro_core::user::User;
 use nostr_sdk::prelude::*;
 use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
+use tracing::Instrument;
+/// Max age in seconds of the events of `action`, the action override if any
+fn max_event_age(action: &Action, mostro_settings: &Mostro) -> u64 {
+    mostro_settings
+        .event_max_ages
+        .iter()
+        .find(|age| age.action == action.to_string())
+        .map(|age| age.max_age_secs)
+        .unwrap_or(mostro_settings.max_event_age_secs)
+}
+
+fn is_event_too_old(created_at: u64, now: u64, max_age: u64) -> bool {
+    created_at < now.saturating_sub(max_age)
+}
+
+/// Parse the rumor content, a message with its optional signature,
+/// None if a peer sent malformed content
+fn parse_rumor_content(content: &str) -> Option<(Message, Option<Signature>)> {
+    match serde_json::from_str(content) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            tracing::warn!("Error deserializing content: {}", e);
+            None
+        }
+    }
+}
+
 /// Helper function to log warning messages for action errors
-fn warning_msg(action: &Action, e: anyhow::Error) {
+fn warning_msg(action: &Action, e: MostroError) {
     tracing::warn!("Error in {} with context {}", action, e);
 }
 
@@ -57,14 +109,20 @@ fn warning_msg(action: &Action, e: anyhow::Error) {
 /// 1. It checks if the action associated with the incoming message is related to trading (NewOrder, TakeBuy, or TakeSell).
 /// 2. If the user is found in the database, it verifies the trade index and the signature of the message.
 ///    - If valid, it updates the user's trade index.
-///    - If invalid, it logs a warning and sends a message indicating the issue.
+///    - If invalid, it logs a warning and returns the reason to reject the message.
 /// 3. If the user is not found, it creates a new user entry with the provided trade index if applicable.
 ///
+/// The message must not be handled if this returns an error.
+///
 /// # Arguments
 /// * `pool` - The database connection pool used to query and update user data.
 /// * `event` - The unwrapped gift event containing the sender's information.
 /// * `msg` - The message containing action details and trade index information.
-async fn check_trade_index(pool: &Pool<Sqlite>, event: &UnwrappedGift, msg: &Message) {
+async fn check_trade_index(
+    pool: &Pool<Sqlite>,
+    event: &UnwrappedGift,
+    msg: &Message,
+) -> Result<(), CantDoReason> {
     let message_kind = msg.get_inner_message_kind();
 
     // Only process actions related to trading
@@ -72,49 +130,38 @@ async fn check_trade_index(pool: &Pool<Sqlite>, event: &UnwrappedGift, msg: &Mes
         message_kind.action,
         Action::NewOrder | Action::TakeBuy | Action::TakeSell
     ) {
-        return;
+        return Ok(());
     }
 
     // If user is present, we check the trade index and signature
     match is_user_present(pool, event.sender.to_string()).await {
         Ok(user) => {
             if let (true, index) = message_kind.has_trade_index() {
-                let content: (Message, Signature) = match serde_json::from_str::<(
-                    Message,
-                    nostr_sdk::secp256k1::schnorr::Signature,
-                )>(&event.rumor.content)
-                {
-                    Ok(data) => data,
-                    Err(e) => {
-                        tracing::error!("Error deserializing content: {}", e);
-                        return;
-                    }
+                let sig = match parse_rumor_content(&event.rumor.content) {
+                    Some((_, Some(sig))) => sig,
+                    _ => return Ok(()),
                 };
 
-                let (_, sig) = content;
-
-                if index <= user.last_trade_index {
-                    tracing::info!("Invalid trade index");
-                    send_cant_do_msg(
-                        None,
-                        message_kind.id,
-                        Some(CantDoReason::InvalidTradeIndex),
-                        &event.rumor.pubkey,
-                    )
-                    .await;
-                    return;
+                if let Err(suspicious) = check_trade_index_jump(
+                    index,
+                    user.last_trade_index,
+                    Settings::get_mostro().max_trade_index_jump,
+                ) {
+                    if suspicious {
+                        tracing::warn!(
+                            "Suspicious trade index jump of user {} from {} to {index}",
+                            event.sender,
+                            user.last_trade_index
+                        );
+                    } else {
+                        tracing::info!("Invalid trade index");
+                    }
+                    return Err(CantDoReason::InvalidTradeIndex);
                 }
 
                 if !message_kind.verify_signature(event.rumor.pubkey, sig) {
                     tracing::info!("Invalid signature");
-                    send_cant_do_msg(
-                        None,
-                        message_kind.id,
-                        Some(CantDoReason::InvalidSignature),
-                        &event.rumor.pubkey,
-                    )
-                    .await;
-                    return;
+                    return Err(CantDoReason::InvalidSignature);
                 }
 
                 if let Err(e) = update_user_trade_index(pool, event.sender.to_string(), index).await
@@ -132,17 +179,259 @@ async fn check_trade_index(pool: &Pool<Sqlite>, event: &UnwrappedGift, msg: &Mes
                 };
                 if let Err(e) = add_new_user(pool, new_user).await {
                     tracing::error!("Error creating new user: {}", e);
-                    send_cant_do_msg(
-                        None,
-                        msg.get_inner_message_kind().id,
-                        Some(CantDoReason::CantCreateUser),
-                        &event.rumor.pubkey,
-                    )
-                    .await;
+                    return Err(CantDoReason::CantCreateUser);
                 }
             }
         }
     }
+
+    Ok(())
+}
+
+/// Check a new trade index follows the last one of the user, without jumping
+/// more than `max_jump` ahead if set, `Err(true)` for a suspicious jump
+fn check_trade_index_jump(index: i64, last_index: i64, max_jump: u32) -> Result<(), bool> {
+    if index <= last_index {
+        return Err(false);
+    }
+    if max_jump > 0 && index - last_index > max_jump as i64 {
+        return Err(true);
+    }
+    Ok(())
+}
+
+/// Sats for `fiat_amount` at the bitcoin `price`, a price not above zero
+/// can't give an amount
+fn sats_at_price(fiat_amount: i64, price: f64) -> Result<u64, CantDoReason> {
+    if price.is_nan() || price <= 0.0 {
+        return Err(CantDoReason::InvalidAmount);
+    }
+    Ok((fiat_amount as f64 / price * 1E8) as u64)
+}
+
+/// Get the sats amount involved in a trading message, `None` for non trading actions.
+/// Market price orders are estimated with the last known bitcoin price.
+async fn get_message_amount(
+    pool: &Pool<Sqlite>,
+    msg: &Message,
+) -> Result<Option<u64>, CantDoReason> {
+    let message_kind = msg.get_inner_message_kind();
+
+    let (amount, fiat_amount, fiat_code) = match message_kind.action {
+        Action::NewOrder => {
+            let Some(order) = message_kind.get_order() else {
+                return Ok(None);
+            };
+            let fiat_amount = order.max_amount.unwrap_or(order.fiat_amount);
+            (order.amount, fiat_amount, order.fiat_code.clone())
+        }
+        Action::TakeBuy | Action::TakeSell => {
+            let Some(order_id) = message_kind.id else {
+                return Ok(None);
+            };
+            let Ok(Some(order)) = Order::by_id(pool, order_id).await else {
+                return Ok(None);
+            };
+            let fiat_amount = order.max_amount.unwrap_or(order.fiat_amount);
+            (order.amount, fiat_amount, order.fiat_code)
+        }
+        _ => return Ok(None),
+    };
+
+    if amount > 0 {
+        return Ok(Some(amount as u64));
+    }
+    match get_bitcoin_price(&fiat_code) {
+        Ok(price) => sats_at_price(fiat_amount, price).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Check that the gift wrap POW is enough for the amount of the order,
+/// larger orders can require more work than the base POW.
+///
+/// # Arguments
+/// * `pool` - The database connection pool used to look up taken orders.
+/// * `event` - The unwrapped gift event containing the sender's information.
+/// * `msg` - The message containing action details.
+/// * `event_pow` - The POW of the gift wrap event carrying the message.
+async fn check_amount_pow(
+    pool: &Pool<Sqlite>,
+    event: &UnwrappedGift,
+    msg: &Message,
+    event_pow: u8,
+) -> bool {
+    let mostro_settings = Settings::get_mostro();
+    if mostro_settings.pow_tiers.is_empty() {
+        return true;
+    }
+    let message_kind = msg.get_inner_message_kind();
+    let amount = match get_message_amount(pool, msg).await {
+        Ok(Some(amount)) => amount,
+        Ok(None) => return true,
+        Err(reason) => {
+            tracing::info!("No sats amount for the message, bitcoin price not valid");
+            send_cant_do_msg(
+                message_kind.request_id,
+                message_kind.id,
+                Some(reason),
+                &event.rumor.pubkey,
+            )
+            .await;
+            return false;
+        }
+    };
+    let required_pow = get_required_pow(mostro_settings.pow, &mostro_settings.pow_tiers, amount);
+    if event_pow >= required_pow {
+        return true;
+    }
+
+    tracing::info!(
+        "Not enough POW for amount {} sats: got {}, required {}",
+        amount,
+        event_pow,
+        required_pow
+    );
+    send_cant_do_msg(
+        message_kind.request_id,
+        message_kind.id,
+        Some(CantDoReason::InvalidParameters),
+        &event.rumor.pubkey,
+    )
+    .await;
+
+    false
+}
+
+fn meets_take_pow(event_pow: u8, required_pow: Option<u8>) -> bool {
+    required_pow.map_or(true, |pow| event_pow >= pow)
+}
+
+/// Checks the POW of a take against the POW required by the maker of the order.
+/// Returns false, after notifying the sender, if the take doesn't meet it.
+async fn check_take_pow(
+    pool: &Pool<Sqlite>,
+    event: &UnwrappedGift,
+    msg: &Message,
+    event_pow: u8,
+) -> bool {
+    let message_kind = msg.get_inner_message_kind();
+    if !matches!(message_kind.action, Action::TakeBuy | Action::TakeSell) {
+        return true;
+    }
+    let Some(order_id) = message_kind.id else {
+        return true;
+    };
+    let required_pow = find_order_take_pow(pool, order_id).await.unwrap_or(None);
+    if meets_take_pow(event_pow, required_pow) {
+        return true;
+    }
+
+    tracing::info!(
+        "Not enough POW to take order {}: got {}, required {:?}",
+        order_id,
+        event_pow,
+        required_pow
+    );
+    send_cant_do_msg(
+        message_kind.request_id,
+        Some(order_id),
+        Some(CantDoReason::InvalidParameters),
+        &event.rumor.pubkey,
+    )
+    .await;
+
+    false
+}
+
+/// Handle a request sent as a command tag, the action of its message is ignored
+async fn handle_command(
+    command: Result<Command, String>,
+    msg: Message,
+    event: &UnwrappedGift,
+    wrap_id: &EventId,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    let command = match command {
+        Ok(command) => command,
+        Err(name) => {
+            tracing::info!("Received unknown command {name}");
+            return Err(MostroError::CantDo(CantDoReason::InvalidParameters));
+        }
+    };
+    match command {
+        Command::AdminAbortSettle => admin_abort_settle_action(msg, event, my_keys, pool).await,
+        Command::AdminGetOrder => admin_get_order_action(msg, event, my_keys, pool).await,
+        Command::ExtendOrder => extend_order_action(msg, event, my_keys, pool).await,
+        Command::ConfirmReceipt => confirm_receipt_action(msg, event, wrap_id, pool).await,
+        Command::OrderInterest => order_interest_action(msg, event, pool).await,
+        Command::ApproveOrder => approve_order_action(msg, event, my_keys, pool).await,
+        Command::RejectOrder => reject_order_action(msg, event, my_keys, pool).await,
+        Command::ReassignDispute => admin_reassign_dispute_action(msg, event, my_keys, pool).await,
+    }
+}
+
+/// Check if LND is available for actions needing it, while LND is
+/// reconnecting those actions are rejected so the user can retry them later
+fn check_lnd_available(action: &Action, lnd_available: bool) -> Result<(), CantDoReason> {
+    let needs_lnd = matches!(
+        action,
+        Action::TakeSell
+            | Action::TakeBuy
+            | Action::AddInvoice
+            | Action::Release
+            | Action::PayInvoice
+            | Action::Cancel
+            | Action::AdminCancel
+            | Action::AdminSettle
+    );
+    if needs_lnd && !lnd_available {
+        return Err(CantDoReason::NotAllowedByStatus);
+    }
+    Ok(())
+}
+
+/// Span of the handling of a message, the log lines of the flow carry the
+/// action, order id, request id and sender of the message
+fn action_span(action: &Action, msg: &Message, event: &UnwrappedGift) -> tracing::Span {
+    let inner = msg.get_inner_message_kind();
+    let span = tracing::info_span!(
+        "action",
+        action = ?action,
+        order_id = tracing::field::Empty,
+        request_id = tracing::field::Empty,
+        sender = %event.sender,
+        trade_pubkey = %event.rumor.pubkey,
+    );
+    if let Some(order_id) = inner.id {
+        span.record("order_id", tracing::field::display(order_id));
+    }
+    if let Some(request_id) = inner.request_id {
+        span.record("request_id", request_id);
+    }
+
+    span
+}
+
+/// Run the handler of a gift wrap, the gift wrap is recorded as processed
+/// only once handled so a failed one is handled again if delivered again
+async fn handle_once<F>(
+    pool: &Pool<Sqlite>,
+    wrap_id: &EventId,
+    handler: F,
+) -> Result<(), MostroError>
+where
+    F: std::future::Future<Output = Result<(), MostroError>>,
+{
+    handler.await?;
+    if let Err(e) =
+        mark_event_processed(pool, &wrap_id.to_hex(), Timestamp::now().as_u64() as i64).await
+    {
+        tracing::error!("Error recording processed event: {e}");
+    }
+
+    Ok(())
 }
 
 /// Handles the processing of a single message action by routing it to the appropriate handler
@@ -152,6 +441,7 @@ async fn check_trade_index(pool: &Pool<Sqlite>, event: &UnwrappedGift, msg: &Mes
 /// * `action` - The type of action to be performed
 /// * `msg` - The message containing action details
 /// * `event` - The unwrapped gift wrap event
+/// * `wrap_id` - Id of the gift wrap event signed and published by the sender
 /// * `my_keys` - Node keypair for signing/verification
 /// * `pool` - Database connection pool
 /// * `ln_client` - Lightning network connector
@@ -160,11 +450,51 @@ async fn handle_message_action(
     action: &Action,
     msg: Message,
     event: &UnwrappedGift,
+    wrap_id: &EventId,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
     rate_list: Arc<Mutex<Vec<Event>>>,
-) -> Result<()> {
+) -> Result<(), MostroError> {
+    let request_id = msg.get_inner_message_kind().request_id;
+    let order_id = msg.get_inner_message_kind().id;
+
+    // Requests without an action of their own come as a command tag
+    let result = match get_command(event) {
+        Some(command) => handle_command(command, msg, event, wrap_id, my_keys, pool).await,
+        None => route_action(action, msg, event, my_keys, pool, ln_client, rate_list).await,
+    };
+
+    // Errors of the handlers are translated to the reason sent to the user
+    // here, the only place replying a CantDo to a handled message
+    if let Err(e) = &result {
+        send_cant_do_msg(
+            request_id,
+            order_id,
+            e.cant_do_reason(),
+            &event.rumor.pubkey,
+        )
+        .await;
+    }
+
+    result
+}
+
+/// Route a message to the handler of its action
+async fn route_action(
+    action: &Action,
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+    rate_list: Arc<Mutex<Vec<Event>>>,
+) -> Result<(), MostroError> {
+    if let Err(reason) = check_lnd_available(action, is_lnd_available()) {
+        tracing::warn!("LND not available, action {:?} rejected", action);
+        return Err(MostroError::CantDo(reason));
+    }
+
     match action {
         // Order-related actions
         Action::NewOrder => order_action(msg, event, my_keys, pool).await,
@@ -175,7 +505,7 @@ async fn handle_message_action(
         Action::FiatSent => fiat_sent_action(msg, event, my_keys, pool).await,
         Action::Release => release_action(msg, event, my_keys, pool, ln_client).await,
         Action::AddInvoice => add_invoice_action(msg, event, my_keys, pool).await,
-        Action::PayInvoice => todo!(),
+        Action::PayInvoice => pay_invoice_action(msg, event, my_keys, pool).await,
 
         // Dispute and rating actions
         Action::Dispute => dispute_action(msg, event, my_keys, pool).await,
@@ -199,6 +529,7 @@ async fn handle_message_action(
 
 /// Main event loop that processes incoming Nostr events.
 /// Handles message verification, POW checking, and routes valid messages to appropriate handlers.
+/// Returns once the shutdown starts, the message being handled is finished first.
 ///
 /// # Arguments
 /// * `my_keys` - The node's keypair
@@ -206,20 +537,52 @@ async fn handle_message_action(
 /// * `ln_client` - Lightning network connector
 /// * `pool` - SQLite connection pool
 /// * `rate_list` - Shared list of rating events
+/// * `shutdown` - Receiver of the shutdown, see [`shutdown::subscribe`]
 pub async fn run(
     my_keys: Keys,
     client: &Client,
-    ln_client: &mut LndConnector,
+    ln_client: &mut dyn LightningBackend,
     pool: Pool<Sqlite>,
     rate_list: Arc<Mutex<Vec<Event>>>,
+    mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
+    // Required pow rising under load, if enabled
+    let mostro_settings = Settings::get_mostro();
+    let mut adaptive_pow = mostro_settings.adaptive_pow.then(|| {
+        AdaptivePow::new(
+            mostro_settings.pow,
+            mostro_settings.adaptive_pow_ceiling,
+            mostro_settings.adaptive_pow_threshold as usize,
+            mostro_settings.adaptive_pow_window_seconds as i64,
+            Timestamp::now().as_u64() as i64,
+        )
+    });
     loop {
         let mut notifications = client.notifications();
 
         // Get pow from config
         let pow = Settings::get_mostro().pow;
-        while let Ok(notification) = notifications.recv().await {
+        loop {
+            // Stop taking new events once the shutdown starts
+            let notification = tokio::select! {
+                _ = shutdown::wait(&mut shutdown) => {
+                    tracing::info!("Shutting down, no more events accepted");
+                    return Ok(());
+                }
+                notification = notifications.recv() => notification,
+            };
+            let Ok(notification) = notification else {
+                break;
+            };
             if let RelayPoolNotification::Event { event, .. } = notification {
+                let pow = match adaptive_pow.as_mut() {
+                    Some(adaptive_pow) => {
+                        let required = adaptive_pow.record(Timestamp::now().as_u64() as i64);
+                        pow::set_required_pow(required);
+                        required
+                    }
+                    None => pow,
+                };
                 // Verify proof of work
                 if !event.check_pow(pow) {
                     // Discard events that don't meet POW requirements
@@ -231,6 +594,18 @@ pub async fn run(
                     if event.verify().is_err() {
                         tracing::warn!("Error in event verification")
                     };
+                    // Relays may deliver the same gift wrap again, handle it once
+                    match is_event_processed(&pool, &event.id.to_hex()).await {
+                        Ok(true) => {
+                            tracing::info!("Event {} already processed", event.id);
+                            continue;
+                        }
+                        Ok(false) => {}
+                        Err(e) => tracing::error!("Error checking processed event: {e}"),
+                    }
+                    // Keep gift wrap POW to check it against the order amount
+                    let event_pow = nip13::get_leading_zero_bits(event.id.as_bytes());
+                    let wrap_id = event.id;
 
                     let event = match nip59::extract_rumor(&my_keys, &event).await {
                         Ok(u) => u,
@@ -239,25 +614,21 @@ pub async fn run(
                             continue;
                         }
                     };
-                    // Discard events older than 10 seconds to prevent replay attacks
-                    let since_time = chrono::Utc::now()
-                        .checked_sub_signed(chrono::Duration::seconds(10))
-                        .unwrap()
-                        .timestamp() as u64;
-                    if event.rumor.created_at.as_u64() < since_time {
+                    let Some((message, sig)) = parse_rumor_content(&event.rumor.content) else {
                         continue;
-                    }
-
-                    let (message, sig): (Message, Option<Signature>) =
-                        match serde_json::from_str(&event.rumor.content) {
-                            Ok(data) => data,
-                            Err(e) => {
-                                tracing::error!("Error deserializing content: {}", e);
-                                continue;
-                            }
-                        };
+                    };
                     let inner_message = message.get_inner_message_kind();
 
+                    // Discard old events to prevent replay attacks
+                    let max_age = max_event_age(&inner_message.action, &Settings::get_mostro());
+                    if is_event_too_old(
+                        event.rumor.created_at.as_u64(),
+                        Timestamp::now().as_u64(),
+                        max_age,
+                    ) {
+                        continue;
+                    }
+
                     let sender_matches_rumor = event.sender == event.rumor.pubkey;
 
                     if let Some(sig) = sig {
@@ -274,23 +645,41 @@ pub async fn run(
                         continue;
                     }
 
+                    // Larger orders may require more POW than the base one
+                    if !check_amount_pow(&pool, &event, &message, event_pow).await {
+                        continue;
+                    }
+
+                    // Makers may require more POW to take their orders
+                    if !check_take_pow(&pool, &event, &message, event_pow).await {
+                        continue;
+                    }
+
                     // Check if message is message with trade index
-                    check_trade_index(&pool, &event, &message).await;
+                    if let Err(reason) = check_trade_index(&pool, &event, &message).await {
+                        send_cant_do_msg(None, inner_message.id, Some(reason), &event.rumor.pubkey)
+                            .await;
+                        continue;
+                    }
 
                     if inner_message.verify() {
                         if let Some(action) = message.inner_action() {
-                            if let Err(e) = handle_message_action(
+                            let span = action_span(&action, &message, &event);
+                            let handler = handle_message_action(
                                 &action,
                                 message,
                                 &event,
+                                &wrap_id,
                                 &my_keys,
                                 &pool,
                                 ln_client,
                                 rate_list.clone(),
-                            )
-                            .await
+                            );
+                            if let Err(e) = handle_once(&pool, &wrap_id, handler)
+                                .instrument(span.clone())
+                                .await
                             {
-                                warning_msg(&action, e)
+                                span.in_scope(|| warning_msg(&action, e))
                             }
                         }
                     }
@@ -299,3 +688,281 @@ pub async fn run(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::settings::EventMaxAge;
+    use crate::lightning::mock::MockNode;
+    use std::env::set_var;
+    use std::path::PathBuf;
+    use std::sync::RwLock;
+
+    #[test]
+    fn test_actions_rejected_while_lnd_unavailable() {
+        for action in [
+            Action::TakeSell,
+            Action::TakeBuy,
+            Action::AddInvoice,
+            Action::Release,
+            Action::Cancel,
+            Action::AdminCancel,
+            Action::AdminSettle,
+        ] {
+            assert_eq!(
+                check_lnd_available(&action, false),
+                Err(CantDoReason::NotAllowedByStatus)
+            );
+            assert_eq!(check_lnd_available(&action, true), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_actions_without_lnd_allowed_while_unavailable() {
+        for action in [Action::NewOrder, Action::FiatSent, Action::RateUser] {
+            assert_eq!(check_lnd_available(&action, false), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_sats_at_price() {
+        assert_eq!(sats_at_price(100, 50_000.0), Ok(200_000));
+        // A zero or unknown price doesn't give an amount
+        for price in [0.0, -1.0, f64::NAN] {
+            assert_eq!(sats_at_price(100, price), Err(CantDoReason::InvalidAmount));
+        }
+    }
+
+    #[test]
+    fn test_event_age_within_window() {
+        let now = 1_700_000_000;
+        let mostro_settings = Mostro {
+            max_event_age_secs: 10,
+            ..Default::default()
+        };
+        let max_age = max_event_age(&Action::TakeBuy, &mostro_settings);
+        assert!(!is_event_too_old(now - 10, now, max_age));
+        assert!(is_event_too_old(now - 11, now, max_age));
+    }
+
+    #[test]
+    fn test_event_age_by_action() {
+        let now = 1_700_000_000;
+        let mostro_settings = Mostro {
+            max_event_age_secs: 10,
+            event_max_ages: vec![EventMaxAge {
+                action: Action::AdminSettle.to_string(),
+                max_age_secs: 60,
+            }],
+            ..Default::default()
+        };
+        let max_age = max_event_age(&Action::AdminSettle, &mostro_settings);
+        assert!(!is_event_too_old(now - 60, now, max_age));
+        assert!(is_event_too_old(now - 61, now, max_age));
+        // Other actions keep the default window
+        let max_age = max_event_age(&Action::TakeBuy, &mostro_settings);
+        assert!(is_event_too_old(now - 11, now, max_age));
+    }
+
+    #[test]
+    fn test_malformed_rumor_content_discarded() {
+        for content in [
+            "garbage",
+            "",
+            "{}",
+            "[1, 2]",
+            r#"[{"order":{"version":1,"action":"not-an-action"}}, null]"#,
+            r#"[{"order":{"version":1,"request_id":1,"trade_index":null,"id":null,"action":"new-order","payload":null}}, "not a signature"]"#,
+        ] {
+            assert!(parse_rumor_content(content).is_none());
+        }
+    }
+
+    #[test]
+    fn test_rumor_content_parsed() {
+        let content = r#"[{"order":{"version":1,"request_id":1,"trade_index":null,"id":"7dd204d2-d06c-4406-a3d9-4415f4a8b9c9","action":"fiat-sent","payload":null}}, null]"#;
+        let (message, sig) = parse_rumor_content(content).unwrap();
+        assert!(matches!(
+            message.get_inner_message_kind().action,
+            Action::FiatSent
+        ));
+        assert!(sig.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_exits_on_shutdown() {
+        set_var("RUN_MODE", ".tpl");
+        crate::MOSTRO_CONFIG.get_or_init(|| {
+            RwLock::new(std::sync::Arc::new(
+                Settings::new(PathBuf::from("./")).unwrap(),
+            ))
+        });
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let client = Client::default();
+        // A shutdown of its own, the global one would stop the other tests
+        let (stop, shutdown) = tokio::sync::watch::channel(false);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            stop.send_replace(true);
+        });
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            run(
+                Keys::generate(),
+                &client,
+                &mut MockNode::default(),
+                pool,
+                Arc::new(Mutex::new(vec![])),
+                shutdown,
+            ),
+        )
+        .await;
+        assert!(matches!(result, Ok(Ok(()))));
+    }
+
+    /// Log lines written by the subscriber under test
+    #[derive(Clone, Default)]
+    struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_logs_carry_action_fields() {
+        let keys = Keys::generate();
+        let order_id = uuid::Uuid::new_v4();
+        let message = Message::new_order(Some(order_id), Some(7), None, Action::Release, None);
+        let event = UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: EventBuilder::text_note("").build(keys.public_key()),
+        };
+
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            action_span(&Action::Release, &message, &event)
+                .in_scope(|| tracing::info!("Order released"));
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(logs.trim()).unwrap();
+        assert_eq!(line["fields"]["message"], "Order released");
+        assert_eq!(line["span"]["order_id"], order_id.to_string());
+        assert_eq!(line["span"]["request_id"], 7);
+        assert_eq!(line["span"]["sender"], keys.public_key().to_string());
+    }
+
+    #[test]
+    fn test_trade_index_jump() {
+        // Default is permissive
+        assert_eq!(check_trade_index_jump(5, 4, 0), Ok(()));
+        assert_eq!(check_trade_index_jump(1_000_004, 4, 0), Ok(()));
+        // With a limit a normal increment passes and a huge jump doesn't
+        assert_eq!(check_trade_index_jump(5, 4, 100), Ok(()));
+        assert_eq!(check_trade_index_jump(104, 4, 100), Ok(()));
+        assert_eq!(check_trade_index_jump(1_000_004, 4, 100), Err(true));
+        // Reused indexes are invalid but not suspicious
+        assert_eq!(check_trade_index_jump(4, 4, 100), Err(false));
+        assert_eq!(check_trade_index_jump(3, 4, 0), Err(false));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_trade_index_rejects_new_order() {
+        set_var("RUN_MODE", ".tpl");
+        crate::MOSTRO_CONFIG.get_or_init(|| {
+            RwLock::new(std::sync::Arc::new(
+                Settings::new(PathBuf::from("./")).unwrap(),
+            ))
+        });
+        let (pool, _db) = crate::db::connect_test_db().await;
+
+        let identity = Keys::generate();
+        let trade_keys = Keys::generate();
+        let user = User {
+            pubkey: identity.public_key().to_hex(),
+            last_trade_index: 5,
+            ..Default::default()
+        };
+        add_new_user(&pool, user).await.unwrap();
+        let new_order = |trade_index: i64| {
+            let message = Message::new_order(None, None, Some(trade_index), Action::NewOrder, None);
+            let sig = message.get_inner_message_kind().sign(&trade_keys);
+            let content = serde_json::to_string(&(message.clone(), sig)).unwrap();
+            let event = UnwrappedGift {
+                sender: identity.public_key(),
+                rumor: EventBuilder::text_note(content).build(trade_keys.public_key()),
+            };
+            (message, event)
+        };
+
+        // A reused index is rejected before the order is handled
+        let (message, event) = new_order(5);
+        assert_eq!(
+            check_trade_index(&pool, &event, &message).await,
+            Err(CantDoReason::InvalidTradeIndex)
+        );
+        let user = is_user_present(&pool, identity.public_key().to_hex())
+            .await
+            .unwrap();
+        assert_eq!(user.last_trade_index, 5);
+
+        let (message, event) = new_order(6);
+        assert_eq!(check_trade_index(&pool, &event, &message).await, Ok(()));
+        let user = is_user_present(&pool, identity.public_key().to_hex())
+            .await
+            .unwrap();
+        assert_eq!(user.last_trade_index, 6);
+    }
+
+    #[test]
+    fn test_take_meeting_required_pow() {
+        assert!(meets_take_pow(12, Some(12)));
+        assert!(meets_take_pow(20, Some(12)));
+        // No POW required by the maker
+        assert!(meets_take_pow(0, None));
+    }
+
+    #[test]
+    fn test_take_not_meeting_required_pow() {
+        assert!(!meets_take_pow(11, Some(12)));
+        assert!(!meets_take_pow(0, Some(1)));
+    }
+
+    #[tokio::test]
+    async fn test_release_handled_once() {
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let wrap_id = EventId::all_zeros();
+        let mut payments = 0;
+
+        // A failed release is handled again when delivered again
+        let failed = async { Err(MostroError::CantDo(CantDoReason::InvalidInvoice)) };
+        assert!(handle_once(&pool, &wrap_id, failed).await.is_err());
+        assert!(!is_event_processed(&pool, &wrap_id.to_hex()).await.unwrap());
+
+        // Same release delivered twice, the payment is attempted once
+        for _ in 0..2 {
+            if is_event_processed(&pool, &wrap_id.to_hex()).await.unwrap() {
+                continue;
+            }
+            let release = async {
+                payments += 1;
+                Ok(())
+            };
+            handle_once(&pool, &wrap_id, release).await.unwrap();
+        }
+        assert_eq!(payments, 1);
+    }
+}
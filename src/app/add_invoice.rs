@@ -1,7 +1,9 @@
-use crate::lightning::invoice::is_valid_invoice;
-use crate::util::{send_cant_do_msg, send_new_order_msg, show_hold_invoice, update_order_event};
+use crate::error::MostroError;
+use crate::lightning::invoice::{decode_invoice, is_valid_invoice};
+use crate::util::{get_required_id, send_new_order_msg, show_hold_invoice, update_order_event};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
+use lightning_invoice::Bolt11Invoice;
 
 use mostro_core::message::{Action, CantDoReason, Message, Payload};
 use mostro_core::order::SmallOrder;
@@ -11,33 +13,69 @@ use nostr_sdk::prelude::*;
 use sqlx::{Pool, Sqlite};
 use sqlx_crud::Crud;
 use std::str::FromStr;
+use std::time::Duration;
 use tracing::error;
 
+/// Check the order needs a buyer invoice, it is waiting for it or the
+/// payment to the buyer failed and a new one can be sent
+pub fn check_invoice_applicable(order: &Order, status: &Status) -> Result<(), CantDoReason> {
+    match status {
+        Status::WaitingBuyerInvoice => Ok(()),
+        Status::SettledHoldInvoice if order.failed_payment => Ok(()),
+        Status::Canceled | Status::CooperativelyCanceled | Status::CanceledByAdmin => {
+            Err(CantDoReason::OrderAlreadyCanceled)
+        }
+        _ => Err(CantDoReason::NotAllowedByStatus),
+    }
+}
+
+/// Check a buyer bolt11 invoice can pay the order at `now`, seconds since unix
+/// epoch, its amount must be the order amount minus fee and amountless invoices
+/// are only accepted when the order amount is known
+pub fn check_buyer_invoice(
+    invoice: &Bolt11Invoice,
+    order: &Order,
+    now: u64,
+) -> Result<(), CantDoReason> {
+    if invoice.would_expire(Duration::from_secs(now)) {
+        return Err(CantDoReason::InvalidInvoice);
+    }
+    let expected = order
+        .amount
+        .checked_sub(order.fee)
+        .filter(|amount| *amount > 0);
+    match (invoice.amount_milli_satoshis(), expected) {
+        (Some(msat), Some(amount)) if msat == amount as u64 * 1000 => Ok(()),
+        (None, Some(_)) => Ok(()),
+        _ => Err(CantDoReason::InvalidAmount),
+    }
+}
+
 pub async fn add_invoice_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-) -> Result<()> {
+) -> Result<(), MostroError> {
     // Get the order message
     let order_msg = msg.get_inner_message_kind();
     // Get the request id
     let request_id = order_msg.request_id;
     // Get the order
-    let mut order = if let Some(order_id) = order_msg.id {
-        match Order::by_id(pool, order_id).await? {
-            Some(order) => order,
-            None => return Err(Error::msg("Order Id {order_id} not found!")),
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+    let mut order = match Order::by_id(pool, order_id).await? {
+        Some(order) => order,
+        None => {
+            error!("Order Id {order_id} not found!");
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
         }
-    } else {
-        return Err(Error::msg("Missing message Id"));
     };
 
     let order_status = match Status::from_str(&order.status) {
         Ok(s) => s,
         Err(e) => {
             error!("Order Id {} wrong status: {e:?}", order.id);
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::InvalidOrderStatus));
         }
     };
 
@@ -45,7 +83,7 @@ pub async fn add_invoice_action(
         Ok(k) => k,
         Err(e) => {
             error!("Order Id {} wrong kind: {e:?}", order.id);
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::InvalidOrderKind));
         }
     };
 
@@ -53,50 +91,41 @@ pub async fn add_invoice_action(
         Some(pk) => PublicKey::from_str(pk)?,
         None => {
             error!("Buyer pubkey not found for order {}!", order.id);
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::InvalidPeer));
         }
     };
     // Only the buyer can add an invoice
     if buyer_pubkey != event.rumor.pubkey {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::InvalidPeer),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidPeer));
+    }
+
+    // Order must be waiting for the buyer invoice
+    if let Err(reason) = check_invoice_applicable(&order, &order_status) {
+        return Err(MostroError::CantDo(reason));
     }
 
     // Invoice variable
     let invoice: String;
     // If a buyer sent me a lightning invoice or a ln address we handle it
     if let Some(payment_request) = order_msg.get_payment_request() {
-        invoice = {
-            // Verify if invoice is valid
-            match is_valid_invoice(
-                payment_request.clone(),
-                Some(order.amount as u64),
-                Some(order.fee as u64),
-            )
-            .await
-            {
-                Ok(_) => payment_request,
-                Err(_) => {
-                    send_cant_do_msg(
-                        request_id,
-                        Some(order.id),
-                        Some(CantDoReason::InvalidAmount),
-                        &event.rumor.pubkey,
-                    )
-                    .await;
-                    return Ok(());
-                }
+        // Bolt11 invoices must match the order, ln addresses are resolved with the right amount
+        if let Ok(bolt11) = decode_invoice(&payment_request) {
+            if let Err(reason) = check_buyer_invoice(&bolt11, &order, Timestamp::now().as_u64()) {
+                return Err(MostroError::CantDo(reason));
             }
-        };
+        }
+        // Verify if invoice is valid, the reason of an invalid invoice
+        // is sent to the buyer by the action dispatcher
+        is_valid_invoice(
+            payment_request.clone(),
+            Some(order.amount as u64),
+            Some(order.fee as u64),
+        )
+        .await?;
+        invoice = payment_request;
     } else {
         error!("Order Id {} wrong get_payment_request", order.id);
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidInvoice));
     }
     // We save the invoice on db
     order.buyer_invoice = Some(invoice);
@@ -118,20 +147,13 @@ pub async fn add_invoice_action(
             return Ok(());
         }
         _ => {
-            send_cant_do_msg(
-                request_id,
-                Some(order.id),
-                Some(CantDoReason::NotAllowedByStatus),
-                &event.rumor.pubkey,
-            )
-            .await;
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
         }
     }
 
     let seller_pubkey = match &order.seller_pubkey {
         Some(seller) => PublicKey::from_str(seller.as_str())?,
-        _ => return Err(Error::msg("Missing pubkeys")),
+        _ => return Err(MostroError::Internal("Missing pubkeys".to_string())),
     };
 
     if order.preimage.is_some() {
@@ -157,7 +179,7 @@ pub async fn add_invoice_action(
         );
         // We publish a new replaceable kind nostr event with the status updated
         // and update on local database the status and new event id
-        if let Ok(order_updated) = update_order_event(my_keys, Status::Active, &order).await {
+        if let Ok(order_updated) = update_order_event(my_keys, Status::Active, &order, pool).await {
             let _ = order_updated.update(pool).await;
         }
 
@@ -183,6 +205,7 @@ pub async fn add_invoice_action(
         .await;
     } else {
         show_hold_invoice(
+            pool,
             my_keys,
             None,
             &buyer_pubkey,
@@ -195,3 +218,88 @@ pub async fn add_invoice_action(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 50000 sats invoice
+    const INVOICE: &str = "lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n";
+
+    fn order_with_amount(amount: i64, fee: i64) -> Order {
+        Order {
+            amount,
+            fee,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_buyer_invoice_matching_order() {
+        let invoice = decode_invoice(INVOICE).unwrap();
+        let created_at = invoice.duration_since_epoch().as_secs();
+        let order = order_with_amount(50_300, 300);
+        assert!(check_buyer_invoice(&invoice, &order, created_at).is_ok());
+    }
+
+    #[test]
+    fn test_buyer_invoice_with_wrong_amount() {
+        let invoice = decode_invoice(INVOICE).unwrap();
+        let created_at = invoice.duration_since_epoch().as_secs();
+        // Fee not discounted
+        let order = order_with_amount(50_000, 300);
+        assert!(matches!(
+            check_buyer_invoice(&invoice, &order, created_at),
+            Err(CantDoReason::InvalidAmount)
+        ));
+        // Fee higher than the order amount
+        let order = order_with_amount(300, 500);
+        assert!(matches!(
+            check_buyer_invoice(&invoice, &order, created_at),
+            Err(CantDoReason::InvalidAmount)
+        ));
+    }
+
+    #[test]
+    fn test_expired_buyer_invoice() {
+        let invoice = decode_invoice(INVOICE).unwrap();
+        let expires_at = invoice.duration_since_epoch().as_secs() + invoice.expiry_time().as_secs();
+        let order = order_with_amount(50_300, 300);
+        assert!(matches!(
+            check_buyer_invoice(&invoice, &order, expires_at + 1),
+            Err(CantDoReason::InvalidInvoice)
+        ));
+    }
+
+    #[test]
+    fn test_invoice_for_applicable_orders() {
+        let order = Order::default();
+        assert!(check_invoice_applicable(&order, &Status::WaitingBuyerInvoice).is_ok());
+        // Buyer payment failed, a new invoice is needed
+        let order = Order {
+            failed_payment: true,
+            ..Default::default()
+        };
+        assert!(check_invoice_applicable(&order, &Status::SettledHoldInvoice).is_ok());
+    }
+
+    #[test]
+    fn test_invoice_for_non_applicable_orders() {
+        let order = Order::default();
+        // Payment to the buyer may be in flight
+        assert!(matches!(
+            check_invoice_applicable(&order, &Status::SettledHoldInvoice),
+            Err(CantDoReason::NotAllowedByStatus)
+        ));
+        for status in [Status::Pending, Status::Active, Status::Success] {
+            assert!(matches!(
+                check_invoice_applicable(&order, &status),
+                Err(CantDoReason::NotAllowedByStatus)
+            ));
+        }
+        assert!(matches!(
+            check_invoice_applicable(&order, &Status::Canceled),
+            Err(CantDoReason::OrderAlreadyCanceled)
+        ));
+    }
+}
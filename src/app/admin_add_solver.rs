@@ -1,5 +1,8 @@
+use crate::cli::settings::Settings;
 use crate::db::add_new_user;
-use crate::util::{send_cant_do_msg, send_dm};
+use crate::error::MostroError;
+use crate::nip33::{new_event, solver_to_tags};
+use crate::util::{get_nostr_client, sanitize_text_message, send_dm};
 
 use anyhow::Result;
 use mostro_core::message::{Action, CantDoReason, Message, Payload};
@@ -14,7 +17,7 @@ pub async fn admin_add_solver_action(
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-) -> Result<()> {
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
 
@@ -23,33 +26,38 @@ pub async fn admin_add_solver_action(
         payload
     } else {
         error!("No pubkey found!");
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
     };
     let npubkey = if let Payload::TextMessage(p) = payload {
         p
     } else {
         error!("No pubkey found!");
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
     };
 
     // Check if the pubkey is Mostro
     if event.rumor.pubkey.to_string() != my_keys.public_key().to_string() {
-        // We create a Message
-        send_cant_do_msg(
-            request_id,
-            None,
-            Some(CantDoReason::InvalidPubkey),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
     }
+    let npubkey = sanitize_text_message(
+        npubkey,
+        Settings::get_mostro().max_text_message_length as usize,
+    )
+    .map_err(MostroError::CantDo)?;
     let trade_index = inner_message.trade_index.unwrap_or(0);
-    let public_key = PublicKey::from_bech32(npubkey)?.to_hex();
-    let user = User::new(public_key, 0, 1, 0, 0, trade_index);
+    let public_key = PublicKey::from_bech32(&npubkey)
+        .map_err(|_| MostroError::CantDo(CantDoReason::InvalidPubkey))?
+        .to_hex();
+    let user = User::new(public_key.clone(), 0, 1, 0, 0, trade_index);
+    let tags = solver_to_tags(&user);
     // Use CRUD to create user
     match add_new_user(pool, user).await {
-        Ok(r) => info!("Solver added: {:#?}", r),
+        Ok(r) => {
+            info!("Solver added: {:#?}", r);
+            if Settings::get_mostro().publish_solver_reputation {
+                publish_solver_reputation(my_keys, &public_key, tags).await;
+            }
+        }
         Err(ee) => error!("Error creating solver: {:#?}", ee),
     }
     // We create a Message for admin
@@ -61,3 +69,24 @@ pub async fn admin_add_solver_action(
 
     Ok(())
 }
+
+/// Publish the reputation event of a new solver, nip33 kind with the solver
+/// pubkey as identifier like the users reputation events
+async fn publish_solver_reputation(my_keys: &Keys, solver: &str, tags: Tags) {
+    let event = match new_event(my_keys, "", solver.to_string(), tags) {
+        Ok(event) => event,
+        Err(e) => {
+            error!("Error creating solver reputation event: {e}");
+            return;
+        }
+    };
+    info!("Solver reputation event to be published: {event:#?}");
+    match get_nostr_client() {
+        Ok(client) => {
+            if let Err(e) = client.send_event(event).await {
+                error!("Error publishing solver reputation event: {e}");
+            }
+        }
+        Err(e) => error!("Error publishing solver reputation event: {e}"),
+    }
+}
@@ -1,12 +1,15 @@
 use std::borrow::Cow;
 use std::str::FromStr;
 
-use crate::db::{find_dispute_by_order_id, is_assigned_solver};
-use crate::lightning::LndConnector;
+use crate::app::admin_settle::vote_dispute_resolution;
+use crate::cli::settings::Settings;
+use crate::db::{find_dispute_by_order_id, is_assigned_solver, take_pending_settlement};
+use crate::error::MostroError;
+use crate::lightning::backend::LightningBackend;
 use crate::nip33::new_event;
-use crate::util::{get_nostr_client, send_cant_do_msg, send_dm, update_order_event};
+use crate::util::{get_required_id, publish_status_event, send_dm, update_order_event};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use mostro_core::dispute::Status as DisputeStatus;
 use mostro_core::message::{Action, CantDoReason, Message, MessageKind};
 use mostro_core::order::{Order, Status};
@@ -14,40 +17,55 @@ use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
 use sqlx::{Pool, Sqlite};
 use sqlx_crud::Crud;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+/// Get the seller and buyer pubkeys of the order, a missing or malformed
+/// pubkey is logged and skipped so the other party can still be notified
+fn get_parties_pubkeys(order: &Order) -> Vec<PublicKey> {
+    [
+        ("seller", &order.seller_pubkey),
+        ("buyer", &order.buyer_pubkey),
+    ]
+    .into_iter()
+    .filter_map(|(party, pubkey)| match pubkey.as_deref() {
+        Some(pubkey) => match PublicKey::from_str(pubkey) {
+            Ok(pk) => Some(pk),
+            Err(e) => {
+                error!("Order Id {}: wrong {party} pubkey: {e}", order.id);
+                None
+            }
+        },
+        None => {
+            warn!(
+                "Order Id {}: missing {party} pubkey, not notified",
+                order.id
+            );
+            None
+        }
+    })
+    .collect()
+}
 
 pub async fn admin_cancel_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
-) -> Result<()> {
+    ln_client: &mut dyn LightningBackend,
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
 
-    let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
-        order_id
-    } else {
-        return Err(Error::msg("No order id"));
-    };
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
     let inner_message = msg.get_inner_message_kind();
 
     match is_assigned_solver(pool, &event.rumor.pubkey.to_string(), order_id).await {
         Ok(false) => {
-            send_cant_do_msg(
-                request_id,
-                Some(order_id),
-                Some(CantDoReason::IsNotYourDispute),
-                &event.rumor.pubkey,
-            )
-            .await;
-
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::IsNotYourDispute));
         }
         Err(e) => {
             error!("Error checking if solver is assigned to order: {:?}", e);
-            return Ok(());
+            return Err(e.into());
         }
         _ => {}
     }
@@ -56,7 +74,7 @@ pub async fn admin_cancel_action(
         Some(order) => order,
         None => {
             error!("Order Id {order_id} not found!");
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
         }
     };
 
@@ -69,24 +87,34 @@ pub async fn admin_cancel_action(
             Action::CooperativeCancelAccepted,
             None,
         );
-        if let Ok(message) = message.as_json() {
-            let sender_keys = crate::util::get_keys().unwrap();
+        if let (Ok(message), Ok(sender_keys)) = (message.as_json(), crate::util::get_keys()) {
             let _ = send_dm(&event.rumor.pubkey, sender_keys, message, None).await;
         }
         return Ok(());
     }
 
     if order.status != Status::Dispute.to_string() {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::NotAllowedByStatus),
-            &event.rumor.pubkey,
-        )
-        .await;
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+    }
 
+    // Several solvers must agree on the cancellation
+    let quorum = Settings::get_mostro().dispute_solvers_quorum as usize;
+    if !vote_dispute_resolution(
+        pool,
+        event,
+        order.id,
+        request_id,
+        Action::AdminCancel,
+        quorum,
+    )
+    .await?
+    {
         return Ok(());
     }
+    // A settlement waiting for its cooldown is dropped, the order is canceled instead
+    if take_pending_settlement(pool, order.id).await? {
+        info!("Order Id {}: scheduled settlement dropped", order.id);
+    }
 
     if order.hash.is_some() {
         // We return funds to seller
@@ -122,19 +150,14 @@ pub async fn admin_cancel_action(
         // nip33 kind with dispute id as identifier
         let event = new_event(my_keys, "", dispute_id.to_string(), tags)?;
 
-        match get_nostr_client() {
-            Ok(client) => {
-                if let Err(e) = client.send_event(event).await {
-                    error!("Failed to send dispute status event: {}", e);
-                }
-            }
-            Err(e) => error!("Failed to get Nostr client: {}", e),
+        if let Err(e) = publish_status_event(event).await {
+            error!("Failed to send dispute status event: {}", e);
         }
     }
 
     // We publish a new replaceable kind nostr event with the status updated
     // and update on local database the status and new event id
-    let order_updated = update_order_event(my_keys, Status::CanceledByAdmin, &order).await?;
+    let order_updated = update_order_event(my_keys, Status::CanceledByAdmin, &order, pool).await?;
     order_updated.update(pool).await?;
     // We create a Message for cancel
     let message = Message::new_order(
@@ -146,20 +169,67 @@ pub async fn admin_cancel_action(
     );
     let message = message.as_json()?;
     // Message to admin
-    let sender_keys = crate::util::get_keys().unwrap();
-    send_dm(&event.rumor.pubkey, sender_keys, message.clone(), None).await?;
-
-    let (seller_pubkey, buyer_pubkey) = match (&order.seller_pubkey, &order.buyer_pubkey) {
-        (Some(seller), Some(buyer)) => (
-            PublicKey::from_str(seller.as_str())?,
-            PublicKey::from_str(buyer.as_str())?,
-        ),
-        (None, _) => return Err(Error::msg("Missing seller pubkey")),
-        (_, None) => return Err(Error::msg("Missing buyer pubkey")),
-    };
-    let sender_keys = crate::util::get_keys().unwrap();
-    send_dm(&seller_pubkey, sender_keys.clone(), message.clone(), None).await?;
-    send_dm(&buyer_pubkey, sender_keys, message, None).await?;
+    let sender_keys = crate::util::get_keys()?;
+    send_dm(
+        &event.rumor.pubkey,
+        sender_keys.clone(),
+        message.clone(),
+        None,
+    )
+    .await?;
+
+    // Message to both parties, a missing party is not notified
+    for pubkey in get_parties_pubkeys(&order) {
+        send_dm(&pubkey, sender_keys.clone(), message.clone(), None).await?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parties_pubkeys_both_present() {
+        let seller = Keys::generate().public_key();
+        let buyer = Keys::generate().public_key();
+        let order = Order {
+            seller_pubkey: Some(seller.to_string()),
+            buyer_pubkey: Some(buyer.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(get_parties_pubkeys(&order), vec![seller, buyer]);
+    }
+
+    #[test]
+    fn test_parties_pubkeys_missing_seller() {
+        let buyer = Keys::generate().public_key();
+        let order = Order {
+            buyer_pubkey: Some(buyer.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(get_parties_pubkeys(&order), vec![buyer]);
+    }
+
+    #[test]
+    fn test_parties_pubkeys_missing_buyer() {
+        let seller = Keys::generate().public_key();
+        let order = Order {
+            seller_pubkey: Some(seller.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(get_parties_pubkeys(&order), vec![seller]);
+    }
+
+    #[test]
+    fn test_parties_pubkeys_malformed() {
+        let seller = Keys::generate().public_key();
+        let order = Order {
+            seller_pubkey: Some(seller.to_string()),
+            buyer_pubkey: Some("npub1wrong".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(get_parties_pubkeys(&order), vec![seller]);
+    }
+}
@@ -0,0 +1,162 @@
+//! This module lets admins and solvers inspect the full record of an order,
+//! with the sensitive fields redacted if configured. It's requested with the
+//! `admin-get-order` command.
+
+use crate::cli::settings::Settings;
+use crate::db::is_assigned_solver;
+use crate::error::MostroError;
+use crate::util::{get_required_id, send_new_order_msg};
+
+use anyhow::Result;
+use mostro_core::message::{CantDoReason, Message, Payload};
+use mostro_core::order::Order;
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use tracing::{error, info};
+use uuid::Uuid;
+
+const REDACTED: &str = "redacted";
+
+/// Mostro admin can inspect any order, a solver only the orders of the
+/// disputes assigned to them, co-solvers of the dispute included
+pub async fn can_inspect_order(
+    pool: &Pool<Sqlite>,
+    pubkey: &PublicKey,
+    admin_pubkey: &PublicKey,
+    order_id: Uuid,
+) -> Result<bool> {
+    if pubkey == admin_pubkey {
+        return Ok(true);
+    }
+    is_assigned_solver(pool, &pubkey.to_hex(), order_id).await
+}
+
+/// Hide the fields that allow to move the funds of the order
+pub fn redact_order(mut order: Order) -> Order {
+    if order.preimage.is_some() {
+        order.preimage = Some(REDACTED.to_string());
+    }
+    if order.buyer_invoice.is_some() {
+        order.buyer_invoice = Some(REDACTED.to_string());
+    }
+
+    order
+}
+
+/// Handler for admins and solvers requesting the full record of an order,
+/// the record is sent as json in a text message
+pub async fn admin_get_order_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    // Get request id
+    let request_id = msg.get_inner_message_kind().request_id;
+
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+
+    let order = match Order::by_id(pool, order_id).await? {
+        Some(order) => order,
+        None => {
+            error!("Order Id {order_id} not found!");
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
+        }
+    };
+
+    if !can_inspect_order(pool, &event.rumor.pubkey, &my_keys.public_key(), order_id).await? {
+        return Err(MostroError::CantDo(CantDoReason::IsNotYourDispute));
+    }
+
+    let order = if Settings::get_mostro().redact_admin_order_fields {
+        redact_order(order)
+    } else {
+        order
+    };
+    info!("Order Id {}: inspected by {}", order.id, event.rumor.pubkey);
+
+    send_new_order_msg(
+        request_id,
+        Some(order.id),
+        msg.get_inner_message_kind().action.clone(),
+        Some(Payload::TextMessage(serde_json::to_string(&order)?)),
+        &event.rumor.pubkey,
+        None,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::add_dispute_solver;
+    use mostro_core::dispute::{Dispute, Status as DisputeStatus};
+
+    #[tokio::test]
+    async fn test_admin_and_assigned_solvers_can_inspect_order() {
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let admin = Keys::generate().public_key();
+        let solver = Keys::generate().public_key();
+        let co_solver = Keys::generate().public_key();
+        let order_id = Uuid::new_v4();
+        let mut dispute = Dispute::new(order_id);
+        dispute.status = DisputeStatus::InProgress.to_string();
+        dispute.solver_pubkey = Some(solver.to_string());
+        dispute.create(&pool).await.unwrap();
+        add_dispute_solver(&pool, order_id, &co_solver.to_hex())
+            .await
+            .unwrap();
+
+        for pubkey in [admin, solver, co_solver] {
+            assert!(can_inspect_order(&pool, &pubkey, &admin, order_id)
+                .await
+                .unwrap());
+        }
+        // The admin can inspect orders without a dispute
+        assert!(can_inspect_order(&pool, &admin, &admin, Uuid::new_v4())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_pubkey_cannot_inspect_order() {
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let admin = Keys::generate().public_key();
+        let solver = Keys::generate().public_key();
+        let other = Keys::generate().public_key();
+        let order_id = Uuid::new_v4();
+        let mut dispute = Dispute::new(order_id);
+        dispute.status = DisputeStatus::InProgress.to_string();
+        dispute.solver_pubkey = Some(solver.to_string());
+        dispute.create(&pool).await.unwrap();
+
+        // Not assigned to the dispute
+        assert!(!can_inspect_order(&pool, &other, &admin, order_id)
+            .await
+            .unwrap());
+        // No dispute on the order
+        assert!(!can_inspect_order(&pool, &solver, &admin, Uuid::new_v4())
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn test_redact_order() {
+        let order = Order {
+            preimage: Some("preimage".to_string()),
+            buyer_invoice: Some("lnbc1".to_string()),
+            hash: Some("hash".to_string()),
+            amount: 1000,
+            ..Default::default()
+        };
+        let order = redact_order(order);
+        assert_eq!(order.preimage.as_deref(), Some(REDACTED));
+        assert_eq!(order.buyer_invoice.as_deref(), Some(REDACTED));
+        assert_eq!(order.hash.as_deref(), Some("hash"));
+        assert_eq!(order.amount, 1000);
+    }
+}
@@ -1,72 +1,211 @@
-use crate::db::{find_dispute_by_order_id, is_assigned_solver};
-use crate::lightning::LndConnector;
+use crate::cli::settings::Settings;
+use crate::db::{
+    abort_pending_settlement, add_pending_settlement, clear_dispute_votes,
+    find_dispute_by_order_id, is_assigned_solver, record_dispute_vote, set_order_settled,
+    take_pending_settlement, PendingSettlement,
+};
+use crate::error::MostroError;
+use crate::lightning::backend::LightningBackend;
+use crate::lightning::reconcile::SettleOutcome;
+use crate::metrics::{increment, Counter};
 use crate::nip33::new_event;
 use crate::util::{
-    get_nostr_client, send_cant_do_msg, send_dm, settle_seller_hold_invoice, update_order_event,
+    cancel_order_without_funds, get_required_id, lock_order_settlement, publish_status_event,
+    send_cant_do_msg, send_dm, settle_seller_hold_invoice, update_order_event,
 };
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use mostro_core::dispute::Status as DisputeStatus;
-use mostro_core::message::{Action, CantDoReason, Message, MessageKind};
+use mostro_core::message::{Action, CantDoReason, Message, MessageKind, Payload};
 use mostro_core::order::{Order, Status};
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
 use sqlx::{Pool, Sqlite};
 use sqlx_crud::Crud;
 use std::str::FromStr;
-use tracing::error;
+use tracing::{error, info};
+use uuid::Uuid;
 
 use super::release::do_payment;
 
-pub async fn admin_settle_action(
+/// Check if the settlement of an order of `amount` sats needs several approvals
+pub fn requires_settle_approval(amount: i64, threshold: u64) -> bool {
+    threshold > 0 && amount > threshold as i64
+}
+
+/// Abort an admin settlement still in its cooldown, the hold invoice is not
+/// settled yet so the order stays in dispute with the seller funds held and
+/// the solvers can resolve it again
+pub async fn admin_abort_settle_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
-) -> Result<()> {
-    // Get request id
+) -> Result<(), MostroError> {
     let request_id = msg.get_inner_message_kind().request_id;
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+    let sender = event.rumor.pubkey;
+    if sender != my_keys.public_key()
+        && !is_assigned_solver(pool, &sender.to_string(), order_id).await?
+    {
+        return Err(MostroError::CantDo(CantDoReason::IsNotYourDispute));
+    }
+    if !abort_pending_settlement(pool, order_id, Timestamp::now().as_u64() as i64).await? {
+        info!("Order Id {order_id}: no settlement in its cooldown to abort");
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+    }
+    info!("Order Id {order_id}: admin settlement aborted by {sender}");
 
-    let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
-        order_id
-    } else {
-        return Err(Error::msg("No order id"));
+    let message = Message::new_dispute(
+        Some(order_id),
+        request_id,
+        None,
+        Action::AdminSettle,
+        Some(Payload::TextMessage(
+            "Settlement aborted, the order is still in dispute".to_string(),
+        )),
+    );
+    send_dm(&sender, my_keys.clone(), message.as_json()?, None).await?;
+
+    Ok(())
+}
+
+/// Settle an order whose settlement cooldown ended, unless it was aborted
+pub async fn settle_after_cooldown(
+    pool: &Pool<Sqlite>,
+    my_keys: &Keys,
+    ln_client: &mut dyn LightningBackend,
+    settlement: PendingSettlement,
+) -> Result<()> {
+    if !take_pending_settlement(pool, settlement.order_id).await? {
+        return Ok(());
+    }
+    let Some(order) = Order::by_id(pool, settlement.order_id).await? else {
+        return Ok(());
     };
-    let inner_message = msg.get_inner_message_kind();
+    let admin_pubkey = PublicKey::from_str(&settlement.admin_pubkey)?;
+    let request_id = settlement.request_id.map(|id| id as u64);
+    if let Err(e) = settle_order(pool, my_keys, ln_client, order, request_id, &admin_pubkey).await {
+        // The admin is told why the scheduled settlement was not done
+        send_cant_do_msg(
+            request_id,
+            Some(settlement.order_id),
+            e.cant_do_reason(),
+            &admin_pubkey,
+        )
+        .await;
+        return Err(e.into());
+    }
 
-    match is_assigned_solver(pool, &event.rumor.pubkey.to_string(), order_id).await {
-        Ok(false) => {
-            send_cant_do_msg(
-                request_id,
-                Some(order_id),
-                Some(CantDoReason::IsNotYourDispute),
-                &event.rumor.pubkey,
-            )
-            .await;
+    Ok(())
+}
 
-            return Ok(());
-        }
-        Err(e) => {
-            error!("Error checking if solver is assigned to order: {:?}", e);
-            return Ok(());
-        }
-        _ => {}
+/// Check if the votes for a resolution reach the quorum, a quorum of
+/// 0 or 1 means a single solver resolves the dispute
+pub fn has_quorum(votes: usize, quorum: usize) -> bool {
+    votes >= quorum
+}
+
+/// Record the vote of a solver to resolve a disputed order and acknowledge
+/// it, returns true when the resolution reached the `required` votes and must
+/// be done, the votes are forgotten then. Up to 1 vote a single solver
+/// resolves the dispute
+pub async fn vote_dispute_resolution(
+    pool: &Pool<Sqlite>,
+    event: &UnwrappedGift,
+    order_id: Uuid,
+    request_id: Option<u64>,
+    action: Action,
+    required: usize,
+) -> Result<bool> {
+    if required <= 1 {
+        return Ok(true);
+    }
+    let dispute = find_dispute_by_order_id(pool, order_id).await?;
+    let vote = action.to_string();
+    let votes =
+        record_dispute_vote(pool, dispute.id, &event.rumor.pubkey.to_string(), &vote).await?;
+    info!(
+        "Order Id {}: {} voted {}, {} of {} votes",
+        order_id, event.rumor.pubkey, vote, votes, required
+    );
+    if has_quorum(votes, required) {
+        clear_dispute_votes(pool, dispute.id).await?;
+        return Ok(true);
     }
+    let message = Message::new_dispute(
+        Some(order_id),
+        request_id,
+        None,
+        action,
+        Some(Payload::TextMessage(format!(
+            "Vote recorded, {votes} of {required} votes needed"
+        ))),
+    );
+    let sender_keys = crate::util::get_keys()?;
+    send_dm(&event.rumor.pubkey, sender_keys, message.as_json()?, None).await?;
+
+    Ok(false)
+}
+
+/// Votes needed to settle a disputed order, high value settlements need the
+/// approvals required when they are more than the solvers quorum
+pub fn required_settle_votes(quorum: u32, needs_approval: bool, approvals_required: u32) -> usize {
+    if needs_approval {
+        quorum.max(approvals_required) as usize
+    } else {
+        quorum as usize
+    }
+}
+
+/// Check if a dispute was opened at least `min_age` seconds ago
+pub fn is_dispute_old_enough(created_at: i64, now: i64, min_age: i64) -> bool {
+    now - created_at >= min_age
+}
+
+pub async fn admin_settle_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+    ln_client: &mut dyn LightningBackend,
+) -> Result<(), MostroError> {
+    let inner_message = msg.get_inner_message_kind();
+    // Get request id
+    let request_id = inner_message.request_id;
+
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
 
     let order = match Order::by_id(pool, order_id).await? {
         Some(order) => order,
         None => {
             error!("Order Id {order_id} not found!");
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
+        }
+    };
+
+    let mostro_settings = Settings::get_mostro();
+    let needs_approval =
+        requires_settle_approval(order.amount, mostro_settings.settle_approval_amount);
+
+    // Only the solvers assigned to the dispute settle it or approve its settlement
+    let allowed = match is_assigned_solver(pool, &event.rumor.pubkey.to_string(), order_id).await {
+        Ok(allowed) => allowed,
+        Err(e) => {
+            error!("Error checking if solver is assigned to order: {:?}", e);
+            return Err(e.into());
         }
     };
+    if !allowed {
+        return Err(MostroError::CantDo(CantDoReason::IsNotYourDispute));
+    }
 
-    // Was orde cooperatively cancelled?
+    // A cooperatively cancelled order is never settled, the admin is
+    // notified and the action ends here
     if order.status == Status::CooperativelyCanceled.to_string() {
         let message = MessageKind::new(
             Some(order_id),
-            msg.get_inner_message_kind().request_id,
+            request_id,
             inner_message.trade_index,
             Action::CooperativeCancelAccepted,
             None,
@@ -79,19 +218,104 @@ pub async fn admin_settle_action(
     }
 
     if order.status != Status::Dispute.to_string() {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::NotAllowedByStatus),
-            &event.rumor.pubkey,
-        )
-        .await;
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+    }
 
-        return Ok(());
+    // Give the dispute a minimum investigation period before settling it
+    let min_age = mostro_settings.min_dispute_age_before_settle as i64;
+    if min_age > 0 {
+        if let Ok(dispute) = find_dispute_by_order_id(pool, order_id).await {
+            if !is_dispute_old_enough(
+                dispute.created_at,
+                Timestamp::now().as_u64() as i64,
+                min_age,
+            ) {
+                info!("Order Id {}: dispute too recent to be settled", order.id);
+                return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+            }
+        }
     }
 
-    settle_seller_hold_invoice(
+    // Several solvers must agree on the settlement, high value ones may need more
+    let required = required_settle_votes(
+        mostro_settings.dispute_solvers_quorum,
+        needs_approval,
+        mostro_settings.settle_approvals_required,
+    );
+    if !vote_dispute_resolution(
+        pool,
         event,
+        order.id,
+        request_id,
+        Action::AdminSettle,
+        required,
+    )
+    .await?
+    {
+        return Ok(());
+    }
+
+    // The settlement waits for its cooldown, it can be aborted meanwhile
+    let cooldown = mostro_settings.settle_payout_cooldown_seconds as i64;
+    if cooldown > 0 {
+        let settle_at = Timestamp::now().as_u64() as i64 + cooldown;
+        let admin_pubkey = event.rumor.pubkey.to_string();
+        if add_pending_settlement(pool, order.id, &admin_pubkey, request_id, settle_at).await? {
+            info!(
+                "Order Id {}: settlement scheduled in {} seconds",
+                order.id, cooldown
+            );
+            let message = Message::new_dispute(
+                Some(order.id),
+                request_id,
+                None,
+                Action::AdminSettle,
+                Some(Payload::TextMessage(format!(
+                    "Settlement scheduled in {cooldown} seconds, send the admin-abort-settle command to abort it"
+                ))),
+            );
+            send_dm(
+                &event.rumor.pubkey,
+                my_keys.clone(),
+                message.as_json()?,
+                None,
+            )
+            .await?;
+        }
+        return Ok(());
+    }
+
+    settle_order(
+        pool,
+        my_keys,
+        ln_client,
+        order,
+        request_id,
+        &event.rumor.pubkey,
+    )
+    .await
+}
+
+/// Settle the hold invoice of a disputed order, let the parties and the admin
+/// know and pay the buyer
+async fn settle_order(
+    pool: &Pool<Sqlite>,
+    my_keys: &Keys,
+    ln_client: &mut dyn LightningBackend,
+    mut order: Order,
+    request_id: Option<u64>,
+    admin_pubkey: &PublicKey,
+) -> Result<(), MostroError> {
+    let order_id = order.id;
+    // A seller release of the same order may be running, only one of them
+    // settles it, the order may also be resolved during the cooldown
+    let _settle_lock = match lock_order_settlement(pool, order.id).await? {
+        Some((lock, locked)) if locked.status == Status::Dispute.to_string() => lock,
+        _ => return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus)),
+    };
+
+    let outcome = settle_seller_hold_invoice(
+        admin_pubkey,
         ln_client,
         Action::AdminSettled,
         true,
@@ -99,8 +323,20 @@ pub async fn admin_settle_action(
         request_id,
     )
     .await?;
+    if outcome == SettleOutcome::Canceled {
+        cancel_order_without_funds(pool, my_keys, &order).await?;
+        return Ok(());
+    }
+    // Record the settlement before anything else can fail
+    if !set_order_settled(pool, order.id).await? {
+        info!("Order Id {}: already settled, no payout", order.id);
+        return Ok(());
+    }
+    order.status = Status::SettledHoldInvoice.to_string();
+    increment(Counter::OrdersSettled);
 
-    let order_updated = update_order_event(my_keys, Status::SettledHoldInvoice, &order).await?;
+    let order_updated =
+        update_order_event(my_keys, Status::SettledHoldInvoice, &order, pool).await?;
 
     // we check if there is a dispute
     let dispute = find_dispute_by_order_id(pool, order_id).await;
@@ -129,35 +365,22 @@ pub async fn admin_settle_action(
         // nip33 kind with dispute id as identifier
         let event = new_event(my_keys, "", dispute_id.to_string(), tags)?;
 
-        match get_nostr_client() {
-            Ok(client) => {
-                if let Err(e) = client.send_event(event).await {
-                    error!("Failed to send dispute settlement event: {}", e);
-                }
-            }
-            Err(e) => {
-                error!("Failed to get Nostr client for dispute settlement: {}", e);
-            }
+        if let Err(e) = publish_status_event(event).await {
+            error!("Failed to send dispute settlement event: {}", e);
         }
     }
     // We create a Message for settle
     let message = Message::new_order(
         Some(order_updated.id),
         request_id,
-        inner_message.trade_index,
+        None,
         Action::AdminSettled,
         None,
     );
     let message = message.as_json()?;
     // Message to admin
     let sender_keys = crate::util::get_keys().unwrap();
-    send_dm(
-        &event.rumor.pubkey,
-        sender_keys.clone(),
-        message.clone(),
-        None,
-    )
-    .await?;
+    send_dm(admin_pubkey, sender_keys.clone(), message.clone(), None).await?;
     if let Some(ref seller_pubkey) = order_updated.seller_pubkey {
         send_dm(
             &PublicKey::from_str(seller_pubkey)?,
@@ -176,7 +399,47 @@ pub async fn admin_settle_action(
         )
         .await?;
     }
-    let _ = do_payment(order_updated, request_id).await;
+    let _ = do_payment(order_updated, request_id, my_keys, pool).await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispute_resolution_quorum() {
+        // 2 of 3 solvers
+        assert!(!has_quorum(1, 2));
+        assert!(has_quorum(2, 2));
+        assert!(has_quorum(3, 2));
+    }
+
+    #[test]
+    fn test_high_value_settlement_needs_approval() {
+        assert!(requires_settle_approval(2_000_000, 1_000_000));
+        assert_eq!(required_settle_votes(0, true, 2), 2);
+        // The solvers quorum applies when higher than the approvals
+        assert_eq!(required_settle_votes(3, true, 2), 3);
+        assert_eq!(required_settle_votes(1, false, 2), 1);
+        // Low value settlements don't need approvals
+        assert!(!requires_settle_approval(1_000_000, 1_000_000));
+        assert!(!requires_settle_approval(2_000_000, 0));
+    }
+
+    #[test]
+    fn test_settle_before_min_dispute_age_rejected() {
+        let created_at = 1_700_000_000;
+        assert!(!is_dispute_old_enough(created_at, created_at + 600, 3600));
+    }
+
+    #[test]
+    fn test_settle_after_min_dispute_age_allowed() {
+        let created_at = 1_700_000_000;
+        assert!(is_dispute_old_enough(created_at, created_at + 3600, 3600));
+        assert!(is_dispute_old_enough(created_at, created_at + 7200, 3600));
+        // No minimum age
+        assert!(is_dispute_old_enough(created_at, created_at, 0));
+    }
+}
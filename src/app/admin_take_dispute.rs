@@ -1,8 +1,12 @@
-use crate::db::find_solver_pubkey;
+use crate::app::confirm_receipt::get_receipt_dispute_note;
+use crate::app::dispute_evidence::get_dispute_evidence_messages;
+use crate::cli::settings::Settings;
+use crate::db::{add_dispute_solver, find_solver_pubkey, pubkeys_match};
+use crate::error::MostroError;
 use crate::nip33::new_event;
-use crate::util::{get_nostr_client, send_cant_do_msg, send_dm};
+use crate::util::{get_required_id, publish_status_event, send_dm};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use mostro_core::dispute::{Dispute, Status};
 use mostro_core::message::{Action, CantDoReason, Message, Payload, Peer};
 use mostro_core::order::Order;
@@ -17,6 +21,7 @@ pub async fn pubkey_event_can_solve(
     pool: &Pool<Sqlite>,
     ev_pubkey: &PublicKey,
     status: Status,
+    multi_solver: bool,
 ) -> bool {
     if let Ok(my_keys) = crate::util::get_keys() {
         // Is mostro admin taking dispute?
@@ -29,7 +34,10 @@ pub async fn pubkey_event_can_solve(
 
     // Is a solver taking a dispute
     if let Ok(solver) = find_solver_pubkey(pool, ev_pubkey.to_string()).await {
-        if solver.is_solver != 0_i64 && status == Status::Initiated {
+        // With a quorum of solvers a dispute in progress can be joined
+        let can_take =
+            status == Status::Initiated || (multi_solver && status == Status::InProgress);
+        if solver.is_solver != 0_i64 && can_take {
             return true;
         }
     }
@@ -37,57 +45,84 @@ pub async fn pubkey_event_can_solve(
     false
 }
 
+/// Check if the dispute is already assigned to a solver different from `pubkey`
+pub fn is_taken_by_other_solver(dispute: &Dispute, pubkey: &str) -> bool {
+    match dispute.solver_pubkey.as_deref() {
+        Some(solver) => {
+            dispute.status == Status::InProgress.to_string() && !pubkeys_match(solver, pubkey)
+        }
+        None => false,
+    }
+}
+
 pub async fn admin_take_dispute_action(
     msg: Message,
     event: &UnwrappedGift,
     pool: &Pool<Sqlite>,
-) -> Result<()> {
+) -> Result<(), MostroError> {
+    take_dispute(msg, event, pool, false).await
+}
+
+/// Mostro admin taking over a dispute already assigned to another solver
+pub async fn admin_reassign_dispute_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    if event.rumor.pubkey != my_keys.public_key() {
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
+    }
+    take_dispute(msg, event, pool, true).await
+}
+
+async fn take_dispute(
+    msg: Message,
+    event: &UnwrappedGift,
+    pool: &Pool<Sqlite>,
+    reassign: bool,
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
 
     // Find dipute id in the message
-    let dispute_id = if let Some(dispute_id) = msg.get_inner_message_kind().id {
-        dispute_id
-    } else {
-        return Err(Error::msg("No order id"));
-    };
+    let dispute_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
 
     // Fetch dispute from db
     let mut dispute = match Dispute::by_id(pool, dispute_id).await? {
         Some(dispute) => dispute,
         None => {
-            send_cant_do_msg(
-                request_id,
-                Some(dispute_id),
-                Some(CantDoReason::NotFound),
-                &event.rumor.pubkey,
-            )
-            .await;
-
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
         }
     };
 
+    let multi_solver = Settings::get_mostro().dispute_solvers_quorum > 1;
     // Check if the pubkey is a solver or admin
     if let Ok(dispute_status) = Status::from_str(&dispute.status) {
-        if !pubkey_event_can_solve(pool, &event.rumor.pubkey, dispute_status).await {
+        if !pubkey_event_can_solve(pool, &event.rumor.pubkey, dispute_status, multi_solver).await {
             // We create a Message
-            send_cant_do_msg(
-                request_id,
-                Some(dispute_id),
-                Some(CantDoReason::InvalidPubkey),
-                &event.rumor.pubkey,
-            )
-            .await;
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
         }
     } else {
-        return Err(Error::msg("No dispute status"));
+        return Err(MostroError::Internal("No dispute status".to_string()));
     };
 
+    // Don't silently overwrite the solver of a dispute already taken, with a
+    // quorum of solvers the taker joins the assigned solvers unless the admin
+    // is reassigning it
+    let taken_by_other = is_taken_by_other_solver(&dispute, &event.rumor.pubkey.to_string());
+    let co_solver = taken_by_other && multi_solver && !reassign;
+    if taken_by_other && !co_solver && !reassign {
+        info!(
+            "Dispute {} is already assigned to solver {:?}",
+            dispute_id, dispute.solver_pubkey
+        );
+        return Err(MostroError::CantDo(CantDoReason::IsNotYourDispute));
+    }
+
     let order = match Order::by_id(pool, dispute.order_id).await? {
         Some(o) => o,
-        None => return Err(Error::msg("No order id")),
+        None => return Err(MostroError::Internal("No order id".to_string())),
     };
 
     let mut new_order = order.as_new_order();
@@ -99,17 +134,24 @@ pub async fn admin_take_dispute_action(
         .seller_trade_pubkey
         .clone_from(&order.master_seller_pubkey);
 
-    // Update dispute fields
-    dispute.status = Status::InProgress.to_string();
-    dispute.solver_pubkey = Some(event.rumor.pubkey.to_string());
-    dispute.taken_at = Timestamp::now().as_u64() as i64;
-
-    info!("Dispute {} taken by {}", dispute_id, event.rumor.pubkey);
     // Assign token for admin message
     new_order.seller_token = dispute.seller_token;
     new_order.buyer_token = dispute.buyer_token;
-    // Save it to DB
-    dispute.update(pool).await?;
+    if co_solver {
+        info!("Dispute {} joined by {}", dispute_id, event.rumor.pubkey);
+    } else {
+        // Update dispute fields
+        dispute.status = Status::InProgress.to_string();
+        dispute.solver_pubkey = Some(event.rumor.pubkey.to_string());
+        dispute.taken_at = Timestamp::now().as_u64() as i64;
+
+        info!("Dispute {} taken by {}", dispute_id, event.rumor.pubkey);
+        // Save it to DB
+        dispute.update(pool).await?;
+    }
+    if multi_solver {
+        add_dispute_solver(pool, order.id, &event.rumor.pubkey.to_string()).await?;
+    }
 
     // We create a Message for admin
     let message = Message::new_dispute(
@@ -122,6 +164,34 @@ pub async fn admin_take_dispute_action(
     let message = message.as_json()?;
     let sender_keys = crate::util::get_keys().unwrap();
     send_dm(&event.rumor.pubkey, sender_keys, message, None).await?;
+    // Let the solver know if the buyer confirmed the receipt
+    if let Some(note) = get_receipt_dispute_note(pool, order.id).await {
+        let message = Message::new_dispute(
+            Some(dispute_id),
+            request_id,
+            None,
+            Action::AdminTookDispute,
+            Some(Payload::TextMessage(note)),
+        );
+        let sender_keys = crate::util::get_keys()?;
+        send_dm(&event.rumor.pubkey, sender_keys, message.as_json()?, None).await?;
+    }
+    // Send the solver all the evidence submitted by the parties
+    for evidence in get_dispute_evidence_messages(pool, dispute_id, &order).await {
+        let message = Message::new_dispute(
+            Some(dispute_id),
+            request_id,
+            None,
+            Action::AdminTookDispute,
+            Some(Payload::TextMessage(evidence)),
+        );
+        let sender_keys = crate::util::get_keys()?;
+        send_dm(&event.rumor.pubkey, sender_keys, message.as_json()?, None).await?;
+    }
+    // Parties keep talking with the first solver of the dispute
+    if co_solver {
+        return Ok(());
+    }
     // Now we create a message to both parties of the order
     // to them know who will assist them on the dispute
     let solver_pubkey = Peer::new(event.rumor.pubkey.to_hex());
@@ -146,8 +216,8 @@ pub async fn admin_take_dispute_action(
             PublicKey::from_str(seller.as_str())?,
             PublicKey::from_str(buyer.as_str())?,
         ),
-        (None, _) => return Err(Error::msg("Missing seller pubkey")),
-        (_, None) => return Err(Error::msg("Missing buyer pubkey")),
+        (None, _) => return Err(MostroError::Internal("Missing seller pubkey".to_string())),
+        (_, None) => return Err(MostroError::Internal("Missing buyer pubkey".to_string())),
     };
     let sender_keys = crate::util::get_keys().unwrap();
     send_dm(
@@ -177,18 +247,64 @@ pub async fn admin_take_dispute_action(
     let event = new_event(&crate::util::get_keys()?, "", dispute_id.to_string(), tags)?;
     info!("Dispute event to be published: {event:#?}");
 
-    let client = get_nostr_client().map_err(|e| {
-        info!(
-            "Failed to get nostr client for dispute {}: {}",
-            dispute_id, e
-        );
-        e
-    })?;
-
-    client.send_event(event).await.map_err(|e| {
+    publish_status_event(event).await.map_err(|e| {
         info!("Failed to send dispute {} status event: {}", dispute_id, e);
         e
     })?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_dispute_taken_by_other_solver() {
+        let solver_keys = Keys::generate();
+        let solver = solver_keys.public_key().to_string();
+        let other_solver = Keys::generate().public_key().to_string();
+        let mut dispute = Dispute::new(Uuid::new_v4());
+        dispute.status = Status::InProgress.to_string();
+        dispute.solver_pubkey = Some(solver.clone());
+
+        // Another solver can't take it
+        assert!(is_taken_by_other_solver(&dispute, &other_solver));
+        // The assigned solver can take it again
+        assert!(!is_taken_by_other_solver(&dispute, &solver));
+        // Whatever the encoding of its pubkey
+        let npub = solver_keys.public_key().to_bech32().unwrap();
+        assert!(!is_taken_by_other_solver(&dispute, &npub));
+    }
+
+    #[tokio::test]
+    async fn test_reassign_dispute_only_by_admin() {
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let mostro_keys = Keys::generate();
+        let solver_keys = Keys::generate();
+        let msg = Message::new_dispute(
+            Some(Uuid::new_v4()),
+            None,
+            None,
+            Action::AdminTakeDispute,
+            None,
+        );
+        let event = UnwrappedGift {
+            sender: solver_keys.public_key(),
+            rumor: EventBuilder::text_note("").build(solver_keys.public_key()),
+        };
+        let result = admin_reassign_dispute_action(msg, &event, &mostro_keys, &pool).await;
+        assert!(matches!(
+            result,
+            Err(MostroError::CantDo(CantDoReason::InvalidPubkey))
+        ));
+    }
+
+    #[test]
+    fn test_dispute_not_taken() {
+        let solver = Keys::generate().public_key().to_string();
+        let dispute = Dispute::new(Uuid::new_v4());
+        assert!(!is_taken_by_other_solver(&dispute, &solver));
+    }
+}
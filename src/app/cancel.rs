@@ -2,12 +2,13 @@ use crate::db::{
     edit_buyer_pubkey_order, edit_master_buyer_pubkey_order, edit_master_seller_pubkey_order,
     edit_seller_pubkey_order, find_order_by_id, update_order_to_initial_state,
 };
-use crate::lightning::LndConnector;
-use crate::util::{send_cant_do_msg, send_new_order_msg, update_order_event};
+use crate::error::MostroError;
+use crate::lightning::backend::LightningBackend;
+use crate::util::{get_required_id, send_new_order_msg, update_order_event};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use mostro_core::message::{Action, CantDoReason, Message};
-use mostro_core::order::{Kind as OrderKind, Status};
+use mostro_core::order::{Kind as OrderKind, Order, Status};
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
 use sqlx::{Pool, Sqlite};
@@ -15,28 +16,59 @@ use sqlx_crud::Crud;
 use std::str::FromStr;
 use tracing::{error, info};
 
+/// Step of the cooperative cancel of an active order
+#[derive(Debug, PartialEq, Eq)]
+pub enum CooperativeCancel {
+    /// A party asked to cancel, the order stays active until the other confirms
+    Initiated,
+    /// Both parties asked to cancel, the order is canceled
+    Accepted,
+}
+
+/// Record the cancel request of `user` on an active order, only a party of the
+/// order can ask for it and a party asking twice doesn't cancel it alone
+pub fn cooperative_cancel_step(
+    order: &mut Order,
+    user: &str,
+) -> Result<CooperativeCancel, CantDoReason> {
+    if order.buyer_pubkey.as_deref() == Some(user) {
+        order.buyer_cooperativecancel = true;
+    } else if order.seller_pubkey.as_deref() == Some(user) {
+        order.seller_cooperativecancel = true;
+    } else {
+        return Err(CantDoReason::IsNotYourOrder);
+    }
+    match order.cancel_initiator_pubkey.as_deref() {
+        Some(initiator) if initiator == user => Err(CantDoReason::NotAllowedByStatus),
+        Some(_) => {
+            order.status = Status::CooperativelyCanceled.to_string();
+            Ok(CooperativeCancel::Accepted)
+        }
+        None => {
+            order.cancel_initiator_pubkey = Some(user.to_string());
+            Ok(CooperativeCancel::Initiated)
+        }
+    }
+}
+
 pub async fn cancel_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
-) -> Result<()> {
+    ln_client: &mut dyn LightningBackend,
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
 
-    let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
-        order_id
-    } else {
-        return Err(Error::msg("No order id"));
-    };
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
     let user_pubkey = event.rumor.pubkey.to_string();
 
     let mut order = match find_order_by_id(pool, order_id, &user_pubkey).await {
         Ok(order) => order,
         Err(_) => {
             error!("Order Id {order_id} not found for user with pubkey: {user_pubkey}");
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
         }
     };
 
@@ -44,43 +76,30 @@ pub async fn cancel_action(
         || order.status == Status::CooperativelyCanceled.to_string()
         || order.status == Status::CanceledByAdmin.to_string()
     {
-        send_cant_do_msg(
-            request_id,
-            Some(order_id),
-            Some(CantDoReason::OrderAlreadyCanceled),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::OrderAlreadyCanceled));
     }
 
     if order.status == Status::Pending.to_string() {
         // Validates if this user is the order creator
         if user_pubkey != order.creator_pubkey {
-            send_cant_do_msg(
-                request_id,
-                Some(order.id),
-                Some(CantDoReason::IsNotYourOrder),
-                &event.rumor.pubkey,
-            )
-            .await;
-        } else {
-            // We publish a new replaceable kind nostr event with the status updated
-            // and update on local database the status and new event id
-            if let Ok(order_updated) = update_order_event(my_keys, Status::Canceled, &order).await {
-                let _ = order_updated.update(pool).await;
-            }
-            // We create a Message for cancel
-            send_new_order_msg(
-                request_id,
-                Some(order.id),
-                Action::Canceled,
-                None,
-                &event.rumor.pubkey,
-                None,
-            )
-            .await;
+            return Err(MostroError::CantDo(CantDoReason::IsNotYourOrder));
+        }
+        // We publish a new replaceable kind nostr event with the status updated
+        // and update on local database the status and new event id
+        if let Ok(order_updated) = update_order_event(my_keys, Status::Canceled, &order, pool).await
+        {
+            let _ = order_updated.update(pool).await;
         }
+        // We create a Message for cancel
+        send_new_order_msg(
+            request_id,
+            Some(order.id),
+            Action::Canceled,
+            None,
+            &event.rumor.pubkey,
+            None,
+        )
+        .await;
 
         return Ok(());
     }
@@ -90,8 +109,8 @@ pub async fn cancel_action(
     {
         let (seller_pubkey, buyer_pubkey) = match (&order.seller_pubkey, &order.buyer_pubkey) {
             (Some(seller), Some(buyer)) => (seller, buyer),
-            (None, _) => return Err(Error::msg("Missing seller pubkey")),
-            (_, None) => return Err(Error::msg("Missing buyer pubkey")),
+            (None, _) => return Err(MostroError::Internal("Missing seller pubkey".to_string())),
+            (_, None) => return Err(MostroError::Internal("Missing buyer pubkey".to_string())),
         };
 
         let taker_pubkey: String = if seller_pubkey == &order.creator_pubkey {
@@ -101,7 +120,9 @@ pub async fn cancel_action(
         };
 
         if user_pubkey == order.creator_pubkey {
-            if let Ok(order_updated) = update_order_event(my_keys, Status::Canceled, &order).await {
+            if let Ok(order_updated) =
+                update_order_event(my_keys, Status::Canceled, &order, pool).await
+            {
                 let _ = order_updated.update(pool).await;
             }
 
@@ -159,7 +180,7 @@ pub async fn cancel_action(
                 edit_seller_pubkey_order(pool, order.id, None).await?;
                 edit_master_seller_pubkey_order(pool, order.id, None).await?;
                 update_order_to_initial_state(pool, order.id, order.amount, order.fee).await?;
-                update_order_event(my_keys, Status::Pending, &order).await?;
+                update_order_event(my_keys, Status::Pending, &order, pool).await?;
                 info!(
                     "{}: Canceled order Id {} republishing order",
                     buyer_pubkey, order.id
@@ -185,7 +206,7 @@ pub async fn cancel_action(
                 edit_buyer_pubkey_order(pool, order.id, None).await?;
                 edit_master_buyer_pubkey_order(pool, order.id, None).await?;
                 update_order_to_initial_state(pool, order.id, order.amount, order.fee).await?;
-                update_order_event(my_keys, Status::Pending, &order).await?;
+                update_order_event(my_keys, Status::Pending, &order, pool).await?;
                 info!(
                     "{}: Canceled order Id {} republishing order",
                     buyer_pubkey, order.id
@@ -202,8 +223,7 @@ pub async fn cancel_action(
             )
             .await;
         } else {
-            send_cant_do_msg(request_id, Some(order.id), None, &event.rumor.pubkey).await;
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::IsNotYourOrder));
         }
     }
 
@@ -213,66 +233,59 @@ pub async fn cancel_action(
     {
         let (seller_pubkey, buyer_pubkey) = match (&order.seller_pubkey, &order.buyer_pubkey) {
             (Some(seller), Some(buyer)) => (seller, buyer),
-            (None, _) => return Err(Error::msg("Missing seller pubkey")),
-            (_, None) => return Err(Error::msg("Missing buyer pubkey")),
+            (None, _) => return Err(MostroError::Internal("Missing seller pubkey".to_string())),
+            (_, None) => return Err(MostroError::Internal("Missing buyer pubkey".to_string())),
         };
 
-        let counterparty_pubkey: String;
-        if buyer_pubkey == &user_pubkey {
-            order.buyer_cooperativecancel = true;
-            counterparty_pubkey = seller_pubkey.to_string();
+        let counterparty_pubkey = if buyer_pubkey == &user_pubkey {
+            seller_pubkey.to_string()
         } else {
-            order.seller_cooperativecancel = true;
-            counterparty_pubkey = buyer_pubkey.to_string();
-        }
+            buyer_pubkey.to_string()
+        };
+        let counterparty_pubkey = PublicKey::from_str(&counterparty_pubkey)?;
 
-        match order.cancel_initiator_pubkey {
-            Some(ref initiator_pubkey) => {
-                if initiator_pubkey == &user_pubkey {
-                    // We create a Message
-                    send_cant_do_msg(request_id, Some(order_id), None, &event.rumor.pubkey).await;
-                    return Ok(());
-                } else {
-                    if let Some(hash) = &order.hash {
-                        // We return funds to seller
-                        ln_client.cancel_hold_invoice(hash).await?;
-                        info!(
-                            "Cooperative cancel: Order Id {}: Funds returned to seller",
-                            &order.id
-                        );
-                    }
-                    order.status = Status::CooperativelyCanceled.to_string();
-                    // update db
-                    let order = order.update(pool).await?;
-                    // We publish a new replaceable kind nostr event with the status updated
-                    // and update on local database the status and new event id
-                    update_order_event(my_keys, Status::CooperativelyCanceled, &order).await?;
-                    // We create a Message for an accepted cooperative cancel and send it to both parties
-                    send_new_order_msg(
-                        request_id,
-                        Some(order.id),
-                        Action::CooperativeCancelAccepted,
-                        None,
-                        &event.rumor.pubkey,
-                        None,
-                    )
-                    .await;
-                    let counterparty_pubkey = PublicKey::from_str(&counterparty_pubkey)?;
-                    send_new_order_msg(
-                        None,
-                        Some(order.id),
-                        Action::CooperativeCancelAccepted,
-                        None,
-                        &counterparty_pubkey,
-                        None,
-                    )
-                    .await;
-                    info!("Cancel: Order Id {order_id} canceled cooperatively!");
+        match cooperative_cancel_step(&mut order, &user_pubkey) {
+            Err(reason) => {
+                return Err(MostroError::CantDo(reason));
+            }
+            Ok(CooperativeCancel::Accepted) => {
+                if let Some(hash) = &order.hash {
+                    // We return funds to seller
+                    ln_client.cancel_hold_invoice(hash).await?;
+                    info!(
+                        "Cooperative cancel: Order Id {}: Funds returned to seller",
+                        &order.id
+                    );
                 }
+                // We publish a new replaceable kind nostr event with the status updated
+                // and update on local database the status and new event id
+                let order =
+                    update_order_event(my_keys, Status::CooperativelyCanceled, &order, pool)
+                        .await?;
+                order.update(pool).await?;
+                // We create a Message for an accepted cooperative cancel and send it to both parties
+                send_new_order_msg(
+                    request_id,
+                    Some(order.id),
+                    Action::CooperativeCancelAccepted,
+                    None,
+                    &event.rumor.pubkey,
+                    None,
+                )
+                .await;
+                send_new_order_msg(
+                    None,
+                    Some(order.id),
+                    Action::CooperativeCancelAccepted,
+                    None,
+                    &counterparty_pubkey,
+                    None,
+                )
+                .await;
+                info!("Cancel: Order Id {order_id} canceled cooperatively!");
             }
-            None => {
-                order.cancel_initiator_pubkey = Some(user_pubkey.clone());
-                // update db
+            Ok(CooperativeCancel::Initiated) => {
+                // The order stays active until the counterparty confirms
                 let order = order.update(pool).await?;
                 // We create a Message to start a cooperative cancel and send it to both parties
                 send_new_order_msg(
@@ -284,7 +297,6 @@ pub async fn cancel_action(
                     None,
                 )
                 .await;
-                let counterparty_pubkey = PublicKey::from_str(&counterparty_pubkey)?;
                 send_new_order_msg(
                     None,
                     Some(order.id),
@@ -299,3 +311,64 @@ pub async fn cancel_action(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active_order() -> Order {
+        Order {
+            status: Status::Active.to_string(),
+            buyer_pubkey: Some("buyer".to_string()),
+            seller_pubkey: Some("seller".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cooperative_cancel_buyer_first() {
+        let mut order = active_order();
+        assert_eq!(
+            cooperative_cancel_step(&mut order, "buyer"),
+            Ok(CooperativeCancel::Initiated)
+        );
+        // A single party can't cancel
+        assert_eq!(order.status, Status::Active.to_string());
+        assert_eq!(
+            cooperative_cancel_step(&mut order, "buyer"),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+        assert_eq!(
+            cooperative_cancel_step(&mut order, "seller"),
+            Ok(CooperativeCancel::Accepted)
+        );
+        assert_eq!(order.status, Status::CooperativelyCanceled.to_string());
+        assert!(order.buyer_cooperativecancel && order.seller_cooperativecancel);
+    }
+
+    #[test]
+    fn test_cooperative_cancel_seller_first() {
+        let mut order = active_order();
+        assert_eq!(
+            cooperative_cancel_step(&mut order, "seller"),
+            Ok(CooperativeCancel::Initiated)
+        );
+        assert_eq!(order.cancel_initiator_pubkey.as_deref(), Some("seller"));
+        assert_eq!(order.status, Status::Active.to_string());
+        assert_eq!(
+            cooperative_cancel_step(&mut order, "buyer"),
+            Ok(CooperativeCancel::Accepted)
+        );
+        assert_eq!(order.status, Status::CooperativelyCanceled.to_string());
+    }
+
+    #[test]
+    fn test_cooperative_cancel_not_a_party() {
+        let mut order = active_order();
+        assert_eq!(
+            cooperative_cancel_step(&mut order, "stranger"),
+            Err(CantDoReason::IsNotYourOrder)
+        );
+        assert!(order.cancel_initiator_pubkey.is_none());
+    }
+}
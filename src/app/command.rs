@@ -0,0 +1,127 @@
+//! Requests without an action of their own in the protocol, clients send them
+//! adding a `command` tag to the rumor, e.g. `["command", "admin-abort-settle"]`,
+//! and the message carries the order id and request id as usual
+
+use nostr::nips::nip59::UnwrappedGift;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Abort an admin settlement still in its cooldown
+    AdminAbortSettle,
+    /// Full record of an order for the admin or the solver of its dispute
+    AdminGetOrder,
+    /// Push out the expiration of a pending order
+    ExtendOrder,
+    /// Buyer confirmation of the receipt of a trade
+    ConfirmReceipt,
+    /// Taker interest on a pending order, notified to its maker
+    OrderInterest,
+    /// Publish an order held for review
+    ApproveOrder,
+    /// Cancel an order held for review
+    RejectOrder,
+    /// Admin take over of a dispute assigned to another solver
+    ReassignDispute,
+}
+
+impl FromStr for Command {
+    type Err = ();
+
+    fn from_str(command: &str) -> Result<Self, Self::Err> {
+        match command {
+            "admin-abort-settle" => Ok(Command::AdminAbortSettle),
+            "admin-get-order" => Ok(Command::AdminGetOrder),
+            "extend-order" => Ok(Command::ExtendOrder),
+            "confirm-receipt" => Ok(Command::ConfirmReceipt),
+            "order-interest" => Ok(Command::OrderInterest),
+            "approve-order" => Ok(Command::ApproveOrder),
+            "reject-order" => Ok(Command::RejectOrder),
+            "reassign-dispute" => Ok(Command::ReassignDispute),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let command = match self {
+            Command::AdminAbortSettle => "admin-abort-settle",
+            Command::AdminGetOrder => "admin-get-order",
+            Command::ExtendOrder => "extend-order",
+            Command::ConfirmReceipt => "confirm-receipt",
+            Command::OrderInterest => "order-interest",
+            Command::ApproveOrder => "approve-order",
+            Command::RejectOrder => "reject-order",
+            Command::ReassignDispute => "reassign-dispute",
+        };
+        write!(f, "{command}")
+    }
+}
+
+/// Command requested in the `command` tag of the rumor, if any. An unknown
+/// command is returned as an error so it's not handled as the message action
+pub fn get_command(event: &UnwrappedGift) -> Option<Result<Command, String>> {
+    event
+        .rumor
+        .tags
+        .iter()
+        .find_map(|tag| match tag.as_slice() {
+            [name, command, ..] if name == "command" => {
+                Some(Command::from_str(command).map_err(|_| command.to_string()))
+            }
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::prelude::*;
+
+    fn rumor(tags: Vec<Tag>) -> UnwrappedGift {
+        let keys = Keys::generate();
+        UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: EventBuilder::text_note("")
+                .tags(tags)
+                .build(keys.public_key()),
+        }
+    }
+
+    #[test]
+    fn test_command_tag() {
+        let command = |name: &str| {
+            rumor(vec![Tag::custom(
+                TagKind::Custom("command".into()),
+                vec![name.to_string()],
+            )])
+        };
+        assert_eq!(
+            get_command(&command("admin-abort-settle")),
+            Some(Ok(Command::AdminAbortSettle))
+        );
+        assert_eq!(
+            get_command(&command("self-destruct")),
+            Some(Err("self-destruct".to_string()))
+        );
+        assert_eq!(get_command(&rumor(vec![])), None);
+    }
+
+    #[test]
+    fn test_command_names() {
+        for command in [
+            Command::AdminAbortSettle,
+            Command::AdminGetOrder,
+            Command::ExtendOrder,
+            Command::ConfirmReceipt,
+            Command::OrderInterest,
+            Command::ApproveOrder,
+            Command::RejectOrder,
+            Command::ReassignDispute,
+        ] {
+            assert_eq!(Command::from_str(&command.to_string()), Ok(command));
+        }
+    }
+}
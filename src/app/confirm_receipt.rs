@@ -0,0 +1,127 @@
+//! This module lets buyers confirm they received what they paid for, the
+//! confirmation is recorded on the order as evidence for later disputes.
+//! Requested with the `confirm-receipt` command.
+
+use crate::db::{add_receipt_confirmation, find_receipt_confirmation};
+use crate::error::MostroError;
+use crate::util::{get_required_id, send_new_order_msg};
+
+use anyhow::Result;
+use mostro_core::message::{CantDoReason, Message};
+use mostro_core::order::{Order, Status};
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use std::str::FromStr;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Receipt can be confirmed once the trade started, before or after the seller release
+pub fn can_confirm_receipt(status: Status) -> bool {
+    matches!(
+        status,
+        Status::Active
+            | Status::FiatSent
+            | Status::Dispute
+            | Status::SettledHoldInvoice
+            | Status::Success
+    )
+}
+
+/// Note for the solver of a dispute about the buyer receipt confirmation
+pub fn receipt_dispute_note(confirmed_at: i64) -> String {
+    format!("Buyer confirmed the receipt of this trade at {confirmed_at}")
+}
+
+/// Get the receipt note of an order to be shown in its dispute, if confirmed
+pub async fn get_receipt_dispute_note(pool: &Pool<Sqlite>, order_id: Uuid) -> Option<String> {
+    match find_receipt_confirmation(pool, order_id).await {
+        Ok(confirmed_at) => confirmed_at.map(receipt_dispute_note),
+        Err(e) => {
+            error!("Order Id {order_id}: error getting receipt confirmation: {e}");
+            None
+        }
+    }
+}
+
+/// Handler for the buyer confirmation of receipt, `wrap_id` is the id of the
+/// gift wrap event carrying it
+pub async fn confirm_receipt_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    wrap_id: &EventId,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    // Get request id
+    let request_id = msg.get_inner_message_kind().request_id;
+
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+
+    let order = match Order::by_id(pool, order_id).await? {
+        Some(order) => order,
+        None => {
+            error!("Order Id {order_id} not found!");
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
+        }
+    };
+
+    // Only the buyer can confirm the receipt
+    if order.buyer_pubkey != Some(event.rumor.pubkey.to_string()) {
+        return Err(MostroError::CantDo(CantDoReason::IsNotYourOrder));
+    }
+
+    match Status::from_str(&order.status) {
+        Ok(status) if can_confirm_receipt(status) => {}
+        _ => {
+            return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+        }
+    }
+
+    // The rumor is not signed, the gift wrap published on relays is kept as evidence
+    if add_receipt_confirmation(
+        pool,
+        order.id,
+        &event.rumor.pubkey.to_string(),
+        &wrap_id.to_hex(),
+        Timestamp::now().as_u64() as i64,
+    )
+    .await?
+    {
+        info!("Order Id {}: buyer confirmed receipt", order.id);
+    }
+
+    send_new_order_msg(
+        request_id,
+        Some(order.id),
+        msg.get_inner_message_kind().action.clone(),
+        None,
+        &event.rumor.pubkey,
+        None,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipt_confirmed_after_trade_started() {
+        assert!(can_confirm_receipt(Status::Active));
+        assert!(can_confirm_receipt(Status::FiatSent));
+        assert!(can_confirm_receipt(Status::Success));
+        assert!(!can_confirm_receipt(Status::Pending));
+        assert!(!can_confirm_receipt(Status::WaitingPayment));
+    }
+
+    #[test]
+    fn test_receipt_dispute_note() {
+        assert_eq!(
+            receipt_dispute_note(1_700_000_000),
+            "Buyer confirmed the receipt of this trade at 1700000000"
+        );
+    }
+}
@@ -4,10 +4,18 @@
 
 use std::borrow::Cow;
 use std::str::FromStr;
+use std::sync::Mutex;
 
-use crate::db::find_dispute_by_order_id;
+use crate::app::dispute_evidence::dispute_evidence_action;
+use crate::cli::settings::Settings;
+use crate::db::find_dispute_rounds;
+use crate::error::MostroError;
+use crate::messages::{dispute_opened_message, order_amount};
+use crate::metrics::{increment, Counter};
 use crate::nip33::new_event;
-use crate::util::{get_nostr_client, send_cant_do_msg, send_new_order_msg};
+use crate::util::{
+    get_required_id, publish_status_event, send_dm, send_new_order_msg, SlidingWindowLimiter,
+};
 
 use anyhow::{Error, Result};
 use mostro_core::dispute::Dispute;
@@ -15,18 +23,42 @@ use mostro_core::message::{Action, CantDoReason, Message, Payload};
 use mostro_core::order::{Order, Status};
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
+use once_cell::sync::Lazy;
 use rand::Rng;
 use sqlx::{Pool, Sqlite};
 use sqlx_crud::traits::Crud;
 use uuid::Uuid;
 
+static DISPUTE_LIMITER: Lazy<Mutex<SlidingWindowLimiter>> =
+    Lazy::new(|| Mutex::new(SlidingWindowLimiter::default()));
+
+/// Check a new dispute round can be opened for an order with `rounds` disputes
+/// already opened, only one dispute can be open at a time and `max_rounds`
+/// limits the reopenings (0 means no limit)
+pub fn check_dispute_round(rounds: u32, open: bool, max_rounds: u32) -> Result<(), CantDoReason> {
+    if open || (max_rounds > 0 && rounds >= max_rounds) {
+        return Err(CantDoReason::NotAllowedByStatus);
+    }
+    Ok(())
+}
+
 /// Publishes a dispute event to the Nostr network.
 ///
 /// Creates and publishes a NIP-33 replaceable event containing dispute details
 /// including status and application metadata.
-async fn publish_dispute_event(dispute: &Dispute, my_keys: &Keys) -> Result<()> {
+pub async fn publish_dispute_event(dispute: &Dispute, my_keys: &Keys) -> Result<()> {
+    publish_dispute(dispute, my_keys, false).await
+}
+
+/// Publish the event of a dispute nobody took in time with an `unattended`
+/// tag so solvers notice it
+pub async fn publish_unattended_dispute_event(dispute: &Dispute, my_keys: &Keys) -> Result<()> {
+    publish_dispute(dispute, my_keys, true).await
+}
+
+async fn publish_dispute(dispute: &Dispute, my_keys: &Keys, unattended: bool) -> Result<()> {
     // Create tags for the dispute event
-    let tags = Tags::new(vec![
+    let mut tags = vec![
         // Status tag - indicates the current state of the dispute
         Tag::custom(
             TagKind::Custom(Cow::Borrowed("s")),
@@ -42,7 +74,14 @@ async fn publish_dispute_event(dispute: &Dispute, my_keys: &Keys) -> Result<()>
             TagKind::Custom(Cow::Borrowed("z")),
             vec!["dispute".to_string()],
         ),
-    ]);
+    ];
+    if unattended {
+        tags.push(Tag::custom(
+            TagKind::Custom(Cow::Borrowed("unattended")),
+            Vec::<String>::new(),
+        ));
+    }
+    let tags = Tags::new(tags);
 
     // Create a new NIP-33 replaceable event
     // Empty content string as the information is in the tags
@@ -51,24 +90,18 @@ async fn publish_dispute_event(dispute: &Dispute, my_keys: &Keys) -> Result<()>
 
     tracing::info!("Publishing dispute event: {:#?}", event);
 
-    // Get nostr client and publish the event
-    match get_nostr_client() {
-        Ok(client) => match client.send_event(event).await {
-            Ok(_) => {
-                tracing::info!(
-                    "Successfully published dispute event for dispute ID: {}",
-                    dispute.id
-                );
-                Ok(())
-            }
-            Err(e) => {
-                tracing::error!("Failed to send dispute event: {}", e);
-                Err(Error::msg("Failed to send dispute event"))
-            }
-        },
+    // Publish the event to all the relays
+    match publish_status_event(event).await {
+        Ok(_) => {
+            tracing::info!(
+                "Successfully published dispute event for dispute ID: {}",
+                dispute.id
+            );
+            Ok(())
+        }
         Err(e) => {
-            tracing::error!("Failed to get Nostr client: {}", e);
-            Err(Error::msg("Failed to get Nostr client"))
+            tracing::error!("Failed to send dispute event: {}", e);
+            Err(Error::msg("Failed to send dispute event"))
         }
     }
 }
@@ -94,47 +127,28 @@ fn get_counterpart_info(sender: &str, buyer: &str, seller: &str) -> Result<(Stri
 /// Checks that:
 /// - The order exists
 /// - The order status allows disputes (Active or FiatSent)
-async fn get_valid_order(
-    pool: &Pool<Sqlite>,
-    order_id: Uuid,
-    event: &UnwrappedGift,
-    request_id: Option<u64>,
-) -> Result<Order> {
+async fn get_valid_order(pool: &Pool<Sqlite>, order_id: Uuid) -> Result<Order, MostroError> {
     // Try to fetch the order from the database
     let order = match Order::by_id(pool, order_id).await? {
         Some(order) => order,
         None => {
             tracing::error!("Order Id {order_id} not found!");
-            return Err(Error::msg("Order not found"));
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
         }
     };
 
-    // Parse and validate the order status
+    // Only allow disputes for Active or FiatSent orders
     match Status::from_str(&order.status) {
-        Ok(status) => {
-            // Only allow disputes for Active or FiatSent orders
-            if !matches!(status, Status::Active | Status::FiatSent) {
-                // Notify the sender that the action is not allowed for this status
-                send_cant_do_msg(
-                    request_id,
-                    Some(order.id),
-                    Some(CantDoReason::NotAllowedByStatus),
-                    &event.rumor.pubkey,
-                )
-                .await;
-
-                return Err(Error::msg(format!(
-                    "Order {} with status {} does not allow disputes. Must be Active or FiatSent",
-                    order.id, order.status
-                )));
-            }
-        }
-        Err(_) => {
-            return Err(Error::msg("Invalid order status"));
+        Ok(Status::Active | Status::FiatSent) => Ok(order),
+        _ => {
+            tracing::info!(
+                "Order {} with status {} does not allow disputes. Must be Active or FiatSent",
+                order.id,
+                order.status
+            );
+            Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus))
         }
-    };
-
-    Ok(order)
+    }
 }
 
 /// Main handler for dispute actions.
@@ -151,31 +165,30 @@ pub async fn dispute_action(
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-) -> Result<()> {
-    // Get request id
-    let request_id = msg.get_inner_message_kind().request_id;
+) -> Result<(), MostroError> {
+    // A dispute message with a text from a party is evidence for the open dispute
+    if let Some(Payload::TextMessage(_)) = msg.get_inner_message_kind().payload {
+        return dispute_evidence_action(msg, event, pool).await;
+    }
 
-    let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
-        order_id
-    } else {
-        return Err(Error::msg("No order id"));
-    };
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
 
-    // Check dispute for this order id is yet present.
-    if find_dispute_by_order_id(pool, order_id).await.is_ok() {
-        return Err(Error::msg(format!(
-            "Dispute already exists for order {}",
-            order_id
-        )));
+    // Check a dispute for this order id is not open and rounds are left
+    let (rounds, open) = find_dispute_rounds(pool, order_id).await?;
+    if let Err(reason) =
+        check_dispute_round(rounds, open, Settings::get_mostro().max_dispute_rounds)
+    {
+        tracing::info!("Order Id {order_id}: dispute not allowed, {rounds} rounds opened");
+        return Err(MostroError::CantDo(reason));
     }
 
     // Get and validate order
-    let mut order = get_valid_order(pool, order_id, event, request_id).await?;
+    let mut order = get_valid_order(pool, order_id).await?;
 
     let (seller, buyer) = match (&order.seller_pubkey, &order.buyer_pubkey) {
         (Some(seller), Some(buyer)) => (seller.to_owned(), buyer.to_owned()),
-        (None, _) => return Err(Error::msg("Missing seller pubkey")),
-        (_, None) => return Err(Error::msg("Missing buyer pubkey")),
+        (None, _) => return Err(MostroError::Internal("Missing seller pubkey".to_string())),
+        (_, None) => return Err(MostroError::Internal("Missing buyer pubkey".to_string())),
     };
 
     let message_sender = event.rumor.pubkey.to_string();
@@ -183,40 +196,39 @@ pub async fn dispute_action(
         match get_counterpart_info(&message_sender, &buyer, &seller) {
             Ok((counterpart, is_buyer_dispute)) => (counterpart, is_buyer_dispute),
             Err(_) => {
-                send_cant_do_msg(
-                    request_id,
-                    Some(order.id),
-                    Some(CantDoReason::InvalidPubkey),
-                    &event.rumor.pubkey,
-                )
-                .await;
-                return Ok(());
+                return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
             }
         };
 
+    // Limit the disputes a user can open
+    let mostro_settings = Settings::get_mostro();
+    if mostro_settings.max_disputes_per_window > 0
+        && !DISPUTE_LIMITER.lock().unwrap().try_record(
+            &event.sender.to_string(),
+            Timestamp::now().as_u64() as i64,
+            mostro_settings.dispute_rate_window_seconds as i64,
+            mostro_settings.max_disputes_per_window as usize,
+        )
+    {
+        tracing::info!("User {} over the dispute rate limit", event.sender);
+        return Err(MostroError::CantDo(CantDoReason::DisputeCreationError));
+    }
+
     // Get the opposite dispute status
     let is_seller_dispute = !is_buyer_dispute;
 
-    // Update dispute flags based on who initiated
-    let mut update_seller_dispute = false;
-    let mut update_buyer_dispute = false;
-    if is_seller_dispute && !order.seller_dispute {
-        update_seller_dispute = true;
-        order.seller_dispute = update_seller_dispute;
-    } else if is_buyer_dispute && !order.buyer_dispute {
-        update_buyer_dispute = true;
-        order.buyer_dispute = update_buyer_dispute;
-    };
+    // Update dispute flags based on who initiated, flags stay set
+    // from previous rounds
+    if is_seller_dispute {
+        order.seller_dispute = true;
+    } else {
+        order.buyer_dispute = true;
+    }
     order.status = Status::Dispute.to_string();
+    let amount = order_amount(&order);
 
     // Update the database with dispute information
-    // Save the dispute to DB
-    if !update_buyer_dispute && !update_seller_dispute {
-        return Ok(());
-    } else {
-        // Need to update dispute status
-        order.update(pool).await?;
-    }
+    order.update(pool).await?;
 
     // Create new dispute record and generate security tokens
     let mut dispute = Dispute::new(order_id);
@@ -231,13 +243,16 @@ pub async fn dispute_action(
 
     // Save dispute to database
     let dispute = dispute.create(pool).await?;
+    increment(Counter::DisputesOpened);
 
     // Send notification to dispute initiator
     let initiator_pubkey = match PublicKey::from_str(&message_sender) {
         Ok(pk) => pk,
         Err(e) => {
             tracing::error!("Error parsing initiator pubkey: {:#?}", e);
-            return Err(Error::msg("Failed to parse initiator public key"));
+            return Err(MostroError::Internal(
+                "Failed to parse initiator public key".to_string(),
+            ));
         }
     };
 
@@ -256,7 +271,9 @@ pub async fn dispute_action(
         Ok(pk) => pk,
         Err(e) => {
             tracing::error!("Error parsing counterpart pubkey: {:#?}", e);
-            return Err(Error::msg("Failed to parse counterpart public key"));
+            return Err(MostroError::Internal(
+                "Failed to parse counterpart public key".to_string(),
+            ));
         }
     };
     send_new_order_msg(
@@ -269,7 +286,58 @@ pub async fn dispute_action(
     )
     .await;
 
+    // Let both parties know where to get help with the dispute, the dispute
+    // was already notified so the contact goes as a plain direct message
+    let contact = Settings::get_mostro().operator_contact;
+    if !contact.trim().is_empty() {
+        let message = Message::new_dispute(
+            Some(dispute.id),
+            msg.get_inner_message_kind().request_id,
+            None,
+            Action::SendDm,
+            Some(Payload::TextMessage(dispute_opened_message(
+                &amount, &contact,
+            ))),
+        );
+        for pubkey in [&initiator_pubkey, &counterpart_pubkey] {
+            let sent = match message.as_json() {
+                Ok(message) => send_dm(pubkey, my_keys.clone(), message, None).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = sent {
+                tracing::error!("Dispute {}: operator contact not sent: {e}", dispute.id);
+            }
+        }
+    }
+
     // Publish dispute event to network
     publish_dispute_event(&dispute, my_keys).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispute_rounds_within_limit() {
+        assert!(check_dispute_round(0, false, 2).is_ok());
+        // Reopening after the first round was closed
+        assert!(check_dispute_round(1, false, 2).is_ok());
+        // No limit
+        assert!(check_dispute_round(10, false, 0).is_ok());
+    }
+
+    #[test]
+    fn test_dispute_rounds_beyond_limit() {
+        assert!(matches!(
+            check_dispute_round(2, false, 2),
+            Err(CantDoReason::NotAllowedByStatus)
+        ));
+        // A dispute already open can't be opened again
+        assert!(matches!(
+            check_dispute_round(1, true, 0),
+            Err(CantDoReason::NotAllowedByStatus)
+        ));
+    }
+}
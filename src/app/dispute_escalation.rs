@@ -0,0 +1,218 @@
+use crate::app::dispute::publish_unattended_dispute_event;
+use crate::cli::settings::Settings;
+use crate::db::{find_open_disputes, mark_dispute_escalated};
+use crate::util::{get_keys, send_dm};
+
+use anyhow::Result;
+use mostro_core::dispute::{Dispute, Status as DisputeStatus};
+use mostro_core::message::{Action, Message, Payload};
+use mostro_core::order::Order;
+use nostr_sdk::prelude::*;
+use sqlx::SqlitePool;
+use sqlx_crud::Crud;
+use tracing::{error, warn};
+
+/// Check if an open dispute is stuck at `now`, no solver resolved it `timeout`
+/// seconds after it was taken, or after it was opened if nobody took it
+pub fn is_dispute_stuck(dispute: &Dispute, now: i64, timeout: i64) -> bool {
+    let open = dispute.status == DisputeStatus::Initiated.to_string()
+        || dispute.status == DisputeStatus::InProgress.to_string();
+    let since = match dispute.taken_at {
+        0 => dispute.created_at,
+        taken_at => taken_at,
+    };
+
+    open && timeout > 0 && now - since > timeout
+}
+
+/// Context of a stuck dispute for the admin to resolve it manually
+pub fn escalation_context(dispute: &Dispute, order: &Order) -> String {
+    format!(
+        "Stuck dispute {} - Order {} ({}): {} sats, {} {} by {} - Buyer: {} - Seller: {} - Solver: {} - Dispute status: {}, opened at {}, taken at {}",
+        dispute.id,
+        order.id,
+        order.status,
+        order.amount,
+        order.fiat_code,
+        order.fiat_amount,
+        order.payment_method,
+        order.buyer_pubkey.as_deref().unwrap_or("none"),
+        order.seller_pubkey.as_deref().unwrap_or("none"),
+        dispute.solver_pubkey.as_deref().unwrap_or("none"),
+        dispute.status,
+        dispute.created_at,
+        dispute.taken_at,
+    )
+}
+
+/// Escalate the disputes stuck at `now` to the admin, each one is escalated
+/// once, the ones nobody took are published again tagged as unattended,
+/// returns the number of disputes escalated
+pub async fn escalate_stuck_disputes(pool: &SqlitePool, now: i64) -> Result<usize> {
+    let mostro_settings = Settings::get_mostro();
+    let timeout = mostro_settings.dispute_escalation_hours as i64 * 3600;
+    if timeout == 0 {
+        return Ok(0);
+    }
+    let admin_pubkey = match mostro_settings.admin_pubkey.as_str() {
+        "" => None,
+        pubkey => Some(PublicKey::parse(pubkey)?),
+    };
+
+    let mut escalated = 0;
+    for dispute in find_open_disputes(pool).await? {
+        if !is_dispute_stuck(&dispute, now, timeout)
+            || !mark_dispute_escalated(pool, dispute.id, now).await?
+        {
+            continue;
+        }
+        let Some(order) = Order::by_id(pool, dispute.order_id).await? else {
+            error!(
+                "Order Id {} of dispute {} not found!",
+                dispute.order_id, dispute.id
+            );
+            continue;
+        };
+        let context = escalation_context(&dispute, &order);
+        warn!("ALERT: {context}");
+        if let Some(admin_pubkey) = admin_pubkey.as_ref() {
+            let message = Message::new_dispute(
+                Some(dispute.id),
+                None,
+                None,
+                Action::Dispute,
+                Some(Payload::TextMessage(context)),
+            );
+            send_dm(admin_pubkey, get_keys()?, message.as_json()?, None).await?;
+        }
+        if dispute.taken_at == 0 {
+            let published = match get_keys() {
+                Ok(my_keys) => publish_unattended_dispute_event(&dispute, &my_keys).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = published {
+                warn!(
+                    "Dispute {}: unattended event not published: {e}",
+                    dispute.id
+                );
+            }
+        }
+        escalated += 1;
+    }
+
+    Ok(escalated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MOSTRO_CONFIG;
+    use std::env::set_var;
+    use std::path::PathBuf;
+    use std::sync::{Arc, RwLock};
+    use uuid::Uuid;
+
+    const HOUR: i64 = 3600;
+
+    fn dispute(status: DisputeStatus, created_at: i64, taken_at: i64) -> Dispute {
+        Dispute {
+            status: status.to_string(),
+            created_at,
+            taken_at,
+            ..Dispute::new(Uuid::new_v4())
+        }
+    }
+
+    #[test]
+    fn test_taken_dispute_stuck_after_timeout() {
+        let taken_at = 1_700_000_000;
+        let dispute = dispute(DisputeStatus::InProgress, taken_at - HOUR, taken_at);
+        let mut now = taken_at;
+        assert!(!is_dispute_stuck(&dispute, now, 48 * HOUR));
+        now += 48 * HOUR;
+        assert!(!is_dispute_stuck(&dispute, now, 48 * HOUR));
+        now += 1;
+        assert!(is_dispute_stuck(&dispute, now, 48 * HOUR));
+    }
+
+    #[test]
+    fn test_dispute_not_taken_stuck_after_timeout() {
+        let created_at = 1_700_000_000;
+        let dispute = dispute(DisputeStatus::Initiated, created_at, 0);
+        assert!(!is_dispute_stuck(&dispute, created_at + HOUR, 2 * HOUR));
+        assert!(is_dispute_stuck(&dispute, created_at + 3 * HOUR, 2 * HOUR));
+    }
+
+    #[test]
+    fn test_resolved_dispute_never_stuck() {
+        let created_at = 1_700_000_000;
+        for status in [DisputeStatus::Settled, DisputeStatus::SellerRefunded] {
+            let dispute = dispute(status, created_at, created_at);
+            assert!(!is_dispute_stuck(&dispute, created_at + 100 * HOUR, HOUR));
+        }
+        // Escalation disabled
+        let dispute = dispute(DisputeStatus::InProgress, created_at, created_at);
+        assert!(!is_dispute_stuck(&dispute, created_at + 100 * HOUR, 0));
+    }
+
+    #[test]
+    fn test_escalation_context() {
+        let order = Order {
+            id: Uuid::new_v4(),
+            amount: 100_000,
+            fiat_code: "USD".to_string(),
+            buyer_pubkey: Some("buyer".to_string()),
+            ..Default::default()
+        };
+        let dispute = Dispute {
+            solver_pubkey: Some("solver".to_string()),
+            ..dispute(DisputeStatus::InProgress, 1, 2)
+        };
+        let context = escalation_context(&dispute, &order);
+        for expected in [
+            dispute.id.to_string(),
+            order.id.to_string(),
+            "100000 sats".to_string(),
+            "Buyer: buyer".to_string(),
+            "Seller: none".to_string(),
+            "Solver: solver".to_string(),
+        ] {
+            assert!(context.contains(&expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unattended_dispute_escalated_once() {
+        set_var("RUN_MODE", ".tpl");
+        MOSTRO_CONFIG
+            .get_or_init(|| RwLock::new(Arc::new(Settings::new(PathBuf::from("./")).unwrap())));
+        let timeout = Settings::get_mostro().dispute_escalation_hours as i64 * HOUR;
+        let (pool, _db) = crate::db::connect_test_db().await;
+
+        let opened_at = 1_700_000_000;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: "dispute".to_string(),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        Dispute {
+            order_id: order.id,
+            ..dispute(DisputeStatus::Initiated, opened_at, 0)
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+
+        // Simulated clock, nobody takes the dispute
+        let mut now = opened_at + HOUR;
+        assert_eq!(escalate_stuck_disputes(&pool, now).await.unwrap(), 0);
+        now = opened_at + timeout + 1;
+        assert_eq!(escalate_stuck_disputes(&pool, now).await.unwrap(), 1);
+        // Never escalated twice
+        now += 10 * timeout;
+        assert_eq!(escalate_stuck_disputes(&pool, now).await.unwrap(), 0);
+    }
+}
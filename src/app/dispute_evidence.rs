@@ -0,0 +1,188 @@
+//! Evidence submitted by the parties of a dispute, sent as a dispute message
+//! with a text payload, stored by dispute and relayed to its solvers.
+
+use crate::cli::settings::Settings;
+use crate::db::{
+    add_dispute_evidence, find_dispute_by_order_id, find_dispute_evidence, find_dispute_solvers,
+};
+use crate::error::MostroError;
+use crate::util::{get_keys, get_required_id, sanitize_text_message, send_dm};
+
+use anyhow::Result;
+use mostro_core::dispute::{Dispute, Status as DisputeStatus};
+use mostro_core::message::{Action, CantDoReason, Message, Payload};
+use mostro_core::order::Order;
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use std::str::FromStr;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Role of `pubkey` on the order, only the buyer and seller can submit evidence
+pub fn party_role(order: &Order, pubkey: &str) -> Option<&'static str> {
+    if order.buyer_pubkey.as_deref() == Some(pubkey) {
+        Some("buyer")
+    } else if order.seller_pubkey.as_deref() == Some(pubkey) {
+        Some("seller")
+    } else {
+        None
+    }
+}
+
+/// Evidence is accepted until the dispute is resolved
+pub fn is_dispute_open(dispute: &Dispute) -> bool {
+    dispute.status == DisputeStatus::Initiated.to_string()
+        || dispute.status == DisputeStatus::InProgress.to_string()
+}
+
+/// Text of an evidence as shown to the solvers
+pub fn evidence_message(role: &str, content: &str, created_at: i64) -> String {
+    format!("Evidence from {role} at {created_at}: {content}")
+}
+
+/// Get all the evidence of a dispute to be sent to a solver taking it
+pub async fn get_dispute_evidence_messages(
+    pool: &Pool<Sqlite>,
+    dispute_id: Uuid,
+    order: &Order,
+) -> Vec<String> {
+    match find_dispute_evidence(pool, dispute_id).await {
+        Ok(evidence) => evidence
+            .iter()
+            .map(|(sender, content, created_at)| {
+                let role = party_role(order, sender).unwrap_or("unknown");
+                evidence_message(role, content, *created_at)
+            })
+            .collect(),
+        Err(e) => {
+            error!("Dispute {dispute_id}: error getting evidence: {e}");
+            vec![]
+        }
+    }
+}
+
+/// Handler for the evidence submitted by a party of an order in dispute
+pub async fn dispute_evidence_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    // Get request id
+    let request_id = msg.get_inner_message_kind().request_id;
+
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+    let content = match &msg.get_inner_message_kind().payload {
+        Some(Payload::TextMessage(text)) => text,
+        _ => {
+            return Err(MostroError::CantDo(CantDoReason::InvalidParameters));
+        }
+    };
+    let content = match sanitize_text_message(
+        content,
+        Settings::get_mostro().max_text_message_length as usize,
+    ) {
+        Ok(content) if !content.is_empty() => content,
+        _ => {
+            return Err(MostroError::CantDo(CantDoReason::InvalidParameters));
+        }
+    };
+
+    let order = match Order::by_id(pool, order_id).await? {
+        Some(order) => order,
+        None => {
+            error!("Order Id {order_id} not found!");
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
+        }
+    };
+    let Some(role) = party_role(&order, &event.rumor.pubkey.to_string()) else {
+        return Err(MostroError::CantDo(CantDoReason::IsNotYourDispute));
+    };
+    let dispute = match find_dispute_by_order_id(pool, order_id).await {
+        Ok(dispute) if is_dispute_open(&dispute) => dispute,
+        _ => {
+            return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+        }
+    };
+
+    let created_at = Timestamp::now().as_u64() as i64;
+    add_dispute_evidence(
+        pool,
+        dispute.id,
+        &event.rumor.pubkey.to_string(),
+        &content,
+        created_at,
+    )
+    .await?;
+    info!("Dispute {}: evidence submitted by {role}", dispute.id);
+
+    // Relay the evidence to the solvers working on the dispute
+    let mut solvers = find_dispute_solvers(pool, order_id).await?;
+    if let Some(solver) = dispute.solver_pubkey.as_ref() {
+        if !solvers.contains(solver) {
+            solvers.push(solver.clone());
+        }
+    }
+    let message = Message::new_dispute(
+        Some(dispute.id),
+        None,
+        None,
+        Action::Dispute,
+        Some(Payload::TextMessage(evidence_message(
+            role, &content, created_at,
+        ))),
+    )
+    .as_json()?;
+    for solver in solvers {
+        let solver = PublicKey::from_str(&solver)?;
+        send_dm(&solver, get_keys()?, message.clone(), None).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order() -> Order {
+        Order {
+            buyer_pubkey: Some("buyer".to_string()),
+            seller_pubkey: Some("seller".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parties_can_submit_evidence() {
+        let order = order();
+        assert_eq!(party_role(&order, "buyer"), Some("buyer"));
+        assert_eq!(party_role(&order, "seller"), Some("seller"));
+    }
+
+    #[test]
+    fn test_non_party_cannot_submit_evidence() {
+        let order = order();
+        assert_eq!(party_role(&order, "stranger"), None);
+        // A missing party is never matched
+        let order = Order {
+            buyer_pubkey: None,
+            ..order
+        };
+        assert_eq!(party_role(&order, ""), None);
+    }
+
+    #[test]
+    fn test_evidence_only_for_open_disputes() {
+        let mut dispute = Dispute::new(Uuid::new_v4());
+        for status in [DisputeStatus::Initiated, DisputeStatus::InProgress] {
+            dispute.status = status.to_string();
+            assert!(is_dispute_open(&dispute));
+        }
+        for status in [DisputeStatus::Settled, DisputeStatus::SellerRefunded] {
+            dispute.status = status.to_string();
+            assert!(!is_dispute_open(&dispute));
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! This module lets makers keep an unfilled order alive by pushing out its
+//! expiration, bounded by the maximum lifetime allowed for an order.
+//! Requested with the `extend-order` command.
+
+use crate::cli::settings::Settings;
+use crate::error::MostroError;
+use crate::util::{get_required_id, send_new_order_msg, update_order_event};
+
+use anyhow::Result;
+use chrono::Duration;
+use mostro_core::message::{CantDoReason, Message, Payload};
+use mostro_core::order::{Order, Status};
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use tracing::{error, info};
+
+/// Computes the new expiration of an order being extended.
+///
+/// The order is extended `extension` seconds from its current expiration (or from now
+/// if it is already due) and capped to `max_lifetime` seconds since its creation.
+/// Returns `None` when the order already reached its maximum lifetime.
+pub fn get_extended_expiration(
+    created_at: i64,
+    expires_at: i64,
+    now: i64,
+    extension: i64,
+    max_lifetime: i64,
+) -> Option<i64> {
+    let max_expires_at = created_at + max_lifetime;
+    if expires_at >= max_expires_at {
+        return None;
+    }
+    let new_expires_at = expires_at.max(now) + extension;
+
+    Some(new_expires_at.min(max_expires_at))
+}
+
+/// Handler for order extension requests.
+///
+/// Only the maker of a pending order can extend it, the order is republished
+/// with the new expiration and the maker receives the updated order.
+pub async fn extend_order_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    // Get request id
+    let request_id = msg.get_inner_message_kind().request_id;
+
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+
+    let mut order = match Order::by_id(pool, order_id).await? {
+        Some(order) => order,
+        None => {
+            error!("Order Id {order_id} not found!");
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
+        }
+    };
+
+    // Only the maker can extend the order
+    if order.creator_pubkey != event.rumor.pubkey.to_string() {
+        return Err(MostroError::CantDo(CantDoReason::IsNotYourOrder));
+    }
+
+    // Only pending orders can be extended
+    if order.status != Status::Pending.to_string() {
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+    }
+
+    let mostro_settings = Settings::get_mostro();
+    let new_expires_at = match get_extended_expiration(
+        order.created_at,
+        order.expires_at,
+        Timestamp::now().as_u64() as i64,
+        Duration::hours(mostro_settings.expiration_hours as i64).num_seconds(),
+        Duration::days(mostro_settings.max_expiration_days as i64).num_seconds(),
+    ) {
+        Some(expires_at) => expires_at,
+        None => {
+            return Err(MostroError::CantDo(CantDoReason::InvalidParameters));
+        }
+    };
+    order.expires_at = new_expires_at;
+
+    // We publish a new replaceable kind nostr event with the new expiration
+    // and update on local database the expiration and new event id
+    let order_updated = update_order_event(my_keys, Status::Pending, &order, pool).await?;
+    let order_updated = order_updated.update(pool).await?;
+    info!(
+        "Order Id {}: expiration extended to {}",
+        order_updated.id, order_updated.expires_at
+    );
+
+    // We send the updated order to the maker
+    send_new_order_msg(
+        request_id,
+        Some(order_updated.id),
+        msg.get_inner_message_kind().action.clone(),
+        Some(Payload::Order(order_updated.as_new_order())),
+        &event.rumor.pubkey,
+        None,
+    )
+    .await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MOSTRO_CONFIG;
+    use mostro_core::message::Action;
+    use std::env::set_var;
+    use std::path::PathBuf;
+    use std::sync::{Arc, RwLock};
+    use uuid::Uuid;
+
+    const HOUR: i64 = 3600;
+    const DAY: i64 = 24 * HOUR;
+
+    #[test]
+    fn test_extend_order_expiration() {
+        let created_at = 1_700_000_000;
+        let expires_at = created_at + DAY;
+        let now = created_at + 12 * HOUR;
+        let new_expires_at = get_extended_expiration(created_at, expires_at, now, DAY, 15 * DAY);
+        assert_eq!(new_expires_at, Some(expires_at + DAY));
+    }
+
+    #[test]
+    fn test_extend_order_capped_to_max_lifetime() {
+        let created_at = 1_700_000_000;
+        let expires_at = created_at + 14 * DAY + 12 * HOUR;
+        let now = created_at + 14 * DAY;
+        let new_expires_at = get_extended_expiration(created_at, expires_at, now, DAY, 15 * DAY);
+        assert_eq!(new_expires_at, Some(created_at + 15 * DAY));
+    }
+
+    #[test]
+    fn test_extend_order_beyond_max_lifetime_rejected() {
+        let created_at = 1_700_000_000;
+        let expires_at = created_at + 15 * DAY;
+        let now = created_at + 14 * DAY;
+        let new_expires_at = get_extended_expiration(created_at, expires_at, now, DAY, 15 * DAY);
+        assert_eq!(new_expires_at, None);
+    }
+
+    /// Extension request of `order_id` sent by `sender`
+    fn extend_request(sender: &Keys, order_id: Uuid) -> (Message, UnwrappedGift) {
+        let message = Message::new_order(Some(order_id), Some(1), None, Action::NewOrder, None);
+        let event = UnwrappedGift {
+            sender: sender.public_key(),
+            rumor: EventBuilder::text_note("").build(sender.public_key()),
+        };
+        (message, event)
+    }
+
+    #[tokio::test]
+    async fn test_maker_extends_pending_order() {
+        set_var("RUN_MODE", ".tpl");
+        MOSTRO_CONFIG
+            .get_or_init(|| RwLock::new(Arc::new(Settings::new(PathBuf::from("./")).unwrap())));
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let maker = Keys::generate();
+        let now = Timestamp::now().as_u64() as i64;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: Status::Pending.to_string(),
+            creator_pubkey: maker.public_key().to_string(),
+            created_at: now,
+            expires_at: now + HOUR,
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+
+        let (message, event) = extend_request(&maker, order.id);
+        extend_order_action(message, &event, &Keys::generate(), &pool)
+            .await
+            .unwrap();
+        let extended = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        let extension = Settings::get_mostro().expiration_hours as i64 * HOUR;
+        assert_eq!(extended.expires_at, order.expires_at + extension);
+        assert_ne!(extended.event_id, order.event_id);
+
+        // Nobody else extends it
+        let (message, event) = extend_request(&Keys::generate(), order.id);
+        let result = extend_order_action(message, &event, &Keys::generate(), &pool).await;
+        assert_eq!(
+            result,
+            Err(MostroError::CantDo(CantDoReason::IsNotYourOrder))
+        );
+        let not_extended = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(not_extended.expires_at, extended.expires_at);
+    }
+
+    #[tokio::test]
+    async fn test_order_at_max_lifetime_not_extended() {
+        set_var("RUN_MODE", ".tpl");
+        MOSTRO_CONFIG
+            .get_or_init(|| RwLock::new(Arc::new(Settings::new(PathBuf::from("./")).unwrap())));
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let maker = Keys::generate();
+        let created_at = Timestamp::now().as_u64() as i64 - DAY;
+        let max_lifetime = Settings::get_mostro().max_expiration_days as i64 * DAY;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: Status::Pending.to_string(),
+            creator_pubkey: maker.public_key().to_string(),
+            created_at,
+            expires_at: created_at + max_lifetime,
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+
+        let (message, event) = extend_request(&maker, order.id);
+        let result = extend_order_action(message, &event, &Keys::generate(), &pool).await;
+        assert_eq!(
+            result,
+            Err(MostroError::CantDo(CantDoReason::InvalidParameters))
+        );
+        let order_after = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(order_after.expires_at, order.expires_at);
+    }
+}
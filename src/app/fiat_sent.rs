@@ -1,6 +1,9 @@
-use crate::util::{send_cant_do_msg, send_new_order_msg, update_order_event};
+use crate::cli::settings::Settings;
+use crate::db::add_seller_fiat_confirmation;
+use crate::error::MostroError;
+use crate::util::{get_required_id, send_new_order_msg, update_order_event};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use mostro_core::message::{Action, CantDoReason, Message, Payload, Peer};
 use mostro_core::order::{Order, Status};
 use nostr::nips::nip59::UnwrappedGift;
@@ -8,51 +11,50 @@ use nostr_sdk::prelude::*;
 use sqlx::{Pool, Sqlite};
 use sqlx_crud::Crud;
 use std::str::FromStr;
-use tracing::error;
+use tracing::{error, info};
 
 pub async fn fiat_sent_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-) -> Result<()> {
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
 
-    let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
-        order_id
-    } else {
-        return Err(Error::msg("No order id"));
-    };
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
     let order = match Order::by_id(pool, order_id).await? {
         Some(order) => order,
         None => {
             error!("Order Id {order_id} not found!");
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
         }
     };
-    // Send to user a DM with the error
-    if order.status != Status::Active.to_string() {
-        send_cant_do_msg(
+    // The seller acknowledges the fiat sent by the buyer
+    if Settings::get_mostro().require_fiat_confirmations
+        && order.status == Status::FiatSent.to_string()
+        && Some(event.rumor.pubkey.to_string()) == order.seller_pubkey
+    {
+        add_seller_fiat_confirmation(pool, order.id, Timestamp::now().as_u64() as i64).await?;
+        info!("Order Id {}: seller acknowledged the fiat sent", order.id);
+        send_new_order_msg(
             request_id,
             Some(order.id),
-            Some(CantDoReason::NotAllowedByStatus),
+            Action::FiatSentOk,
+            None,
             &event.rumor.pubkey,
+            None,
         )
         .await;
-
         return Ok(());
     }
+    // Send to user a DM with the error
+    if order.status != Status::Active.to_string() {
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+    }
     // Check if the pubkey is the buyer
     if Some(event.rumor.pubkey.to_string()) != order.buyer_pubkey {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::InvalidPubkey),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
     }
     let next_trade: Option<(String, u32)> = match &msg.get_inner_message_kind().payload {
         Some(Payload::NextTrade(pubkey, index)) => Some((pubkey.clone(), *index)),
@@ -60,11 +62,12 @@ pub async fn fiat_sent_action(
     };
     // We publish a new replaceable kind nostr event with the status updated
     // and update on local database the status and new event id
-    let mut order_updated = match update_order_event(my_keys, Status::FiatSent, &order).await {
+    let mut order_updated = match update_order_event(my_keys, Status::FiatSent, &order, pool).await
+    {
         Ok(order) => order.update(pool).await?,
         Err(e) => {
             error!("Failed to update order {}: {}", order.id, e);
-            return Ok(());
+            return Err(e.into());
         }
     };
 
@@ -72,7 +75,7 @@ pub async fn fiat_sent_action(
         Some(pk) => PublicKey::from_str(pk)?,
         None => {
             error!("Seller pubkey not found for order {}!", order_updated.id);
-            return Ok(());
+            return Err(MostroError::Internal("Missing seller pubkey".to_string()));
         }
     };
     let peer = Peer::new(event.rumor.pubkey.to_string());
@@ -111,7 +114,7 @@ pub async fn fiat_sent_action(
                     "Failed to update next trade fields for order {}: {}",
                     order_id, e
                 );
-                return Ok(());
+                return Err(e.into());
             }
         }
     }
@@ -1,20 +1,54 @@
+use crate::app::quarantine::quarantine_reason;
 use crate::cli::settings::Settings;
+use crate::db::OrderOptions;
+use crate::error::MostroError;
 use crate::lightning::invoice::is_valid_invoice;
-use crate::util::{get_bitcoin_price, publish_order, send_cant_do_msg};
+use crate::metrics::{increment, Counter};
+use crate::util::{
+    get_bitcoin_price, get_take_pow_request, is_nip05_required_request, is_unlisted_request,
+    is_within_locked_funds_cap, publish_order, SlidingWindowLimiter,
+};
 use anyhow::Result;
 use mostro_core::message::{CantDoReason, Message};
+use mostro_core::order::SmallOrder;
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
 use nostr_sdk::Keys;
+use once_cell::sync::Lazy;
 use sqlx::{Pool, Sqlite};
-use tracing::error;
+use std::sync::Mutex;
+use tracing::{error, info};
+
+static NEW_ORDER_LIMITER: Lazy<Mutex<SlidingWindowLimiter>> =
+    Lazy::new(|| Mutex::new(SlidingWindowLimiter::default()));
+
+/// Check the amounts of a new order, sats and fiat can't float at the same
+/// time: either sats are fixed at creation and the fiat amount is only a
+/// reference, or sats are calculated from the fiat amount at market price
+pub fn check_order_amounts(order: &SmallOrder) -> Result<(), CantDoReason> {
+    if let (Some(min), Some(max)) = (order.min_amount, order.max_amount) {
+        // Range orders are always market price orders
+        if min >= max || order.amount != 0 {
+            return Err(CantDoReason::InvalidAmount);
+        }
+        return Ok(());
+    }
+    match order.amount {
+        // Market price order, fiat amount is needed to get the sats amount
+        0 if order.fiat_amount == 0 => Err(CantDoReason::InvalidAmount),
+        0 => Ok(()),
+        // Fixed sats order, premium only applies to market price
+        _ if order.premium != 0 => Err(CantDoReason::InvalidParameters),
+        _ => Ok(()),
+    }
+}
 
 pub async fn order_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-) -> Result<()> {
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
 
@@ -28,66 +62,28 @@ pub async fn order_action(
             match is_valid_invoice(invoice.clone(), None, None).await {
                 Ok(_) => (),
                 Err(_) => {
-                    send_cant_do_msg(
-                        request_id,
-                        order.id,
-                        Some(CantDoReason::InvalidAmount),
-                        &event.rumor.pubkey,
-                    )
-                    .await;
-
-                    return Ok(());
+                    return Err(MostroError::CantDo(CantDoReason::InvalidAmount));
                 }
             }
         }
 
+        if let Err(reason) = check_order_amounts(order) {
+            return Err(MostroError::CantDo(reason));
+        }
+
         // Default case single amount
         let mut amount_vec = vec![order.fiat_amount];
 
         // Get max and and min amount in case of range order
         // in case of single order do like usual
         if let (Some(min), Some(max)) = (order.min_amount, order.max_amount) {
-            if min >= max {
-                send_cant_do_msg(
-                    request_id,
-                    order.id,
-                    Some(CantDoReason::InvalidAmount),
-                    &event.rumor.pubkey,
-                )
-                .await;
-                return Ok(());
-            }
-            if order.amount != 0 {
-                send_cant_do_msg(
-                    request_id,
-                    None,
-                    Some(CantDoReason::InvalidAmount),
-                    &event.rumor.pubkey,
-                )
-                .await;
-
-                return Ok(());
-            }
             amount_vec.clear();
             amount_vec.push(min);
             amount_vec.push(max);
         }
 
-        let premium = (order.premium != 0).then_some(order.premium);
-        let fiat_amount = (order.fiat_amount != 0).then_some(order.fiat_amount);
-        let amount = (order.amount != 0).then_some(order.amount);
-
-        if premium.is_some() && fiat_amount.is_some() && amount.is_some() {
-            send_cant_do_msg(
-                request_id,
-                None,
-                Some(CantDoReason::InvalidParameters),
-                &event.rumor.pubkey,
-            )
-            .await;
-            return Ok(());
-        }
-
+        // Biggest amount in sats the order can lock
+        let mut max_quote = 0;
         for fiat_amount in amount_vec.iter() {
             let quote = match order.amount {
                 0 => match get_bitcoin_price(&order.fiat_code) {
@@ -97,7 +93,7 @@ pub async fn order_action(
                     }
                     Err(e) => {
                         error!("{:?}", e.to_string());
-                        return Ok(());
+                        return Err(MostroError::NoAPIResponse);
                     }
                 },
                 _ => order.amount,
@@ -105,31 +101,46 @@ pub async fn order_action(
 
             // Check amount is positive - extra safety check
             if quote < 0 {
-                send_cant_do_msg(
-                    request_id,
-                    None,
-                    Some(CantDoReason::InvalidAmount),
-                    &event.rumor.pubkey,
-                )
-                .await;
-
-                return Ok(());
+                return Err(MostroError::CantDo(CantDoReason::InvalidAmount));
             }
 
             if quote > mostro_settings.max_order_amount as i64
                 || quote < mostro_settings.min_payment_amount as i64
             {
-                send_cant_do_msg(
-                    request_id,
-                    None,
-                    Some(CantDoReason::OutOfRangeSatsAmount),
-                    &event.rumor.pubkey,
-                )
-                .await;
-                return Ok(());
+                return Err(MostroError::CantDo(CantDoReason::OutOfRangeSatsAmount));
             }
+            max_quote = max_quote.max(quote);
+        }
+
+        // The new order can't exceed the funds locked across all orders
+        if !is_within_locked_funds_cap(pool, None, max_quote).await? {
+            return Err(MostroError::CantDo(CantDoReason::OutOfRangeSatsAmount));
+        }
+
+        // Limit the orders a user can create
+        if mostro_settings.max_orders_per_window > 0
+            && !NEW_ORDER_LIMITER.lock().unwrap().try_record(
+                &event.sender.to_string(),
+                Timestamp::now().as_u64() as i64,
+                mostro_settings.order_rate_window_seconds as i64,
+                mostro_settings.max_orders_per_window as usize,
+            )
+        {
+            info!("User {} over the new order rate limit", event.sender);
+            return Err(MostroError::CantDo(CantDoReason::InvalidParameters));
         }
 
+        let options = OrderOptions {
+            unlisted: is_unlisted_request(event),
+            take_pow: get_take_pow_request(event),
+            requires_nip05: is_nip05_required_request(event),
+            quarantine: quarantine_reason(
+                order,
+                get_bitcoin_price(&order.fiat_code).ok(),
+                mostro_settings.quarantine_price_deviation_percent,
+                mostro_settings.quarantine_amount,
+            ),
+        };
         publish_order(
             pool,
             my_keys,
@@ -139,8 +150,65 @@ pub async fn order_action(
             event.rumor.pubkey,
             request_id,
             msg.get_inner_message_kind().trade_index,
+            options,
         )
         .await?;
+        increment(Counter::OrdersCreated);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_order(amount: i64, fiat_amount: i64, premium: i64) -> SmallOrder {
+        SmallOrder {
+            amount,
+            fiat_amount,
+            premium,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fixed_sats_order() {
+        // Sats locked at creation, fiat only as reference
+        assert!(check_order_amounts(&small_order(100_000, 50, 0)).is_ok());
+        assert!(check_order_amounts(&small_order(100_000, 0, 0)).is_ok());
+        // Premium is only applied to market price orders
+        assert!(matches!(
+            check_order_amounts(&small_order(100_000, 50, 2)),
+            Err(CantDoReason::InvalidParameters)
+        ));
+    }
+
+    #[test]
+    fn test_market_price_order() {
+        assert!(check_order_amounts(&small_order(0, 50, 2)).is_ok());
+        // Sats and fiat floating at the same time
+        assert!(matches!(
+            check_order_amounts(&small_order(0, 0, 0)),
+            Err(CantDoReason::InvalidAmount)
+        ));
+    }
+
+    #[test]
+    fn test_range_order_amounts() {
+        let order = SmallOrder {
+            min_amount: Some(10),
+            max_amount: Some(100),
+            ..small_order(0, 0, 0)
+        };
+        assert!(check_order_amounts(&order).is_ok());
+        // Range orders can't have fixed sats
+        let order = SmallOrder {
+            amount: 100_000,
+            ..order
+        };
+        assert!(matches!(
+            check_order_amounts(&order),
+            Err(CantDoReason::InvalidAmount)
+        ));
+    }
+}
@@ -0,0 +1,159 @@
+//! This module lets takers show interest on an order before taking it,
+//! the maker is notified if enabled and the notifications are throttled.
+//! Requested with the `order-interest` command.
+
+use crate::cli::settings::Settings;
+use crate::error::MostroError;
+use crate::messages::{order_amount, order_interest_message};
+use crate::util::{get_required_id, send_new_order_msg};
+
+use anyhow::Result;
+use mostro_core::message::{CantDoReason, Message, Payload};
+use mostro_core::order::{Order, Status};
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use once_cell::sync::Lazy;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::{error, info};
+use uuid::Uuid;
+
+static INTEREST_LIMITER: Lazy<Mutex<InterestRateLimiter>> =
+    Lazy::new(|| Mutex::new(InterestRateLimiter::default()));
+
+/// Keeps the last interest notification sent for each order so makers
+/// are not spammed by takers
+#[derive(Debug, Default)]
+pub struct InterestRateLimiter {
+    notified: HashMap<Uuid, i64>,
+}
+
+impl InterestRateLimiter {
+    /// Record a notification for `order_id` at `now` if the last one is older
+    /// than `interval` seconds, returns false if it must be throttled
+    pub fn try_record(&mut self, order_id: Uuid, now: i64, interval: i64) -> bool {
+        // Forget notifications out of the interval
+        self.notified.retain(|_, at| *at > now - interval);
+        if self.notified.contains_key(&order_id) {
+            return false;
+        }
+        self.notified.insert(order_id, now);
+        true
+    }
+}
+
+/// Get the maker to notify about the interest of `taker` on the order,
+/// only pending orders of other users are notified
+pub fn interest_recipient(order: &Order, taker: &PublicKey) -> Option<PublicKey> {
+    if order.status != Status::Pending.to_string() {
+        return None;
+    }
+    let maker = PublicKey::from_str(&order.creator_pubkey).ok()?;
+    (maker != *taker).then_some(maker)
+}
+
+/// Handler for takers showing interest on an order, the maker receives
+/// the same action with the order id and a note about the interest
+pub async fn order_interest_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    // Get request id
+    let request_id = msg.get_inner_message_kind().request_id;
+    let mostro_settings = Settings::get_mostro();
+
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+
+    let order = match Order::by_id(pool, order_id).await? {
+        Some(order) => order,
+        None => {
+            error!("Order Id {order_id} not found!");
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
+        }
+    };
+
+    let maker = match interest_recipient(&order, &event.rumor.pubkey) {
+        Some(maker) => maker,
+        None => {
+            return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+        }
+    };
+
+    // Interest is accepted but makers only get notified if enabled
+    // and not more than once every interval
+    if mostro_settings.notify_order_interest
+        && INTEREST_LIMITER.lock().unwrap().try_record(
+            order.id,
+            Timestamp::now().as_u64() as i64,
+            mostro_settings.order_interest_interval_seconds as i64,
+        )
+    {
+        info!("Order Id {}: notifying maker of taker interest", order.id);
+        send_new_order_msg(
+            None,
+            Some(order.id),
+            msg.get_inner_message_kind().action.clone(),
+            Some(Payload::TextMessage(order_interest_message(&order_amount(
+                &order,
+            )))),
+            &maker,
+            None,
+        )
+        .await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_order(maker: &Keys) -> Order {
+        Order {
+            id: Uuid::new_v4(),
+            status: Status::Pending.to_string(),
+            creator_pubkey: maker.public_key().to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_interest_reaches_maker() {
+        let maker = Keys::generate();
+        let taker = Keys::generate();
+        let order = pending_order(&maker);
+        assert_eq!(
+            interest_recipient(&order, &taker.public_key()),
+            Some(maker.public_key())
+        );
+        // Makers are not notified of their own interest
+        assert_eq!(interest_recipient(&order, &maker.public_key()), None);
+    }
+
+    #[test]
+    fn test_interest_only_on_pending_orders() {
+        let maker = Keys::generate();
+        let taker = Keys::generate();
+        let mut order = pending_order(&maker);
+        order.status = Status::Active.to_string();
+        assert_eq!(interest_recipient(&order, &taker.public_key()), None);
+    }
+
+    #[test]
+    fn test_interest_notifications_throttled() {
+        let mut limiter = InterestRateLimiter::default();
+        let order_id = Uuid::new_v4();
+        let now = 1_700_000_000;
+        assert!(limiter.try_record(order_id, now, 300));
+        assert!(!limiter.try_record(order_id, now + 10, 300));
+        // Other orders have their own interval
+        assert!(limiter.try_record(Uuid::new_v4(), now + 20, 300));
+        // Notified again once the interval passed
+        assert!(limiter.try_record(order_id, now + 300, 300));
+    }
+}
@@ -0,0 +1,97 @@
+use crate::app::release::do_payment;
+use crate::db::claim_failed_payment;
+use crate::error::MostroError;
+use crate::lightning::invoice::{decode_invoice, is_valid_invoice};
+use crate::util::get_required_id;
+
+use anyhow::Result;
+use mostro_core::message::{CantDoReason, Message};
+use mostro_core::order::{Order, Status};
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use std::str::FromStr;
+use tracing::{error, info};
+
+/// Buyer invoice can only be paid once the seller hold invoice was settled
+pub fn can_pay_invoice(status: Status) -> bool {
+    status == Status::SettledHoldInvoice
+}
+
+/// Handler for the buyer asking to be paid to a new bolt11 invoice, the
+/// payment is done with the same flow used after the seller release
+pub async fn pay_invoice_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    // Get request id
+    let request_id = msg.get_inner_message_kind().request_id;
+
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+
+    let mut order = match Order::by_id(pool, order_id).await? {
+        Some(order) => order,
+        None => {
+            error!("Order Id {order_id} not found!");
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
+        }
+    };
+
+    // Only the buyer is paid
+    if order.buyer_pubkey != Some(event.rumor.pubkey.to_string()) {
+        return Err(MostroError::CantDo(CantDoReason::InvalidPeer));
+    }
+
+    // A new invoice is taken only once the payment to the last one failed
+    match Status::from_str(&order.status) {
+        Ok(status) if can_pay_invoice(status) && order.failed_payment => {}
+        _ => {
+            return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+        }
+    }
+
+    let payment_request = match msg.get_inner_message_kind().get_payment_request() {
+        Some(pr) if decode_invoice(&pr).is_ok() => pr,
+        _ => {
+            return Err(MostroError::CantDo(CantDoReason::InvalidInvoice));
+        }
+    };
+    if is_valid_invoice(
+        payment_request.clone(),
+        Some(order.amount as u64),
+        Some(order.fee as u64),
+    )
+    .await
+    .is_err()
+    {
+        return Err(MostroError::CantDo(CantDoReason::InvalidAmount));
+    }
+
+    // The new invoice is paid now, the failed payments job must not retry it
+    // and a payment already in flight is not paid twice
+    if !claim_failed_payment(pool, order.id, Some(&payment_request)).await? {
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+    }
+    order.buyer_invoice = Some(payment_request);
+    order.failed_payment = false;
+    order.payment_attempts = 0;
+    info!("Order Id {}: paying buyer invoice", order.id);
+
+    Ok(do_payment(order, request_id, my_keys, pool).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invoice_paid_after_seller_release() {
+        assert!(can_pay_invoice(Status::SettledHoldInvoice));
+        assert!(!can_pay_invoice(Status::Active));
+        assert!(!can_pay_invoice(Status::FiatSent));
+        assert!(!can_pay_invoice(Status::Success));
+    }
+}
@@ -0,0 +1,306 @@
+//! Orders flagged by heuristics are held for the operator review instead of
+//! being published, the Mostro admin approves them with the `approve-order`
+//! command or rejects them with the `reject-order` command.
+
+use crate::db::remove_quarantined_order;
+use crate::error::MostroError;
+use crate::util::{get_required_id, send_dm, send_new_order_msg, update_order_event};
+
+use anyhow::Result;
+use mostro_core::message::{Action, CantDoReason, Message, Payload};
+use mostro_core::order::{Kind, Order, SmallOrder, Status};
+use nostr::nips::nip59::UnwrappedGift;
+use nostr_sdk::prelude::*;
+use sqlx::{Pool, Sqlite};
+use sqlx_crud::Crud;
+use std::str::FromStr;
+use tracing::info;
+use uuid::Uuid;
+
+/// Reason to quarantine a new order, if any heuristic flags it. Orders of
+/// `max_amount` sats or more and fixed price orders more than
+/// `max_price_deviation` percent off `market_price` are flagged, a limit
+/// of 0 disables its heuristic
+pub fn quarantine_reason(
+    order: &SmallOrder,
+    market_price: Option<f64>,
+    max_price_deviation: f64,
+    max_amount: u64,
+) -> Option<String> {
+    if max_amount > 0 && order.amount >= max_amount as i64 {
+        return Some(format!("unusual amount of {} sats", order.amount));
+    }
+    if let Some(market_price) = market_price {
+        if max_price_deviation > 0.0 && order.amount > 0 && order.fiat_amount > 0 {
+            let price = order.fiat_amount as f64 / order.amount as f64 * 100_000_000_f64;
+            let deviation = (price - market_price).abs() / market_price * 100_f64;
+            if deviation > max_price_deviation {
+                return Some(format!("price {deviation:.1}% off market"));
+            }
+        }
+    }
+
+    None
+}
+
+/// Maker trade pubkey and trade index of an order, the order ack goes to them
+fn maker_trade_keys(order: &Order) -> Option<(PublicKey, Option<i64>)> {
+    let maker = PublicKey::from_str(&order.creator_pubkey).ok()?;
+    let trade_index = if order.kind == Kind::Buy.to_string() {
+        order.trade_index_buyer
+    } else {
+        order.trade_index_seller
+    };
+
+    Some((maker, trade_index))
+}
+
+/// Approve a quarantined order, it's published and can be taken from now on,
+/// false if the order was not waiting for review
+pub async fn approve_quarantined_order(
+    pool: &Pool<Sqlite>,
+    my_keys: &Keys,
+    order_id: Uuid,
+) -> Result<bool> {
+    if !remove_quarantined_order(pool, order_id).await? {
+        return Ok(false);
+    }
+    let Some(order) = Order::by_id(pool, order_id).await? else {
+        return Ok(false);
+    };
+    let order = update_order_event(my_keys, Status::Pending, &order, pool).await?;
+    let order = order.update(pool).await?;
+    info!("Order Id {order_id}: approved after review and published");
+
+    // The maker gets the order ack now that it's published
+    if let Some((maker, trade_index)) = maker_trade_keys(&order) {
+        send_new_order_msg(
+            None,
+            Some(order.id),
+            Action::NewOrder,
+            Some(Payload::Order(order.as_new_order())),
+            &maker,
+            trade_index,
+        )
+        .await;
+    }
+
+    Ok(true)
+}
+
+/// Reject a quarantined order, it's canceled without being ever published,
+/// false if the order was not waiting for review
+pub async fn reject_quarantined_order(pool: &Pool<Sqlite>, order_id: Uuid) -> Result<bool> {
+    if !remove_quarantined_order(pool, order_id).await? {
+        return Ok(false);
+    }
+    let Some(mut order) = Order::by_id(pool, order_id).await? else {
+        return Ok(false);
+    };
+    order.status = Status::CanceledByAdmin.to_string();
+    let order = order.update(pool).await?;
+    info!("Order Id {order_id}: rejected after review");
+
+    // Let the maker know
+    if let Some((maker, trade_index)) = maker_trade_keys(&order) {
+        send_new_order_msg(
+            None,
+            Some(order.id),
+            Action::AdminCanceled,
+            None,
+            &maker,
+            trade_index,
+        )
+        .await;
+    }
+
+    Ok(true)
+}
+
+/// Tell the admin the review of an order is done
+async fn send_review_done(
+    admin: &PublicKey,
+    my_keys: &Keys,
+    order_id: Uuid,
+    request_id: Option<u64>,
+    action: Action,
+    text: &str,
+) -> Result<()> {
+    let message = Message::new_order(
+        Some(order_id),
+        request_id,
+        None,
+        action,
+        Some(Payload::TextMessage(text.to_string())),
+    );
+    send_dm(admin, my_keys.clone(), message.as_json()?, None).await
+}
+
+/// Handle the `approve-order` command of the Mostro admin
+pub async fn approve_order_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    let request_id = msg.get_inner_message_kind().request_id;
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+    if event.rumor.pubkey != my_keys.public_key() {
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
+    }
+    if !approve_quarantined_order(pool, my_keys, order_id).await? {
+        return Err(MostroError::CantDo(CantDoReason::NotFound));
+    }
+    send_review_done(
+        &event.rumor.pubkey,
+        my_keys,
+        order_id,
+        request_id,
+        Action::NewOrder,
+        "Order approved and published",
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Handle the `reject-order` command of the Mostro admin
+pub async fn reject_order_action(
+    msg: Message,
+    event: &UnwrappedGift,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<(), MostroError> {
+    let request_id = msg.get_inner_message_kind().request_id;
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+    if event.rumor.pubkey != my_keys.public_key() {
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
+    }
+    if !reject_quarantined_order(pool, order_id).await? {
+        return Err(MostroError::CantDo(CantDoReason::NotFound));
+    }
+    send_review_done(
+        &event.rumor.pubkey,
+        my_keys,
+        order_id,
+        request_id,
+        Action::AdminCanceled,
+        "Order rejected",
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::settings::Settings;
+    use crate::db::{add_quarantined_order, connect_test_db, is_order_quarantined};
+    use crate::MOSTRO_CONFIG;
+    use std::env::set_var;
+    use std::path::PathBuf;
+    use std::sync::{Arc, RwLock};
+
+    fn order(amount: i64, fiat_amount: i64) -> SmallOrder {
+        SmallOrder {
+            amount,
+            fiat_amount,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_order_flagged_into_quarantine() {
+        // 100 USD for 100k sats is 100k USD/BTC, market at 50k
+        let reason = quarantine_reason(&order(100_000, 100), Some(50_000.0), 20.0, 0);
+        assert_eq!(reason.unwrap(), "price 100.0% off market");
+        let reason = quarantine_reason(&order(5_000_000, 2_500), Some(50_000.0), 20.0, 1_000_000);
+        assert_eq!(reason.unwrap(), "unusual amount of 5000000 sats");
+    }
+
+    #[test]
+    fn test_order_not_flagged() {
+        // 10% off market within the 20% allowed
+        assert!(quarantine_reason(&order(100_000, 55), Some(50_000.0), 20.0, 0).is_none());
+        // Market price orders follow the market
+        assert!(quarantine_reason(&order(0, 100), Some(50_000.0), 20.0, 0).is_none());
+        // No market price known or heuristics disabled
+        assert!(quarantine_reason(&order(100_000, 100), None, 20.0, 0).is_none());
+        assert!(quarantine_reason(&order(100_000, 100), Some(50_000.0), 0.0, 0).is_none());
+    }
+
+    fn review_request(sender: &Keys, order_id: Uuid) -> (Message, UnwrappedGift) {
+        let message = Message::new_order(Some(order_id), Some(1), None, Action::NewOrder, None);
+        let event = UnwrappedGift {
+            sender: sender.public_key(),
+            rumor: EventBuilder::text_note("").build(sender.public_key()),
+        };
+        (message, event)
+    }
+
+    async fn quarantined_order(pool: &Pool<Sqlite>) -> Order {
+        set_var("RUN_MODE", ".tpl");
+        MOSTRO_CONFIG
+            .get_or_init(|| RwLock::new(Arc::new(Settings::new(PathBuf::from("./")).unwrap())));
+        let order = Order {
+            id: Uuid::new_v4(),
+            kind: Kind::Sell.to_string(),
+            status: Status::Pending.to_string(),
+            creator_pubkey: Keys::generate().public_key().to_string(),
+            ..Default::default()
+        }
+        .create(pool)
+        .await
+        .unwrap();
+        add_quarantined_order(pool, order.id, "off-market price", 100)
+            .await
+            .unwrap();
+        order
+    }
+
+    #[tokio::test]
+    async fn test_quarantined_order_approved() {
+        let (pool, _db) = connect_test_db().await;
+        let order = quarantined_order(&pool).await;
+        let admin = Keys::generate();
+
+        // Only the Mostro admin reviews orders
+        let (message, event) = review_request(&Keys::generate(), order.id);
+        let result = approve_order_action(message, &event, &admin, &pool).await;
+        assert_eq!(
+            result,
+            Err(MostroError::CantDo(CantDoReason::InvalidPubkey))
+        );
+        assert!(is_order_quarantined(&pool, order.id).await.unwrap());
+
+        let (message, event) = review_request(&admin, order.id);
+        approve_order_action(message, &event, &admin, &pool)
+            .await
+            .unwrap();
+        assert!(!is_order_quarantined(&pool, order.id).await.unwrap());
+        let approved = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(approved.status, Status::Pending.to_string());
+        assert_ne!(approved.event_id, order.event_id);
+
+        // Reviewed only once
+        let (message, event) = review_request(&admin, order.id);
+        let result = reject_order_action(message, &event, &admin, &pool).await;
+        assert_eq!(result, Err(MostroError::CantDo(CantDoReason::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_quarantined_order_rejected() {
+        let (pool, _db) = connect_test_db().await;
+        let order = quarantined_order(&pool).await;
+        let admin = Keys::generate();
+
+        let (message, event) = review_request(&admin, order.id);
+        reject_order_action(message, &event, &admin, &pool)
+            .await
+            .unwrap();
+        assert!(!is_order_quarantined(&pool, order.id).await.unwrap());
+        let rejected = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(rejected.status, Status::CanceledByAdmin.to_string());
+    }
+}
@@ -1,8 +1,10 @@
-use crate::util::{send_cant_do_msg, send_new_order_msg, update_user_rating_event};
+use crate::error::MostroError;
+use crate::util::{get_required_id, send_new_order_msg, update_user_rating_event};
 use crate::NOSTR_CLIENT;
 
-use crate::db::{is_user_present, update_user_rating};
-use anyhow::{Error, Result};
+use crate::cli::settings::Settings;
+use crate::db::{add_user_rating, claim_order_rating, is_user_present, release_order_rating};
+use anyhow::Result;
 use mostro_core::message::{Action, CantDoReason, Message, Payload};
 use mostro_core::order::{Order, Status};
 use mostro_core::rating::Rating;
@@ -19,6 +21,55 @@ use tracing::error;
 pub const MAX_RATING: u8 = 5;
 pub const MIN_RATING: u8 = 1;
 
+/// Weight of a rating given the reputation of the rater, a rater without
+/// reviews counts `min_weight` and the weight grows with the reviews and
+/// rating of the rater up to 1 after `full_weight_reviews` top ratings
+pub fn get_rating_weight(
+    rater_reviews: i64,
+    rater_rating: f64,
+    min_weight: f64,
+    full_weight_reviews: i64,
+) -> f64 {
+    let min_weight = min_weight.clamp(0.0, 1.0);
+    let experience = if full_weight_reviews <= 0 {
+        1.0
+    } else {
+        (rater_reviews.max(0) as f64 / full_weight_reviews as f64).min(1.0)
+    };
+    let quality = (rater_rating / MAX_RATING as f64).clamp(0.0, 1.0);
+
+    min_weight + (1.0 - min_weight) * experience * quality
+}
+
+/// Fraction a rating given `elapsed` seconds ago still counts in the
+/// reputation, halved every `half_life_days`, no decay if it's 0
+pub fn rating_decay(elapsed: i64, half_life_days: u32) -> f64 {
+    if half_life_days == 0 {
+        return 1.0;
+    }
+    let half_life = half_life_days as f64 * 86400.0;
+    0.5_f64.powf(elapsed.max(0) as f64 / half_life)
+}
+
+/// Weight of a rating sent by `rater`, full weight if weighting is disabled
+async fn get_rater_weight(pool: &Pool<Sqlite>, rater: &str) -> f64 {
+    let mostro_settings = Settings::get_mostro();
+    if !mostro_settings.reputation_weighting {
+        return 1.0;
+    }
+    let (reviews, rating) = match is_user_present(pool, rater.to_string()).await {
+        Ok(user) => (user.total_reviews, user.total_rating),
+        Err(_) => (0, 0.0),
+    };
+
+    get_rating_weight(
+        reviews,
+        rating,
+        mostro_settings.reputation_min_weight,
+        mostro_settings.reputation_full_weight_reviews as i64,
+    )
+}
+
 pub async fn get_user_reputation(user: &str, my_keys: &Keys) -> Result<Option<Rating>> {
     // Request NIP33 of the counterparts
     let filters = Filter::new()
@@ -47,85 +98,78 @@ pub async fn get_user_reputation(user: &str, my_keys: &Keys) -> Result<Option<Ra
     Ok(Some(reputation))
 }
 
+/// Party of an order rating its counterpart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RatingParty {
+    Buyer,
+    Seller,
+}
+
+/// Check `sender` can rate the counterpart of `order`, it must be one of its
+/// parties and the trade completed, disputes settled by a solver end as
+/// success once the buyer is paid, canceled trades can't be rated
+pub fn check_rating_allowed(order: &Order, sender: &str) -> Result<RatingParty, CantDoReason> {
+    let party = if order.buyer_pubkey.as_deref() == Some(sender) {
+        RatingParty::Buyer
+    } else if order.seller_pubkey.as_deref() == Some(sender) {
+        RatingParty::Seller
+    } else {
+        return Err(CantDoReason::InvalidPeer);
+    };
+    if order.status != Status::Success.to_string() {
+        return Err(CantDoReason::InvalidOrderStatus);
+    }
+
+    Ok(party)
+}
+
 pub async fn update_user_reputation_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
     rate_list: Arc<Mutex<Vec<Event>>>,
-) -> Result<()> {
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
 
-    let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
-        order_id
-    } else {
-        return Err(Error::msg("No order id"));
-    };
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
     let order = match Order::by_id(pool, order_id).await? {
         Some(order) => order,
         None => {
             error!("Order Id {order_id} not found!");
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
         }
     };
 
-    // Get needed info about users
-    let (seller, buyer) = match (&order.seller_pubkey, &order.buyer_pubkey) {
-        (Some(seller), Some(buyer)) => (seller.to_owned(), buyer.to_owned()),
-        (None, _) => return Err(Error::msg("Missing seller pubkey")),
-        (_, None) => return Err(Error::msg("Missing buyer pubkey")),
-    };
-
     let message_sender = event.rumor.pubkey.to_string();
 
-    if order.status != Status::Success.to_string() {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::InvalidOrderStatus),
-            &event.rumor.pubkey,
-        )
-        .await;
-        error!("Order Id {order_id} wrong status");
-        return Ok(());
-    }
-    // Get counterpart pubkey
-    let mut counterpart: String = String::new();
-    let mut counterpart_trade_pubkey: String = String::new();
-    let mut buyer_rating: bool = false;
-    let mut seller_rating: bool = false;
-
-    // Find the counterpart public key
-    if message_sender == buyer {
-        counterpart = order
-            .master_seller_pubkey
-            .ok_or_else(|| Error::msg("Missing seller identity pubkey"))?;
-        buyer_rating = true;
-        counterpart_trade_pubkey = order
-            .buyer_pubkey
-            .ok_or_else(|| Error::msg("Missing buyer pubkey"))?;
-    } else if message_sender == seller {
-        counterpart = order
-            .master_buyer_pubkey
-            .ok_or_else(|| Error::msg("Missing buyer identity pubkey"))?;
-        seller_rating = true;
-        counterpart_trade_pubkey = order
-            .seller_pubkey
-            .ok_or_else(|| Error::msg("Missing seller pubkey"))?;
+    // Only the parties of a completed trade can rate it
+    let party = match check_rating_allowed(&order, &message_sender) {
+        Ok(party) => party,
+        Err(reason) => {
+            error!("Order Id {order_id} can't be rated by {message_sender}: {reason:?}");
+            return Err(MostroError::CantDo(reason));
+        }
     };
+    let buyer_rating = party == RatingParty::Buyer;
+    let seller_rating = party == RatingParty::Seller;
 
-    // Add a check in case of no counterpart found
-    if counterpart.is_empty() {
-        // We create a Message
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::InvalidPeer),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+    // Find the counterpart public key
+    let counterpart_trade_pubkey = message_sender.clone();
+    let (counterpart, rater) = match party {
+        RatingParty::Buyer => (
+            order.master_seller_pubkey.clone().ok_or_else(|| {
+                MostroError::Internal("Missing seller identity pubkey".to_string())
+            })?,
+            order.master_buyer_pubkey.clone(),
+        ),
+        RatingParty::Seller => (
+            order.master_buyer_pubkey.clone().ok_or_else(|| {
+                MostroError::Internal("Missing buyer identity pubkey".to_string())
+            })?,
+            order.master_seller_pubkey.clone(),
+        ),
     };
 
     // Check if the order is not rated by the message sender
@@ -138,73 +182,69 @@ pub async fn update_user_reputation_action(
         update_buyer_rate = true;
     };
     if !update_buyer_rate && !update_seller_rate {
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
     };
 
     // Check if content of Peer is the same of counterpart
     let rating =
         if let Some(Payload::RatingUser(v)) = msg.get_inner_message_kind().payload.to_owned() {
             if !(MIN_RATING..=MAX_RATING).contains(&v) {
-                return Err(Error::msg(format!(
+                return Err(MostroError::Internal(format!(
                     "Rating must be between {} and {}",
                     MIN_RATING, MAX_RATING
                 )));
             }
             v
         } else {
-            return Err(Error::msg("No rating present"));
+            return Err(MostroError::Internal("No rating present".to_string()));
         };
 
-    // Get counter to vote from db
-    let mut user_to_vote = is_user_present(pool, counterpart.clone()).await?;
-
-    // Update user reputation
-    // Going on with calculation
-    // increment first
-    user_to_vote.total_reviews += 1;
-    let old_rating = user_to_vote.total_rating as f64;
-    // recompute new rating
-    if user_to_vote.total_reviews <= 1 {
-        user_to_vote.total_rating = rating.into();
-        user_to_vote.max_rating = rating.into();
-        user_to_vote.min_rating = rating.into();
-    } else {
-        user_to_vote.total_rating = old_rating
-            + ((user_to_vote.last_rating as f64) - old_rating)
-                / (user_to_vote.total_reviews as f64);
-        if user_to_vote.max_rating < rating.into() {
-            user_to_vote.max_rating = rating.into();
-        }
-        if user_to_vote.min_rating > rating.into() {
-            user_to_vote.min_rating = rating.into();
-        }
+    // Each party rates an order once, even with concurrent ratings
+    if !claim_order_rating(
+        pool,
+        order.id,
+        &message_sender,
+        Timestamp::now().as_u64() as i64,
+    )
+    .await?
+    {
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
     }
-    // Store last rating
-    user_to_vote.last_rating = rating.into();
+
+    // Update user reputation in db, concurrent ratings of the same user are applied atomically
+    let weight = match rater {
+        Some(rater) => get_rater_weight(pool, &rater).await,
+        None => 1.0,
+    };
+    let user_to_vote = match add_user_rating(
+        pool,
+        counterpart.clone(),
+        rating.into(),
+        weight,
+        Timestamp::now().as_u64() as i64,
+        Settings::get_mostro().reputation_half_life_days,
+    )
+    .await
+    {
+        Ok(user) => user,
+        Err(e) => {
+            release_order_rating(pool, order.id, &message_sender).await?;
+            return Err(MostroError::Internal(format!(
+                "Error updating user rating : {}",
+                e
+            )));
+        }
+    };
     // Create new rating event
     let reputation_event = Rating::new(
         user_to_vote.total_reviews as u64,
-        user_to_vote.total_rating as f64,
+        user_to_vote.total_rating,
         user_to_vote.last_rating as u8,
         user_to_vote.min_rating as u8,
         user_to_vote.max_rating as u8,
     )
-    .to_tags()?;
-
-    // Save new rating to db
-    if let Err(e) = update_user_rating(
-        pool,
-        user_to_vote.pubkey,
-        user_to_vote.last_rating,
-        user_to_vote.min_rating,
-        user_to_vote.max_rating,
-        user_to_vote.total_reviews,
-        user_to_vote.total_rating,
-    )
-    .await
-    {
-        return Err(Error::msg(format!("Error updating user rating : {}", e)));
-    }
+    .to_tags()
+    .map_err(|e| MostroError::Internal(e.to_string()))?;
 
     if buyer_rating || seller_rating {
         // Update db with rate flags
@@ -234,3 +274,81 @@ pub async fn update_user_reputation_action(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(status: Status) -> Order {
+        Order {
+            status: status.to_string(),
+            buyer_pubkey: Some("buyer".to_string()),
+            seller_pubkey: Some("seller".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parties_rate_completed_orders() {
+        let order = order(Status::Success);
+        assert_eq!(
+            check_rating_allowed(&order, "buyer"),
+            Ok(RatingParty::Buyer)
+        );
+        assert_eq!(
+            check_rating_allowed(&order, "seller"),
+            Ok(RatingParty::Seller)
+        );
+    }
+
+    #[test]
+    fn test_third_party_rating_rejected() {
+        for status in [Status::Success, Status::Active] {
+            assert_eq!(
+                check_rating_allowed(&order(status), "stranger"),
+                Err(CantDoReason::InvalidPeer)
+            );
+        }
+    }
+
+    #[test]
+    fn test_premature_rating_rejected() {
+        for status in [
+            Status::Active,
+            Status::FiatSent,
+            Status::SettledHoldInvoice,
+            Status::Dispute,
+            Status::CanceledByAdmin,
+            Status::Canceled,
+        ] {
+            assert_eq!(
+                check_rating_allowed(&order(status), "buyer"),
+                Err(CantDoReason::InvalidOrderStatus)
+            );
+        }
+    }
+
+    #[test]
+    fn test_rating_decay_by_age() {
+        let day = 86400;
+        assert_eq!(rating_decay(0, 30), 1.0);
+        // A rating of N days ago counts 0.5^(N / half-life)
+        assert!((rating_decay(30 * day, 30) - 0.5).abs() < 1e-12);
+        assert!((rating_decay(90 * day, 30) - 0.125).abs() < 1e-12);
+        assert!((rating_decay(15 * day, 30) - 0.5_f64.sqrt()).abs() < 1e-12);
+        // Decay disabled
+        assert_eq!(rating_decay(365 * day, 0), 1.0);
+    }
+
+    #[test]
+    fn test_rating_weight_by_rater_reputation() {
+        // New account counts the minimum weight
+        assert_eq!(get_rating_weight(0, 0.0, 0.2, 10), 0.2);
+        // Reputable rater counts fully
+        assert_eq!(get_rating_weight(25, 5.0, 0.2, 10), 1.0);
+        // Half the reviews needed with top rating
+        assert!((get_rating_weight(5, 5.0, 0.2, 10) - 0.6).abs() < 1e-9);
+        // A high reputation rater weighs more than a low reputation one
+        assert!(get_rating_weight(20, 4.8, 0.2, 10) > get_rating_weight(20, 1.5, 0.2, 10));
+    }
+}
@@ -1,14 +1,27 @@
-use crate::cli::settings::Settings;
-use crate::db::{self};
-use crate::lightning::LndConnector;
+use crate::app::dispute::publish_dispute_event;
+use crate::cli::settings::{ReleasePolicy, Settings};
+use crate::db::{self, pubkeys_match, OrderOptions};
+use crate::error::MostroError;
+use crate::lightning::backend::{connect_backend, LightningBackend};
+use crate::lightning::invoice::{
+    decode_invoice, invoice_fallback_address, is_expired_at, is_onchain_address, onchain_network,
+};
+use crate::lightning::payment_monitor::{record_failed_payment, schedule_payment_retry};
+use crate::lightning::reconcile::SettleOutcome;
+use crate::lightning::PaymentMessage;
 use crate::lnurl::resolv_ln_address;
+use crate::messages::{order_amount, payment_failed_message};
+use crate::metrics::{increment, Counter};
+use crate::shutdown;
 use crate::util::{
-    get_keys, get_nostr_client, send_cant_do_msg, send_new_order_msg, settle_seller_hold_invoice,
+    cancel_order_without_funds, get_keys, get_required_id, lock_order_settlement,
+    publish_status_event, send_cant_do_msg, send_new_order_msg, settle_seller_hold_invoice,
     update_order_event,
 };
 use anyhow::{Error, Result};
 use fedimint_tonic_lnd::lnrpc::payment::PaymentStatus;
 use lnurl::lightning_address::LightningAddress;
+use mostro_core::dispute::Status as DisputeStatus;
 use mostro_core::message::{Action, CantDoReason, Message, Payload};
 use mostro_core::order::{Order, Status};
 use nostr::nips::nip59::UnwrappedGift;
@@ -17,15 +30,16 @@ use sqlx::{Pool, Sqlite};
 use sqlx_crud::Crud;
 use std::cmp::Ordering;
 use std::str::FromStr;
-use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::{channel, Receiver};
 use tracing::{error, info};
 
-pub async fn check_failure_retries(order: &Order, request_id: Option<u64>) -> Result<Order> {
+pub async fn check_failure_retries(
+    order: &Order,
+    request_id: Option<u64>,
+    pool: &Pool<Sqlite>,
+) -> Result<Order> {
     let mut order = order.clone();
 
-    // Handle to db here
-    let pool = db::connect().await?;
-
     // Get max number of retries
     let ln_settings = Settings::get_ln();
     let retries_number = ln_settings.payment_attempts as i64;
@@ -42,61 +56,105 @@ pub async fn check_failure_retries(order: &Order, request_id: Option<u64>) -> Re
         None => return Err(Error::msg("Missing buyer pubkey")),
     };
 
+    // Track failures to alert on repeated failed payments
+    record_failed_payment(&buyer_pubkey.to_string());
+    increment(Counter::PaymentsFailed);
+
+    // Retries back off exponentially to not hammer offline buyer nodes
+    let next_retry = schedule_payment_retry(pool, order.id, order.payment_attempts as u32).await?;
+
     send_new_order_msg(
         request_id,
         Some(order.id),
         Action::PaymentFailed,
-        None,
+        Some(Payload::TextMessage(payment_failed_message(
+            &order_amount(&order),
+            next_retry,
+            &Settings::get_mostro().operator_contact,
+        ))),
         &buyer_pubkey,
         None,
     )
     .await;
 
     // Update order
-    let result = order.update(&pool).await?;
+    let result = order.update(pool).await?;
     Ok(result)
 }
 
+/// Check both parties confirmed the fiat was sent before the release, the
+/// buyer sending fiat-sent and the seller acknowledging it, orders in
+/// dispute can always be released
+pub fn check_fiat_confirmations(
+    required: bool,
+    status: &Status,
+    seller_confirmed: bool,
+) -> Result<(), CantDoReason> {
+    if !required || matches!(status, Status::Dispute) {
+        return Ok(());
+    }
+    if !matches!(status, Status::FiatSent) || !seller_confirmed {
+        return Err(CantDoReason::NotAllowedByStatus);
+    }
+
+    Ok(())
+}
+
+/// Check the release policies of the order payment methods, when several
+/// methods have a policy the strictest conditions apply
+pub fn check_release_policy(
+    order: &Order,
+    policies: &[ReleasePolicy],
+    now: i64,
+) -> Result<(), CantDoReason> {
+    let methods: Vec<String> = order
+        .payment_method
+        .split(',')
+        .map(|m| m.trim().to_lowercase())
+        .collect();
+    let policies = policies
+        .iter()
+        .filter(|p| methods.contains(&p.payment_method.trim().to_lowercase()));
+
+    for policy in policies {
+        if policy.require_fiat_sent && order.status == Status::Active.to_string() {
+            return Err(CantDoReason::NotAllowedByStatus);
+        }
+        if now - order.invoice_held_at < policy.min_hold_seconds as i64 {
+            return Err(CantDoReason::NotAllowedByStatus);
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn release_action(
     msg: Message,
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-    ln_client: &mut LndConnector,
-) -> Result<()> {
+    ln_client: &mut dyn LightningBackend,
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
-    let order_id = msg
-        .get_inner_message_kind()
-        .id
-        .ok_or(Error::msg("Order ID is required but was not provided"))?;
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
 
     let mut order = Order::by_id(pool, order_id)
         .await?
-        .ok_or(Error::msg(format!(
-            "Order {} not found in database",
-            order_id
-        )))?;
+        .ok_or(MostroError::CantDo(CantDoReason::NotFound))?;
 
-    let seller_pubkey_hex = order.seller_pubkey.as_ref().ok_or(Error::msg(format!(
-        "Seller public key not found for order {}",
-        order_id
-    )))?;
+    let seller_pubkey_hex = order
+        .seller_pubkey
+        .as_ref()
+        .ok_or_else(|| MostroError::Internal("Missing seller pubkey".to_string()))?;
 
     // Only seller can release funds
-    if &event.rumor.pubkey.to_string() != seller_pubkey_hex {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::InvalidPeer),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+    if !pubkeys_match(seller_pubkey_hex, &event.rumor.pubkey.to_hex()) {
+        return Err(MostroError::CantDo(CantDoReason::InvalidPeer));
     }
 
-    let next_trade: Option<(String, u32)> = match event.rumor.pubkey.to_string() {
-        pubkey if pubkey == order.creator_pubkey => {
+    let next_trade: Option<(String, u32)> = match event.rumor.pubkey.to_hex() {
+        pubkey if pubkeys_match(&pubkey, &order.creator_pubkey) => {
             if let Some(Payload::NextTrade(pubkey, index)) = &msg.get_inner_message_kind().payload {
                 Some((pubkey.clone(), *index))
             } else {
@@ -109,25 +167,56 @@ pub async fn release_action(
         },
     };
 
-    let current_status =
-        Status::from_str(&order.status).map_err(|_| Error::msg("Wrong order status"))?;
+    let current_status = Status::from_str(&order.status)
+        .map_err(|_| MostroError::CantDo(CantDoReason::InvalidOrderStatus))?;
 
     if !matches!(
         current_status,
         Status::Active | Status::FiatSent | Status::Dispute
     ) {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::NotAllowedByStatus),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
     }
 
-    settle_seller_hold_invoice(
-        event,
+    // An order taken without resolving its sats amount can't be paid out,
+    // the seller funds are kept held instead of settled
+    if order.amount == 0 {
+        tracing::error!("Order Id {}: sats amount not resolved", order.id);
+        return Err(MostroError::CantDo(CantDoReason::InvalidAmount));
+    }
+
+    // Both parties must confirm the fiat was sent
+    let seller_confirmed = db::find_seller_fiat_confirmation(pool, order.id)
+        .await?
+        .is_some();
+    if let Err(reason) = check_fiat_confirmations(
+        Settings::get_mostro().require_fiat_confirmations,
+        &current_status,
+        seller_confirmed,
+    ) {
+        return Err(MostroError::CantDo(reason));
+    }
+
+    // Payment method conditions don't apply to orders in dispute
+    if !matches!(current_status, Status::Dispute) {
+        if let Err(reason) = check_release_policy(
+            &order,
+            &Settings::get_mostro().release_policies,
+            Timestamp::now().as_u64() as i64,
+        ) {
+            return Err(MostroError::CantDo(reason));
+        }
+    }
+
+    // An admin settle of the same order may be running, only one of them settles it
+    let _settle_lock = match lock_order_settlement(pool, order.id).await? {
+        Some((lock, locked)) if locked.status == order.status => lock,
+        _ => {
+            return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+        }
+    };
+
+    let outcome = settle_seller_hold_invoice(
+        &event.rumor.pubkey,
         ln_client,
         Action::Released,
         false,
@@ -135,13 +224,39 @@ pub async fn release_action(
         request_id,
     )
     .await?;
+    if outcome == SettleOutcome::Canceled {
+        cancel_order_without_funds(pool, my_keys, &order).await?;
+        return Ok(());
+    }
+    // Record the settlement before anything else can fail
+    if !db::set_order_settled(pool, order.id).await? {
+        info!("Order Id {}: already settled, no payout", order.id);
+        return Ok(());
+    }
+    order.status = Status::SettledHoldInvoice.to_string();
+    increment(Counter::OrdersSettled);
+
+    // A seller releasing during a dispute resolves it in favor of the buyer
+    if matches!(current_status, Status::Dispute) {
+        if let Some(dispute) =
+            db::resolve_open_dispute(pool, order.id, DisputeStatus::Settled).await?
+        {
+            info!(
+                "Order Id {}: dispute {} resolved by seller release",
+                order.id, dispute.id
+            );
+            if let Err(e) = publish_dispute_event(&dispute, my_keys).await {
+                error!("Order Id {}: {e}", order.id);
+            }
+        }
+    }
 
     // We send a message to buyer indicating seller released funds
     let buyer_pubkey = PublicKey::from_str(
         order
             .buyer_pubkey
             .as_ref()
-            .ok_or(Error::msg("Missing buyer pubkey"))?
+            .ok_or_else(|| MostroError::Internal("Missing buyer pubkey".to_string()))?
             .as_str(),
     )?;
 
@@ -154,15 +269,20 @@ pub async fn release_action(
         None,
     )
     .await;
-    order = update_order_event(my_keys, Status::SettledHoldInvoice, &order).await?;
+    order = update_order_event(my_keys, Status::SettledHoldInvoice, &order, pool).await?;
     // Handle child order for range orders
     if let Ok((Some(child_order), Some(event))) =
         get_child_order(order.clone(), request_id, my_keys).await
     {
-        if let Ok(client) = get_nostr_client() {
-            if client.send_event(event).await.is_err() {
-                tracing::warn!("Failed sending child order event for order id: {}. This may affect order synchronization", child_order.id)
-            }
+        // Child orders keep the options of the range, the range was already
+        // reviewed when it was taken
+        let options = OrderOptions {
+            quarantine: None,
+            ..db::find_order_options(pool, order.id).await?
+        };
+        db::set_order_options(pool, &child_order, &options).await?;
+        if !options.unlisted && publish_status_event(event).await.is_err() {
+            tracing::warn!("Failed sending child order event for order id: {}. This may affect order synchronization", child_order.id)
         }
         handle_child_order(child_order, &order, next_trade, pool, request_id).await?;
     }
@@ -191,7 +311,7 @@ pub async fn release_action(
     .await;
 
     // Finally we try to pay buyer's invoice
-    let _ = do_payment(order, request_id).await;
+    let _ = do_payment(order, request_id, my_keys, pool).await;
 
     Ok(())
 }
@@ -214,13 +334,13 @@ async fn handle_child_order(
     pool: &Pool<Sqlite>,
     request_id: Option<u64>,
 ) -> Result<()> {
+    let mut child_order = child_order;
     if let Some((next_trade_pubkey, next_trade_index)) = next_trade {
-        let mut child_order = child_order;
-        if &order.creator_pubkey == order.seller_pubkey.as_ref().unwrap() {
+        if pubkeys_match(&order.creator_pubkey, order.seller_pubkey.as_ref().unwrap()) {
             child_order.seller_pubkey = Some(next_trade_pubkey.clone());
             child_order.creator_pubkey = next_trade_pubkey.clone();
             child_order.trade_index_seller = Some(next_trade_index as i64);
-        } else if &order.creator_pubkey == order.buyer_pubkey.as_ref().unwrap() {
+        } else if pubkeys_match(&order.creator_pubkey, order.buyer_pubkey.as_ref().unwrap()) {
             child_order.buyer_pubkey = Some(next_trade_pubkey.clone());
             child_order.creator_pubkey = next_trade_pubkey.clone();
             child_order.trade_index_buyer = order.next_trade_index;
@@ -239,47 +359,261 @@ async fn handle_child_order(
             Some(next_trade_index as i64),
         )
         .await;
-        child_order.create(pool).await?;
     }
+    // The child keeps the rest of the range open until it's fully consumed,
+    // without a next trade key it keeps the keys of the range maker
+    child_order.create(pool).await?;
+
+    Ok(())
+}
+
+/// Ask the buyer for a new invoice when the one we have can't be paid,
+/// the order is marked as failed payment to be paid once the buyer sends it
+async fn request_new_invoice(
+    mut order: Order,
+    request_id: Option<u64>,
+    pool: &Pool<Sqlite>,
+) -> Result<()> {
+    let buyer_pubkey = match &order.buyer_pubkey {
+        Some(buyer) => PublicKey::from_str(buyer.as_str())?,
+        None => return Err(Error::msg("Missing buyer pubkey")),
+    };
+
+    // Flag written back even if already set, a claimed retry clears it
+    if !order.failed_payment {
+        order.failed_payment = true;
+        order.payment_attempts = 0;
+    }
+    order = order.update(pool).await?;
+
+    send_cant_do_msg(
+        request_id,
+        Some(order.id),
+        Some(CantDoReason::InvalidInvoice),
+        &buyer_pubkey,
+    )
+    .await;
+    let mut new_order = order.as_new_order();
+    new_order.amount = buyer_payout_amount(&order)? as i64;
+    new_order.status = Some(Status::SettledHoldInvoice);
+    send_new_order_msg(
+        request_id,
+        Some(order.id),
+        Action::AddInvoice,
+        Some(Payload::Order(new_order)),
+        &buyer_pubkey,
+        order.trade_index_buyer,
+    )
+    .await;
+
     Ok(())
 }
 
-pub async fn do_payment(mut order: Order, request_id: Option<u64>) -> Result<()> {
+/// Pay the buyer on-chain with the node wallet, the on-chain fee is taken
+/// from the payout to cover the transaction
+async fn do_onchain_payment(
+    mut order: Order,
+    address: &str,
+    request_id: Option<u64>,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<()> {
+    let buyer_pubkey = match &order.buyer_pubkey {
+        Some(buyer) => PublicKey::from_str(buyer.as_str())?,
+        None => return Err(Error::msg("Missing buyer pubkey")),
+    };
+    let amount = onchain_payout_amount(&order, Settings::get_mostro().onchain_fallback_fee)?;
+    let mut ln_client = connect_backend().await?;
+
+    match ln_client.send_onchain_payment(address, amount as i64).await {
+        Ok(txid) => {
+            info!(
+                "Order Id {}: on-chain payout of {} sats sent, txid: {}",
+                order.id, amount, txid
+            );
+            payment_success(&mut order, &buyer_pubkey, my_keys, request_id, pool).await
+        }
+        Err(e) => {
+            info!("Order Id {}: on-chain payout failed: {}", order.id, e);
+            check_failure_retries(&order, request_id, pool).await?;
+            Err(e.into())
+        }
+    }
+}
+
+/// Address to pay the buyer on-chain once all the lightning payment attempts
+/// failed, the fallback address of the buyer invoice if it has one
+fn lightning_fallback_address(
+    order: &Order,
+    retries_number: i64,
+    network: bitcoin::Network,
+) -> Option<String> {
+    if order.payment_attempts < retries_number {
+        return None;
+    }
+    invoice_fallback_address(order.buyer_invoice.as_deref()?, network)
+}
+
+/// Pay the buyer on-chain if the on-chain fallback is enabled and the
+/// lightning payment failed for the last time, true if it was tried
+async fn try_onchain_fallback(
+    order: &Order,
+    request_id: Option<u64>,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> bool {
+    if !Settings::get_mostro().onchain_fallback {
+        return false;
+    }
+    let retries_number = Settings::get_ln().payment_attempts as i64;
+    let Some(address) = lightning_fallback_address(order, retries_number, onchain_network()) else {
+        return false;
+    };
+    info!(
+        "Order Id {}: lightning payment failed {} times, paying to the invoice fallback address",
+        order.id, order.payment_attempts
+    );
+    if let Err(e) = do_onchain_payment(order.clone(), &address, request_id, my_keys, pool).await {
+        error!("Order Id {}: on-chain fallback failed: {e}", order.id);
+    }
+    true
+}
+
+/// Amount paid to the buyer, the order amount minus the fee, an order with
+/// a fee bigger than its amount or with the amount still to be resolved at
+/// take time can't be paid
+fn buyer_payout_amount(order: &Order) -> Result<u64> {
+    if order.amount == 0 {
+        return Err(Error::msg(format!(
+            "Order Id {}: sats amount not resolved",
+            order.id
+        )));
+    }
+    (order.amount as u64)
+        .checked_sub(order.fee as u64)
+        .ok_or_else(|| {
+            Error::msg(format!(
+                "Order Id {}: fee {} exceeds amount {}",
+                order.id, order.fee, order.amount
+            ))
+        })
+}
+
+/// Amount paid to the buyer on-chain, the lightning payout minus the
+/// on-chain fee, only charged when the payout goes on-chain
+fn onchain_payout_amount(order: &Order, onchain_fee: f64) -> Result<u64> {
+    let amount = buyer_payout_amount(order)?;
+    let fee = (onchain_fee * order.amount as f64).round() as u64;
+    amount.checked_sub(fee).filter(|a| *a > 0).ok_or_else(|| {
+        Error::msg(format!(
+            "Order Id {}: on-chain fee {} exceeds payout {}",
+            order.id, fee, amount
+        ))
+    })
+}
+
+/// Pay the buyer of a settled order, the keys are taken by the caller
+/// before the seller funds are settled so no key failure is left for here
+pub async fn do_payment(
+    order: Order,
+    request_id: Option<u64>,
+    my_keys: &Keys,
+    pool: &Pool<Sqlite>,
+) -> Result<()> {
     let payment_request = match order.buyer_invoice.as_ref() {
         Some(req) => req.to_string(),
         _ => return Err(Error::msg("Missing payment request")),
     };
 
+    let amount = buyer_payout_amount(&order)?;
+
+    // Buyer asked to be paid on-chain
+    if Settings::get_mostro().onchain_fallback
+        && is_onchain_address(&payment_request, onchain_network())
+    {
+        return do_onchain_payment(order, &payment_request, request_id, my_keys, pool).await;
+    }
+
     let ln_addr = LightningAddress::from_str(&payment_request);
-    let amount = order.amount as u64 - order.fee as u64;
-    let payment_request = if let Ok(addr) = ln_addr {
-        resolv_ln_address(&addr.to_string(), amount).await?
+    let payment_request = if let Ok(addr) = &ln_addr {
+        match resolv_ln_address(&addr.to_string(), amount).await {
+            Ok(pr) if !pr.is_empty() => pr,
+            Ok(_) => {
+                info!(
+                    "Order Id {}: lightning address not resolved, asking for a new invoice",
+                    order.id
+                );
+                return request_new_invoice(order, request_id, pool).await;
+            }
+            Err(e) => {
+                error!(
+                    "Order Id {}: lightning address resolution failed: {e}",
+                    order.id
+                );
+                return request_new_invoice(order, request_id, pool).await;
+            }
+        }
     } else {
         payment_request
     };
-    let mut ln_client_payment = LndConnector::new().await?;
+    // The buyer invoice may have expired while waiting, we ask for a new one
+    if ln_addr.is_err()
+        && is_expired_at(&payment_request, Timestamp::now().as_u64()).unwrap_or(false)
+    {
+        info!(
+            "Order Id {}: buyer invoice expired, asking for a new one",
+            order.id
+        );
+        return request_new_invoice(order, request_id, pool).await;
+    }
+
+    let mut ln_client_payment = connect_backend().await?;
     let (tx, mut rx) = channel(100);
 
     let payment_task = ln_client_payment.send_payment(&payment_request, amount as i64, tx);
     if let Err(paymement_result) = payment_task.await {
         info!("Error during ln payment : {}", paymement_result);
-        if let Ok(failed_payment) = check_failure_retries(&order, request_id).await {
+        if let Ok(failed_payment) = check_failure_retries(&order, request_id, pool).await {
             info!(
                 "Order id {} has {} failed payments retries",
                 failed_payment.id, failed_payment.payment_attempts
             );
+            if try_onchain_fallback(&failed_payment, request_id, my_keys, pool).await {
+                return Ok(());
+            }
         }
     }
 
-    let my_keys = get_keys()?;
-
     let buyer_pubkey = match &order.buyer_pubkey {
         Some(buyer) => PublicKey::from_str(buyer.as_str())?,
         None => return Err(Error::msg("Missing buyer pubkey")),
     };
 
+    spawn_payment_listener(
+        order,
+        buyer_pubkey,
+        my_keys.clone(),
+        request_id,
+        rx,
+        pool.clone(),
+    );
+    Ok(())
+}
+
+/// Finish the order once the payment updates received on `rx` report the
+/// payment succeeded, or handle its failure
+fn spawn_payment_listener(
+    mut order: Order,
+    buyer_pubkey: PublicKey,
+    my_keys: Keys,
+    request_id: Option<u64>,
+    mut rx: Receiver<PaymentMessage>,
+    pool: Pool<Sqlite>,
+) {
     let payment = {
         async move {
+            // The shutdown waits for the payment result
+            let _in_flight = shutdown::InFlight::start();
             // We redeclare vars to use inside this block
             // Receiving msgs from send_payment()
             while let Some(msg) = rx.recv().await {
@@ -291,9 +625,14 @@ pub async fn do_payment(mut order: Order, request_id: Option<u64>) -> Result<()>
                                 order.id, msg.payment.payment_hash
                             );
 
-                            let _ =
-                                payment_success(&mut order, &buyer_pubkey, &my_keys, request_id)
-                                    .await;
+                            let _ = payment_success(
+                                &mut order,
+                                &buyer_pubkey,
+                                &my_keys,
+                                request_id,
+                                &pool,
+                            )
+                            .await;
                         }
                         PaymentStatus::Failed => {
                             info!(
@@ -303,12 +642,14 @@ pub async fn do_payment(mut order: Order, request_id: Option<u64>) -> Result<()>
 
                             // Mark payment as failed
                             if let Ok(failed_payment) =
-                                check_failure_retries(&order, request_id).await
+                                check_failure_retries(&order, request_id, &pool).await
                             {
                                 info!(
                                     "Order id {} has {} failed payments retries",
                                     failed_payment.id, failed_payment.payment_attempts
                                 );
+                                try_onchain_fallback(&failed_payment, request_id, &my_keys, &pool)
+                                    .await;
                             }
                         }
                         _ => {}
@@ -318,6 +659,110 @@ pub async fn do_payment(mut order: Order, request_id: Option<u64>) -> Result<()>
         }
     };
     tokio::spawn(payment);
+}
+
+/// What to do with a buyer payment found on startup
+#[derive(Debug, PartialEq, Eq)]
+pub enum PaymentReconciliation {
+    /// Payment completed while mostro was down, the order must be finished
+    Completed,
+    /// Payment failed, it must be retried
+    Failed,
+    /// Payment still in flight, it must be tracked again
+    InFlight,
+    /// Payment never left the node, it must be sent
+    NotSent,
+    /// No payment on the node but a retry is scheduled by the retries job
+    RetryScheduled,
+}
+
+/// What to do with the buyer payment of an order, `status` is the payment
+/// status on the node, None if the node has no payment to the buyer invoice
+pub fn reconcile_payment_status(
+    status: Option<PaymentStatus>,
+    failed_payment: bool,
+) -> PaymentReconciliation {
+    match status {
+        Some(PaymentStatus::Succeeded) => PaymentReconciliation::Completed,
+        Some(PaymentStatus::Failed) => PaymentReconciliation::Failed,
+        Some(_) => PaymentReconciliation::InFlight,
+        None if failed_payment => PaymentReconciliation::RetryScheduled,
+        None => PaymentReconciliation::NotSent,
+    }
+}
+
+/// Query the node for the payments to buyers sent before a restart, as their
+/// result is lost with the payment stream, and finish the orders accordingly.
+/// Payments still in flight are tracked again and payments that never left
+/// the node are sent, the buyer invoice and attempts are kept in the order
+pub async fn reconcile_buyer_payments(pool: &Pool<Sqlite>) -> Result<()> {
+    let mut ln_client = connect_backend().await?;
+    let my_keys = get_keys()?;
+
+    for mut order in db::find_settled_orders(pool).await? {
+        let Some(payment_request) = order.buyer_invoice.clone() else {
+            continue;
+        };
+        // Lightning addresses and on-chain payouts are not tracked by invoice
+        if decode_invoice(&payment_request).is_err() {
+            continue;
+        }
+        let status = match ln_client.lookup_payment_status(&payment_request).await {
+            Ok(status) => status,
+            // Status unknown, the order is left as is until the next start
+            Err(e) => {
+                error!("Order Id {}: payment lookup failed, skipped: {e}", order.id);
+                continue;
+            }
+        };
+        match reconcile_payment_status(status, order.failed_payment) {
+            PaymentReconciliation::Completed => {
+                info!("Order Id {}: buyer paid while mostro was down", order.id);
+                let buyer_pubkey = match &order.buyer_pubkey {
+                    Some(buyer) => PublicKey::from_str(buyer.as_str())?,
+                    None => continue,
+                };
+                payment_success(&mut order, &buyer_pubkey, &my_keys, None, pool).await?;
+            }
+            PaymentReconciliation::Failed => {
+                info!(
+                    "Order Id {}: buyer payment failed while mostro was down",
+                    order.id
+                );
+                check_failure_retries(&order, None, pool).await?;
+            }
+            PaymentReconciliation::InFlight => {
+                info!("Order Id {}: buyer payment still in flight", order.id);
+                let buyer_pubkey = match &order.buyer_pubkey {
+                    Some(buyer) => PublicKey::from_str(buyer.as_str())?,
+                    None => continue,
+                };
+                let (tx, rx) = channel(100);
+                spawn_payment_listener(
+                    order,
+                    buyer_pubkey,
+                    my_keys.clone(),
+                    None,
+                    rx,
+                    pool.clone(),
+                );
+                let mut ln_client = connect_backend().await?;
+                tokio::spawn(async move {
+                    if let Err(e) = ln_client.track_payment(&payment_request, tx).await {
+                        error!("Error tracking buyer payment: {e}");
+                    }
+                });
+            }
+            PaymentReconciliation::NotSent => {
+                info!("Order Id {}: buyer payment never sent, paying", order.id);
+                if let Err(e) = do_payment(order, None, &my_keys, pool).await {
+                    error!("{e}");
+                }
+            }
+            PaymentReconciliation::RetryScheduled => {}
+        }
+    }
+
     Ok(())
 }
 
@@ -326,7 +771,11 @@ async fn payment_success(
     buyer_pubkey: &PublicKey,
     my_keys: &Keys,
     request_id: Option<u64>,
+    pool: &Pool<Sqlite>,
 ) -> Result<()> {
+    db::clear_payment_retry(pool, order.id).await?;
+    increment(Counter::PaymentsSucceeded);
+
     // Purchase completed message to buyer
     send_new_order_msg(
         None,
@@ -338,9 +787,8 @@ async fn payment_success(
     )
     .await;
 
-    if let Ok(order_updated) = update_order_event(my_keys, Status::Success, order).await {
-        let pool = db::connect().await?;
-        if let Ok(order) = order_updated.update(&pool).await {
+    if let Ok(order_updated) = update_order_event(my_keys, Status::Success, order, pool).await {
+        if let Ok(order) = order_updated.update(pool).await {
             // Send dm to buyer to rate counterpart
             send_new_order_msg(
                 request_id,
@@ -369,26 +817,48 @@ pub async fn get_child_order(
         return Ok((None, None));
     };
 
-    if let Some(new_max) = max_amount.checked_sub(order.fiat_amount) {
-        let mut new_order = create_base_order(&order);
-
-        match new_max.cmp(&min_amount) {
-            Ordering::Equal => {
-                let (order, event) = order_for_equal(new_max, &mut new_order, my_keys).await?;
-                return Ok((Some(order), Some(event)));
-            }
-            Ordering::Greater => {
-                let (order, event) = order_for_greater(new_max, &mut new_order, my_keys).await?;
-                return Ok((Some(order), Some(event)));
-            }
-            Ordering::Less => {
-                notify_invalid_amount(&order, request_id).await;
-                return Ok((None, None));
-            }
+    let mut new_order = create_base_order(&order);
+    match remaining_range(min_amount, max_amount, order.fiat_amount) {
+        RemainingRange::Fixed(amount) => {
+            let (order, event) = order_for_equal(amount, &mut new_order, my_keys).await?;
+            Ok((Some(order), Some(event)))
+        }
+        RemainingRange::Range(new_max) => {
+            let (order, event) = order_for_greater(new_max, &mut new_order, my_keys).await?;
+            Ok((Some(order), Some(event)))
         }
+        RemainingRange::BelowMin => {
+            notify_invalid_amount(&order, request_id).await;
+            Ok((None, None))
+        }
+        RemainingRange::Consumed => Ok((None, None)),
     }
+}
 
-    Ok((None, None))
+/// What is left of a range order from `min` to `max` after a take of `taken`
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemainingRange {
+    /// A new range up to this max amount
+    Range(i64),
+    /// Exactly the min amount is left, a fixed amount order
+    Fixed(i64),
+    /// Something is left but below the min amount, it can't be taken
+    BelowMin,
+    /// The whole range was taken
+    Consumed,
+}
+
+pub fn remaining_range(min: i64, max: i64, taken: i64) -> RemainingRange {
+    match max.checked_sub(taken) {
+        Some(0) => RemainingRange::Consumed,
+        Some(new_max) => match new_max.cmp(&min) {
+            Ordering::Equal => RemainingRange::Fixed(new_max),
+            Ordering::Greater => RemainingRange::Range(new_max),
+            Ordering::Less if new_max > 0 => RemainingRange::BelowMin,
+            Ordering::Less => RemainingRange::Consumed,
+        },
+        None => RemainingRange::Consumed,
+    }
 }
 
 fn create_base_order(order: &Order) -> Order {
@@ -488,3 +958,238 @@ async fn notify_invalid_amount(order: &Order, request_id: Option<u64>) {
         .await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining_range_after_take() {
+        // 10 - 100 USD range
+        assert_eq!(remaining_range(10, 100, 30), RemainingRange::Range(70));
+        assert_eq!(remaining_range(10, 100, 90), RemainingRange::Fixed(10));
+        assert_eq!(remaining_range(10, 100, 95), RemainingRange::BelowMin);
+        assert_eq!(remaining_range(10, 100, 100), RemainingRange::Consumed);
+    }
+
+    #[test]
+    fn test_release_blocked_until_both_confirmations() {
+        // Buyer didn't send fiat-sent yet
+        assert!(check_fiat_confirmations(true, &Status::Active, false).is_err());
+        assert!(check_fiat_confirmations(true, &Status::Active, true).is_err());
+        // Seller didn't acknowledge it yet
+        assert!(check_fiat_confirmations(true, &Status::FiatSent, false).is_err());
+    }
+
+    #[test]
+    fn test_release_allowed_after_both_confirmations() {
+        assert!(check_fiat_confirmations(true, &Status::FiatSent, true).is_ok());
+        assert!(check_fiat_confirmations(true, &Status::Dispute, false).is_ok());
+        // Dual confirmation disabled
+        assert!(check_fiat_confirmations(false, &Status::Active, false).is_ok());
+    }
+
+    fn order_with_fee(amount: i64, fee: i64) -> Order {
+        Order {
+            amount,
+            fee,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_payout_amount_fee_under_amount() {
+        let order = order_with_fee(100_000, 300);
+        assert_eq!(buyer_payout_amount(&order).unwrap(), 99_700);
+    }
+
+    #[test]
+    fn test_payout_amount_fee_equal_amount() {
+        let order = order_with_fee(300, 300);
+        assert_eq!(buyer_payout_amount(&order).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_payout_amount_fee_over_amount() {
+        let order = order_with_fee(200, 300);
+        assert!(buyer_payout_amount(&order).is_err());
+    }
+
+    #[test]
+    fn test_onchain_fee_only_on_onchain_payout() {
+        let order = order_with_fee(100_000, 300);
+        // Lightning payouts don't pay the on-chain fee
+        assert_eq!(buyer_payout_amount(&order).unwrap(), 99_700);
+        assert_eq!(onchain_payout_amount(&order, 0.001).unwrap(), 99_600);
+        assert_eq!(onchain_payout_amount(&order, 0.0).unwrap(), 99_700);
+        // Nothing left to pay on-chain
+        assert!(onchain_payout_amount(&order, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_onchain_fallback_after_last_attempt() {
+        use crate::lightning::invoice::tests::test_invoice;
+        use bitcoin::hashes::Hash;
+        use lightning_invoice::Fallback;
+
+        let fallback = Fallback::PubKeyHash(bitcoin::PubkeyHash::from_byte_array([3; 20]));
+        let mut order = Order {
+            buyer_invoice: Some(test_invoice(Some(fallback))),
+            payment_attempts: 1,
+            ..Default::default()
+        };
+        // Lightning attempts left, no on-chain payout
+        assert_eq!(
+            lightning_fallback_address(&order, 3, bitcoin::Network::Regtest),
+            None
+        );
+        // Last lightning attempt failed, paid to the invoice fallback address
+        order.payment_attempts = 3;
+        assert!(lightning_fallback_address(&order, 3, bitcoin::Network::Regtest).is_some());
+        // An invoice without fallback address is not paid on-chain
+        order.buyer_invoice = Some(test_invoice(None));
+        assert_eq!(
+            lightning_fallback_address(&order, 3, bitcoin::Network::Regtest),
+            None
+        );
+    }
+
+    #[test]
+    fn test_payout_amount_unresolved_order() {
+        // Amount to be resolved at take time
+        let order = order_with_fee(0, 0);
+        assert!(buyer_payout_amount(&order).is_err());
+    }
+
+    #[test]
+    fn test_payment_completed_during_downtime() {
+        assert_eq!(
+            reconcile_payment_status(Some(PaymentStatus::Succeeded), false),
+            PaymentReconciliation::Completed
+        );
+        assert_eq!(
+            reconcile_payment_status(Some(PaymentStatus::Failed), true),
+            PaymentReconciliation::Failed
+        );
+        assert_eq!(
+            reconcile_payment_status(Some(PaymentStatus::InFlight), false),
+            PaymentReconciliation::InFlight
+        );
+    }
+
+    #[test]
+    fn test_payment_not_sent_before_restart() {
+        assert_eq!(
+            reconcile_payment_status(None, false),
+            PaymentReconciliation::NotSent
+        );
+        // Failed payments are sent again by the retries job
+        assert_eq!(
+            reconcile_payment_status(None, true),
+            PaymentReconciliation::RetryScheduled
+        );
+    }
+
+    fn policies() -> Vec<ReleasePolicy> {
+        vec![
+            ReleasePolicy {
+                payment_method: "Lightning".to_string(),
+                min_hold_seconds: 0,
+                require_fiat_sent: false,
+            },
+            ReleasePolicy {
+                payment_method: "bank transfer".to_string(),
+                min_hold_seconds: 3600,
+                require_fiat_sent: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_instant_method_releases_right_away() {
+        let now = 1_700_000_000;
+        let order = Order {
+            payment_method: "lightning".to_string(),
+            status: Status::Active.to_string(),
+            invoice_held_at: now - 10,
+            ..Default::default()
+        };
+        assert_eq!(check_release_policy(&order, &policies(), now), Ok(()));
+    }
+
+    #[test]
+    fn test_bank_transfer_waits_hold_time_and_fiat_sent() {
+        let now = 1_700_000_000;
+        let mut order = Order {
+            payment_method: "Bank Transfer".to_string(),
+            status: Status::Active.to_string(),
+            invoice_held_at: now - 7200,
+            ..Default::default()
+        };
+        // Buyer didn't confirm fiat sent
+        assert_eq!(
+            check_release_policy(&order, &policies(), now),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+        // Funds not held long enough
+        order.status = Status::FiatSent.to_string();
+        order.invoice_held_at = now - 600;
+        assert_eq!(
+            check_release_policy(&order, &policies(), now),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+        order.invoice_held_at = now - 3600;
+        assert_eq!(check_release_policy(&order, &policies(), now), Ok(()));
+    }
+
+    #[test]
+    fn test_strictest_policy_applies_to_several_methods() {
+        let now = 1_700_000_000;
+        let order = Order {
+            payment_method: "lightning, bank transfer".to_string(),
+            status: Status::FiatSent.to_string(),
+            invoice_held_at: now - 600,
+            ..Default::default()
+        };
+        assert_eq!(
+            check_release_policy(&order, &policies(), now),
+            Err(CantDoReason::NotAllowedByStatus)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payment_uses_the_given_pool() {
+        use crate::MOSTRO_CONFIG;
+        use std::path::PathBuf;
+        use std::sync::{Arc, RwLock};
+        use uuid::Uuid;
+
+        std::env::set_var("RUN_MODE", ".tpl");
+        MOSTRO_CONFIG
+            .get_or_init(|| RwLock::new(Arc::new(Settings::new(PathBuf::from("./")).unwrap())));
+        let (pool, _db) = crate::db::connect_test_db().await;
+
+        // Expired buyer invoice, a new one is asked for without paying it
+        let order = Order {
+            id: Uuid::new_v4(),
+            amount: 50_000,
+            status: Status::SettledHoldInvoice.to_string(),
+            buyer_pubkey: Some(Keys::generate().public_key().to_hex()),
+            buyer_invoice: Some("lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n".to_string()),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        // The template key is a placeholder, the payment only uses the keys it's given
+        assert!(get_keys().is_err());
+        do_payment(order.clone(), None, &Keys::generate(), &pool)
+            .await
+            .unwrap();
+
+        // The order is updated on the given pool, the application pool is never opened
+        let order = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert!(order.failed_payment);
+        assert!(!db::is_pool_open());
+    }
+}
@@ -1,8 +1,12 @@
+use crate::cli::settings::Settings;
+use crate::db::{is_order_quarantined, transition_order_status};
+use crate::error::MostroError;
 use crate::util::{
-    get_fiat_amount_requested, get_market_amount_and_fee, send_cant_do_msg, show_hold_invoice,
+    check_order_takeable, get_fiat_amount_requested, get_market_amount_and_fee, get_required_id,
+    is_order_fee_free, is_within_locked_funds_cap, meets_nip05_requirement, show_hold_invoice,
 };
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use mostro_core::message::{CantDoReason, Message};
 use mostro_core::order::{Kind, Order, Status};
 use nostr::nips::nip59::UnwrappedGift;
@@ -17,93 +21,83 @@ pub async fn take_buy_action(
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-) -> Result<()> {
-    // Safe unwrap as we verified the message
-    let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
-        order_id
-    } else {
-        return Err(Error::msg("No order id"));
-    };
-
+) -> Result<(), MostroError> {
     let request_id = msg.get_inner_message_kind().request_id;
 
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
+
     let mut order = match Order::by_id(pool, order_id).await? {
         Some(order) => order,
         None => {
             error!("Order Id {order_id} not found!");
-            return Ok(());
+            return Err(MostroError::CantDo(CantDoReason::NotFound));
         }
     };
 
     // Maker can't take own order
     if order.kind != Kind::Buy.to_string() || order.creator_pubkey == event.rumor.pubkey.to_hex() {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::InvalidPubkey),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
     }
 
-    let order_status = match Status::from_str(&order.status) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Order Id {order_id} wrong status: {e:?}");
-            return Ok(());
-        }
-    };
+    if let Err(e) = Status::from_str(&order.status) {
+        error!("Order Id {order_id} wrong status: {e:?}");
+        return Err(MostroError::CantDo(CantDoReason::InvalidOrderStatus));
+    }
     let buyer_pubkey = match order.buyer_pubkey.as_ref() {
         Some(pk) => PublicKey::from_str(pk)?,
         None => {
             error!("Buyer pubkey not found for order {}!", order.id);
-            return Ok(());
+            return Err(MostroError::Internal("Missing buyer pubkey".to_string()));
         }
     };
 
     // We update the pubkey
     let seller_pubkey = event.rumor.pubkey;
-    // Seller can take pending orders only
-    match order_status {
-        Status::Pending => {}
-        _ => {
-            send_cant_do_msg(
-                request_id,
-                Some(order.id),
-                Some(CantDoReason::NotAllowedByStatus),
-                &event.rumor.pubkey,
-            )
-            .await;
+    // Order must be pending, not expired and not too old to be taken
+    if let Err(reason) = check_order_takeable(
+        &order,
+        Timestamp::now().as_u64() as i64,
+        Settings::get_mostro().max_take_order_age_seconds as i64,
+    ) {
+        return Err(MostroError::CantDo(reason));
+    }
 
-            return Ok(());
-        }
+    // Orders waiting for review are not published, takers can't find them
+    if is_order_quarantined(pool, order.id).await? {
+        return Err(MostroError::CantDo(CantDoReason::NotFound));
+    }
+
+    // Maker may require takers with a verified NIP-05
+    if !meets_nip05_requirement(pool, order.id, event).await? {
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
     }
 
     // Get amount request if user requested one for range order - fiat amount will be used below
     if let Some(am) = get_fiat_amount_requested(&order, &msg) {
         order.fiat_amount = am;
     } else {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::OutOfRangeFiatAmount),
-            &event.rumor.pubkey,
-        )
-        .await;
-
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::OutOfRangeFiatAmount));
     }
 
     // Check market price value in sats - if order was with market price then calculate
     if order.amount == 0 {
-        let (new_sats_amount, fee) =
-            get_market_amount_and_fee(order.fiat_amount, &order.fiat_code, order.premium).await?;
+        let (new_sats_amount, fee) = get_market_amount_and_fee(
+            order.fiat_amount,
+            &order.fiat_code,
+            order.premium,
+            is_order_fee_free(&order),
+        )
+        .await?;
         // Update order with new sats value
         order.amount = new_sats_amount;
         order.fee = fee;
     }
 
+    // Taking the order can't exceed the funds locked across all orders
+    if !is_within_locked_funds_cap(pool, Some(order.id), order.amount).await? {
+        return Err(MostroError::CantDo(CantDoReason::OutOfRangeSatsAmount));
+    }
+
     // Add seller identity pubkey to order
     order.master_seller_pubkey = Some(event.sender.to_string());
     // Add seller trade index to order
@@ -111,7 +105,14 @@ pub async fn take_buy_action(
     // Timestamp order take time
     order.taken_at = Timestamp::now().as_u64() as i64;
 
-    show_hold_invoice(
+    // Only one take wins the order, the others find it no longer pending
+    if !transition_order_status(pool, order.id, &Status::Pending, &Status::WaitingPayment).await? {
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+    }
+    order.status = Status::WaitingPayment.to_string();
+
+    if let Err(e) = show_hold_invoice(
+        pool,
         my_keys,
         None,
         &buyer_pubkey,
@@ -119,6 +120,11 @@ pub async fn take_buy_action(
         order,
         request_id,
     )
-    .await?;
+    .await
+    {
+        // Take failed, the order can be taken again
+        transition_order_status(pool, order_id, &Status::WaitingPayment, &Status::Pending).await?;
+        return Err(e.into());
+    }
     Ok(())
 }
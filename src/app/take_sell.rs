@@ -1,10 +1,14 @@
+use crate::cli::settings::Settings;
+use crate::db::{is_order_quarantined, transition_order_status};
+use crate::error::MostroError;
 use crate::lightning::invoice::is_valid_invoice;
 use crate::util::{
-    get_fiat_amount_requested, get_market_amount_and_fee, send_cant_do_msg,
+    check_order_takeable, get_fiat_amount_requested, get_market_amount_and_fee, get_required_id,
+    is_order_fee_free, is_within_locked_funds_cap, meets_nip05_requirement,
     set_waiting_invoice_status, show_hold_invoice, update_order_event,
 };
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use mostro_core::message::{CantDoReason, Message};
 use mostro_core::order::{Kind, Order, Status};
 use nostr::nips::nip59::UnwrappedGift;
@@ -19,38 +23,44 @@ pub async fn take_sell_action(
     event: &UnwrappedGift,
     my_keys: &Keys,
     pool: &Pool<Sqlite>,
-) -> Result<()> {
+) -> Result<(), MostroError> {
     // Get request id
     let request_id = msg.get_inner_message_kind().request_id;
 
     // Safe unwrap as we verified the message
-    let order_id = if let Some(order_id) = msg.get_inner_message_kind().id {
-        order_id
-    } else {
-        return Err(Error::msg("No order id"));
-    };
+    let order_id = get_required_id(&msg).map_err(MostroError::CantDo)?;
 
     let mut order = match Order::by_id(pool, order_id).await? {
         Some(order) => order,
-        None => {
-            return Ok(());
-        }
+        None => return Err(MostroError::CantDo(CantDoReason::NotFound)),
     };
 
     // Maker can't take own order
     if order.creator_pubkey == event.rumor.pubkey.to_hex() {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::InvalidPubkey),
-            &event.rumor.pubkey,
-        )
-        .await;
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
     }
 
     if order.kind != Kind::Sell.to_string() {
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::InvalidOrderKind));
+    }
+
+    if let Err(e) = Status::from_str(&order.status) {
+        error!("Order Id {order_id} wrong status: {e:?}");
+        return Err(MostroError::CantDo(CantDoReason::InvalidOrderStatus));
+    }
+
+    // Order must be pending, not expired and not too old to be taken
+    if let Err(reason) = check_order_takeable(
+        &order,
+        Timestamp::now().as_u64() as i64,
+        Settings::get_mostro().max_take_order_age_seconds as i64,
+    ) {
+        return Err(MostroError::CantDo(reason));
+    }
+
+    // Orders waiting for review are not published, takers can't find them
+    if is_order_quarantined(pool, order.id).await? {
+        return Err(MostroError::CantDo(CantDoReason::NotFound));
     }
 
     // Get trade pubkey of the buyer
@@ -58,7 +68,7 @@ pub async fn take_sell_action(
 
     let seller_pubkey = match &order.seller_pubkey {
         Some(seller) => PublicKey::from_str(seller.as_str())?,
-        _ => return Err(Error::msg("Missing seller pubkeys")),
+        _ => return Err(MostroError::Internal("Missing seller pubkeys".to_string())),
     };
 
     let mut pr: Option<String> = None;
@@ -76,57 +86,23 @@ pub async fn take_sell_action(
             {
                 Ok(_) => Some(payment_request),
                 Err(e) => {
-                    send_cant_do_msg(
-                        request_id,
-                        Some(order.id),
-                        Some(CantDoReason::InvalidInvoice),
-                        &event.rumor.pubkey,
-                    )
-                    .await;
                     error!("{e}");
-                    return Ok(());
+                    return Err(MostroError::CantDo(CantDoReason::InvalidInvoice));
                 }
             }
         };
     }
 
-    let order_status = match Status::from_str(&order.status) {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Order Id {order_id} wrong status: {e:?}");
-            return Ok(());
-        }
-    };
-
-    // Buyer can take Pending orders only
-    match order_status {
-        Status::Pending => {}
-        _ => {
-            send_cant_do_msg(
-                request_id,
-                Some(order.id),
-                Some(CantDoReason::NotAllowedByStatus),
-                &buyer_trade_pubkey,
-            )
-            .await;
-
-            return Ok(());
-        }
+    // Maker may require takers with a verified NIP-05
+    if !meets_nip05_requirement(pool, order.id, event).await? {
+        return Err(MostroError::CantDo(CantDoReason::InvalidPubkey));
     }
 
     // Get amount request if user requested one for range order - fiat amount will be used below
     if let Some(am) = get_fiat_amount_requested(&order, &msg) {
         order.fiat_amount = am;
     } else {
-        send_cant_do_msg(
-            request_id,
-            Some(order.id),
-            Some(CantDoReason::OutOfRangeFiatAmount),
-            &event.rumor.pubkey,
-        )
-        .await;
-
-        return Ok(());
+        return Err(MostroError::CantDo(CantDoReason::OutOfRangeFiatAmount));
     }
 
     // Add buyer pubkey to order
@@ -140,19 +116,39 @@ pub async fn take_sell_action(
 
     // Check market price value in sats - if order was with market price then calculate it and send a DM to buyer
     if order.amount == 0 {
-        let (new_sats_amount, fee) =
-            get_market_amount_and_fee(order.fiat_amount, &order.fiat_code, order.premium).await?;
+        let (new_sats_amount, fee) = get_market_amount_and_fee(
+            order.fiat_amount,
+            &order.fiat_code,
+            order.premium,
+            is_order_fee_free(&order),
+        )
+        .await?;
         // Update order with new sats value
         order.amount = new_sats_amount;
         order.fee = fee;
     }
 
+    // Taking the order can't exceed the funds locked across all orders
+    if !is_within_locked_funds_cap(pool, Some(order.id), order.amount).await? {
+        return Err(MostroError::CantDo(CantDoReason::OutOfRangeSatsAmount));
+    }
+
+    let next_status = match pr {
+        Some(_) => Status::WaitingPayment,
+        None => Status::WaitingBuyerInvoice,
+    };
+    // Only one take wins the order, the others find it no longer pending
+    if !transition_order_status(pool, order.id, &Status::Pending, &next_status).await? {
+        return Err(MostroError::CantDo(CantDoReason::NotAllowedByStatus));
+    }
+    order.status = next_status.to_string();
+
     if pr.is_none() {
         match set_waiting_invoice_status(&mut order, buyer_trade_pubkey, request_id).await {
             Ok(_) => {
                 // Update order status
                 if let Ok(order_updated) =
-                    update_order_event(my_keys, Status::WaitingBuyerInvoice, &order).await
+                    update_order_event(my_keys, Status::WaitingBuyerInvoice, &order, pool).await
                 {
                     let _ = order_updated.update(pool).await;
                     return Ok(());
@@ -160,19 +156,25 @@ pub async fn take_sell_action(
             }
             Err(e) => {
                 error!("Error setting market order sats amount: {:#?}", e);
-                return Ok(());
+                // Take failed, the order can be taken again
+                transition_order_status(pool, order.id, &next_status, &Status::Pending).await?;
+                return Err(e.into());
             }
         }
-    } else {
-        show_hold_invoice(
-            my_keys,
-            pr,
-            &buyer_trade_pubkey,
-            &seller_pubkey,
-            order,
-            request_id,
-        )
-        .await?;
+    } else if let Err(e) = show_hold_invoice(
+        pool,
+        my_keys,
+        pr,
+        &buyer_trade_pubkey,
+        &seller_pubkey,
+        order,
+        request_id,
+    )
+    .await
+    {
+        // Take failed, the order can be taken again
+        transition_order_status(pool, order_id, &next_status, &Status::Pending).await?;
+        return Err(e.into());
     }
     Ok(())
 }
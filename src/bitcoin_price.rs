@@ -1,26 +1,49 @@
+use crate::cli::settings::Settings;
+use crate::error::MostroError;
+
 use anyhow::Result;
+use nostr_sdk::Timestamp;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::RwLock;
 use tracing::info;
 
-const YADIO_API_URL: &str = "https://api.yadio.io/exrates/BTC";
-
 #[derive(Debug, Deserialize)]
 struct YadioResponse {
     #[serde(rename = "BTC")]
     btc: HashMap<String, f64>,
 }
 
-static BITCOIN_PRICES: Lazy<RwLock<HashMap<String, f64>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+/// Bitcoin prices by currency with the time they were got
+#[derive(Debug, Default)]
+struct BitcoinPrices {
+    prices: HashMap<String, f64>,
+    updated_at: i64,
+}
+
+impl BitcoinPrices {
+    /// Price of `currency` if the prices were got less than `ttl` seconds ago
+    fn fresh_price(&self, currency: &str, now: i64, ttl: i64) -> Option<f64> {
+        if now - self.updated_at >= ttl {
+            return None;
+        }
+        self.prices.get(currency).cloned()
+    }
+}
+
+static BITCOIN_PRICES: Lazy<RwLock<BitcoinPrices>> =
+    Lazy::new(|| RwLock::new(BitcoinPrices::default()));
 
 pub struct BitcoinPriceManager;
 
 impl BitcoinPriceManager {
     pub async fn update_prices() -> Result<()> {
-        let response = reqwest::get(YADIO_API_URL).await?;
+        let price_api_url = Settings::get_mostro().price_api_url;
+        let url = format!("{}/exrates/BTC", price_api_url.trim_end_matches('/'));
+        let response = reqwest::get(&url).await?;
         let yadio_response: YadioResponse = response.json().await?;
         info!(
             "Bitcoin prices updated. Got BTC price in {} fiat currencies",
@@ -28,12 +51,126 @@ impl BitcoinPriceManager {
         );
 
         let mut prices_write = BITCOIN_PRICES.write().unwrap();
-        *prices_write = yadio_response.btc;
+        *prices_write = BitcoinPrices {
+            prices: yadio_response.btc,
+            updated_at: Timestamp::now().as_u64() as i64,
+        };
         Ok(())
     }
 
     pub fn get_price(currency: &str) -> Option<f64> {
         let prices_read = BITCOIN_PRICES.read().unwrap();
-        prices_read.get(currency).cloned()
+        prices_read.prices.get(currency).cloned()
+    }
+}
+
+/// Future returned by the price sources
+pub type PriceFuture<'a> = Pin<Box<dyn Future<Output = Result<f64, MostroError>> + Send + 'a>>;
+
+/// Source of the bitcoin price used to compute the sats amount of market price orders
+pub trait PriceSource: Send + Sync {
+    /// Price of one bitcoin in `fiat_code`
+    fn btc_price<'a>(&'a self, fiat_code: &'a str) -> PriceFuture<'a>;
+}
+
+/// Prices updated by the scheduler are reused for `price_cache_seconds`,
+/// older ones are requested again to the price API
+impl PriceSource for BitcoinPriceManager {
+    fn btc_price<'a>(&'a self, fiat_code: &'a str) -> PriceFuture<'a> {
+        Box::pin(async move {
+            let now = Timestamp::now().as_u64() as i64;
+            let ttl = Settings::get_mostro().price_cache_seconds as i64;
+            let fresh = BITCOIN_PRICES
+                .read()
+                .unwrap()
+                .fresh_price(fiat_code, now, ttl);
+            if let Some(price) = fresh {
+                return Ok(price);
+            }
+            if let Err(e) = BitcoinPriceManager::update_prices().await {
+                info!("Bitcoin prices not updated: {e}");
+                return Err(MostroError::NoAPIResponse);
+            }
+            BitcoinPriceManager::get_price(fiat_code).ok_or(MostroError::NoCurrency)
+        })
+    }
+}
+
+/// Price of one bitcoin in `fiat_code`, a price source answering with a
+/// price that can't be used is treated as a malformed answer
+pub async fn get_btc_price(source: &dyn PriceSource, fiat_code: &str) -> Result<f64, MostroError> {
+    let price = source.btc_price(fiat_code).await?;
+    if !price.is_finite() || price <= 0.0 {
+        return Err(MostroError::MalformedAPIRes);
+    }
+
+    Ok(price)
+}
+
+/// Sats of `fiat_amount` at `price` plus a `premium` percentage, negative for a discount
+pub fn sats_for_fiat(fiat_amount: i64, price: f64, premium: i64) -> i64 {
+    let sats = fiat_amount as f64 / price * 100_000_000_f64;
+
+    (sats + premium as f64 / 100_f64 * sats) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct MockPriceSource {
+        price: Option<f64>,
+        requests: AtomicUsize,
+    }
+
+    impl PriceSource for MockPriceSource {
+        fn btc_price<'a>(&'a self, _fiat_code: &'a str) -> PriceFuture<'a> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let price = self.price.ok_or(MostroError::NoAPIResponse);
+            Box::pin(async move { price })
+        }
+    }
+
+    fn mock(price: Option<f64>) -> MockPriceSource {
+        MockPriceSource {
+            price,
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn test_prices_cached() {
+        let prices = BitcoinPrices {
+            prices: HashMap::from([("USD".to_string(), 50_000.0)]),
+            updated_at: 1_700_000_000,
+        };
+        for at in [1_700_000_000, 1_700_000_059] {
+            assert_eq!(prices.fresh_price("USD", at, 60), Some(50_000.0));
+        }
+        assert_eq!(prices.fresh_price("EUR", 1_700_000_000, 60), None);
+        // Expired prices are requested again
+        assert_eq!(prices.fresh_price("USD", 1_700_000_060, 60), None);
+    }
+
+    #[tokio::test]
+    async fn test_price_source_unavailable() {
+        let source = mock(None);
+        let price = get_btc_price(&source, "USD").await;
+        assert_eq!(price, Err(MostroError::NoAPIResponse));
+        assert_eq!(source.requests.load(Ordering::SeqCst), 1);
+        let source = mock(Some(0.0));
+        let price = get_btc_price(&source, "USD").await;
+        assert_eq!(price, Err(MostroError::MalformedAPIRes));
+        let source = mock(Some(50_000.0));
+        assert_eq!(get_btc_price(&source, "USD").await, Ok(50_000.0));
+    }
+
+    #[test]
+    fn test_sats_for_fiat_with_premium() {
+        // 100 USD at 50k USD/BTC
+        assert_eq!(sats_for_fiat(100, 50_000.0, 0), 200_000);
+        assert_eq!(sats_for_fiat(100, 50_000.0, 5), 210_000);
+        assert_eq!(sats_for_fiat(100, 50_000.0, -5), 190_000);
     }
 }
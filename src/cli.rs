@@ -28,11 +28,15 @@ pub struct Cli {
     /// Set folder for Mostro settings file - default is HOME/.mostro
     #[arg(short, long)]
     dirsettings: Option<String>,
+    /// List hold invoices on the lightning node without an active order and exit
+    #[arg(long)]
+    pub orphaned_invoices: bool,
+    /// Cancel the hold invoices listed with --orphaned-invoices
+    #[arg(long, requires = "orphaned_invoices")]
+    pub cancel: bool,
 }
 
-pub fn settings_init() -> Result<PathBuf> {
-    let cli = Cli::parse();
-
+pub fn settings_init(cli: &Cli) -> Result<PathBuf> {
     if let Some(path) = cli.dirsettings.as_deref() {
         init_default_dir(Some(path.to_string()))
     } else {
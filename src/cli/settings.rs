@@ -9,6 +9,7 @@ use std::os::unix::ffi::OsStrExt;
 #[cfg(windows)]
 use std::os::windows::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 #[cfg(windows)]
 fn has_trailing_slash(p: &Path) -> bool {
@@ -43,13 +44,29 @@ fn add_trailing_slash(p: &mut PathBuf) {
 #[derive(Debug, Deserialize, Default, Clone)]
 pub struct Database {
     pub url: String,
+    #[serde(default)]
+    pub backup_interval_seconds: u32,
+    #[serde(default = "default_backup_dir")]
+    pub backup_dir: String,
+    #[serde(default)]
+    pub backup_keep: u32,
+    #[serde(default)]
+    pub max_connections: u32,
+    #[serde(default)]
+    pub busy_timeout_seconds: u32,
+    #[serde(default)]
+    pub journal_mode: String,
+}
+
+fn default_backup_dir() -> String {
+    "backups".to_string()
 }
 
 impl TryFrom<Settings> for Database {
     type Error = Error;
 
     fn try_from(_: Settings) -> Result<Self, Error> {
-        Ok(MOSTRO_CONFIG.get().unwrap().database.clone())
+        Ok(Settings::snapshot().database.clone())
     }
 }
 
@@ -63,13 +80,47 @@ pub struct Lightning {
     pub hold_invoice_expiration_window: u32,
     pub payment_attempts: u32,
     pub payment_retries_interval: u32,
+    #[serde(default)]
+    pub failed_payments_window_seconds: u32,
+    #[serde(default)]
+    pub failed_payments_buyer_threshold: u32,
+    #[serde(default)]
+    pub failed_payments_global_threshold: u32,
+    #[serde(default = "default_lnd_health_check_interval")]
+    pub lnd_health_check_interval: u32,
+    #[serde(default)]
+    pub settle_attempts: u32,
+    #[serde(default = "default_payment_retries_multiplier")]
+    pub payment_retries_multiplier: u32,
+    #[serde(default = "default_payment_retries_max_interval")]
+    pub payment_retries_max_interval: u32,
+    #[serde(default)]
+    pub backend: String,
+    #[serde(default)]
+    pub cln_rest_url: String,
+    #[serde(default)]
+    pub cln_rune: String,
+    #[serde(default)]
+    pub hold_invoice_expiry_seconds: u32,
+}
+
+fn default_lnd_health_check_interval() -> u32 {
+    30
+}
+
+fn default_payment_retries_multiplier() -> u32 {
+    2
+}
+
+fn default_payment_retries_max_interval() -> u32 {
+    3600
 }
 
 impl TryFrom<Settings> for Lightning {
     type Error = Error;
 
     fn try_from(_: Settings) -> Result<Self, Error> {
-        Ok(MOSTRO_CONFIG.get().unwrap().lightning.clone())
+        Ok(Settings::snapshot().lightning.clone())
     }
 }
 
@@ -77,16 +128,66 @@ impl TryFrom<Settings> for Lightning {
 pub struct Nostr {
     pub nsec_privkey: String,
     pub relays: Vec<String>,
+    #[serde(default)]
+    pub delegation: Option<Delegation>,
+    #[serde(default)]
+    pub subscription_kinds: Vec<u16>,
+}
+
+/// NIP-26 delegation of the Mostro key, the token is signed offline by the delegator
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Delegation {
+    /// Delegator public key in hex
+    pub delegator_pubkey: String,
+    /// Delegation conditions, e.g. `kind=38383&created_at<1800000000`
+    pub conditions: String,
+    /// Delegator signature of the delegation string
+    pub token: String,
 }
 
 impl TryFrom<Settings> for Nostr {
     type Error = Error;
 
     fn try_from(_: Settings) -> Result<Self, Error> {
-        Ok(MOSTRO_CONFIG.get().unwrap().nostr.clone())
+        Ok(Settings::snapshot().nostr.clone())
     }
 }
 
+/// Minimum POW required for orders from a given amount (sats) onwards
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct PowTier {
+    pub min_amount: u64,
+    pub pow: u8,
+}
+
+/// Max age of the events of an action, overriding `max_event_age_secs`
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct EventMaxAge {
+    pub action: String,
+    pub max_age_secs: u64,
+}
+
+/// Format of the log lines
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+/// Release conditions for orders paid with a given payment method
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct ReleasePolicy {
+    pub payment_method: String,
+    /// Seconds the seller funds must be held before they can be released
+    #[serde(default)]
+    pub min_hold_seconds: u64,
+    /// Buyer must confirm the fiat was sent before the seller can release
+    #[serde(default)]
+    pub require_fiat_sent: bool,
+}
+
 #[derive(Debug, Deserialize, Default, Clone)]
 pub struct Mostro {
     pub fee: f64,
@@ -99,14 +200,216 @@ pub struct Mostro {
     pub max_expiration_days: u32,
     pub publish_relays_interval: u32,
     pub pow: u8,
+    #[serde(default)]
+    pub pow_tiers: Vec<PowTier>,
+    #[serde(default)]
+    pub release_policies: Vec<ReleasePolicy>,
     pub publish_mostro_info_interval: u32,
+    #[serde(default = "default_display_sats_amount")]
+    pub display_sats_amount: bool,
+    #[serde(default)]
+    pub settle_payout_cooldown_seconds: u32,
+    #[serde(default)]
+    pub max_take_order_age_seconds: u32,
+    #[serde(default)]
+    pub onchain_fallback: bool,
+    #[serde(default)]
+    pub onchain_fallback_fee: f64,
+    #[serde(default = "default_onchain_network")]
+    pub onchain_network: String,
+    #[serde(default = "default_redact_admin_order_fields")]
+    pub redact_admin_order_fields: bool,
+    #[serde(default)]
+    pub reputation_weighting: bool,
+    #[serde(default)]
+    pub reputation_min_weight: f64,
+    #[serde(default)]
+    pub reputation_full_weight_reviews: u32,
+    #[serde(default)]
+    pub max_locked_funds: u64,
+    #[serde(default)]
+    pub min_dispute_age_before_settle: u32,
+    #[serde(default)]
+    pub settle_approval_amount: u64,
+    #[serde(default)]
+    pub settle_approvals_required: u32,
+    #[serde(default)]
+    pub show_premium_details: bool,
+    #[serde(default)]
+    pub publish_fee: bool,
+    #[serde(default = "default_max_text_message_length")]
+    pub max_text_message_length: u32,
+    #[serde(default)]
+    pub max_disputes_per_window: u32,
+    #[serde(default = "default_dispute_rate_window_seconds")]
+    pub dispute_rate_window_seconds: u32,
+    #[serde(default)]
+    pub startup_self_test: bool,
+    #[serde(default)]
+    pub notify_order_interest: bool,
+    #[serde(default = "default_order_interest_interval_seconds")]
+    pub order_interest_interval_seconds: u32,
+    #[serde(default)]
+    pub max_dispute_rounds: u32,
+    #[serde(default = "default_max_event_age_secs")]
+    pub max_event_age_secs: u64,
+    #[serde(default)]
+    pub event_max_ages: Vec<EventMaxAge>,
+    #[serde(default = "default_dispute_evidence_retention_days")]
+    pub dispute_evidence_retention_days: u32,
+    #[serde(default)]
+    pub publish_solver_reputation: bool,
+    #[serde(default = "default_expiration_scan_interval")]
+    pub expiration_scan_interval: u64,
+    #[serde(default)]
+    pub rest_api_enabled: bool,
+    #[serde(default = "default_rest_api_address")]
+    pub rest_api_address: String,
+    #[serde(default)]
+    pub rest_api_token: String,
+    #[serde(default)]
+    pub operator_contact: String,
+    #[serde(default)]
+    pub metrics_enabled: bool,
+    #[serde(default)]
+    pub metrics_address: String,
+    #[serde(default)]
+    pub dispute_escalation_hours: u32,
+    #[serde(default)]
+    pub admin_pubkey: String,
+    #[serde(default)]
+    pub max_fee_percent: f64,
+    #[serde(default)]
+    pub dispute_solvers_quorum: u32,
+    #[serde(default)]
+    pub require_fiat_confirmations: bool,
+    #[serde(default)]
+    pub reputation_half_life_days: u32,
+    #[serde(default = "default_rest_api_page_size")]
+    pub rest_api_page_size: u32,
+    #[serde(default = "default_price_api_url")]
+    pub price_api_url: String,
+    #[serde(default = "default_price_cache_seconds")]
+    pub price_cache_seconds: u32,
+    #[serde(default)]
+    pub quarantine_price_deviation_percent: f64,
+    #[serde(default)]
+    pub quarantine_amount: u64,
+    #[serde(default)]
+    pub max_orders_per_window: u32,
+    #[serde(default = "default_order_rate_window_seconds")]
+    pub order_rate_window_seconds: u32,
+    #[serde(default)]
+    pub adaptive_pow: bool,
+    #[serde(default = "default_adaptive_pow_ceiling")]
+    pub adaptive_pow_ceiling: u8,
+    #[serde(default = "default_adaptive_pow_threshold")]
+    pub adaptive_pow_threshold: u32,
+    #[serde(default = "default_adaptive_pow_window_seconds")]
+    pub adaptive_pow_window_seconds: u32,
+    #[serde(default)]
+    pub dispute_close_delay_seconds: u32,
+    #[serde(default)]
+    pub lnurl_timeout_seconds: u32,
+    #[serde(default = "default_nip05_cache_seconds")]
+    pub nip05_cache_seconds: u32,
+    #[serde(default)]
+    pub webhook_url: String,
+    #[serde(default)]
+    pub webhook_secret: String,
+    #[serde(default)]
+    pub fee_flat_sats: u32,
+    #[serde(default)]
+    pub fee_free_pubkeys: Vec<String>,
+    #[serde(default = "default_hold_invoice_reminder_seconds")]
+    pub hold_invoice_reminder_seconds: u32,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub max_trade_index_jump: u32,
+}
+
+fn default_rest_api_page_size() -> u32 {
+    50
+}
+
+fn default_price_api_url() -> String {
+    "https://api.yadio.io".to_string()
+}
+
+fn default_price_cache_seconds() -> u32 {
+    60
+}
+
+fn default_display_sats_amount() -> bool {
+    true
+}
+
+fn default_max_event_age_secs() -> u64 {
+    10
+}
+
+fn default_expiration_scan_interval() -> u64 {
+    60
+}
+
+fn default_hold_invoice_reminder_seconds() -> u32 {
+    300
+}
+
+fn default_max_text_message_length() -> u32 {
+    1000
+}
+
+fn default_dispute_rate_window_seconds() -> u32 {
+    86400
+}
+
+fn default_order_rate_window_seconds() -> u32 {
+    3600
+}
+
+fn default_adaptive_pow_ceiling() -> u8 {
+    20
+}
+
+fn default_adaptive_pow_threshold() -> u32 {
+    600
+}
+
+fn default_adaptive_pow_window_seconds() -> u32 {
+    60
+}
+
+fn default_redact_admin_order_fields() -> bool {
+    true
+}
+
+fn default_order_interest_interval_seconds() -> u32 {
+    300
+}
+
+fn default_nip05_cache_seconds() -> u32 {
+    3600
+}
+
+fn default_rest_api_address() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+fn default_dispute_evidence_retention_days() -> u32 {
+    30
+}
+
+fn default_onchain_network() -> String {
+    "bitcoin".to_string()
 }
 
 impl TryFrom<Settings> for Mostro {
     type Error = Error;
 
     fn try_from(_: Settings) -> Result<Self, Error> {
-        Ok(MOSTRO_CONFIG.get().unwrap().mostro.clone())
+        Ok(Settings::snapshot().mostro.clone())
     }
 }
 
@@ -119,7 +422,44 @@ pub struct Settings {
 }
 
 pub fn init_global_settings(s: Settings) {
-    MOSTRO_CONFIG.set(s).unwrap()
+    MOSTRO_CONFIG.set(RwLock::new(Arc::new(s))).unwrap()
+}
+
+/// Replace the global settings, operations already running keep the snapshot they took
+pub fn reload_global_settings(s: Settings) -> Result<()> {
+    let config = MOSTRO_CONFIG
+        .get()
+        .ok_or_else(|| Error::msg("Settings not initialized"))?;
+    replace_settings(config, s);
+    Ok(())
+}
+
+/// Swap the settings behind `config`, readers holding a snapshot keep it
+pub fn replace_settings(config: &RwLock<Arc<Settings>>, s: Settings) {
+    *config.write().unwrap() = Arc::new(s);
+}
+
+/// Reload settings from file every time Mostro receives a SIGHUP signal.
+/// Database, nostr and lightning connections are not restarted, changes to
+/// those sections need a restart to take effect.
+#[cfg(unix)]
+pub async fn reload_settings_on_signal(config_path: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => return tracing::error!("Failed to listen for SIGHUP: {e}"),
+    };
+
+    while sighup.recv().await.is_some() {
+        match Settings::new(config_path.clone()) {
+            Ok(settings) => match reload_global_settings(settings) {
+                Ok(_) => tracing::info!("Settings reloaded from {}", config_path.display()),
+                Err(e) => tracing::error!("Failed to reload settings: {e}"),
+            },
+            Err(e) => tracing::error!("Failed to read settings, keeping current ones: {e}"),
+        }
+    }
 }
 
 impl Settings {
@@ -152,20 +492,25 @@ impl Settings {
         s.try_deserialize()
     }
 
+    /// Get the current settings, use it to read several values consistently
+    pub fn snapshot() -> Arc<Settings> {
+        MOSTRO_CONFIG.get().unwrap().read().unwrap().clone()
+    }
+
     pub fn get_ln() -> Lightning {
-        MOSTRO_CONFIG.get().unwrap().lightning.clone()
+        Settings::snapshot().lightning.clone()
     }
 
     pub fn get_mostro() -> Mostro {
-        MOSTRO_CONFIG.get().unwrap().mostro.clone()
+        Settings::snapshot().mostro.clone()
     }
 
     pub fn get_db() -> Database {
-        MOSTRO_CONFIG.get().unwrap().database.clone()
+        Settings::snapshot().database.clone()
     }
 
     pub fn get_nostr() -> Nostr {
-        MOSTRO_CONFIG.get().unwrap().nostr.clone()
+        Settings::snapshot().nostr.clone()
     }
 }
 
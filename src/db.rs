@@ -1,21 +1,118 @@
-use crate::app::rate_user::{MAX_RATING, MIN_RATING};
+use crate::app::rate_user::{rating_decay, MAX_RATING, MIN_RATING};
 use anyhow::Result;
-use mostro_core::dispute::Dispute;
+use mostro_core::dispute::{Dispute, Status as DisputeStatus};
 use mostro_core::order::Order;
 use mostro_core::order::Status;
 use mostro_core::user::User;
 use nostr_sdk::prelude::*;
+use serde::Serialize;
 use sqlx::pool::Pool;
-use sqlx::sqlite::SqliteRow;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteRow};
+use sqlx::QueryBuilder;
 use sqlx::Row;
 use sqlx::Sqlite;
 use sqlx::SqlitePool;
-use std::path::Path;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::OnceCell;
 use uuid::Uuid;
 
-use crate::cli::settings::Settings;
+use crate::cli::settings::{Database, Settings};
 
+const DEFAULT_MAX_CONNECTIONS: u32 = 10;
+const DEFAULT_BUSY_TIMEOUT_SECS: u32 = 5;
+
+/// Open a pool on the SQLite database at `db_url` sized and tuned with the
+/// database settings, concurrent writers wait up to the busy timeout for
+/// the lock instead of failing with `database is locked`
+async fn connect_pool(db_url: &str, db_settings: &Database) -> Result<SqlitePool> {
+    let max_connections = match db_settings.max_connections {
+        0 => DEFAULT_MAX_CONNECTIONS,
+        max => max,
+    };
+    let busy_timeout = match db_settings.busy_timeout_seconds {
+        0 => DEFAULT_BUSY_TIMEOUT_SECS,
+        secs => secs,
+    };
+    let journal_mode = match db_settings.journal_mode.as_str() {
+        "" => SqliteJournalMode::Wal,
+        mode => SqliteJournalMode::from_str(mode)
+            .map_err(|_| anyhow::anyhow!("Unknown SQLite journal mode {mode}"))?,
+    };
+    let options = SqliteConnectOptions::from_str(db_url)?
+        .busy_timeout(Duration::from_secs(busy_timeout as u64))
+        .journal_mode(journal_mode);
+
+    Ok(SqlitePoolOptions::new()
+        .max_connections(max_connections)
+        .connect_with(options)
+        .await?)
+}
+
+/// Pool shared by the whole application, opened on first use
+static POOL: OnceCell<SqlitePool> = OnceCell::const_new();
+
+/// Get the application pool, the database is opened on the first call and
+/// the following ones reuse the same pool instead of opening a new one
 pub async fn connect() -> Result<Pool<Sqlite>> {
+    shared_pool(&POOL, open_pool).await
+}
+
+/// Check the application pool was opened
+#[cfg(test)]
+pub fn is_pool_open() -> bool {
+    POOL.initialized()
+}
+
+/// Temporary database file of a test, removed when dropped
+#[cfg(test)]
+pub struct TestDbFile(PathBuf);
+
+#[cfg(test)]
+impl TestDbFile {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("mostro-test-{}.db", Uuid::new_v4()));
+        std::fs::File::create_new(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn url(&self) -> String {
+        format!("sqlite://{}", self.0.display())
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDbFile {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Open a migrated database for a test, it's removed with the returned file
+#[cfg(test)]
+pub async fn connect_test_db() -> (SqlitePool, TestDbFile) {
+    let file = TestDbFile::new();
+    let pool = SqlitePool::connect(&file.url()).await.unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    (pool, file)
+}
+
+async fn shared_pool<F, Fut>(cell: &OnceCell<SqlitePool>, open: F) -> Result<SqlitePool>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<SqlitePool>>,
+{
+    cell.get_or_try_init(open).await.cloned()
+}
+
+async fn open_pool() -> Result<Pool<Sqlite>> {
     // Get mostro settings
     let db_settings = Settings::get_db();
     let mut db_url = db_settings.url;
@@ -31,7 +128,7 @@ pub async fn connect() -> Result<Pool<Sqlite>> {
                 e
             )
         })?;
-        match SqlitePool::connect(&db_url).await {
+        match connect_pool(&db_url, &db_settings).await {
             Ok(pool) => {
                 match sqlx::migrate!().run(&pool).await {
                     Ok(_) => {
@@ -72,7 +169,7 @@ pub async fn connect() -> Result<Pool<Sqlite>> {
             }
         }
     } else {
-        SqlitePool::connect(&db_url).await.map_err(|e| {
+        connect_pool(&db_url, &db_settings).await.map_err(|e| {
             anyhow::anyhow!(
                 "Failed to connect to existing database at {}: {}",
                 db_path.display(),
@@ -144,13 +241,16 @@ pub async fn find_order_by_hash(pool: &SqlitePool, hash: &str) -> anyhow::Result
     Ok(order)
 }
 
+/// Orders not yet active past their expiration date
 pub async fn find_order_by_date(pool: &SqlitePool) -> anyhow::Result<Vec<Order>> {
     let expire_time = Timestamp::now();
     let order = sqlx::query_as::<_, Order>(
         r#"
           SELECT *
           FROM orders
-          WHERE expires_at < ?1 AND status == 'pending'
+          WHERE expires_at < ?1
+            AND status IN ('pending', 'waiting-buyer-invoice', 'waiting-payment')
+            AND id NOT IN (SELECT order_id FROM quarantined_orders)
         "#,
     )
     .bind(expire_time.to_string())
@@ -187,12 +287,59 @@ pub async fn find_dispute_by_order_id(
           SELECT *
           FROM disputes
           WHERE order_id == ?1
+          ORDER BY created_at DESC
+        "#,
+    )
+    .bind(order_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(dispute)
+}
+
+/// Dispute rounds opened for an order and if the last one is still open
+pub async fn find_dispute_rounds(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<(u32, bool)> {
+    let (rounds, open): (i64, i64) = sqlx::query_as(
+        r#"
+          SELECT COUNT(*), COALESCE(SUM(status IN (?2, ?3)), 0)
+          FROM (
+            SELECT status FROM disputes WHERE order_id == ?1
+            UNION ALL
+            SELECT status FROM disputes_archive WHERE order_id == ?1
+          )
         "#,
     )
     .bind(order_id)
+    .bind(DisputeStatus::Initiated.to_string())
+    .bind(DisputeStatus::InProgress.to_string())
     .fetch_one(pool)
     .await?;
 
+    Ok((rounds as u32, open > 0))
+}
+
+/// Close the open dispute of an order with `status`, returns the dispute
+/// updated or None if the order has no open dispute
+pub async fn resolve_open_dispute(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    status: DisputeStatus,
+) -> anyhow::Result<Option<Dispute>> {
+    let dispute = sqlx::query_as::<_, Dispute>(
+        r#"
+          UPDATE disputes
+          SET status = ?1
+          WHERE order_id == ?2 AND status IN (?3, ?4)
+          RETURNING *
+        "#,
+    )
+    .bind(status.to_string())
+    .bind(order_id)
+    .bind(DisputeStatus::Initiated.to_string())
+    .bind(DisputeStatus::InProgress.to_string())
+    .fetch_optional(pool)
+    .await?;
+
     Ok(dispute)
 }
 
@@ -353,143 +500,379 @@ pub async fn find_failed_payment(pool: &SqlitePool) -> anyhow::Result<Vec<Order>
     Ok(order)
 }
 
-pub async fn find_solver_pubkey(pool: &SqlitePool, solver_npub: String) -> anyhow::Result<User> {
-    let user = sqlx::query_as::<_, User>(
+/// Set the time of the next retry of a failed buyer payment
+pub async fn set_payment_retry(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    next_retry_at: i64,
+) -> anyhow::Result<()> {
+    sqlx::query("INSERT OR REPLACE INTO payment_retries (order_id, next_retry_at) VALUES (?1, ?2)")
+        .bind(order_id)
+        .bind(next_retry_at)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Check the backoff of the last failed payment of an order passed, orders
+/// without failed payments are always due
+pub async fn is_payment_retry_due(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let next_retry_at: Option<i64> =
+        sqlx::query("SELECT next_retry_at FROM payment_retries WHERE order_id = ?1")
+            .bind(order_id)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(next_retry_at.map_or(true, |next_retry_at| now >= next_retry_at))
+}
+
+/// Forget the retries of an order once its payment succeeded
+pub async fn clear_payment_retry(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM payment_retries WHERE order_id = ?1")
+        .bind(order_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Claim the retry of a failed buyer payment, the flag is cleared while the
+/// payment is in flight so a single retry wins, a new `buyer_invoice`
+/// replaces the failed one and restarts the attempts
+pub async fn claim_failed_payment(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    buyer_invoice: Option<&str>,
+) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query(
         r#"
-          SELECT *
-          FROM users
-          WHERE pubkey == ?1 AND  is_solver == true
-          LIMIT 1
+          UPDATE orders
+          SET failed_payment = false,
+            buyer_invoice = COALESCE(?2, buyer_invoice),
+            payment_attempts = CASE WHEN ?2 IS NULL THEN payment_attempts ELSE 0 END
+          WHERE id = ?1 AND failed_payment == true AND status == 'settled-hold-invoice'
         "#,
     )
-    .bind(solver_npub)
-    .fetch_one(pool)
-    .await?;
+    .bind(order_id)
+    .bind(buyer_invoice)
+    .execute(pool)
+    .await?
+    .rows_affected();
 
-    Ok(user)
+    Ok(rows_affected > 0)
 }
 
-pub async fn is_user_present(pool: &SqlitePool, public_key: String) -> anyhow::Result<User> {
-    let user = sqlx::query_as::<_, User>(
+/// Sum of the amounts of all orders not finished yet, `exclude` order is not counted
+pub async fn get_locked_funds(pool: &SqlitePool, exclude: Option<Uuid>) -> anyhow::Result<i64> {
+    let locked = sqlx::query(
         r#"
-            SELECT *
-            FROM users
-            WHERE pubkey == ?1
-            LIMIT 1
+          SELECT COALESCE(SUM(amount), 0)
+          FROM orders
+          WHERE status IN ('pending', 'waiting-buyer-invoice', 'waiting-payment', 'active',
+            'fiat-sent', 'settled-hold-invoice', 'dispute') AND id != ?1
         "#,
     )
-    .bind(public_key)
+    .bind(exclude.unwrap_or_default())
+    .map(|row: SqliteRow| row.get(0))
     .fetch_one(pool)
     .await?;
 
-    Ok(user)
+    Ok(locked)
 }
 
-pub async fn add_new_user(pool: &SqlitePool, new_user: User) -> anyhow::Result<()> {
-    // Validate public key format (32-bytes hex)
-    let created_at: Timestamp = Timestamp::now();
-    let result = sqlx::query(
-        "
-            INSERT INTO users (pubkey, is_admin, is_solver, is_banned, category, last_trade_index, total_reviews, total_rating, last_rating, max_rating, min_rating, created_at) 
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
-        ",
+/// Record that the hold invoice of an order was settled, done right after settling
+/// so the order is not left active if publishing the new status fails
+pub async fn set_order_settled(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<bool> {
+    // Only to notify the change, the update below checks the status again
+    let old_status: Option<String> = sqlx::query_scalar("SELECT status FROM orders WHERE id = ?1")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await?;
+    let rows_affected = sqlx::query(
+        r#"
+          UPDATE orders
+          SET status = 'settled-hold-invoice'
+          WHERE id = ?1 AND status IN ('active', 'fiat-sent', 'dispute')
+        "#,
     )
-    .bind(new_user.pubkey)
-    .bind(new_user.is_admin)
-    .bind(new_user.is_solver)
-    .bind(new_user.is_banned)
-    .bind(new_user.category)
-    .bind(new_user.last_trade_index)
-    .bind(new_user.total_reviews)
-    .bind(new_user.total_rating)
-    .bind(new_user.last_rating)
-    .bind(new_user.max_rating)
-    .bind(new_user.min_rating)
-    .bind(created_at.to_string())
+    .bind(order_id)
     .execute(pool)
-    .await;
-
-    match result {
-        Ok(_) => {
-            tracing::info!("New user created successfully");
-            Ok(())
-        }
-        Err(e) => Err(anyhow::anyhow!("Error creating new user: {}", e)),
+    .await?
+    .rows_affected();
+    if rows_affected > 0 {
+        crate::webhook::notify_status_change(
+            order_id,
+            &old_status.unwrap_or_default(),
+            &Status::SettledHoldInvoice.to_string(),
+            Timestamp::now().as_u64() as i64,
+        );
     }
+
+    Ok(rows_affected > 0)
 }
 
-pub async fn update_user_trade_index(
+/// Move an order from `from` to `to` status only if it is still on `from`,
+/// returns false if other request changed it before, e.g. a concurrent take
+pub async fn transition_order_status(
     pool: &SqlitePool,
-    public_key: String,
-    trade_index: i64,
+    order_id: Uuid,
+    from: &Status,
+    to: &Status,
 ) -> anyhow::Result<bool> {
-    // Validate public key format (32-bytes hex)
-    if !public_key.chars().all(|c| c.is_ascii_hexdigit()) || public_key.len() != 64 {
-        return Err(anyhow::anyhow!("Invalid public key format"));
-    }
-    // Validate trade_index
-    if trade_index < 0 {
-        return Err(anyhow::anyhow!("Invalid trade_index: must be non-negative"));
-    }
-
-    let mut conn = pool.acquire().await?;
-
-    let rows_affected = sqlx::query!(
+    let rows_affected = sqlx::query(
         r#"
-            UPDATE users SET last_trade_index = ?1 WHERE pubkey = ?2
+          UPDATE orders
+          SET status = ?1
+          WHERE id = ?2 AND status = ?3
         "#,
-        trade_index,
-        public_key,
     )
-    .execute(&mut conn)
+    .bind(to.to_string())
+    .bind(order_id)
+    .bind(from.to_string())
+    .execute(pool)
     .await?
     .rows_affected();
+    if rows_affected > 0 {
+        crate::webhook::notify_status_change(
+            order_id,
+            &from.to_string(),
+            &to.to_string(),
+            Timestamp::now().as_u64() as i64,
+        );
+    }
 
     Ok(rows_affected > 0)
 }
 
-pub async fn update_user_rating(
-    pool: &SqlitePool,
-    public_key: String,
-    last_rating: i64,
-    min_rating: i64,
-    max_rating: i64,
-    total_reviews: i64,
-    total_rating: f64,
-) -> anyhow::Result<bool> {
-    // Validate public key format (32-bytes hex)
-    if !public_key.chars().all(|c| c.is_ascii_hexdigit()) || public_key.len() != 64 {
-        return Err(anyhow::anyhow!("Invalid public key format"));
+/// Orders with a hold invoice that could have been settled without recording it
+pub async fn find_unsettled_orders(pool: &SqlitePool) -> anyhow::Result<Vec<Order>> {
+    let orders = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE hash IS NOT NULL AND status IN ('active', 'fiat-sent', 'dispute')
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(orders)
+}
+
+/// Hashes of the hold invoices of orders not finished yet
+pub async fn find_active_order_hashes(pool: &SqlitePool) -> anyhow::Result<HashSet<String>> {
+    let hashes: Vec<String> = sqlx::query(
+        r#"
+          SELECT hash
+          FROM orders
+          WHERE hash IS NOT NULL AND status IN ('pending', 'waiting-buyer-invoice',
+            'waiting-payment', 'active', 'fiat-sent', 'settled-hold-invoice', 'dispute')
+        "#,
+    )
+    .map(|row: SqliteRow| row.get(0))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(hashes.into_iter().collect())
+}
+
+/// Count of orders by status, with how many of them have a hold invoice
+pub async fn count_orders_by_status(pool: &SqlitePool) -> anyhow::Result<Vec<(String, i64, i64)>> {
+    let counts = sqlx::query_as::<_, (String, i64, i64)>(
+        "SELECT status, COUNT(*), COUNT(hash) FROM orders GROUP BY status",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(counts)
+}
+
+/// Sort of the active orders pages
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OrderSort {
+    /// Newest first
+    #[default]
+    Recent,
+    /// Biggest amount first
+    Amount,
+    /// Highest premium first
+    Premium,
+}
+
+impl OrderSort {
+    fn order_by(&self) -> &'static str {
+        match self {
+            OrderSort::Recent => "created_at DESC, id",
+            OrderSort::Amount => "amount DESC, id",
+            OrderSort::Premium => "premium DESC, id",
+        }
     }
-    // Validate rating values
-    if !(0..=5).contains(&last_rating) {
-        return Err(anyhow::anyhow!("Invalid rating value"));
+}
+
+/// Page of the orders not finished yet, orders held for review are left out,
+/// pages start at 0
+pub async fn find_active_orders_page(
+    pool: &SqlitePool,
+    sort: OrderSort,
+    page: u32,
+    page_size: u32,
+) -> anyhow::Result<Vec<Order>> {
+    let orders = sqlx::query_as::<_, Order>(&format!(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE status IN ('pending', 'waiting-buyer-invoice', 'waiting-payment', 'active',
+            'fiat-sent', 'settled-hold-invoice', 'dispute')
+            AND id NOT IN (SELECT order_id FROM quarantined_orders)
+          ORDER BY {}
+          LIMIT ?1 OFFSET ?2
+        "#,
+        sort.order_by()
+    ))
+    .bind(page_size as i64)
+    .bind(page as i64 * page_size as i64)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(orders)
+}
+
+/// Filter of the order history, a missing field doesn't filter
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct OrderFilter {
+    pub status: Option<Status>,
+    pub fiat_code: Option<String>,
+    /// Orders created at or after this time
+    pub from: Option<i64>,
+    /// Orders created before this time
+    pub to: Option<i64>,
+    /// Orders where this pubkey is the creator, buyer or seller
+    pub pubkey: Option<String>,
+}
+
+/// Page of the order history with the total of orders matching the filter
+#[derive(Debug, Default)]
+pub struct OrdersPage {
+    pub orders: Vec<Order>,
+    pub total: i64,
+}
+
+/// Append the conditions of `filter` to a query of the orders table, the
+/// values are always bound as parameters
+fn push_order_filter<'a>(query: &mut QueryBuilder<'a, Sqlite>, filter: &'a OrderFilter) {
+    query.push(" WHERE 1 = 1");
+    if let Some(status) = &filter.status {
+        query.push(" AND status = ").push_bind(status.to_string());
     }
-    if !(0..=5).contains(&min_rating) || !(0..=5).contains(&max_rating) {
-        return Err(anyhow::anyhow!("Invalid min/max rating values"));
+    if let Some(fiat_code) = &filter.fiat_code {
+        query.push(" AND fiat_code = ").push_bind(fiat_code);
     }
-    if MIN_RATING as i64 > last_rating || last_rating > MAX_RATING as i64 {
-        return Err(anyhow::anyhow!(
-            "Rating values must satisfy: min_rating <= last_rating <= max_rating"
-        ));
+    if let Some(from) = filter.from {
+        query.push(" AND created_at >= ").push_bind(from);
     }
-    if total_reviews < 0 {
-        return Err(anyhow::anyhow!("Invalid total reviews"));
+    if let Some(to) = filter.to {
+        query.push(" AND created_at < ").push_bind(to);
     }
-    if total_rating < 0.0 || total_rating > (total_reviews * 5) as f64 {
-        return Err(anyhow::anyhow!("Invalid total rating"));
+    if let Some(pubkey) = &filter.pubkey {
+        query
+            .push(" AND (creator_pubkey = ")
+            .push_bind(pubkey)
+            .push(" OR buyer_pubkey = ")
+            .push_bind(pubkey)
+            .push(" OR seller_pubkey = ")
+            .push_bind(pubkey)
+            .push(")");
     }
-    let rows_affected = sqlx::query!(
+}
+
+/// List the orders matching `filter`, newest first, skipping `offset` orders
+pub async fn list_orders(
+    pool: &SqlitePool,
+    filter: &OrderFilter,
+    limit: u32,
+    offset: u32,
+) -> anyhow::Result<OrdersPage> {
+    let mut query = QueryBuilder::new("SELECT * FROM orders");
+    push_order_filter(&mut query, filter);
+    query
+        .push(" ORDER BY created_at DESC, id LIMIT ")
+        .push_bind(limit as i64)
+        .push(" OFFSET ")
+        .push_bind(offset as i64);
+    let orders = query.build_query_as::<Order>().fetch_all(pool).await?;
+
+    let mut count = QueryBuilder::new("SELECT COUNT(*) FROM orders");
+    push_order_filter(&mut count, filter);
+    let total: i64 = count
+        .build()
+        .map(|row: SqliteRow| row.get(0))
+        .fetch_one(pool)
+        .await?;
+
+    Ok(OrdersPage { orders, total })
+}
+
+/// Record the escalation of a dispute, returns false if it was already
+/// escalated so each dispute is escalated once, even across restarts
+pub async fn mark_dispute_escalated(
+    pool: &SqlitePool,
+    dispute_id: Uuid,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected =
+        sqlx::query("UPDATE disputes SET escalated_at = ?1 WHERE id = ?2 AND escalated_at IS NULL")
+            .bind(now)
+            .bind(dispute_id)
+            .execute(pool)
+            .await?
+            .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Disputes not resolved yet
+pub async fn find_open_disputes(pool: &SqlitePool) -> anyhow::Result<Vec<Dispute>> {
+    let disputes = sqlx::query_as::<_, Dispute>(
         r#"
-            UPDATE users SET last_rating = ?1, min_rating = ?2, max_rating = ?3, total_reviews = ?4, total_rating = ?5 WHERE pubkey = ?6
+          SELECT *
+          FROM disputes
+          WHERE status IN (?1, ?2)
+          ORDER BY created_at DESC
+        "#,
+    )
+    .bind(DisputeStatus::Initiated.to_string())
+    .bind(DisputeStatus::InProgress.to_string())
+    .fetch_all(pool)
+    .await?;
+
+    Ok(disputes)
+}
+
+/// Record the buyer confirmation of the fiat receipt of an order, returns
+/// false if the receipt was already confirmed
+pub async fn add_receipt_confirmation(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    buyer_pubkey: &str,
+    event_id: &str,
+    created_at: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query(
+        r#"
+          INSERT OR IGNORE INTO receipts (order_id, buyer_pubkey, event_id, created_at)
+          VALUES (?1, ?2, ?3, ?4)
         "#,
-        last_rating,
-        min_rating,
-        max_rating,
-        total_reviews,
-        total_rating,
-        public_key,
     )
+    .bind(order_id)
+    .bind(buyer_pubkey)
+    .bind(event_id)
+    .bind(created_at)
     .execute(pool)
     .await?
     .rows_affected();
@@ -497,41 +880,2105 @@ pub async fn update_user_rating(
     Ok(rows_affected > 0)
 }
 
-pub async fn is_assigned_solver(
+/// Time the buyer confirmed the receipt of an order, if confirmed
+pub async fn find_receipt_confirmation(
     pool: &SqlitePool,
-    solver_pubkey: &str,
     order_id: Uuid,
-) -> anyhow::Result<bool> {
-    println!("solver_pubkey: {}", solver_pubkey);
-    println!("order_id: {}", order_id);
-    let result = sqlx::query(
-        "SELECT EXISTS(SELECT 1 FROM disputes WHERE solver_pubkey = ? AND order_id = ?)",
+) -> anyhow::Result<Option<i64>> {
+    let created_at = sqlx::query("SELECT created_at FROM receipts WHERE order_id = ?1")
+        .bind(order_id)
+        .map(|row: SqliteRow| row.get(0))
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(created_at)
+}
+
+/// Seller hold invoice about to expire without being paid
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct HoldInvoiceReminder {
+    pub order_id: Uuid,
+    pub payment_request: String,
+    pub expires_at: i64,
+}
+
+/// Record the hold invoice the seller was asked to pay, a new take of the
+/// order replaces it
+pub async fn add_hold_invoice(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    payment_request: &str,
+    expires_at: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO hold_invoices (order_id, payment_request, expires_at) VALUES (?1, ?2, ?3)",
     )
-    .bind(solver_pubkey)
     .bind(order_id)
-    .map(|row: SqliteRow| row.get(0))
-    .fetch_one(pool)
+    .bind(payment_request)
+    .bind(expires_at)
+    .execute(pool)
     .await?;
 
-    Ok(result)
+    Ok(())
 }
 
-pub async fn find_order_by_id(
+/// Hold invoices of orders still waiting for the seller payment that expire
+/// within `before` seconds of `now` and whose seller was not reminded yet
+pub async fn find_hold_invoice_reminders(
     pool: &SqlitePool,
-    order_id: Uuid,
-    user_pubkey: &str,
-) -> anyhow::Result<Order> {
-    let order = sqlx::query_as::<_, Order>(
+    now: i64,
+    before: i64,
+) -> anyhow::Result<Vec<HoldInvoiceReminder>> {
+    let reminders = sqlx::query_as::<_, HoldInvoiceReminder>(
         r#"
-          SELECT *
-          FROM orders
-          WHERE id = ?1 AND (buyer_pubkey = ?2 OR seller_pubkey = ?2)
+          SELECT h.order_id, h.payment_request, h.expires_at
+          FROM hold_invoices h
+          JOIN orders o ON o.id = h.order_id
+          WHERE o.status = 'waiting-payment' AND h.reminded_at IS NULL
+            AND h.expires_at > ?1 AND h.expires_at <= ?1 + ?2
+          ORDER BY h.expires_at
         "#,
     )
-    .bind(order_id)
-    .bind(user_pubkey)
-    .fetch_one(pool)
+    .bind(now)
+    .bind(before)
+    .fetch_all(pool)
     .await?;
 
-    Ok(order)
+    Ok(reminders)
+}
+
+/// Record the seller was reminded, false if it already was
+pub async fn mark_hold_invoice_reminded(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query(
+        "UPDATE hold_invoices SET reminded_at = ?1 WHERE order_id = ?2 AND reminded_at IS NULL",
+    )
+    .bind(now)
+    .bind(order_id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Order held for the operator review before being published
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
+pub struct QuarantinedOrder {
+    pub order_id: Uuid,
+    pub reason: String,
+    pub created_at: i64,
+}
+
+/// Hold an order for review, it's not published nor can be taken until approved
+pub async fn add_quarantined_order(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    reason: &str,
+    created_at: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO quarantined_orders (order_id, reason, created_at) VALUES (?1, ?2, ?3)",
+    )
+    .bind(order_id)
+    .bind(reason)
+    .bind(created_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn is_order_quarantined(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<bool> {
+    let quarantined =
+        sqlx::query("SELECT EXISTS(SELECT 1 FROM quarantined_orders WHERE order_id = ?1)")
+            .bind(order_id)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_one(pool)
+            .await?;
+
+    Ok(quarantined)
+}
+
+/// Take an order out of the review queue, false if it was not there
+pub async fn remove_quarantined_order(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query("DELETE FROM quarantined_orders WHERE order_id = ?1")
+        .bind(order_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Orders waiting for review, oldest first
+pub async fn find_quarantined_orders(pool: &SqlitePool) -> anyhow::Result<Vec<QuarantinedOrder>> {
+    let orders = sqlx::query_as::<_, QuarantinedOrder>(
+        "SELECT order_id, reason, created_at FROM quarantined_orders ORDER BY created_at",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(orders)
+}
+
+/// Admin settlement waiting for its cooldown to end before the hold invoice
+/// is settled, stored so it survives a restart
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct PendingSettlement {
+    pub order_id: Uuid,
+    pub admin_pubkey: String,
+    pub request_id: Option<i64>,
+    pub settle_at: i64,
+}
+
+/// Schedule the settlement of a disputed order at `settle_at`, false if it
+/// is already scheduled
+pub async fn add_pending_settlement(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    admin_pubkey: &str,
+    request_id: Option<u64>,
+    settle_at: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query(
+        "INSERT OR IGNORE INTO pending_settlements (order_id, admin_pubkey, request_id, settle_at) VALUES (?1, ?2, ?3, ?4)",
+    )
+    .bind(order_id)
+    .bind(admin_pubkey)
+    .bind(request_id.map(|id| id as i64))
+    .bind(settle_at)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Settlements whose cooldown ended at `now`
+pub async fn find_due_settlements(
+    pool: &SqlitePool,
+    now: i64,
+) -> anyhow::Result<Vec<PendingSettlement>> {
+    let settlements = sqlx::query_as::<_, PendingSettlement>(
+        "SELECT * FROM pending_settlements WHERE settle_at <= ?1 ORDER BY settle_at",
+    )
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(settlements)
+}
+
+/// Take a scheduled settlement to do it or drop it, false if it was already
+/// taken or aborted
+pub async fn take_pending_settlement(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query("DELETE FROM pending_settlements WHERE order_id = ?1")
+        .bind(order_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Abort a scheduled settlement, only possible before its cooldown ends
+pub async fn abort_pending_settlement(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected =
+        sqlx::query("DELETE FROM pending_settlements WHERE order_id = ?1 AND settle_at > ?2")
+            .bind(order_id)
+            .bind(now)
+            .execute(pool)
+            .await?
+            .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Record the seller acknowledgment of the fiat sent by the buyer
+pub async fn add_seller_fiat_confirmation(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    confirmed_at: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO fiat_confirmations (order_id, seller_confirmed_at) VALUES (?1, ?2)",
+    )
+    .bind(order_id)
+    .bind(confirmed_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Get when the seller acknowledged the fiat sent of an order, if done
+pub async fn find_seller_fiat_confirmation(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> anyhow::Result<Option<i64>> {
+    let confirmed_at =
+        sqlx::query("SELECT seller_confirmed_at FROM fiat_confirmations WHERE order_id = ?1")
+            .bind(order_id)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(confirmed_at)
+}
+
+/// Mark an order as unlisted, it is tracked but never published to relays
+pub async fn add_unlisted_order(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO unlisted_orders (order_id) VALUES (?1)")
+        .bind(order_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn is_order_unlisted(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<bool> {
+    let unlisted = sqlx::query("SELECT order_id FROM unlisted_orders WHERE order_id = ?1")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(unlisted.is_some())
+}
+
+/// Mark an order as only takeable by takers with a verified NIP-05
+pub async fn add_nip05_order(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO nip05_orders (order_id) VALUES (?1)")
+        .bind(order_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn is_nip05_order(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<bool> {
+    let nip05 = sqlx::query("SELECT order_id FROM nip05_orders WHERE order_id = ?1")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(nip05.is_some())
+}
+
+/// Purge the evidence and receipts of resolved disputes recorded before
+/// `before`, returns the number of records purged
+pub async fn purge_resolved_dispute_evidence(
+    pool: &SqlitePool,
+    before: i64,
+) -> anyhow::Result<u64> {
+    let mut tx = pool.begin().await?;
+    let evidence = sqlx::query(
+        r#"
+          DELETE FROM dispute_evidence
+          WHERE created_at < ?1 AND dispute_id IN (
+            SELECT id FROM disputes WHERE status NOT IN (?2, ?3)
+          )
+        "#,
+    )
+    .bind(before)
+    .bind(DisputeStatus::Initiated.to_string())
+    .bind(DisputeStatus::InProgress.to_string())
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    let receipts = sqlx::query(
+        r#"
+          DELETE FROM receipts
+          WHERE created_at < ?1 AND order_id IN (
+            SELECT order_id FROM disputes WHERE status NOT IN (?2, ?3)
+          ) AND order_id NOT IN (
+            SELECT order_id FROM disputes WHERE status IN (?2, ?3)
+          )
+        "#,
+    )
+    .bind(before)
+    .bind(DisputeStatus::Initiated.to_string())
+    .bind(DisputeStatus::InProgress.to_string())
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+
+    Ok(evidence + receipts)
+}
+
+/// Close the resolved disputes, a dispute is seen resolved at `now` the first
+/// time and once resolved for `delay` seconds it's moved with its evidence
+/// to the archive, returns the number of disputes archived
+pub async fn close_resolved_disputes(
+    pool: &SqlitePool,
+    now: i64,
+    delay: i64,
+) -> anyhow::Result<u64> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"
+          UPDATE disputes
+          SET resolved_at = ?1
+          WHERE resolved_at IS NULL AND status NOT IN (?2, ?3)
+        "#,
+    )
+    .bind(now)
+    .bind(DisputeStatus::Initiated.to_string())
+    .bind(DisputeStatus::InProgress.to_string())
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+          INSERT INTO dispute_evidence_archive
+          SELECT * FROM dispute_evidence
+          WHERE dispute_id IN (SELECT id FROM disputes WHERE resolved_at <= ?1)
+        "#,
+    )
+    .bind(now - delay)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+          DELETE FROM dispute_evidence
+          WHERE dispute_id IN (SELECT id FROM disputes WHERE resolved_at <= ?1)
+        "#,
+    )
+    .bind(now - delay)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+          INSERT INTO disputes_archive (id, order_id, status, solver_pubkey, created_at,
+            taken_at, buyer_token, seller_token, resolved_at, closed_at)
+          SELECT id, order_id, status, solver_pubkey, created_at, taken_at, buyer_token,
+            seller_token, resolved_at, ?2
+          FROM disputes
+          WHERE resolved_at <= ?1
+        "#,
+    )
+    .bind(now - delay)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    let archived = sqlx::query("DELETE FROM disputes WHERE resolved_at <= ?1")
+        .bind(now - delay)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+
+    Ok(archived)
+}
+
+/// Check if an event was already processed, e.g. a gift wrap delivered
+/// again by a relay
+pub async fn is_event_processed(pool: &SqlitePool, event_id: &str) -> anyhow::Result<bool> {
+    let processed =
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM processed_events WHERE event_id = ?1")
+            .bind(event_id)
+            .fetch_one(pool)
+            .await?;
+
+    Ok(processed > 0)
+}
+
+/// Record an event as processed, returns false if it was already processed
+pub async fn mark_event_processed(
+    pool: &SqlitePool,
+    event_id: &str,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query(
+        "INSERT OR IGNORE INTO processed_events (event_id, processed_at) VALUES (?1, ?2)",
+    )
+    .bind(event_id)
+    .bind(now)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Forget the events processed before `before`, older events are
+/// discarded anyway by their age
+pub async fn purge_processed_events(pool: &SqlitePool, before: i64) -> anyhow::Result<u64> {
+    let rows_affected = sqlx::query("DELETE FROM processed_events WHERE processed_at < ?1")
+        .bind(before)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+/// Write a consistent copy of the database to `dir` named after `now` and
+/// checkpoint the WAL, returns the path and size in bytes of the backup
+pub async fn backup_database(
+    pool: &SqlitePool,
+    dir: &Path,
+    now: i64,
+) -> anyhow::Result<(PathBuf, u64)> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("mostro-{now}.db"));
+    sqlx::query("VACUUM INTO ?1")
+        .bind(path.to_string_lossy().to_string())
+        .execute(pool)
+        .await?;
+    sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+        .execute(pool)
+        .await?;
+    let size = std::fs::metadata(&path)?.len();
+
+    Ok((path, size))
+}
+
+/// Remove the oldest backups in `dir` keeping the last `keep`, returns the
+/// number of backups removed
+pub fn rotate_backups(dir: &Path, keep: usize) -> anyhow::Result<usize> {
+    let mut backups: Vec<(i64, PathBuf)> = std::fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?;
+            let time = name.strip_prefix("mostro-")?.strip_suffix(".db")?;
+            Some((time.parse().ok()?, path))
+        })
+        .collect();
+    backups.sort();
+    let remove = backups.len().saturating_sub(keep);
+    for (_, path) in backups.iter().take(remove) {
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(remove)
+}
+
+/// Set the POW the maker requires from takers of an order
+pub async fn set_order_take_pow(pool: &SqlitePool, order_id: Uuid, pow: u8) -> anyhow::Result<()> {
+    sqlx::query("INSERT OR REPLACE INTO order_take_pow (order_id, pow) VALUES (?1, ?2)")
+        .bind(order_id)
+        .bind(pow as i64)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn find_order_take_pow(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<Option<u8>> {
+    let pow: Option<i64> = sqlx::query("SELECT pow FROM order_take_pow WHERE order_id = ?1")
+        .bind(order_id)
+        .map(|row: SqliteRow| row.get(0))
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(pow.map(|pow| pow as u8))
+}
+
+/// Options of an order set by its maker on creation, and the reason it's
+/// held for review if a heuristic flagged it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OrderOptions {
+    /// Never published, takers use the order id shared by the maker
+    pub unlisted: bool,
+    /// POW the maker requires from takers
+    pub take_pow: Option<u8>,
+    /// Only takers with a verified NIP-05 can take it
+    pub requires_nip05: bool,
+    /// Reason the order is held for review
+    pub quarantine: Option<String>,
+}
+
+/// Store the options of an order
+pub async fn set_order_options(
+    pool: &SqlitePool,
+    order: &Order,
+    options: &OrderOptions,
+) -> anyhow::Result<()> {
+    if options.unlisted {
+        add_unlisted_order(pool, order.id).await?;
+    }
+    if let Some(pow) = options.take_pow {
+        set_order_take_pow(pool, order.id, pow).await?;
+    }
+    if options.requires_nip05 {
+        add_nip05_order(pool, order.id).await?;
+    }
+    if let Some(reason) = &options.quarantine {
+        add_quarantined_order(pool, order.id, reason, order.created_at).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn find_order_options(pool: &SqlitePool, order_id: Uuid) -> anyhow::Result<OrderOptions> {
+    let quarantine = sqlx::query("SELECT reason FROM quarantined_orders WHERE order_id = ?1")
+        .bind(order_id)
+        .map(|row: SqliteRow| row.get(0))
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(OrderOptions {
+        unlisted: is_order_unlisted(pool, order_id).await?,
+        take_pow: find_order_take_pow(pool, order_id).await?,
+        requires_nip05: is_nip05_order(pool, order_id).await?,
+        quarantine,
+    })
+}
+
+/// Orders whose buyer payment could have been sent, found on startup
+/// to know the result of payments in flight when mostro stopped
+pub async fn find_settled_orders(pool: &SqlitePool) -> anyhow::Result<Vec<Order>> {
+    let orders = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE status == 'settled-hold-invoice' AND buyer_invoice IS NOT NULL
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(orders)
+}
+
+pub async fn find_solver_pubkey(pool: &SqlitePool, solver_npub: String) -> anyhow::Result<User> {
+    let user = sqlx::query_as::<_, User>(
+        r#"
+          SELECT *
+          FROM users
+          WHERE pubkey == ?1 AND  is_solver == true
+          LIMIT 1
+        "#,
+    )
+    .bind(solver_npub)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(user)
+}
+
+pub async fn is_user_present(pool: &SqlitePool, public_key: String) -> anyhow::Result<User> {
+    let user = sqlx::query_as::<_, User>(
+        r#"
+            SELECT *
+            FROM users
+            WHERE pubkey == ?1
+            LIMIT 1
+        "#,
+    )
+    .bind(public_key)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(user)
+}
+
+pub async fn add_new_user(pool: &SqlitePool, new_user: User) -> anyhow::Result<()> {
+    // Validate public key format (32-bytes hex)
+    let created_at: Timestamp = Timestamp::now();
+    let result = sqlx::query(
+        "
+            INSERT INTO users (pubkey, is_admin, is_solver, is_banned, category, last_trade_index, total_reviews, total_rating, last_rating, max_rating, min_rating, created_at) 
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+        ",
+    )
+    .bind(new_user.pubkey)
+    .bind(new_user.is_admin)
+    .bind(new_user.is_solver)
+    .bind(new_user.is_banned)
+    .bind(new_user.category)
+    .bind(new_user.last_trade_index)
+    .bind(new_user.total_reviews)
+    .bind(new_user.total_rating)
+    .bind(new_user.last_rating)
+    .bind(new_user.max_rating)
+    .bind(new_user.min_rating)
+    .bind(created_at.to_string())
+    .execute(pool)
+    .await;
+
+    match result {
+        Ok(_) => {
+            tracing::info!("New user created successfully");
+            Ok(())
+        }
+        Err(e) => Err(anyhow::anyhow!("Error creating new user: {}", e)),
+    }
+}
+
+pub async fn update_user_trade_index(
+    pool: &SqlitePool,
+    public_key: String,
+    trade_index: i64,
+) -> anyhow::Result<bool> {
+    // Validate public key format (32-bytes hex)
+    if !public_key.chars().all(|c| c.is_ascii_hexdigit()) || public_key.len() != 64 {
+        return Err(anyhow::anyhow!("Invalid public key format"));
+    }
+    // Validate trade_index
+    if trade_index < 0 {
+        return Err(anyhow::anyhow!("Invalid trade_index: must be non-negative"));
+    }
+
+    let mut conn = pool.acquire().await?;
+
+    let rows_affected = sqlx::query!(
+        r#"
+            UPDATE users SET last_trade_index = ?1 WHERE pubkey = ?2
+        "#,
+        trade_index,
+        public_key,
+    )
+    .execute(&mut conn)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Claim the rating of an order by `rater_pubkey`, false if it was already
+/// rated so each party rates each order once
+pub async fn claim_order_rating(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    rater_pubkey: &str,
+    now: i64,
+) -> anyhow::Result<bool> {
+    let rows_affected = sqlx::query(
+        "INSERT OR IGNORE INTO order_ratings (order_id, rater_pubkey, created_at) VALUES (?1, ?2, ?3)",
+    )
+    .bind(order_id)
+    .bind(rater_pubkey)
+    .bind(now)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected > 0)
+}
+
+/// Give back a rating claim whose rating could not be applied
+pub async fn release_order_rating(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    rater_pubkey: &str,
+) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM order_ratings WHERE order_id = ?1 AND rater_pubkey = ?2")
+        .bind(order_id)
+        .bind(rater_pubkey)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Add a new rating to a user in a single statement, concurrent ratings
+/// of the same user are applied one after the other and none is lost.
+/// The rating moves the user mean proportionally to `weight` (0 to 1) and
+/// inversely to the decayed count of the previous ratings
+pub async fn add_user_rating(
+    pool: &SqlitePool,
+    public_key: String,
+    rating: i64,
+    weight: f64,
+    now: i64,
+    half_life_days: u32,
+) -> anyhow::Result<User> {
+    // Validate public key format (32-bytes hex)
+    if !public_key.chars().all(|c| c.is_ascii_hexdigit()) || public_key.len() != 64 {
+        return Err(anyhow::anyhow!("Invalid public key format"));
+    }
+    // Validate rating value
+    if MIN_RATING as i64 > rating || rating > MAX_RATING as i64 {
+        return Err(anyhow::anyhow!("Invalid rating value"));
+    }
+    // Previous ratings count less as time goes by since the last one, the
+    // last rating time is read in the same transaction as the update so a
+    // concurrent rating can't change it in between
+    let mut tx = pool.begin().await?;
+    let decay = if half_life_days > 0 {
+        let last_rating_at: Option<i64> =
+            sqlx::query("SELECT last_rating_at FROM users WHERE pubkey = ?1")
+                .bind(&public_key)
+                .map(|row: SqliteRow| row.get(0))
+                .fetch_optional(&mut *tx)
+                .await?;
+        rating_decay(now - last_rating_at.unwrap_or(now), half_life_days)
+    } else {
+        1.0
+    };
+    let user = sqlx::query_as::<_, User>(
+        r#"
+            UPDATE users
+            SET
+            total_rating = CASE WHEN total_reviews <= 0 THEN ?1
+              ELSE total_rating + ?3 * (?1 - total_rating) / (rating_weight * ?4 + 1) END,
+            max_rating = CASE WHEN total_reviews <= 0 OR max_rating < ?1 THEN ?1 ELSE max_rating END,
+            min_rating = CASE WHEN total_reviews <= 0 OR min_rating > ?1 THEN ?1 ELSE min_rating END,
+            last_rating = ?1,
+            last_rating_at = ?5,
+            rating_weight = rating_weight * ?4 + 1,
+            total_reviews = total_reviews + 1
+            WHERE pubkey = ?2
+            RETURNING *
+        "#,
+    )
+    .bind(rating)
+    .bind(public_key)
+    .bind(weight)
+    .bind(decay)
+    .bind(now)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(user)
+}
+
+/// Check two pubkeys given in hex or bech32 are the same key, pubkeys are
+/// compared in their hex form and a malformed one never matches
+pub fn pubkeys_match(a: &str, b: &str) -> bool {
+    match (PublicKey::parse(a), PublicKey::parse(b)) {
+        (Ok(a), Ok(b)) => a.to_hex() == b.to_hex(),
+        _ => false,
+    }
+}
+
+pub async fn is_assigned_solver(
+    pool: &SqlitePool,
+    solver_pubkey: &str,
+    order_id: Uuid,
+) -> anyhow::Result<bool> {
+    tracing::debug!("Checking solver {solver_pubkey} of order {order_id}");
+    // Solver pubkeys are compared in hex whatever encoding they were stored in,
+    // the solvers who joined the dispute only count while it's in progress
+    let solvers: Vec<String> = sqlx::query(
+        r#"
+          SELECT solver_pubkey FROM disputes WHERE order_id = ?1 AND solver_pubkey IS NOT NULL
+          UNION
+          SELECT ds.solver_pubkey FROM dispute_solvers ds
+          JOIN disputes d ON d.order_id = ds.order_id
+          WHERE ds.order_id = ?1 AND d.status = ?2
+        "#,
+    )
+    .bind(order_id)
+    .bind(DisputeStatus::InProgress.to_string())
+    .map(|row: SqliteRow| row.get(0))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(solvers
+        .iter()
+        .any(|solver| pubkeys_match(solver, solver_pubkey)))
+}
+
+/// Assign one more solver to the dispute of an order
+pub async fn add_dispute_solver(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    solver_pubkey: &str,
+) -> anyhow::Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO dispute_solvers (order_id, solver_pubkey) VALUES (?1, ?2)")
+        .bind(order_id)
+        .bind(solver_pubkey)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Record the vote of a solver to resolve a dispute, a new vote replaces the
+/// previous one of the same solver, returns the number of votes for the same
+/// resolution
+pub async fn record_dispute_vote(
+    pool: &SqlitePool,
+    dispute_id: Uuid,
+    solver_pubkey: &str,
+    vote: &str,
+) -> anyhow::Result<usize> {
+    sqlx::query(
+        r#"
+          INSERT INTO dispute_votes (dispute_id, solver_pubkey, vote, voted_at)
+          VALUES (?1, ?2, ?3, ?4)
+          ON CONFLICT (dispute_id, solver_pubkey)
+          DO UPDATE SET vote = excluded.vote, voted_at = excluded.voted_at
+        "#,
+    )
+    .bind(dispute_id)
+    .bind(solver_pubkey)
+    .bind(vote)
+    .bind(Timestamp::now().as_u64() as i64)
+    .execute(pool)
+    .await?;
+
+    let votes: i64 =
+        sqlx::query("SELECT COUNT(*) FROM dispute_votes WHERE dispute_id = ?1 AND vote = ?2")
+            .bind(dispute_id)
+            .bind(vote)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_one(pool)
+            .await?;
+
+    Ok(votes as usize)
+}
+
+/// Forget the votes of a dispute once resolved
+pub async fn clear_dispute_votes(pool: &SqlitePool, dispute_id: Uuid) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM dispute_votes WHERE dispute_id = ?1")
+        .bind(dispute_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Get the solvers who took or joined the dispute of an order, only stored
+/// when the dispute needs a quorum of solvers
+pub async fn find_dispute_solvers(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> anyhow::Result<Vec<String>> {
+    let solvers = sqlx::query("SELECT solver_pubkey FROM dispute_solvers WHERE order_id = ?1")
+        .bind(order_id)
+        .map(|row: SqliteRow| row.get(0))
+        .fetch_all(pool)
+        .await?;
+
+    Ok(solvers)
+}
+
+/// Store evidence submitted by a party of a dispute
+pub async fn add_dispute_evidence(
+    pool: &SqlitePool,
+    dispute_id: Uuid,
+    sender_pubkey: &str,
+    content: &str,
+    created_at: i64,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+          INSERT INTO dispute_evidence (dispute_id, sender_pubkey, content, created_at)
+          VALUES (?1, ?2, ?3, ?4)
+        "#,
+    )
+    .bind(dispute_id)
+    .bind(sender_pubkey)
+    .bind(content)
+    .bind(created_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Get the evidence of a dispute as (sender pubkey, content, created at), oldest first
+pub async fn find_dispute_evidence(
+    pool: &SqlitePool,
+    dispute_id: Uuid,
+) -> anyhow::Result<Vec<(String, String, i64)>> {
+    let evidence = sqlx::query(
+        r#"
+          SELECT sender_pubkey, content, created_at
+          FROM dispute_evidence
+          WHERE dispute_id = ?1
+          ORDER BY id
+        "#,
+    )
+    .bind(dispute_id)
+    .map(|row: SqliteRow| (row.get(0), row.get(1), row.get(2)))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(evidence)
+}
+
+pub async fn find_order_by_id(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    user_pubkey: &str,
+) -> anyhow::Result<Order> {
+    let order = sqlx::query_as::<_, Order>(
+        r#"
+          SELECT *
+          FROM orders
+          WHERE id = ?1 AND (buyer_pubkey = ?2 OR seller_pubkey = ?2)
+        "#,
+    )
+    .bind(order_id)
+    .bind(user_pubkey)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx_crud::Crud;
+
+    #[tokio::test]
+    async fn test_shared_pool_opened_once() {
+        let cell = OnceCell::new();
+        let opened = &std::sync::atomic::AtomicUsize::new(0);
+        let open = move || async move {
+            opened.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(connect_test_db().await.0)
+        };
+        // Payments and jobs get the pool opened the first time
+        for _ in 0..3 {
+            shared_pool(&cell, open).await.unwrap();
+        }
+        assert_eq!(opened.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connect_pool_applies_settings() {
+        let db = TestDbFile::new();
+        let db_settings = Database {
+            max_connections: 3,
+            busy_timeout_seconds: 7,
+            ..Default::default()
+        };
+        let pool = connect_pool(&db.url(), &db_settings).await.unwrap();
+
+        assert_eq!(pool.options().get_max_connections(), 3);
+        let journal_mode: String = sqlx::query("PRAGMA journal_mode")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(journal_mode, "wal");
+        let busy_timeout: i64 = sqlx::query("PRAGMA busy_timeout")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(busy_timeout, 7_000);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_journal_mode_rejected() {
+        let db_settings = Database {
+            journal_mode: "fast".to_string(),
+            ..Default::default()
+        };
+        assert!(connect_pool("sqlite://unused.db", &db_settings)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_user_rating() {
+        let (pool, _db) = connect_test_db().await;
+        let pubkey = Keys::generate().public_key().to_hex();
+        let user = User {
+            pubkey: pubkey.clone(),
+            ..Default::default()
+        };
+        add_new_user(&pool, user).await.unwrap();
+
+        let user = add_user_rating(&pool, pubkey.clone(), 4, 1.0, 0, 0)
+            .await
+            .unwrap();
+        assert_eq!(user.total_reviews, 1);
+        assert_eq!(user.total_rating, 4.0);
+        assert_eq!((user.min_rating, user.max_rating), (4, 4));
+
+        let user = add_user_rating(&pool, pubkey, 2, 1.0, 0, 0).await.unwrap();
+        assert_eq!(user.total_reviews, 2);
+        assert_eq!(user.total_rating, 3.0);
+        assert_eq!(user.last_rating, 2);
+        assert_eq!((user.min_rating, user.max_rating), (2, 4));
+    }
+
+    #[tokio::test]
+    async fn test_order_rated_once() {
+        let (pool, _db) = connect_test_db().await;
+        let pubkey = Keys::generate().public_key().to_hex();
+        add_new_user(
+            &pool,
+            User {
+                pubkey: pubkey.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let order_id = Uuid::new_v4();
+
+        // Ratings are applied only when the claim succeeds, as rate_user does
+        for rating in [5, 1] {
+            if claim_order_rating(&pool, order_id, "rater", 0)
+                .await
+                .unwrap()
+            {
+                add_user_rating(&pool, pubkey.clone(), rating, 1.0, 0, 0)
+                    .await
+                    .unwrap();
+            }
+        }
+        let user = is_user_present(&pool, pubkey.clone()).await.unwrap();
+        assert_eq!(user.total_reviews, 1);
+        assert_eq!(user.total_rating, 5.0);
+
+        // The counterparty rates the same order once too
+        assert!(claim_order_rating(&pool, order_id, "counterparty", 0)
+            .await
+            .unwrap());
+        assert!(!claim_order_rating(&pool, order_id, "counterparty", 0)
+            .await
+            .unwrap());
+        // A failed rating can be sent again
+        release_order_rating(&pool, order_id, "counterparty")
+            .await
+            .unwrap();
+        assert!(claim_order_rating(&pool, order_id, "counterparty", 0)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_old_ratings_decay() {
+        let (pool, _db) = connect_test_db().await;
+        let pubkey = Keys::generate().public_key().to_hex();
+        let user = User {
+            pubkey: pubkey.clone(),
+            ..Default::default()
+        };
+        add_new_user(&pool, user).await.unwrap();
+
+        let now = 1_700_000_000;
+        add_user_rating(&pool, pubkey.clone(), 4, 1.0, now, 30)
+            .await
+            .unwrap();
+        // The rating of 30 days ago counts half of the new one
+        let user = add_user_rating(&pool, pubkey.clone(), 1, 1.0, now + 30 * 86400, 30)
+            .await
+            .unwrap();
+        assert_eq!(user.total_reviews, 2);
+        assert!((user.total_rating - 2.0).abs() < 1e-9);
+
+        // The old rating keeps its decayed weight in the next ones
+        let user = add_user_rating(&pool, pubkey.clone(), 5, 1.0, now + 30 * 86400, 30)
+            .await
+            .unwrap();
+        assert_eq!(user.total_reviews, 3);
+        assert!((user.total_rating - 3.2).abs() < 1e-9);
+        let rating_weight: f64 = sqlx::query("SELECT rating_weight FROM users WHERE pubkey = ?1")
+            .bind(&pubkey)
+            .map(|row: SqliteRow| row.get(0))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!((rating_weight - 2.5).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_ratings_are_not_lost() {
+        let (pool, _db) = connect_test_db().await;
+        let pubkey = Keys::generate().public_key().to_hex();
+        let user = User {
+            pubkey: pubkey.clone(),
+            ..Default::default()
+        };
+        add_new_user(&pool, user).await.unwrap();
+
+        // Ratings from 1 to 5 sent at the same time, 4 times each
+        let tasks: Vec<_> = (0..20)
+            .map(|i| {
+                let pool = pool.clone();
+                let pubkey = pubkey.clone();
+                tokio::spawn(
+                    async move { add_user_rating(&pool, pubkey, i % 5 + 1, 1.0, 0, 0).await },
+                )
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        let user = is_user_present(&pool, pubkey).await.unwrap();
+        assert_eq!(user.total_reviews, 20);
+        assert!((user.total_rating - 3.0).abs() < 1e-9);
+        assert_eq!((user.min_rating, user.max_rating), (1, 5));
+    }
+
+    #[tokio::test]
+    async fn test_weighted_rating_delta() {
+        let (pool, _db) = connect_test_db().await;
+        let mut deltas = vec![];
+        for weight in [1.0, 0.2] {
+            let pubkey = Keys::generate().public_key().to_hex();
+            let user = User {
+                pubkey: pubkey.clone(),
+                ..Default::default()
+            };
+            add_new_user(&pool, user).await.unwrap();
+            add_user_rating(&pool, pubkey.clone(), 5, 1.0, 0, 0)
+                .await
+                .unwrap();
+            let user = add_user_rating(&pool, pubkey, 1, weight, 0, 0)
+                .await
+                .unwrap();
+            deltas.push(5.0 - user.total_rating);
+        }
+        // A rating from a high reputation rater moves the mean more
+        assert!((deltas[0] - 2.0).abs() < 1e-9);
+        assert!((deltas[1] - 0.4).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_get_locked_funds() {
+        let (pool, _db) = connect_test_db().await;
+        for (amount, status) in [
+            (10_000, Status::Pending),
+            (20_000, Status::Active),
+            (40_000, Status::Success),
+            (80_000, Status::Canceled),
+        ] {
+            let order = Order {
+                id: Uuid::new_v4(),
+                amount,
+                status: status.to_string(),
+                ..Default::default()
+            };
+            order.create(&pool).await.unwrap();
+        }
+        // Finished orders don't lock funds
+        assert_eq!(get_locked_funds(&pool, None).await.unwrap(), 30_000);
+    }
+
+    #[tokio::test]
+    async fn test_settled_order_recorded_before_event_update() {
+        let (pool, _db) = connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            hash: Some("hash".to_string()),
+            status: Status::FiatSent.to_string(),
+            ..Default::default()
+        };
+        let order = order.create(&pool).await.unwrap();
+        assert_eq!(find_unsettled_orders(&pool).await.unwrap().len(), 1);
+
+        // Hold invoice settled, the order event update fails afterwards
+        assert!(set_order_settled(&pool, order.id).await.unwrap());
+
+        let order = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(order.status, Status::SettledHoldInvoice.to_string());
+        assert!(find_unsettled_orders(&pool).await.unwrap().is_empty());
+        // Already recorded
+        assert!(!set_order_settled(&pool, order.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_status_changes_notified() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1000);
+        crate::webhook::WEBHOOK_QUEUE.set(tx).unwrap();
+        let (pool, _db) = connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: Status::Pending.to_string(),
+            ..Default::default()
+        };
+        let order = order.create(&pool).await.unwrap();
+
+        assert!(
+            transition_order_status(&pool, order.id, &Status::Pending, &Status::Active)
+                .await
+                .unwrap()
+        );
+        // Nothing changed, nothing notified
+        assert!(
+            !transition_order_status(&pool, order.id, &Status::Pending, &Status::Active)
+                .await
+                .unwrap()
+        );
+        assert!(set_order_settled(&pool, order.id).await.unwrap());
+        assert!(!set_order_settled(&pool, order.id).await.unwrap());
+
+        let mut changes = vec![];
+        while let Ok(change) = rx.try_recv() {
+            if change.order_id == order.id {
+                changes.push((change.old_status, change.new_status));
+            }
+        }
+        assert_eq!(
+            changes,
+            vec![
+                ("pending".to_string(), "active".to_string()),
+                ("active".to_string(), "settled-hold-invoice".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_receipt_confirmation() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        let buyer = Keys::generate().public_key().to_hex();
+        assert_eq!(
+            find_receipt_confirmation(&pool, order_id).await.unwrap(),
+            None
+        );
+
+        assert!(
+            add_receipt_confirmation(&pool, order_id, &buyer, "event", 1_700_000_000)
+                .await
+                .unwrap()
+        );
+        // Confirmed only once
+        assert!(
+            !add_receipt_confirmation(&pool, order_id, &buyer, "event", 1_700_000_100)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            find_receipt_confirmation(&pool, order_id).await.unwrap(),
+            Some(1_700_000_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unlisted_order_takeable_by_id() {
+        let (pool, _db) = connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: "pending".to_string(),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        let listed = Order {
+            id: Uuid::new_v4(),
+            status: "pending".to_string(),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        add_unlisted_order(&pool, order.id).await.unwrap();
+
+        assert!(is_order_unlisted(&pool, order.id).await.unwrap());
+        assert!(!is_order_unlisted(&pool, listed.id).await.unwrap());
+        // Unlisted orders are still found by id to be taken
+        let found = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(found.status, "pending");
+    }
+
+    #[tokio::test]
+    async fn test_dispute_rounds() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        assert_eq!(
+            find_dispute_rounds(&pool, order_id).await.unwrap(),
+            (0, false)
+        );
+
+        Dispute::new(order_id).create(&pool).await.unwrap();
+        assert_eq!(
+            find_dispute_rounds(&pool, order_id).await.unwrap(),
+            (1, true)
+        );
+
+        resolve_open_dispute(&pool, order_id, DisputeStatus::Settled)
+            .await
+            .unwrap();
+        assert_eq!(
+            find_dispute_rounds(&pool, order_id).await.unwrap(),
+            (1, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_resolved_dispute_evidence() {
+        let (pool, _db) = connect_test_db().await;
+        let now = 1_700_000_000;
+        let retention = 30 * 86400;
+        let buyer = Keys::generate().public_key().to_hex();
+
+        // Resolved dispute with evidence past retention
+        let old_resolved = Uuid::new_v4();
+        Dispute::new(old_resolved).create(&pool).await.unwrap();
+        resolve_open_dispute(&pool, old_resolved, DisputeStatus::Settled)
+            .await
+            .unwrap();
+        add_receipt_confirmation(&pool, old_resolved, &buyer, "event", now - retention - 1)
+            .await
+            .unwrap();
+        let old_dispute = find_dispute_by_order_id(&pool, old_resolved).await.unwrap();
+        add_dispute_evidence(&pool, old_dispute.id, &buyer, "paid", now - retention - 1)
+            .await
+            .unwrap();
+        // Resolved dispute with recent evidence
+        let recent_resolved = Uuid::new_v4();
+        Dispute::new(recent_resolved).create(&pool).await.unwrap();
+        resolve_open_dispute(&pool, recent_resolved, DisputeStatus::Settled)
+            .await
+            .unwrap();
+        add_receipt_confirmation(&pool, recent_resolved, &buyer, "event", now - 60)
+            .await
+            .unwrap();
+        // Open dispute with old evidence
+        let open = Uuid::new_v4();
+        Dispute::new(open).create(&pool).await.unwrap();
+        add_receipt_confirmation(&pool, open, &buyer, "event", now - retention - 1)
+            .await
+            .unwrap();
+        let open_dispute = find_dispute_by_order_id(&pool, open).await.unwrap();
+        add_dispute_evidence(&pool, open_dispute.id, &buyer, "paid", now - retention - 1)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            purge_resolved_dispute_evidence(&pool, now - retention)
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(find_dispute_evidence(&pool, old_dispute.id)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            find_dispute_evidence(&pool, open_dispute.id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            find_receipt_confirmation(&pool, old_resolved)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(find_receipt_confirmation(&pool, recent_resolved)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(find_receipt_confirmation(&pool, open)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_close_resolved_disputes() {
+        let (pool, _db) = connect_test_db().await;
+        let now = 1_700_000_000;
+        let delay = 86400;
+        let buyer = Keys::generate().public_key().to_hex();
+
+        let resolved = Uuid::new_v4();
+        let dispute = Dispute::new(resolved).create(&pool).await.unwrap();
+        add_dispute_evidence(&pool, dispute.id, &buyer, "paid", now)
+            .await
+            .unwrap();
+        resolve_open_dispute(&pool, resolved, DisputeStatus::Settled)
+            .await
+            .unwrap();
+        let open = Uuid::new_v4();
+        Dispute::new(open).create(&pool).await.unwrap();
+
+        // Resolved now, closed after the delay
+        assert_eq!(close_resolved_disputes(&pool, now, delay).await.unwrap(), 0);
+        assert!(find_dispute_by_order_id(&pool, resolved).await.is_ok());
+        assert_eq!(
+            close_resolved_disputes(&pool, now + delay - 1, delay)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            close_resolved_disputes(&pool, now + delay, delay)
+                .await
+                .unwrap(),
+            1
+        );
+
+        // Archived with its evidence, the open dispute is kept
+        assert!(find_dispute_by_order_id(&pool, resolved).await.is_err());
+        assert!(find_dispute_evidence(&pool, dispute.id)
+            .await
+            .unwrap()
+            .is_empty());
+        let (archived, evidence): (i64, i64) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM disputes_archive), (SELECT COUNT(*) FROM dispute_evidence_archive)",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!((archived, evidence), (1, 1));
+        assert!(find_dispute_by_order_id(&pool, open).await.is_ok());
+        // Archived disputes still count as rounds of the order
+        assert_eq!(
+            find_dispute_rounds(&pool, resolved).await.unwrap(),
+            (1, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_event_processed_once() {
+        let (pool, db) = connect_test_db().await;
+        let now = 1_700_000_000;
+
+        // Same release delivered twice, the payment is attempted once
+        let mut payments = 0;
+        for _ in 0..2 {
+            if mark_event_processed(&pool, "release", now).await.unwrap() {
+                payments += 1;
+            }
+        }
+        assert_eq!(payments, 1);
+
+        // Still processed after a restart
+        pool.close().await;
+        let pool = SqlitePool::connect(&db.url()).await.unwrap();
+        assert!(is_event_processed(&pool, "release").await.unwrap());
+        assert!(!mark_event_processed(&pool, "release", now + 5)
+            .await
+            .unwrap());
+
+        assert_eq!(purge_processed_events(&pool, now + 1).await.unwrap(), 1);
+        assert!(!is_event_processed(&pool, "release").await.unwrap());
+        assert!(mark_event_processed(&pool, "release", now + 5)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_list_orders_filtered() {
+        let (pool, _db) = connect_test_db().await;
+        let buyer = "buyer".to_string();
+        for (i, (status, fiat_code)) in [
+            (Status::Success, "USD"),
+            (Status::Success, "EUR"),
+            (Status::Canceled, "USD"),
+            (Status::Success, "USD"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            Order {
+                id: Uuid::new_v4(),
+                status: status.to_string(),
+                fiat_code: fiat_code.to_string(),
+                buyer_pubkey: (i < 2).then(|| buyer.clone()),
+                created_at: 1_000 + i as i64,
+                ..Default::default()
+            }
+            .create(&pool)
+            .await
+            .unwrap();
+        }
+
+        let filter = OrderFilter {
+            status: Some(Status::Success),
+            fiat_code: Some("USD".to_string()),
+            ..Default::default()
+        };
+        let page = list_orders(&pool, &filter, 10, 0).await.unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.orders[0].created_at, 1_003);
+
+        let filter = OrderFilter {
+            pubkey: Some(buyer),
+            ..Default::default()
+        };
+        assert_eq!(list_orders(&pool, &filter, 10, 0).await.unwrap().total, 2);
+
+        let filter = OrderFilter {
+            from: Some(1_001),
+            to: Some(1_003),
+            ..Default::default()
+        };
+        let page = list_orders(&pool, &filter, 10, 0).await.unwrap();
+        assert_eq!(page.total, 2);
+
+        // Second page of one order, the total is still the whole history
+        let page = list_orders(&pool, &OrderFilter::default(), 1, 1)
+            .await
+            .unwrap();
+        assert_eq!((page.orders.len(), page.total), (1, 4));
+        assert_eq!(page.orders[0].created_at, 1_002);
+        // Values are bound, never part of the query
+        let filter = OrderFilter {
+            fiat_code: Some("USD' OR '1'='1".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(list_orders(&pool, &filter, 10, 0).await.unwrap().total, 0);
+    }
+
+    #[tokio::test]
+    async fn test_backup_rotation() {
+        let (pool, _db) = connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        };
+        order.create(&pool).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("mostro-backups-{}", Uuid::new_v4()));
+
+        for now in 1..=4 {
+            let (path, size) = backup_database(&pool, &dir, now).await.unwrap();
+            assert!(size > 0);
+            assert!(path.ends_with(format!("mostro-{now}.db")));
+        }
+        // Other files are never removed
+        std::fs::write(dir.join("notes.txt"), "keep").unwrap();
+        assert_eq!(rotate_backups(&dir, 2).unwrap(), 2);
+        assert!(!dir.join("mostro-2.db").exists());
+        assert!(dir.join("mostro-3.db").exists());
+        assert!(dir.join("notes.txt").exists());
+
+        // The backup is a working database
+        let backup =
+            SqlitePool::connect(&format!("sqlite://{}", dir.join("mostro-4.db").display()))
+                .await
+                .unwrap();
+        assert!(Order::by_id(&backup, order.id).await.unwrap().is_some());
+        backup.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_order_take_pow() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        assert_eq!(find_order_take_pow(&pool, order_id).await.unwrap(), None);
+        set_order_take_pow(&pool, order_id, 12).await.unwrap();
+        assert_eq!(
+            find_order_take_pow(&pool, order_id).await.unwrap(),
+            Some(12)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payment_retry_backoff_persisted() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        assert!(is_payment_retry_due(&pool, order_id, 100).await.unwrap());
+        set_payment_retry(&pool, order_id, 200).await.unwrap();
+        assert!(!is_payment_retry_due(&pool, order_id, 199).await.unwrap());
+        assert!(is_payment_retry_due(&pool, order_id, 200).await.unwrap());
+        // Rescheduled after another failure
+        set_payment_retry(&pool, order_id, 400).await.unwrap();
+        assert!(!is_payment_retry_due(&pool, order_id, 300).await.unwrap());
+        clear_payment_retry(&pool, order_id).await.unwrap();
+        assert!(is_payment_retry_due(&pool, order_id, 300).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_order_options() {
+        let (pool, _db) = connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            created_at: 100,
+            ..Default::default()
+        };
+        assert_eq!(
+            find_order_options(&pool, order.id).await.unwrap(),
+            OrderOptions::default()
+        );
+        let options = OrderOptions {
+            unlisted: true,
+            take_pow: Some(12),
+            requires_nip05: true,
+            quarantine: Some("off-market price".to_string()),
+        };
+        set_order_options(&pool, &order, &options).await.unwrap();
+        assert_eq!(find_order_options(&pool, order.id).await.unwrap(), options);
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_takes_single_winner() {
+        let (pool, _db) = connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: Status::Pending.to_string(),
+            ..Default::default()
+        };
+        let order = order.create(&pool).await.unwrap();
+
+        let take = |to: Status| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                transition_order_status(&pool, order.id, &Status::Pending, &to).await
+            })
+        };
+        let (first, second) = tokio::join!(
+            take(Status::WaitingBuyerInvoice),
+            take(Status::WaitingPayment)
+        );
+        let (first, second) = (first.unwrap().unwrap(), second.unwrap().unwrap());
+        assert!(first ^ second);
+
+        let order = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        let winner = if first {
+            Status::WaitingBuyerInvoice
+        } else {
+            Status::WaitingPayment
+        };
+        assert_eq!(order.status, winner.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_find_expired_orders() {
+        let (pool, _db) = connect_test_db().await;
+        let now = Timestamp::now().as_u64() as i64;
+        let mut expired = HashSet::new();
+        for (expires_at, status) in [
+            (now - 60, Status::Pending),
+            (now - 60, Status::WaitingBuyerInvoice),
+            (now - 60, Status::WaitingPayment),
+            (now - 60, Status::Active),
+            (now + 3600, Status::WaitingPayment),
+        ] {
+            let order = Order {
+                id: Uuid::new_v4(),
+                expires_at,
+                status: status.to_string(),
+                ..Default::default()
+            };
+            let order = order.create(&pool).await.unwrap();
+            if expires_at < now && status != Status::Active {
+                expired.insert(order.id);
+            }
+        }
+
+        let orders = find_order_by_date(&pool).await.unwrap();
+        assert_eq!(
+            orders.into_iter().map(|o| o.id).collect::<HashSet<_>>(),
+            expired
+        );
+    }
+
+    #[tokio::test]
+    async fn test_count_orders_by_status() {
+        let (pool, _db) = connect_test_db().await;
+        for (status, hash) in [
+            (Status::Pending, None),
+            (Status::Active, Some("hash1")),
+            (Status::Active, Some("hash2")),
+            (Status::Active, None),
+        ] {
+            let order = Order {
+                id: Uuid::new_v4(),
+                status: status.to_string(),
+                hash: hash.map(str::to_string),
+                ..Default::default()
+            };
+            order.create(&pool).await.unwrap();
+        }
+        let mut counts = count_orders_by_status(&pool).await.unwrap();
+        counts.sort();
+        assert_eq!(
+            counts,
+            vec![
+                (Status::Active.to_string(), 3, 2),
+                (Status::Pending.to_string(), 1, 0)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispute_solver_votes() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        let mut dispute = Dispute::new(order_id);
+        dispute.status = DisputeStatus::InProgress.to_string();
+        let dispute = dispute.create(&pool).await.unwrap();
+        let solvers: Vec<String> = (0..3)
+            .map(|_| Keys::generate().public_key().to_hex())
+            .collect();
+        for solver in &solvers {
+            add_dispute_solver(&pool, order_id, solver).await.unwrap();
+            assert!(is_assigned_solver(&pool, solver, order_id).await.unwrap());
+        }
+        let other = Keys::generate().public_key().to_hex();
+        assert!(!is_assigned_solver(&pool, &other, order_id).await.unwrap());
+        // A solver stored in bech32 is the same key as its hex form
+        let npub_solver = Keys::generate().public_key();
+        add_dispute_solver(&pool, order_id, &npub_solver.to_bech32().unwrap())
+            .await
+            .unwrap();
+        assert!(is_assigned_solver(&pool, &npub_solver.to_hex(), order_id)
+            .await
+            .unwrap());
+
+        let votes = record_dispute_vote(&pool, dispute.id, &solvers[0], "settle").await;
+        assert_eq!(votes.unwrap(), 1);
+        // Voting twice counts once
+        let votes = record_dispute_vote(&pool, dispute.id, &solvers[0], "settle").await;
+        assert_eq!(votes.unwrap(), 1);
+        let votes = record_dispute_vote(&pool, dispute.id, &solvers[1], "cancel").await;
+        assert_eq!(votes.unwrap(), 1);
+        let votes = record_dispute_vote(&pool, dispute.id, &solvers[2], "settle").await;
+        assert_eq!(votes.unwrap(), 2);
+        // A solver changing the vote
+        let votes = record_dispute_vote(&pool, dispute.id, &solvers[0], "cancel").await;
+        assert_eq!(votes.unwrap(), 2);
+        // Votes are forgotten once the dispute is resolved
+        clear_dispute_votes(&pool, dispute.id).await.unwrap();
+        let votes = record_dispute_vote(&pool, dispute.id, &solvers[1], "cancel").await;
+        assert_eq!(votes.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_joined_solver_not_assigned_out_of_dispute() {
+        let (pool, _db) = connect_test_db().await;
+        let solver = Keys::generate().public_key().to_hex();
+        // Joined an order without a dispute
+        let order_id = Uuid::new_v4();
+        add_dispute_solver(&pool, order_id, &solver).await.unwrap();
+        assert!(!is_assigned_solver(&pool, &solver, order_id).await.unwrap());
+        // Joined a dispute no longer in progress
+        let mut dispute = Dispute::new(order_id);
+        dispute.status = DisputeStatus::Settled.to_string();
+        dispute.create(&pool).await.unwrap();
+        assert!(!is_assigned_solver(&pool, &solver, order_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_seller_fiat_confirmation() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        assert_eq!(
+            find_seller_fiat_confirmation(&pool, order_id)
+                .await
+                .unwrap(),
+            None
+        );
+        add_seller_fiat_confirmation(&pool, order_id, 100)
+            .await
+            .unwrap();
+        // The first acknowledgment is kept
+        add_seller_fiat_confirmation(&pool, order_id, 200)
+            .await
+            .unwrap();
+        assert_eq!(
+            find_seller_fiat_confirmation(&pool, order_id)
+                .await
+                .unwrap(),
+            Some(100)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispute_evidence() {
+        let (pool, _db) = connect_test_db().await;
+        let dispute_id = Uuid::new_v4();
+        let buyer = Keys::generate().public_key().to_hex();
+        let seller = Keys::generate().public_key().to_hex();
+        assert!(find_dispute_evidence(&pool, dispute_id)
+            .await
+            .unwrap()
+            .is_empty());
+        add_dispute_evidence(&pool, dispute_id, &buyer, "bank receipt", 100)
+            .await
+            .unwrap();
+        add_dispute_evidence(&pool, dispute_id, &seller, "no payment", 200)
+            .await
+            .unwrap();
+        add_dispute_evidence(&pool, Uuid::new_v4(), &seller, "other", 300)
+            .await
+            .unwrap();
+        assert_eq!(
+            find_dispute_evidence(&pool, dispute_id).await.unwrap(),
+            vec![
+                (buyer, "bank receipt".to_string(), 100),
+                (seller, "no payment".to_string(), 200)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_active_orders_pages() {
+        let (pool, _db) = connect_test_db().await;
+        for (i, (amount, premium)) in [(300, 1), (100, 5), (500, -2), (200, 3), (400, 0)]
+            .into_iter()
+            .enumerate()
+        {
+            let order = Order {
+                id: Uuid::new_v4(),
+                status: Status::Pending.to_string(),
+                amount,
+                premium,
+                created_at: 1_700_000_000 + i as i64,
+                ..Default::default()
+            };
+            order.create(&pool).await.unwrap();
+        }
+        let finished = Order {
+            id: Uuid::new_v4(),
+            status: Status::Success.to_string(),
+            amount: 1000,
+            ..Default::default()
+        };
+        finished.create(&pool).await.unwrap();
+        let quarantined = Order {
+            id: Uuid::new_v4(),
+            status: Status::Pending.to_string(),
+            amount: 2000,
+            ..Default::default()
+        };
+        quarantined.create(&pool).await.unwrap();
+        add_quarantined_order(&pool, quarantined.id, "off-market price", 100)
+            .await
+            .unwrap();
+
+        let amounts = |orders: Vec<Order>| orders.iter().map(|o| o.amount).collect::<Vec<_>>();
+        let page = find_active_orders_page(&pool, OrderSort::Amount, 0, 2).await;
+        assert_eq!(amounts(page.unwrap()), vec![500, 400]);
+        let page = find_active_orders_page(&pool, OrderSort::Amount, 1, 2).await;
+        assert_eq!(amounts(page.unwrap()), vec![300, 200]);
+        // Last page is not full
+        let page = find_active_orders_page(&pool, OrderSort::Amount, 2, 2).await;
+        assert_eq!(amounts(page.unwrap()), vec![100]);
+        let page = find_active_orders_page(&pool, OrderSort::Amount, 3, 2).await;
+        assert!(page.unwrap().is_empty());
+
+        let page = find_active_orders_page(&pool, OrderSort::Premium, 0, 5).await;
+        let premiums: Vec<i64> = page.unwrap().iter().map(|o| o.premium).collect();
+        assert_eq!(premiums, vec![5, 3, 1, 0, -2]);
+        let page = find_active_orders_page(&pool, OrderSort::Recent, 0, 5).await;
+        assert_eq!(amounts(page.unwrap()), vec![400, 200, 500, 100, 300]);
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_review_queue() {
+        let (pool, _db) = connect_test_db().await;
+        let flagged = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        add_quarantined_order(&pool, flagged, "off-market price", 100)
+            .await
+            .unwrap();
+        assert!(is_order_quarantined(&pool, flagged).await.unwrap());
+        assert!(!is_order_quarantined(&pool, other).await.unwrap());
+        assert_eq!(
+            find_quarantined_orders(&pool).await.unwrap(),
+            vec![QuarantinedOrder {
+                order_id: flagged,
+                reason: "off-market price".to_string(),
+                created_at: 100,
+            }]
+        );
+
+        // Reviewed once, approved or rejected
+        assert!(remove_quarantined_order(&pool, flagged).await.unwrap());
+        assert!(!remove_quarantined_order(&pool, flagged).await.unwrap());
+        assert!(!is_order_quarantined(&pool, flagged).await.unwrap());
+        assert!(find_quarantined_orders(&pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_quarantined_order_not_expired() {
+        let (pool, _db) = connect_test_db().await;
+        let expired = |id| Order {
+            id,
+            status: Status::Pending.to_string(),
+            expires_at: 100,
+            ..Default::default()
+        };
+        let published = expired(Uuid::new_v4()).create(&pool).await.unwrap();
+        let flagged = expired(Uuid::new_v4()).create(&pool).await.unwrap();
+        add_quarantined_order(&pool, flagged.id, "off-market price", 50)
+            .await
+            .unwrap();
+
+        // Orders waiting for review expire only once approved
+        let orders = find_order_by_date(&pool).await.unwrap();
+        assert_eq!(
+            orders.iter().map(|o| o.id).collect::<Vec<_>>(),
+            vec![published.id]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abort_settlement_within_cooldown() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        assert!(
+            add_pending_settlement(&pool, order_id, "admin", Some(7), 160)
+                .await
+                .unwrap()
+        );
+        // Scheduled once
+        assert!(!add_pending_settlement(&pool, order_id, "admin", None, 200)
+            .await
+            .unwrap());
+        assert!(find_due_settlements(&pool, 130).await.unwrap().is_empty());
+
+        assert!(abort_pending_settlement(&pool, order_id, 130)
+            .await
+            .unwrap());
+        // Never settled once aborted
+        assert!(find_due_settlements(&pool, 200).await.unwrap().is_empty());
+        assert!(!take_pending_settlement(&pool, order_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_abort_settlement_after_cooldown() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        add_pending_settlement(&pool, order_id, "admin", Some(7), 160)
+            .await
+            .unwrap();
+
+        assert!(!abort_pending_settlement(&pool, order_id, 160)
+            .await
+            .unwrap());
+        assert_eq!(
+            find_due_settlements(&pool, 160).await.unwrap(),
+            vec![PendingSettlement {
+                order_id,
+                admin_pubkey: "admin".to_string(),
+                request_id: Some(7),
+                settle_at: 160,
+            }]
+        );
+        // The settlement goes on, taken once
+        assert!(take_pending_settlement(&pool, order_id).await.unwrap());
+        assert!(!take_pending_settlement(&pool, order_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_seller_release_resolves_open_dispute() {
+        let (pool, _db) = connect_test_db().await;
+        let order_id = Uuid::new_v4();
+        let dispute = Dispute::new(order_id);
+        dispute.create(&pool).await.unwrap();
+
+        let dispute = resolve_open_dispute(&pool, order_id, DisputeStatus::Settled)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dispute.status, DisputeStatus::Settled.to_string());
+        let dispute = find_dispute_by_order_id(&pool, order_id).await.unwrap();
+        assert_eq!(dispute.status, DisputeStatus::Settled.to_string());
+
+        // Already resolved disputes are not changed
+        assert!(
+            resolve_open_dispute(&pool, order_id, DisputeStatus::SellerRefunded)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hold_invoice_reminders() {
+        let (pool, _db) = connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: "waiting-payment".to_string(),
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+        let expires_at = 1_700_000_900;
+        add_hold_invoice(&pool, order.id, "lnbcrt1", expires_at)
+            .await
+            .unwrap();
+
+        // Not yet within the reminder window
+        let now = expires_at - 600;
+        assert!(find_hold_invoice_reminders(&pool, now, 300)
+            .await
+            .unwrap()
+            .is_empty());
+        let now = expires_at - 200;
+        let reminders = find_hold_invoice_reminders(&pool, now, 300).await.unwrap();
+        assert_eq!(
+            reminders,
+            vec![HoldInvoiceReminder {
+                order_id: order.id,
+                payment_request: "lnbcrt1".to_string(),
+                expires_at,
+            }]
+        );
+
+        // Each hold invoice is reminded once
+        assert!(mark_hold_invoice_reminded(&pool, order.id, now)
+            .await
+            .unwrap());
+        assert!(!mark_hold_invoice_reminded(&pool, order.id, now)
+            .await
+            .unwrap());
+        assert!(find_hold_invoice_reminders(&pool, now, 300)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // A new take brings a new hold invoice to remind
+        add_hold_invoice(&pool, order.id, "lnbcrt2", expires_at + 900)
+            .await
+            .unwrap();
+        let now = expires_at + 700;
+        assert_eq!(
+            find_hold_invoice_reminders(&pool, now, 300).await.unwrap()[0].payment_request,
+            "lnbcrt2"
+        );
+    }
+
+    #[test]
+    fn test_pubkeys_match_across_encodings() {
+        let pubkey = Keys::generate().public_key();
+        let hex = pubkey.to_hex();
+        let npub = pubkey.to_bech32().unwrap();
+        assert!(pubkeys_match(&hex, &npub));
+        assert!(pubkeys_match(&npub, &hex));
+        assert!(pubkeys_match(&hex, &hex));
+        let other = Keys::generate().public_key().to_bech32().unwrap();
+        assert!(!pubkeys_match(&hex, &other));
+        // Malformed keys never match, not even themselves
+        assert!(!pubkeys_match("npub1wrong", "npub1wrong"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_payment_claimed_once() {
+        let (pool, _db) = connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: Status::SettledHoldInvoice.to_string(),
+            buyer_invoice: Some("lnbcrt1".to_string()),
+            failed_payment: true,
+            payment_attempts: 2,
+            ..Default::default()
+        }
+        .create(&pool)
+        .await
+        .unwrap();
+
+        // The buyer sends a new invoice while the retries job runs
+        assert!(claim_failed_payment(&pool, order.id, Some("lnbcrt2"))
+            .await
+            .unwrap());
+        assert!(!claim_failed_payment(&pool, order.id, None).await.unwrap());
+        let claimed = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert!(!claimed.failed_payment);
+        assert_eq!(claimed.buyer_invoice.as_deref(), Some("lnbcrt2"));
+        assert_eq!(claimed.payment_attempts, 0);
+
+        // Failed again, the retries job keeps the invoice and attempts
+        let mut failed = claimed;
+        failed.failed_payment = true;
+        failed.payment_attempts = 1;
+        failed.update(&pool).await.unwrap();
+        assert!(claim_failed_payment(&pool, order.id, None).await.unwrap());
+        let claimed = Order::by_id(&pool, order.id).await.unwrap().unwrap();
+        assert_eq!(claimed.buyer_invoice.as_deref(), Some("lnbcrt2"));
+        assert_eq!(claimed.payment_attempts, 1);
+    }
 }
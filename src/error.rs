@@ -1,3 +1,4 @@
+use mostro_core::message::CantDoReason;
 use std::fmt;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -17,6 +18,9 @@ pub enum MostroError {
     LnPaymentError(String),
     LnNodeError(String),
     InvalidOrderKind,
+    CantDo(CantDoReason),
+    /// Failure of Mostro itself, e.g. its database or the relays
+    Internal(String),
 }
 
 impl std::error::Error for MostroError {}
@@ -39,6 +43,37 @@ impl fmt::Display for MostroError {
             MostroError::LnPaymentError(e) => write!(f, "Lightning payment failure cause: {}",e),
             MostroError::LnNodeError(e) => write!(f, "Lightning node connection failure caused by: {}",e),
             MostroError::InvalidOrderKind => write!(f, "Invalid order kind"),
+            MostroError::CantDo(reason) => write!(f, "Action not allowed: {:?}", reason),
+            MostroError::Internal(e) => write!(f, "Internal error: {}", e),
+        }
+    }
+}
+
+impl MostroError {
+    /// Reason sent to the user in the `CantDo` message when an action fails
+    /// with this error. Failures of Mostro itself, its lightning node or the
+    /// price API are not caused by the request, the user gets a `CantDo`
+    /// without reason and can retry the action later
+    pub fn cant_do_reason(&self) -> Option<CantDoReason> {
+        match self {
+            MostroError::ParsingInvoiceError
+            | MostroError::InvoiceExpiredError
+            | MostroError::MinExpirationTimeError
+            | MostroError::LnAddressParseError
+            | MostroError::LnPaymentError(_) => Some(CantDoReason::InvalidInvoice),
+            MostroError::MinAmountError
+            | MostroError::WrongAmountError
+            | MostroError::NegativeAmount
+            | MostroError::LnAddressWrongAmount => Some(CantDoReason::InvalidAmount),
+            MostroError::ParsingNumberError | MostroError::NoCurrency => {
+                Some(CantDoReason::InvalidParameters)
+            }
+            MostroError::InvalidOrderKind => Some(CantDoReason::InvalidOrderKind),
+            MostroError::CantDo(reason) => Some(reason.clone()),
+            MostroError::NoAPIResponse
+            | MostroError::MalformedAPIRes
+            | MostroError::LnNodeError(_)
+            | MostroError::Internal(_) => None,
         }
     }
 }
@@ -66,3 +101,122 @@ impl From<reqwest::Error> for MostroError {
         MostroError::NoAPIResponse
     }
 }
+
+impl From<anyhow::Error> for MostroError {
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast::<MostroError>()
+            .unwrap_or_else(|e| MostroError::Internal(e.to_string()))
+    }
+}
+
+impl From<sqlx::Error> for MostroError {
+    fn from(e: sqlx::Error) -> Self {
+        MostroError::Internal(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for MostroError {
+    fn from(e: serde_json::Error) -> Self {
+        MostroError::Internal(e.to_string())
+    }
+}
+
+impl From<nostr_sdk::nostr::key::Error> for MostroError {
+    fn from(e: nostr_sdk::nostr::key::Error) -> Self {
+        MostroError::Internal(e.to_string())
+    }
+}
+
+impl From<nostr_sdk::nostr::event::builder::Error> for MostroError {
+    fn from(e: nostr_sdk::nostr::event::builder::Error) -> Self {
+        MostroError::Internal(e.to_string())
+    }
+}
+
+impl From<uuid::Error> for MostroError {
+    fn from(e: uuid::Error) -> Self {
+        MostroError::Internal(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cant_do_reason_mapping() {
+        let table = [
+            (
+                MostroError::ParsingInvoiceError,
+                Some(CantDoReason::InvalidInvoice),
+            ),
+            (
+                MostroError::InvoiceExpiredError,
+                Some(CantDoReason::InvalidInvoice),
+            ),
+            (
+                MostroError::MinExpirationTimeError,
+                Some(CantDoReason::InvalidInvoice),
+            ),
+            (
+                MostroError::LnAddressParseError,
+                Some(CantDoReason::InvalidInvoice),
+            ),
+            (
+                MostroError::LnPaymentError("no route".to_string()),
+                Some(CantDoReason::InvalidInvoice),
+            ),
+            (
+                MostroError::MinAmountError,
+                Some(CantDoReason::InvalidAmount),
+            ),
+            (
+                MostroError::WrongAmountError,
+                Some(CantDoReason::InvalidAmount),
+            ),
+            (
+                MostroError::NegativeAmount,
+                Some(CantDoReason::InvalidAmount),
+            ),
+            (
+                MostroError::LnAddressWrongAmount,
+                Some(CantDoReason::InvalidAmount),
+            ),
+            (
+                MostroError::ParsingNumberError,
+                Some(CantDoReason::InvalidParameters),
+            ),
+            (
+                MostroError::NoCurrency,
+                Some(CantDoReason::InvalidParameters),
+            ),
+            (
+                MostroError::InvalidOrderKind,
+                Some(CantDoReason::InvalidOrderKind),
+            ),
+            (
+                MostroError::CantDo(CantDoReason::IsNotYourDispute),
+                Some(CantDoReason::IsNotYourDispute),
+            ),
+            // Failures of Mostro itself are not blamed on the request
+            (MostroError::NoAPIResponse, None),
+            (MostroError::MalformedAPIRes, None),
+            (MostroError::LnNodeError("unavailable".to_string()), None),
+            (MostroError::Internal("database locked".to_string()), None),
+        ];
+        for (error, reason) in table {
+            assert_eq!(error.cant_do_reason(), reason, "{error}");
+        }
+    }
+
+    #[test]
+    fn test_handler_errors_converted() {
+        let error = anyhow::Error::from(MostroError::InvoiceExpiredError);
+        assert_eq!(MostroError::from(error), MostroError::InvoiceExpiredError);
+        let error = anyhow::anyhow!("database locked");
+        assert_eq!(
+            MostroError::from(error),
+            MostroError::Internal("database locked".to_string())
+        );
+    }
+}
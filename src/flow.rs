@@ -110,7 +110,9 @@ pub async fn hold_invoice_paid(hash: &str, request_id: Option<u64>) -> Result<()
     }
     // We publish a new replaceable kind nostr event with the status updated
     // and update on local database the status and new event id
-    if let Ok(updated_order) = crate::util::update_order_event(&my_keys, status, &order).await {
+    if let Ok(updated_order) =
+        crate::util::update_order_event(&my_keys, status, &order, &pool).await
+    {
         // Update order on db
         let _ = updated_order.update(&pool).await;
     }
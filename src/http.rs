@@ -0,0 +1,311 @@
+//! HTTP listener shared by the node metrics and the REST API, each distinct
+//! address set on settings is bound once and serves the enabled services
+//! set on it
+
+use crate::cli::settings::{Mostro, Settings};
+use crate::metrics;
+#[cfg(feature = "rest-api")]
+use crate::rest_api;
+
+use anyhow::Result;
+use sqlx::SqlitePool;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info};
+
+/// Max size of a request, only GET requests without body are served
+pub const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Services served on a listener
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Services {
+    metrics: bool,
+    #[cfg_attr(not(feature = "rest-api"), allow(dead_code))]
+    rest_api: bool,
+}
+
+/// Response to a request, sent with `Connection: close`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: String) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn to_http(&self) -> String {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        };
+        format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+    }
+}
+
+/// Addresses to bind with the services on each of them, the metrics and the
+/// REST API share the listener when both are set on the same address
+fn listeners(mostro_settings: &Mostro) -> Vec<(String, Services)> {
+    let mut listeners: Vec<(String, Services)> = Vec::new();
+    let mut add = |address: &str, enable: fn(&mut Services)| match listeners
+        .iter_mut()
+        .find(|(a, _)| a == address)
+    {
+        Some((_, services)) => enable(services),
+        None => {
+            let mut services = Services::default();
+            enable(&mut services);
+            listeners.push((address.to_string(), services));
+        }
+    };
+    if mostro_settings.metrics_enabled {
+        add(&mostro_settings.metrics_address, |s| s.metrics = true);
+    }
+    if cfg!(feature = "rest-api") && mostro_settings.rest_api_enabled {
+        add(&mostro_settings.rest_api_address, |s| s.rest_api = true);
+    }
+    listeners
+}
+
+/// Read a request up to the end of its headers, the request can arrive in
+/// several reads. Returns `None` when the connection closes before the headers
+/// end or they don't fit in `MAX_REQUEST_SIZE`
+async fn read_request<R: AsyncRead + Unpin>(socket: &mut R) -> std::io::Result<Option<String>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+/// Method and path of the request line
+pub fn request_line(request: &str) -> (&str, &str) {
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    (method, path)
+}
+
+async fn route(request: &str, pool: &SqlitePool, services: Services) -> Response {
+    let (_, path) = request_line(request);
+    if services.metrics && path == "/metrics" {
+        return metrics::handle_request(request, pool).await;
+    }
+    #[cfg(feature = "rest-api")]
+    if services.rest_api {
+        let mostro_settings = Settings::get_mostro();
+        return rest_api::handle_request(
+            request,
+            pool,
+            &mostro_settings.rest_api_token,
+            mostro_settings.rest_api_page_size,
+        )
+        .await;
+    }
+    Response::json(404, r#"{"error":"not found"}"#.to_string())
+}
+
+async fn handle_connection(mut socket: TcpStream, pool: SqlitePool, services: Services) {
+    let response = match read_request(&mut socket).await {
+        Ok(Some(request)) => route(&request, &pool, services).await,
+        Ok(None) => Response::json(400, r#"{"error":"bad request"}"#.to_string()),
+        Err(e) => return error!("HTTP: {e}"),
+    };
+    if let Err(e) = socket.write_all(response.to_http().as_bytes()).await {
+        error!("HTTP: {e}");
+    }
+}
+
+/// Start a listener on each address of the enabled services
+pub async fn start_http(pool: SqlitePool) -> Result<()> {
+    let mostro_settings = Settings::get_mostro();
+    if mostro_settings.rest_api_enabled && mostro_settings.rest_api_token.is_empty() {
+        error!("REST API token not set, all requests will be rejected");
+    }
+    for (address, services) in listeners(&mostro_settings) {
+        let listener = TcpListener::bind(&address).await?;
+        info!("HTTP listening on {address}, {services:?}");
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, _)) => {
+                        tokio::spawn(handle_connection(socket, pool.clone(), services));
+                    }
+                    Err(e) => error!("HTTP: {e}"),
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::net::SocketAddr;
+
+    /// Value of the header `name` of a request, whatever its case
+    pub fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request
+            .split("\r\n\r\n")
+            .next()?
+            .lines()
+            .skip(1)
+            .find_map(|line| {
+                let (header, value) = line.split_once(':')?;
+                header.eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+    }
+
+    /// Read a whole request, body included, headers and body may come in
+    /// separate packets
+    async fn read_whole_request(socket: &mut TcpStream) -> String {
+        let mut request = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            let Some(headers_end) = text.find("\r\n\r\n") else {
+                if n == 0 {
+                    return text;
+                }
+                continue;
+            };
+            let content_length = header(&text, "content-length")
+                .and_then(|length| length.parse::<usize>().ok())
+                .unwrap_or(0);
+            if n == 0 || request.len() >= headers_end + 4 + content_length {
+                return text;
+            }
+        }
+    }
+
+    /// Start a server answering each request with the response of `handler`,
+    /// for the tests of the HTTP clients of the node. Returns its address
+    pub async fn mock_server<F, Fut>(handler: F) -> SocketAddr
+    where
+        F: Fn(String) -> Fut + Send + 'static,
+        Fut: Future<Output = Response> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let request = read_whole_request(&mut socket).await;
+                let response = handler(request).await;
+                let _ = socket.write_all(response.to_http().as_bytes()).await;
+            }
+        });
+
+        addr
+    }
+
+    fn settings(metrics_address: &str, rest_api_address: &str) -> Mostro {
+        Mostro {
+            metrics_enabled: true,
+            metrics_address: metrics_address.to_string(),
+            rest_api_enabled: true,
+            rest_api_address: rest_api_address.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_shared_address_bound_once() {
+        let bound = listeners(&settings("127.0.0.1:8080", "127.0.0.1:8080"));
+        assert_eq!(bound.len(), 1);
+        assert!(bound[0].1.metrics);
+        assert_eq!(bound[0].1.rest_api, cfg!(feature = "rest-api"));
+    }
+
+    #[test]
+    fn test_listener_per_address() {
+        let bound = listeners(&settings("127.0.0.1:9090", "127.0.0.1:8080"));
+        assert_eq!(
+            bound[0],
+            (
+                "127.0.0.1:9090".to_string(),
+                Services {
+                    metrics: true,
+                    rest_api: false
+                }
+            )
+        );
+        assert_eq!(bound.len(), if cfg!(feature = "rest-api") { 2 } else { 1 });
+        // Nothing enabled, nothing bound
+        assert!(listeners(&Mostro::default()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_request_read_in_several_parts() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let reader = tokio::spawn(async move { read_request(&mut server).await });
+        client.write_all(b"GET /orders HTTP/1.1\r\n").await.unwrap();
+        tokio::task::yield_now().await;
+        client.write_all(b"Host: localhost\r\n\r\n").await.unwrap();
+        let request = reader.await.unwrap().unwrap().unwrap();
+        assert_eq!(request_line(&request), ("GET", "/orders"));
+    }
+
+    #[tokio::test]
+    async fn test_request_too_large() {
+        let mut request = b"GET /orders HTTP/1.1\r\n".to_vec();
+        request.extend(std::iter::repeat(b'a').take(MAX_REQUEST_SIZE));
+        assert!(read_request(&mut request.as_slice())
+            .await
+            .unwrap()
+            .is_none());
+        // Connection closed before the end of the headers
+        let mut request: &[u8] = b"GET /orders HTTP/1.1\r\n";
+        assert!(read_request(&mut request).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_path_not_found() {
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let services = Services {
+            metrics: true,
+            rest_api: false,
+        };
+        let response = route("GET /orders HTTP/1.1\r\n\r\n", &pool, services).await;
+        assert_eq!(response.status, 404);
+        let response = route("GET /metrics HTTP/1.1\r\n\r\n", &pool, services).await;
+        assert_eq!(response.status, 200);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::cli::settings::Settings;
+use crate::error::MostroError;
+use crate::lightning::cln::ClnConnector;
+use crate::lightning::reconcile::HoldInvoiceInfo;
+use crate::lightning::{InvoiceMessage, LnStatus, LndConnector, PaymentMessage};
+
+use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
+use fedimint_tonic_lnd::lnrpc::payment::PaymentStatus;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::mpsc::Sender;
+
+/// Future returned by the lightning backends
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, MostroError>> + Send + 'a>>;
+
+/// Lightning node operations used by Mostro, each supported node implements
+/// them so the actions don't depend on the node the operator runs
+pub trait LightningBackend: Send {
+    /// Create a hold invoice, returns the payment request, preimage and hash
+    fn create_hold_invoice<'a>(
+        &'a mut self,
+        description: &'a str,
+        amount: i64,
+    ) -> BackendFuture<'a, (String, Vec<u8>, Vec<u8>)>;
+
+    fn settle_hold_invoice<'a>(&'a mut self, preimage: &'a str) -> BackendFuture<'a, ()>;
+
+    fn cancel_hold_invoice<'a>(&'a mut self, hash: &'a str) -> BackendFuture<'a, ()>;
+
+    fn lookup_invoice_state<'a>(&'a mut self, hash: &'a str) -> BackendFuture<'a, InvoiceState>;
+
+    /// Pay `payment_request`, the payment updates are sent to `listener`
+    fn send_payment<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+        amount: i64,
+        listener: Sender<PaymentMessage>,
+    ) -> BackendFuture<'a, ()>;
+
+    /// Status of a payment done to `payment_request`, None if it was never attempted
+    fn lookup_payment_status<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+    ) -> BackendFuture<'a, Option<PaymentStatus>>;
+
+    /// Follow a payment already sent to `payment_request` until it ends, the
+    /// updates are sent to `listener`
+    fn track_payment<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+        listener: Sender<PaymentMessage>,
+    ) -> BackendFuture<'a, ()>;
+
+    /// Send `amount` sats on-chain to `address` from the node wallet, returns the transaction id
+    fn send_onchain_payment<'a>(
+        &'a mut self,
+        address: &'a str,
+        amount: i64,
+    ) -> BackendFuture<'a, String>;
+
+    /// Hold invoices not settled nor canceled on the node
+    fn list_hold_invoices(&mut self) -> BackendFuture<'_, Vec<HoldInvoiceInfo>>;
+
+    /// Send the state changes of the invoice with `r_hash` to `listener`
+    fn subscribe_invoice(
+        &mut self,
+        r_hash: Vec<u8>,
+        listener: Sender<InvoiceMessage>,
+    ) -> BackendFuture<'_, ()>;
+
+    fn get_node_info(&mut self) -> BackendFuture<'_, LnStatus>;
+}
+
+impl LightningBackend for LndConnector {
+    fn create_hold_invoice<'a>(
+        &'a mut self,
+        description: &'a str,
+        amount: i64,
+    ) -> BackendFuture<'a, (String, Vec<u8>, Vec<u8>)> {
+        Box::pin(async move {
+            let (invoice, preimage, hash) =
+                LndConnector::create_hold_invoice(self, description, amount).await?;
+            Ok((invoice.payment_request, preimage, hash))
+        })
+    }
+
+    fn settle_hold_invoice<'a>(&'a mut self, preimage: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            LndConnector::settle_hold_invoice(self, preimage)
+                .await
+                .map(|_| ())
+        })
+    }
+
+    fn cancel_hold_invoice<'a>(&'a mut self, hash: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            LndConnector::cancel_hold_invoice(self, hash)
+                .await
+                .map(|_| ())
+        })
+    }
+
+    fn lookup_invoice_state<'a>(&'a mut self, hash: &'a str) -> BackendFuture<'a, InvoiceState> {
+        Box::pin(LndConnector::lookup_invoice_state(self, hash))
+    }
+
+    fn send_payment<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+        amount: i64,
+        listener: Sender<PaymentMessage>,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(LndConnector::send_payment(
+            self,
+            payment_request,
+            amount,
+            listener,
+        ))
+    }
+
+    fn lookup_payment_status<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+    ) -> BackendFuture<'a, Option<PaymentStatus>> {
+        Box::pin(LndConnector::lookup_payment_status(self, payment_request))
+    }
+
+    fn track_payment<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+        listener: Sender<PaymentMessage>,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(LndConnector::track_payment(self, payment_request, listener))
+    }
+
+    fn send_onchain_payment<'a>(
+        &'a mut self,
+        address: &'a str,
+        amount: i64,
+    ) -> BackendFuture<'a, String> {
+        Box::pin(LndConnector::send_onchain_payment(self, address, amount))
+    }
+
+    fn list_hold_invoices(&mut self) -> BackendFuture<'_, Vec<HoldInvoiceInfo>> {
+        Box::pin(LndConnector::list_hold_invoices(self))
+    }
+
+    fn subscribe_invoice(
+        &mut self,
+        r_hash: Vec<u8>,
+        listener: Sender<InvoiceMessage>,
+    ) -> BackendFuture<'_, ()> {
+        Box::pin(async move {
+            LndConnector::subscribe_invoice(self, r_hash, listener)
+                .await
+                .map_err(|e| MostroError::LnNodeError(e.to_string()))
+        })
+    }
+
+    fn get_node_info(&mut self) -> BackendFuture<'_, LnStatus> {
+        Box::pin(async move {
+            let info = LndConnector::get_node_info(self).await?;
+            Ok(LnStatus::from_get_info_response(info))
+        })
+    }
+}
+
+/// Connect to the lightning node selected on settings, LND by default
+pub async fn connect_backend() -> anyhow::Result<Box<dyn LightningBackend>> {
+    match Settings::get_ln().backend.as_str() {
+        "cln" => Ok(Box::new(ClnConnector::new()?)),
+        "" | "lnd" => Ok(Box::new(LndConnector::new().await?)),
+        other => Err(anyhow::anyhow!("Unknown lightning backend {other}")),
+    }
+}
@@ -0,0 +1,440 @@
+//! Core Lightning backend, it uses the CLN REST API and the `holdinvoice`
+//! plugin as hold invoices are not supported by CLN itself.
+
+use crate::cli::settings::Settings;
+use crate::error::MostroError;
+use crate::lightning::backend::{BackendFuture, LightningBackend};
+use crate::lightning::invoice::decode_invoice;
+use crate::lightning::reconcile::HoldInvoiceInfo;
+use crate::lightning::{hold_invoice_limits, InvoiceMessage, LnStatus, PaymentMessage};
+use crate::util::bytes_to_string;
+
+use easy_hasher::easy_hasher::*;
+use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
+use fedimint_tonic_lnd::lnrpc::payment::PaymentStatus;
+use fedimint_tonic_lnd::lnrpc::Payment;
+use nostr_sdk::nostr::hashes::hex::FromHex;
+use nostr_sdk::nostr::secp256k1::rand::{self, RngCore};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tracing::{info, warn};
+
+/// Interval between invoice state checks, the plugin has no subscriptions
+const INVOICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Interval between checks of a payment without a final result
+const PAYMENT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct ClnConnector {
+    client: reqwest::Client,
+    url: String,
+    rune: String,
+}
+
+/// Map the state of a hold invoice of the plugin to the LND one used by Mostro
+pub fn hold_state_to_invoice_state(state: &str) -> Option<InvoiceState> {
+    match state {
+        "OPEN" => Some(InvoiceState::Open),
+        "ACCEPTED" => Some(InvoiceState::Accepted),
+        "SETTLED" => Some(InvoiceState::Settled),
+        "CANCELED" => Some(InvoiceState::Canceled),
+        _ => None,
+    }
+}
+
+/// Map the status of a payment of `pay` or `listpays` to the LND one used by
+/// Mostro, any status but a final one means the payment is still in flight
+pub fn pay_status_to_payment_status(status: &str) -> PaymentStatus {
+    match status {
+        "complete" => PaymentStatus::Succeeded,
+        "failed" => PaymentStatus::Failed,
+        _ => PaymentStatus::InFlight,
+    }
+}
+
+/// Payment hash of a hold invoice from its preimage in hex
+pub fn preimage_to_hash(preimage: &str) -> Result<String, MostroError> {
+    let preimage: Vec<u8> =
+        FromHex::from_hex(preimage).map_err(|e| MostroError::LnNodeError(format!("{e:?}")))?;
+
+    Ok(bytes_to_string(&raw_sha256(preimage).to_vec()))
+}
+
+/// Send the final status of the payment of `payment_hash` to `listener`
+async fn send_payment_status(
+    listener: &Sender<PaymentMessage>,
+    payment_hash: String,
+    status: PaymentStatus,
+) -> Result<(), MostroError> {
+    let msg = PaymentMessage {
+        payment: Payment {
+            payment_hash,
+            status: status as i32,
+            ..Default::default()
+        },
+    };
+    listener
+        .send(msg)
+        .await
+        .map_err(|e| MostroError::LnNodeError(e.to_string()))
+}
+
+impl ClnConnector {
+    pub fn new() -> anyhow::Result<Self> {
+        let ln_settings = Settings::get_ln();
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url: ln_settings.cln_rest_url.trim_end_matches('/').to_string(),
+            rune: ln_settings.cln_rune,
+        })
+    }
+
+    /// Call a CLN RPC method through the REST API
+    async fn call(&self, method: &str, params: Value) -> Result<Value, MostroError> {
+        let res = self
+            .client
+            .post(format!("{}/v1/{method}", self.url))
+            .header("Rune", &self.rune)
+            .json(&params)
+            .send()
+            .await
+            .map_err(|e| MostroError::LnNodeError(e.to_string()))?;
+        let status = res.status();
+        let body: Value = res
+            .json()
+            .await
+            .map_err(|e| MostroError::LnNodeError(e.to_string()))?;
+        if !status.is_success() {
+            return Err(MostroError::LnNodeError(format!("{method}: {body}")));
+        }
+
+        Ok(body)
+    }
+
+    async fn invoice_state(&self, hash: &str) -> Result<InvoiceState, MostroError> {
+        let res = self
+            .call("holdinvoicelookup", json!({ "payment_hash": hash }))
+            .await?;
+        let state = res["holdstate"].as_str().unwrap_or_default();
+
+        hold_state_to_invoice_state(state)
+            .ok_or_else(|| MostroError::LnNodeError(format!("Unknown invoice state {state}")))
+    }
+
+    /// Status of the payment of `hash` on the node, None if it was never attempted
+    async fn payment_status(&self, hash: &str) -> Result<Option<PaymentStatus>, MostroError> {
+        let res = self
+            .call("listpays", json!({ "payment_hash": hash }))
+            .await?;
+        let pays = res["pays"].as_array().cloned().unwrap_or_default();
+        // A successful attempt is the final result whatever happened to the others
+        let status = pays
+            .iter()
+            .map(|pay| pay_status_to_payment_status(pay["status"].as_str().unwrap_or_default()))
+            .max_by_key(|status| match status {
+                PaymentStatus::Succeeded => 2,
+                PaymentStatus::InFlight => 1,
+                _ => 0,
+            });
+
+        Ok(status)
+    }
+
+    /// Wait for the final result of a payment without one, an unknown status
+    /// is never taken as a failure as the payment could still succeed
+    async fn wait_payment(&self, hash: &str) -> PaymentStatus {
+        loop {
+            match self.payment_status(hash).await {
+                Ok(Some(status)) if status != PaymentStatus::InFlight => return status,
+                // Nothing reached the node, the payment can be retried
+                Ok(None) => return PaymentStatus::Failed,
+                Ok(Some(_)) => {}
+                Err(e) => warn!("Payment {hash}: status unknown, checking again: {e}"),
+            }
+            tokio::time::sleep(PAYMENT_POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl LightningBackend for ClnConnector {
+    fn create_hold_invoice<'a>(
+        &'a mut self,
+        description: &'a str,
+        amount: i64,
+    ) -> BackendFuture<'a, (String, Vec<u8>, Vec<u8>)> {
+        Box::pin(async move {
+            let mut preimage = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut preimage);
+            let hash = raw_sha256(preimage.to_vec());
+            let (cltv_delta, expiry) = hold_invoice_limits(
+                &Settings::get_ln(),
+                Settings::get_mostro().expiration_seconds,
+            );
+
+            let res = self
+                .call(
+                    "holdinvoice",
+                    json!({
+                        "amount_msat": amount * 1000,
+                        "description": description,
+                        "payment_hash": bytes_to_string(&hash.to_vec()),
+                        "cltv": cltv_delta,
+                        "expiry": expiry,
+                    }),
+                )
+                .await?;
+            let bolt11 = res["bolt11"]
+                .as_str()
+                .ok_or_else(|| MostroError::LnNodeError("Missing bolt11".to_string()))?;
+
+            Ok((bolt11.to_string(), preimage.to_vec(), hash.to_vec()))
+        })
+    }
+
+    fn settle_hold_invoice<'a>(&'a mut self, preimage: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let hash = preimage_to_hash(preimage)?;
+            self.call("holdinvoicesettle", json!({ "payment_hash": hash }))
+                .await
+                .map(|_| ())
+        })
+    }
+
+    fn cancel_hold_invoice<'a>(&'a mut self, hash: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.call("holdinvoicecancel", json!({ "payment_hash": hash }))
+                .await
+                .map(|_| ())
+        })
+    }
+
+    fn lookup_invoice_state<'a>(&'a mut self, hash: &'a str) -> BackendFuture<'a, InvoiceState> {
+        Box::pin(async move { self.invoice_state(hash).await })
+    }
+
+    fn send_payment<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+        amount: i64,
+        listener: Sender<PaymentMessage>,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let invoice = decode_invoice(payment_request)?;
+            let mostro_settings = Settings::get_mostro();
+
+            // Same max routing fee used with LND
+            let max_fee = match amount <= 1000 {
+                true => amount as f64 * 0.01,
+                false => amount as f64 * mostro_settings.max_routing_fee,
+            };
+            let mut params = json!({
+                "bolt11": payment_request,
+                "maxfee": (max_fee * 1000.0) as u64,
+            });
+            match invoice.amount_milli_satoshis() {
+                Some(amt) if amt != amount as u64 * 1000 => {
+                    info!("Aborting paying invoice with wrong amount to buyer");
+                    return Err(MostroError::LnPaymentError("Wrong amount".to_string()));
+                }
+                Some(_) => {}
+                // We add amount to the request only if the invoice doesn't have amount
+                None => params["amount_msat"] = json!(amount * 1000),
+            }
+
+            // Only a final status of the payment is trusted, a pending payment or
+            // an error of the call itself leave the payment status to be checked
+            let payment_hash = bytes_to_string(invoice.payment_hash().as_ref());
+            let status = match self.call("pay", params).await {
+                Ok(res) => pay_status_to_payment_status(res["status"].as_str().unwrap_or_default()),
+                Err(e) => {
+                    warn!("Payment {payment_hash}: {e}");
+                    PaymentStatus::InFlight
+                }
+            };
+            let status = match status {
+                PaymentStatus::InFlight => self.wait_payment(&payment_hash).await,
+                status => status,
+            };
+            send_payment_status(&listener, payment_hash, status).await
+        })
+    }
+
+    fn lookup_payment_status<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+    ) -> BackendFuture<'a, Option<PaymentStatus>> {
+        Box::pin(async move {
+            let invoice = decode_invoice(payment_request)?;
+            self.payment_status(&bytes_to_string(invoice.payment_hash().as_ref()))
+                .await
+        })
+    }
+
+    fn track_payment<'a>(
+        &'a mut self,
+        payment_request: &'a str,
+        listener: Sender<PaymentMessage>,
+    ) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            let invoice = decode_invoice(payment_request)?;
+            let payment_hash = bytes_to_string(invoice.payment_hash().as_ref());
+            let status = self.wait_payment(&payment_hash).await;
+            send_payment_status(&listener, payment_hash, status).await
+        })
+    }
+
+    fn send_onchain_payment<'a>(
+        &'a mut self,
+        address: &'a str,
+        amount: i64,
+    ) -> BackendFuture<'a, String> {
+        Box::pin(async move {
+            let res = self
+                .call(
+                    "withdraw",
+                    json!({ "destination": address, "satoshi": amount }),
+                )
+                .await
+                .map_err(|e| MostroError::LnPaymentError(e.to_string()))?;
+            res["txid"]
+                .as_str()
+                .map(|txid| txid.to_string())
+                .ok_or_else(|| MostroError::LnPaymentError("Missing txid".to_string()))
+        })
+    }
+
+    fn list_hold_invoices(&mut self) -> BackendFuture<'_, Vec<HoldInvoiceInfo>> {
+        Box::pin(async move {
+            let res = self.call("listinvoices", json!({})).await?;
+            let invoices = res["invoices"].as_array().cloned().unwrap_or_default();
+            let mut hold_invoices = vec![];
+            // Hold invoices are unpaid for CLN until settled, the plugin knows their state
+            for invoice in invoices.iter().filter(|i| i["status"] == "unpaid") {
+                let Some(hash) = invoice["payment_hash"].as_str() else {
+                    continue;
+                };
+                let Ok(state) = self.invoice_state(hash).await else {
+                    continue;
+                };
+                if matches!(state, InvoiceState::Open | InvoiceState::Accepted) {
+                    hold_invoices.push(HoldInvoiceInfo {
+                        hash: hash.to_string(),
+                        amount: invoice["amount_msat"].as_i64().unwrap_or_default() / 1000,
+                        state,
+                    });
+                }
+            }
+
+            Ok(hold_invoices)
+        })
+    }
+
+    fn subscribe_invoice(
+        &mut self,
+        r_hash: Vec<u8>,
+        listener: Sender<InvoiceMessage>,
+    ) -> BackendFuture<'_, ()> {
+        Box::pin(async move {
+            let hash = bytes_to_string(&r_hash);
+            let mut last_state = None;
+            loop {
+                let state = self.invoice_state(&hash).await?;
+                if last_state != Some(state) {
+                    last_state = Some(state);
+                    let msg = InvoiceMessage {
+                        hash: r_hash.clone(),
+                        state,
+                    };
+                    listener
+                        .send(msg)
+                        .await
+                        .map_err(|e| MostroError::LnNodeError(e.to_string()))?;
+                }
+                if matches!(state, InvoiceState::Settled | InvoiceState::Canceled) {
+                    return Ok(());
+                }
+                tokio::time::sleep(INVOICE_POLL_INTERVAL).await;
+            }
+        })
+    }
+
+    fn get_node_info(&mut self) -> BackendFuture<'_, LnStatus> {
+        Box::pin(async move {
+            let info = self.call("getinfo", json!({})).await?;
+            let node_pubkey = info["id"].as_str().unwrap_or_default().to_string();
+            let uris = info["address"]
+                .as_array()
+                .map(|addresses| {
+                    addresses
+                        .iter()
+                        .filter_map(|a| {
+                            Some(format!(
+                                "{node_pubkey}@{}:{}",
+                                a["address"].as_str()?,
+                                a["port"].as_u64()?
+                            ))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            Ok(LnStatus {
+                version: info["version"].as_str().unwrap_or_default().to_string(),
+                commit_hash: String::new(),
+                node_alias: info["alias"].as_str().unwrap_or_default().to_string(),
+                chains: vec!["bitcoin".to_string()],
+                networks: vec![info["network"].as_str().unwrap_or_default().to_string()],
+                uris,
+                node_pubkey,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hold_invoice_states() {
+        assert_eq!(
+            hold_state_to_invoice_state("ACCEPTED"),
+            Some(InvoiceState::Accepted)
+        );
+        assert_eq!(
+            hold_state_to_invoice_state("SETTLED"),
+            Some(InvoiceState::Settled)
+        );
+        assert_eq!(hold_state_to_invoice_state("UNKNOWN"), None);
+    }
+
+    #[test]
+    fn test_pay_statuses() {
+        assert_eq!(
+            pay_status_to_payment_status("complete"),
+            PaymentStatus::Succeeded
+        );
+        assert_eq!(
+            pay_status_to_payment_status("failed"),
+            PaymentStatus::Failed
+        );
+        // Anything else is not a final result
+        assert_eq!(
+            pay_status_to_payment_status("pending"),
+            PaymentStatus::InFlight
+        );
+        assert_eq!(pay_status_to_payment_status(""), PaymentStatus::InFlight);
+    }
+
+    #[test]
+    fn test_preimage_to_hash() {
+        let preimage = "0000000000000000000000000000000000000000000000000000000000000000";
+        assert_eq!(
+            preimage_to_hash(preimage).unwrap(),
+            "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925"
+        );
+        assert!(preimage_to_hash("not hex").is_err());
+    }
+}
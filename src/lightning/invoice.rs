@@ -2,12 +2,34 @@ use crate::cli::settings::Settings;
 use crate::error::MostroError;
 use crate::lnurl::ln_exists;
 
+use bitcoin::Network;
 use chrono::prelude::*;
 use chrono::TimeDelta;
 use lightning_invoice::{Bolt11Invoice, SignedRawBolt11Invoice};
 use lnurl::lightning_address::LightningAddress;
 use std::str::FromStr;
 
+/// Network of the node wallet paying on-chain, bitcoin if not set or unknown
+pub fn onchain_network() -> Network {
+    Network::from_str(&Settings::get_mostro().onchain_network).unwrap_or(Network::Bitcoin)
+}
+
+/// Check if a payment request is a bitcoin address of `network` to be paid on-chain
+pub fn is_onchain_address(payment_request: &str, network: Network) -> bool {
+    bitcoin::Address::from_str(payment_request)
+        .is_ok_and(|address| address.is_valid_for_network(network))
+}
+
+/// On-chain fallback address of a bolt11 invoice for `network`, if it has one
+pub fn invoice_fallback_address(payment_request: &str, network: Network) -> Option<String> {
+    decode_invoice(payment_request)
+        .ok()?
+        .fallback_addresses()
+        .into_iter()
+        .map(|address| address.to_string())
+        .find(|address| is_onchain_address(address, network))
+}
+
 /// Decode a lightning invoice (bolt11)
 pub fn decode_invoice(payment_request: &str) -> Result<Bolt11Invoice, MostroError> {
     let invoice = Bolt11Invoice::from_str(payment_request)?;
@@ -15,6 +37,20 @@ pub fn decode_invoice(payment_request: &str) -> Result<Bolt11Invoice, MostroErro
     Ok(invoice)
 }
 
+/// Time a bolt11 invoice expires, seconds since unix epoch
+pub fn expires_at(payment_request: &str) -> Result<u64, MostroError> {
+    let invoice = decode_invoice(payment_request)?;
+
+    Ok((invoice.duration_since_epoch() + invoice.expiry_time()).as_secs())
+}
+
+/// Check if a bolt11 invoice is expired at `at`, seconds since unix epoch
+pub fn is_expired_at(payment_request: &str, at: u64) -> Result<bool, MostroError> {
+    let invoice = decode_invoice(payment_request)?;
+
+    Ok(invoice.would_expire(std::time::Duration::from_secs(at)))
+}
+
 /// Verify if a buyer invoice is valid,
 /// if the invoice have amount we check if the amount minus fee is the same
 pub async fn is_valid_invoice(
@@ -22,6 +58,12 @@ pub async fn is_valid_invoice(
     amount: Option<u64>,
     fee: Option<u64>,
 ) -> Result<(), MostroError> {
+    // Buyer can be paid on-chain if allowed
+    if Settings::get_mostro().onchain_fallback
+        && is_onchain_address(&payment_request, onchain_network())
+    {
+        return Ok(());
+    }
     // Check if it's a lightning address
     let ln_addr = LightningAddress::from_str(&payment_request);
     // Is it a ln address
@@ -77,17 +119,46 @@ pub async fn is_valid_invoice(
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::env::set_var;
     use std::path::PathBuf;
+    use std::sync::{Arc, RwLock};
 
-    use super::is_valid_invoice;
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::{Network, PubkeyHash};
+    use lightning_invoice::{Currency, Fallback, InvoiceBuilder, PaymentSecret};
+
+    use super::{
+        decode_invoice, expires_at, invoice_fallback_address, is_expired_at, is_onchain_address,
+        is_valid_invoice,
+    };
     use crate::{cli::settings::Settings, error::MostroError, MOSTRO_CONFIG};
 
+    /// Regtest invoice of 100k sats with an optional on-chain fallback
+    pub fn test_invoice(fallback: Option<Fallback>) -> String {
+        let key = SecretKey::from_slice(&[42; 32]).unwrap();
+        let builder = InvoiceBuilder::new(Currency::Regtest)
+            .description("mostro".to_string())
+            .payment_hash(sha256::Hash::from_byte_array([1; 32]))
+            .payment_secret(PaymentSecret([2; 32]))
+            .current_timestamp()
+            .min_final_cltv_expiry_delta(144)
+            .amount_milli_satoshis(100_000_000);
+        let builder = match fallback {
+            Some(fallback) => builder.fallback(fallback),
+            None => builder,
+        };
+        builder
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &key))
+            .unwrap()
+            .to_string()
+    }
+
     fn init_settings_test() {
         let test_path = PathBuf::from("./");
         set_var("RUN_MODE", ".tpl");
-        MOSTRO_CONFIG.get_or_init(|| Settings::new(test_path).unwrap());
+        MOSTRO_CONFIG.get_or_init(|| RwLock::new(Arc::new(Settings::new(test_path).unwrap())));
     }
 
     #[tokio::test]
@@ -113,4 +184,74 @@ mod tests {
         let min_amount_err = is_valid_invoice(payment_request, None, None);
         assert_eq!(Err(MostroError::MinAmountError), min_amount_err.await);
     }
+
+    #[test]
+    fn test_invoice_expired_before_payment() {
+        let payment_request = "lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n";
+        let invoice = decode_invoice(payment_request).unwrap();
+        let expires_at = expires_at(payment_request).unwrap();
+        assert_eq!(
+            expires_at,
+            invoice.duration_since_epoch().as_secs() + invoice.expiry_time().as_secs()
+        );
+        assert_eq!(is_expired_at(payment_request, expires_at - 1), Ok(false));
+        assert_eq!(is_expired_at(payment_request, expires_at + 1), Ok(true));
+    }
+
+    #[test]
+    fn test_invoice_valid_before_payment() {
+        let payment_request = "lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n";
+        let invoice = decode_invoice(payment_request).unwrap();
+        let created_at = invoice.duration_since_epoch().as_secs();
+        assert_eq!(is_expired_at(payment_request, created_at + 1), Ok(false));
+    }
+
+    #[test]
+    fn test_invoice_expiry_wrong_invoice() {
+        assert_eq!(
+            is_expired_at("lnbcrt1wrong", 0),
+            Err(MostroError::ParsingInvoiceError)
+        );
+    }
+
+    #[test]
+    fn test_onchain_address_payout() {
+        assert!(is_onchain_address(
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+            Network::Bitcoin
+        ));
+        assert!(is_onchain_address(
+            "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+            Network::Regtest
+        ));
+        // Addresses of other networks are not paid
+        assert!(!is_onchain_address(
+            "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+            Network::Bitcoin
+        ));
+        assert!(!is_onchain_address(
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+            Network::Regtest
+        ));
+        // Lightning invoices and addresses are paid on lightning
+        assert!(!is_onchain_address("lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n", Network::Regtest));
+        assert!(!is_onchain_address("user@mostro.network", Network::Bitcoin));
+    }
+
+    #[test]
+    fn test_invoice_fallback_address() {
+        let fallback = Fallback::PubKeyHash(PubkeyHash::from_byte_array([3; 20]));
+        let with_fallback = test_invoice(Some(fallback));
+        let address = invoice_fallback_address(&with_fallback, Network::Regtest).unwrap();
+        assert!(is_onchain_address(&address, Network::Regtest));
+        // The fallback must be of the node network
+        assert_eq!(
+            invoice_fallback_address(&with_fallback, Network::Bitcoin),
+            None
+        );
+        assert_eq!(
+            invoice_fallback_address(&test_invoice(None), Network::Regtest),
+            None
+        );
+    }
 }
@@ -0,0 +1,108 @@
+//! Lightning node double for the tests, it settles, cancels, lists and looks
+//! up hold invoices, anything else isn't expected to be called
+
+use crate::error::MostroError;
+use crate::lightning::backend::{BackendFuture, LightningBackend};
+use crate::lightning::reconcile::HoldInvoiceInfo;
+use crate::lightning::{InvoiceMessage, LnStatus, PaymentMessage};
+use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
+use fedimint_tonic_lnd::lnrpc::payment::PaymentStatus;
+use tokio::sync::mpsc::Sender;
+
+/// Settlement fails the first `settle_failures` calls, the invoice state is
+/// `state` until settled, `Accepted` by default
+#[derive(Default)]
+pub struct MockNode {
+    pub invoices: Vec<HoldInvoiceInfo>,
+    pub canceled: Vec<String>,
+    pub settle_failures: u32,
+    pub settle_calls: u32,
+    pub settled: bool,
+    pub state: Option<InvoiceState>,
+}
+
+impl LightningBackend for MockNode {
+    fn create_hold_invoice<'a>(
+        &'a mut self,
+        _description: &'a str,
+        _amount: i64,
+    ) -> BackendFuture<'a, (String, Vec<u8>, Vec<u8>)> {
+        unimplemented!()
+    }
+
+    fn settle_hold_invoice<'a>(&'a mut self, _preimage: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.settle_calls += 1;
+            if self.settle_calls <= self.settle_failures {
+                return Err(MostroError::LnNodeError("connection reset".to_string()));
+            }
+            self.settled = true;
+            Ok(())
+        })
+    }
+
+    fn cancel_hold_invoice<'a>(&'a mut self, hash: &'a str) -> BackendFuture<'a, ()> {
+        Box::pin(async move {
+            self.canceled.push(hash.to_string());
+            Ok(())
+        })
+    }
+
+    fn lookup_invoice_state<'a>(&'a mut self, _hash: &'a str) -> BackendFuture<'a, InvoiceState> {
+        Box::pin(async move {
+            Ok(match (self.settled, self.state) {
+                (true, _) => InvoiceState::Settled,
+                (false, Some(state)) => state,
+                (false, None) => InvoiceState::Accepted,
+            })
+        })
+    }
+
+    fn send_payment<'a>(
+        &'a mut self,
+        _payment_request: &'a str,
+        _amount: i64,
+        _listener: Sender<PaymentMessage>,
+    ) -> BackendFuture<'a, ()> {
+        unimplemented!()
+    }
+
+    fn lookup_payment_status<'a>(
+        &'a mut self,
+        _payment_request: &'a str,
+    ) -> BackendFuture<'a, Option<PaymentStatus>> {
+        unimplemented!()
+    }
+
+    fn track_payment<'a>(
+        &'a mut self,
+        _payment_request: &'a str,
+        _listener: Sender<PaymentMessage>,
+    ) -> BackendFuture<'a, ()> {
+        unimplemented!()
+    }
+
+    fn send_onchain_payment<'a>(
+        &'a mut self,
+        _address: &'a str,
+        _amount: i64,
+    ) -> BackendFuture<'a, String> {
+        unimplemented!()
+    }
+
+    fn list_hold_invoices(&mut self) -> BackendFuture<'_, Vec<HoldInvoiceInfo>> {
+        Box::pin(async move { Ok(self.invoices.clone()) })
+    }
+
+    fn subscribe_invoice(
+        &mut self,
+        _r_hash: Vec<u8>,
+        _listener: Sender<InvoiceMessage>,
+    ) -> BackendFuture<'_, ()> {
+        unimplemented!()
+    }
+
+    fn get_node_info(&mut self) -> BackendFuture<'_, LnStatus> {
+        unimplemented!()
+    }
+}
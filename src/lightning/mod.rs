@@ -1,6 +1,12 @@
+pub mod backend;
+pub mod cln;
 pub mod invoice;
+#[cfg(test)]
+pub mod mock;
+pub mod payment_monitor;
+pub mod reconcile;
 
-use crate::cli::settings::Settings;
+use crate::cli::settings::{Lightning, Settings};
 use crate::error::MostroError;
 use crate::lightning::invoice::decode_invoice;
 use crate::util::bytes_to_string;
@@ -11,14 +17,103 @@ use fedimint_tonic_lnd::invoicesrpc::{
     AddHoldInvoiceRequest, AddHoldInvoiceResp, CancelInvoiceMsg, CancelInvoiceResp,
     SettleInvoiceMsg, SettleInvoiceResp,
 };
-use fedimint_tonic_lnd::lnrpc::{invoice::InvoiceState, GetInfoRequest, GetInfoResponse, Payment};
+use fedimint_tonic_lnd::lnrpc::payment::PaymentStatus;
+use fedimint_tonic_lnd::lnrpc::{
+    invoice::InvoiceState, GetInfoRequest, GetInfoResponse, Payment, PaymentHash, SendCoinsRequest,
+};
 use fedimint_tonic_lnd::routerrpc::{SendPaymentRequest, TrackPaymentRequest};
-use fedimint_tonic_lnd::Client;
+use fedimint_tonic_lnd::{tonic, Client};
 use nostr_sdk::nostr::hashes::hex::FromHex;
 use nostr_sdk::nostr::secp256k1::rand::{self, RngCore};
 use std::cmp::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use tokio::sync::mpsc::Sender;
-use tracing::info;
+use tracing::{info, warn};
+
+/// Status of the first update of a tracked payment, tracking fails with
+/// NotFound only if the node has no payment to the invoice, any other
+/// failure leaves the payment status unknown
+fn tracked_payment_status(
+    update: Result<Option<Payment>, tonic::Status>,
+) -> Result<Option<PaymentStatus>, MostroError> {
+    match update {
+        Ok(Some(payment)) => PaymentStatus::try_from(payment.status)
+            .map(Some)
+            .map_err(|_| {
+                MostroError::LnNodeError(format!("Unknown payment status {}", payment.status))
+            }),
+        Ok(None) => Err(MostroError::LnNodeError(
+            "Payment tracking ended without updates".to_string(),
+        )),
+        Err(e) if e.code() == tonic::Code::NotFound => Ok(None),
+        Err(e) => Err(MostroError::LnNodeError(e.to_string())),
+    }
+}
+
+/// LND availability, updated by the scheduler health check
+static LND_AVAILABLE: AtomicBool = AtomicBool::new(true);
+
+/// Mark LND as available or not, e.g. while reconnecting
+pub fn set_lnd_available(available: bool) {
+    LND_AVAILABLE.store(available, AtomicOrdering::SeqCst);
+}
+
+pub fn is_lnd_available() -> bool {
+    LND_AVAILABLE.load(AtomicOrdering::SeqCst)
+}
+
+/// Bounds of the hold invoice CLTV delta in blocks
+const HOLD_INVOICE_CLTV_DELTA_RANGE: (u32, u32) = (18, 2016);
+/// Bounds of the hold invoice expiry in seconds
+const HOLD_INVOICE_EXPIRY_RANGE: (u32, u32) = (60, 604_800);
+
+/// CLTV delta and expiry of the hold invoices, settings out of bounds are
+/// clamped to them, the expiry defaults to the order `expiration_seconds`
+pub fn hold_invoice_limits(ln_settings: &Lightning, expiration_seconds: u32) -> (u64, i64) {
+    let clamp = |name: &str, value: u32, (min, max): (u32, u32)| {
+        let clamped = value.clamp(min, max);
+        if clamped != value {
+            warn!("{name} {value} out of bounds, using {clamped}");
+        }
+        clamped
+    };
+    let cltv_delta = clamp(
+        "hold_invoice_cltv_delta",
+        ln_settings.hold_invoice_cltv_delta,
+        HOLD_INVOICE_CLTV_DELTA_RANGE,
+    );
+    let expiry = match ln_settings.hold_invoice_expiry_seconds {
+        0 => expiration_seconds,
+        expiry => expiry,
+    };
+    let expiry = clamp(
+        "hold_invoice_expiry_seconds",
+        expiry,
+        HOLD_INVOICE_EXPIRY_RANGE,
+    );
+
+    (cltv_delta as u64, expiry as i64)
+}
+
+/// Request of a hold invoice of `amount` sats locked to `hash`
+fn hold_invoice_request(
+    hash: Vec<u8>,
+    description: &str,
+    amount: i64,
+    ln_settings: &Lightning,
+    expiration_seconds: u32,
+) -> AddHoldInvoiceRequest {
+    let (cltv_expiry, expiry) = hold_invoice_limits(ln_settings, expiration_seconds);
+
+    AddHoldInvoiceRequest {
+        hash,
+        memo: description.to_string(),
+        value: amount,
+        cltv_expiry,
+        expiry,
+        ..Default::default()
+    }
+}
 
 pub struct LndConnector {
     client: Client,
@@ -60,16 +155,13 @@ impl LndConnector {
         let mut preimage = [0u8; 32];
         rand::thread_rng().fill_bytes(&mut preimage);
         let hash = raw_sha256(preimage.to_vec());
-        let ln_settings = Settings::get_ln();
-        let cltv_expiry = ln_settings.hold_invoice_cltv_delta as u64;
-
-        let invoice = AddHoldInvoiceRequest {
-            hash: hash.to_vec(),
-            memo: description.to_string(),
-            value: amount,
-            cltv_expiry,
-            ..Default::default()
-        };
+        let invoice = hold_invoice_request(
+            hash.to_vec(),
+            description,
+            amount,
+            &Settings::get_ln(),
+            Settings::get_mostro().expiration_seconds,
+        );
         let holdinvoice = self
             .client
             .invoices()
@@ -142,6 +234,24 @@ impl LndConnector {
         }
     }
 
+    /// Get the current state of an invoice by its payment hash
+    pub async fn lookup_invoice_state(&mut self, hash: &str) -> Result<InvoiceState, MostroError> {
+        let r_hash =
+            FromHex::from_hex(hash).map_err(|e| MostroError::LnNodeError(format!("{e:?}")))?;
+        let invoice = self
+            .client
+            .lightning()
+            .lookup_invoice(PaymentHash {
+                r_hash,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| MostroError::LnNodeError(e.to_string()))?;
+
+        InvoiceState::try_from(invoice.into_inner().state)
+            .map_err(|e| MostroError::LnNodeError(e.to_string()))
+    }
+
     pub async fn cancel_hold_invoice(
         &mut self,
         hash: &str,
@@ -253,6 +363,89 @@ impl LndConnector {
         Ok(())
     }
 
+    /// Send an on-chain payment from the node wallet, returns the transaction id
+    pub async fn send_onchain_payment(
+        &mut self,
+        address: &str,
+        amount: i64,
+    ) -> Result<String, MostroError> {
+        let request = SendCoinsRequest {
+            addr: address.to_string(),
+            amount,
+            target_conf: 6,
+            ..Default::default()
+        };
+        let send = self
+            .client
+            .lightning()
+            .send_coins(request)
+            .await
+            .map_err(|e| MostroError::LnPaymentError(e.to_string()))?;
+
+        Ok(send.into_inner().txid)
+    }
+
+    /// Get the status of a payment done to `payment_request`, None if it was never attempted
+    pub async fn lookup_payment_status(
+        &mut self,
+        payment_request: &str,
+    ) -> Result<Option<PaymentStatus>, MostroError> {
+        use bitcoin::hashes::Hash;
+
+        let invoice = decode_invoice(payment_request)?;
+        let track_payment_req = TrackPaymentRequest {
+            payment_hash: invoice.payment_hash().to_byte_array().to_vec(),
+            no_inflight_updates: true,
+        };
+
+        let mut stream = match self
+            .client
+            .router()
+            .track_payment_v2(track_payment_req)
+            .await
+        {
+            Ok(stream) => stream.into_inner(),
+            Err(e) => return tracked_payment_status(Err(e)),
+        };
+        tracked_payment_status(stream.message().await)
+    }
+
+    /// Follow a payment already sent to `payment_request` until it ends, its
+    /// updates are lost with the stream of `send_payment` on a restart
+    pub async fn track_payment(
+        &mut self,
+        payment_request: &str,
+        listener: Sender<PaymentMessage>,
+    ) -> Result<(), MostroError> {
+        use bitcoin::hashes::Hash;
+
+        let invoice = decode_invoice(payment_request)?;
+        let track_payment_req = TrackPaymentRequest {
+            payment_hash: invoice.payment_hash().to_byte_array().to_vec(),
+            no_inflight_updates: true,
+        };
+        let mut stream = self
+            .client
+            .router()
+            .track_payment_v2(track_payment_req)
+            .await
+            .map_err(|e| MostroError::LnPaymentError(e.to_string()))?
+            .into_inner();
+
+        while let Ok(Some(payment)) = stream
+            .message()
+            .await
+            .map_err(|e| MostroError::LnPaymentError(e.to_string()))
+        {
+            listener
+                .send(PaymentMessage { payment })
+                .await
+                .map_err(|e| MostroError::LnNodeError(e.to_string()))?
+        }
+
+        Ok(())
+    }
+
     pub async fn get_node_info(&mut self) -> Result<GetInfoResponse, MostroError> {
         let info = self.client.lightning().get_info(GetInfoRequest {}).await;
 
@@ -287,3 +480,60 @@ impl LnStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ln_settings(cltv_delta: u32, expiry: u32) -> Lightning {
+        Lightning {
+            hold_invoice_cltv_delta: cltv_delta,
+            hold_invoice_expiry_seconds: expiry,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_hold_invoice_request_uses_settings() {
+        let request =
+            hold_invoice_request(vec![1; 32], "order", 50_000, &ln_settings(40, 1_200), 900);
+        assert_eq!(request.cltv_expiry, 40);
+        assert_eq!(request.expiry, 1_200);
+        assert_eq!(request.value, 50_000);
+        assert_eq!(request.memo, "order");
+    }
+
+    #[test]
+    fn test_hold_invoice_expiry_defaults_to_order_expiration() {
+        assert_eq!(hold_invoice_limits(&ln_settings(144, 0), 900), (144, 900));
+    }
+
+    #[test]
+    fn test_hold_invoice_limits_clamped() {
+        assert_eq!(hold_invoice_limits(&ln_settings(1, 10), 900), (18, 60));
+        assert_eq!(
+            hold_invoice_limits(&ln_settings(10_000, 10_000_000), 900),
+            (2016, 604_800)
+        );
+    }
+
+    #[test]
+    fn test_payment_lookup_not_found_only_when_never_sent() {
+        let payment = Payment {
+            status: PaymentStatus::Succeeded as i32,
+            ..Default::default()
+        };
+        assert_eq!(
+            tracked_payment_status(Ok(Some(payment))).unwrap(),
+            Some(PaymentStatus::Succeeded)
+        );
+        assert_eq!(
+            tracked_payment_status(Err(tonic::Status::not_found("payment isn't initiated")))
+                .unwrap(),
+            None
+        );
+        // The node not answering doesn't mean the payment was never sent
+        assert!(tracked_payment_status(Err(tonic::Status::unavailable("node down"))).is_err());
+        assert!(tracked_payment_status(Ok(None)).is_err());
+    }
+}
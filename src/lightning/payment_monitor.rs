@@ -0,0 +1,215 @@
+use crate::cli::settings::Settings;
+use crate::db::set_payment_retry;
+
+use anyhow::Result;
+use nostr_sdk::Timestamp;
+use once_cell::sync::Lazy;
+use sqlx::SqlitePool;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tracing::warn;
+use uuid::Uuid;
+
+static FAILED_PAYMENTS: Lazy<Mutex<FailedPaymentMonitor>> =
+    Lazy::new(|| Mutex::new(FailedPaymentMonitor::default()));
+
+/// Limits of failed payments allowed within a time window
+#[derive(Debug, Clone, Copy)]
+pub struct FailureThresholds {
+    pub window: i64,
+    pub per_buyer: usize,
+    pub global: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PaymentAlert {
+    /// Too many failed payments to the same buyer
+    Buyer { pubkey: String, failures: usize },
+    /// Too many failed payments to any buyer
+    Global { failures: usize },
+}
+
+/// Keeps track of the recent failed payments to detect failure patterns
+#[derive(Debug, Default)]
+pub struct FailedPaymentMonitor {
+    failures: VecDeque<(i64, String)>,
+}
+
+impl FailedPaymentMonitor {
+    /// Record a failed payment to `buyer` at `now` and return the alerts raised by it,
+    /// an alert is raised only when a threshold is reached to avoid flooding the operator
+    pub fn record_failure(
+        &mut self,
+        buyer: &str,
+        now: i64,
+        thresholds: FailureThresholds,
+    ) -> Vec<PaymentAlert> {
+        // Forget failures out of the window
+        while let Some((at, _)) = self.failures.front() {
+            if *at > now - thresholds.window {
+                break;
+            }
+            self.failures.pop_front();
+        }
+        self.failures.push_back((now, buyer.to_string()));
+
+        let mut alerts = vec![];
+        let buyer_failures = self.failures.iter().filter(|(_, b)| b == buyer).count();
+        if thresholds.per_buyer > 0 && buyer_failures == thresholds.per_buyer {
+            alerts.push(PaymentAlert::Buyer {
+                pubkey: buyer.to_string(),
+                failures: buyer_failures,
+            });
+        }
+        let global_failures = self.failures.len();
+        if thresholds.global > 0 && global_failures == thresholds.global {
+            alerts.push(PaymentAlert::Global {
+                failures: global_failures,
+            });
+        }
+
+        alerts
+    }
+}
+
+/// Record a failed payment to a buyer and alert the operator on elevated failure rates
+pub fn record_failed_payment(buyer: &str) -> Vec<PaymentAlert> {
+    let ln_settings = Settings::get_ln();
+    if ln_settings.failed_payments_window_seconds == 0 {
+        return vec![];
+    }
+    let thresholds = FailureThresholds {
+        window: ln_settings.failed_payments_window_seconds as i64,
+        per_buyer: ln_settings.failed_payments_buyer_threshold as usize,
+        global: ln_settings.failed_payments_global_threshold as usize,
+    };
+
+    let alerts = FAILED_PAYMENTS.lock().unwrap().record_failure(
+        buyer,
+        Timestamp::now().as_u64() as i64,
+        thresholds,
+    );
+    for alert in alerts.iter() {
+        match alert {
+            PaymentAlert::Buyer { pubkey, failures } => warn!(
+                "ALERT: {failures} failed payments to buyer {pubkey} in the last {} seconds",
+                thresholds.window
+            ),
+            PaymentAlert::Global { failures } => warn!(
+                "ALERT: {failures} failed payments in the last {} seconds",
+                thresholds.window
+            ),
+        }
+    }
+
+    alerts
+}
+
+/// Seconds to wait before retrying a payment that failed `attempts` times,
+/// `base * multiplier^attempts` limited to `max` (0 means no limit)
+pub fn payment_retry_delay(base: u64, multiplier: u64, max: u64, attempts: u32) -> i64 {
+    let delay = base.saturating_mul(multiplier.max(1).saturating_pow(attempts));
+    let max = if max > 0 { max } else { u64::MAX };
+
+    delay.min(max).min(i64::MAX as u64) as i64
+}
+
+/// Schedule the next retry of a payment that failed `attempts` times, the
+/// retry is stored so the backoff survives a restart, returns its time
+pub async fn schedule_payment_retry(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    attempts: u32,
+) -> Result<i64> {
+    let ln_settings = Settings::get_ln();
+    let delay = payment_retry_delay(
+        ln_settings.payment_retries_interval as u64,
+        ln_settings.payment_retries_multiplier as u64,
+        ln_settings.payment_retries_max_interval as u64,
+        attempts,
+    );
+    let next_retry = (Timestamp::now().as_u64() as i64).saturating_add(delay);
+    set_payment_retry(pool, order_id, next_retry).await?;
+
+    Ok(next_retry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLDS: FailureThresholds = FailureThresholds {
+        window: 3600,
+        per_buyer: 3,
+        global: 5,
+    };
+
+    #[test]
+    fn test_buyer_failure_spike_alert() {
+        let mut monitor = FailedPaymentMonitor::default();
+        let now = 1_700_000_000;
+        assert!(monitor.record_failure("buyer", now, THRESHOLDS).is_empty());
+        assert!(monitor
+            .record_failure("buyer", now + 10, THRESHOLDS)
+            .is_empty());
+        let alerts = monitor.record_failure("buyer", now + 20, THRESHOLDS);
+        assert_eq!(
+            alerts,
+            vec![PaymentAlert::Buyer {
+                pubkey: "buyer".to_string(),
+                failures: 3
+            }]
+        );
+        // Alert is not repeated for each new failure
+        assert!(monitor
+            .record_failure("buyer", now + 30, THRESHOLDS)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_global_failure_spike_alert() {
+        let mut monitor = FailedPaymentMonitor::default();
+        let now = 1_700_000_000;
+        for i in 0..4 {
+            let buyer = format!("buyer{i}");
+            assert!(monitor
+                .record_failure(&buyer, now + i, THRESHOLDS)
+                .is_empty());
+        }
+        let alerts = monitor.record_failure("buyer4", now + 5, THRESHOLDS);
+        assert_eq!(alerts, vec![PaymentAlert::Global { failures: 5 }]);
+    }
+
+    #[test]
+    fn test_old_failures_do_not_alert() {
+        let mut monitor = FailedPaymentMonitor::default();
+        let now = 1_700_000_000;
+        assert!(monitor.record_failure("buyer", now, THRESHOLDS).is_empty());
+        assert!(monitor
+            .record_failure("buyer", now + 10, THRESHOLDS)
+            .is_empty());
+        // First failures are out of the window
+        let alerts = monitor.record_failure("buyer", now + 4000, THRESHOLDS);
+        assert!(alerts.is_empty());
+    }
+
+    #[test]
+    fn test_payment_retry_backoff() {
+        assert_eq!(payment_retry_delay(60, 2, 0, 0), 60);
+        assert_eq!(payment_retry_delay(60, 2, 0, 1), 120);
+        assert_eq!(payment_retry_delay(60, 2, 0, 3), 480);
+        // Multiplier 1 keeps the interval flat
+        assert_eq!(payment_retry_delay(60, 1, 0, 3), 60);
+        assert_eq!(payment_retry_delay(60, 0, 0, 3), 60);
+    }
+
+    #[test]
+    fn test_payment_retry_backoff_capped() {
+        assert_eq!(payment_retry_delay(60, 2, 300, 2), 240);
+        assert_eq!(payment_retry_delay(60, 2, 300, 3), 300);
+        // Huge number of attempts doesn't overflow
+        assert_eq!(payment_retry_delay(60, 2, 300, 100), 300);
+        assert_eq!(payment_retry_delay(60, 2, 0, 100), i64::MAX);
+        assert_eq!(payment_retry_delay(u64::MAX, 2, 0, 1), i64::MAX);
+    }
+}
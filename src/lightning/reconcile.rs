@@ -0,0 +1,301 @@
+use crate::error::MostroError;
+use crate::lightning::backend::LightningBackend;
+use crate::lightning::LndConnector;
+use crate::util::bytes_to_string;
+
+use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
+use fedimint_tonic_lnd::lnrpc::ListInvoiceRequest;
+use std::collections::HashSet;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// Max number of invoices requested to the node in a single call
+const MAX_INVOICES: u64 = 10_000;
+
+/// Delay before retrying a failed hold invoice settlement, doubled on each attempt
+pub const SETTLE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Hold invoice not settled nor canceled on the node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HoldInvoiceInfo {
+    pub hash: String,
+    pub amount: i64,
+    pub state: InvoiceState,
+}
+
+impl LndConnector {
+    /// Hold invoices not settled nor canceled on the node
+    pub async fn list_hold_invoices(&mut self) -> Result<Vec<HoldInvoiceInfo>, MostroError> {
+        let invoices = self
+            .client
+            .lightning()
+            .list_invoices(ListInvoiceRequest {
+                pending_only: true,
+                num_max_invoices: MAX_INVOICES,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| MostroError::LnNodeError(e.to_string()))?
+            .into_inner()
+            .invoices;
+
+        // Preimage of hold invoices is unknown to the node until they are settled
+        Ok(invoices
+            .into_iter()
+            .filter(|i| i.r_preimage.is_empty())
+            .filter_map(|i| {
+                let state = InvoiceState::try_from(i.state).ok()?;
+                matches!(state, InvoiceState::Open | InvoiceState::Accepted).then(|| {
+                    HoldInvoiceInfo {
+                        hash: bytes_to_string(&i.r_hash),
+                        amount: i.value,
+                        state,
+                    }
+                })
+            })
+            .collect())
+    }
+}
+
+/// Settle a hold invoice retrying transient failures up to `attempts` times,
+/// a failed call is checked against the invoice state as the node could have
+/// settled it before the failure
+pub(crate) async fn settle_with_retry(
+    node: &mut dyn LightningBackend,
+    preimage: &str,
+    hash: Option<&str>,
+    attempts: u32,
+    delay: Duration,
+) -> Result<(), MostroError> {
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        let error = match node.settle_hold_invoice(preimage).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        if let Some(hash) = hash {
+            if let Ok(InvoiceState::Settled) = node.lookup_invoice_state(hash).await {
+                return Ok(());
+            }
+        }
+        if attempt >= attempts {
+            return Err(error);
+        }
+        info!("Hold invoice settle attempt {attempt} of {attempts} failed: {error}");
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Result of settling a seller hold invoice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettleOutcome {
+    Settled,
+    /// Settled out of band, the payout can go on
+    AlreadySettled,
+    /// Canceled out of band, the seller funds are back and the order can't be paid
+    Canceled,
+}
+
+/// Settle a hold invoice after checking its state on the node, an invoice
+/// already settled or canceled is reported instead of failing on settle and
+/// one the seller never paid can't be settled
+pub(crate) async fn settle_checked(
+    node: &mut dyn LightningBackend,
+    preimage: &str,
+    hash: Option<&str>,
+    attempts: u32,
+    delay: Duration,
+) -> Result<SettleOutcome, MostroError> {
+    if let Some(hash) = hash {
+        match node.lookup_invoice_state(hash).await {
+            Ok(InvoiceState::Settled) => return Ok(SettleOutcome::AlreadySettled),
+            Ok(InvoiceState::Canceled) => return Ok(SettleOutcome::Canceled),
+            Ok(InvoiceState::Open) => {
+                return Err(MostroError::LnPaymentError(format!(
+                    "hold invoice {hash} not paid, nothing to settle"
+                )))
+            }
+            Ok(InvoiceState::Accepted) => {}
+            // The settle attempts check the state again
+            Err(e) => info!("Hold invoice {hash} state unknown: {e}"),
+        }
+    }
+
+    settle_with_retry(node, preimage, hash, attempts, delay)
+        .await
+        .map(|_| SettleOutcome::Settled)
+}
+
+/// List the hold invoices on the node without a matching active order
+pub(crate) async fn find_orphaned_invoices(
+    node: &mut dyn LightningBackend,
+    active_hashes: &HashSet<String>,
+) -> Result<Vec<HoldInvoiceInfo>, MostroError> {
+    let invoices = node.list_hold_invoices().await?;
+
+    Ok(invoices
+        .into_iter()
+        .filter(|i| !active_hashes.contains(&i.hash))
+        .collect())
+}
+
+/// Cancel the given hold invoices, returns how many were canceled
+pub(crate) async fn cancel_orphaned_invoices(
+    node: &mut dyn LightningBackend,
+    invoices: &[HoldInvoiceInfo],
+) -> usize {
+    let mut canceled = 0;
+    for invoice in invoices {
+        match node.cancel_hold_invoice(&invoice.hash).await {
+            Ok(_) => {
+                info!("Orphaned hold invoice {} canceled", invoice.hash);
+                canceled += 1;
+            }
+            Err(e) => error!("Error canceling hold invoice {}: {e}", invoice.hash),
+        }
+    }
+
+    canceled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::mock::MockNode;
+
+    fn invoice(hash: &str, state: InvoiceState) -> HoldInvoiceInfo {
+        HoldInvoiceInfo {
+            hash: hash.to_string(),
+            amount: 10_000,
+            state,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_orphaned_invoices_identified() {
+        let mut node = MockNode {
+            invoices: vec![
+                invoice("active", InvoiceState::Accepted),
+                invoice("orphan_open", InvoiceState::Open),
+                invoice("orphan_accepted", InvoiceState::Accepted),
+            ],
+            ..Default::default()
+        };
+        let active_hashes = HashSet::from(["active".to_string()]);
+
+        let orphaned = find_orphaned_invoices(&mut node, &active_hashes)
+            .await
+            .unwrap();
+        assert_eq!(
+            orphaned,
+            vec![
+                invoice("orphan_open", InvoiceState::Open),
+                invoice("orphan_accepted", InvoiceState::Accepted),
+            ]
+        );
+
+        assert_eq!(cancel_orphaned_invoices(&mut node, &orphaned).await, 2);
+        assert_eq!(node.canceled, vec!["orphan_open", "orphan_accepted"]);
+    }
+
+    #[tokio::test]
+    async fn test_no_orphaned_invoices() {
+        let mut node = MockNode {
+            invoices: vec![invoice("active", InvoiceState::Open)],
+            ..Default::default()
+        };
+        let active_hashes = HashSet::from(["active".to_string()]);
+
+        let orphaned = find_orphaned_invoices(&mut node, &active_hashes)
+            .await
+            .unwrap();
+        assert!(orphaned.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_settle_transient_failure_then_success() {
+        let mut node = MockNode {
+            settle_failures: 1,
+            ..Default::default()
+        };
+        let result = settle_with_retry(
+            &mut node,
+            "preimage",
+            Some("hash"),
+            3,
+            Duration::from_millis(1),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(node.settle_calls, 2);
+        assert!(node.settled);
+    }
+
+    #[tokio::test]
+    async fn test_settle_failing_on_every_attempt() {
+        let mut node = MockNode {
+            settle_failures: 5,
+            ..Default::default()
+        };
+        let result = settle_with_retry(
+            &mut node,
+            "preimage",
+            Some("hash"),
+            3,
+            Duration::from_millis(1),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(node.settle_calls, 3);
+        assert!(!node.settled);
+    }
+
+    async fn settle_in_state(
+        state: InvoiceState,
+    ) -> (Result<SettleOutcome, MostroError>, MockNode) {
+        let mut node = MockNode {
+            state: Some(state),
+            ..Default::default()
+        };
+        let result = settle_checked(
+            &mut node,
+            "preimage",
+            Some("hash"),
+            3,
+            Duration::from_millis(1),
+        )
+        .await;
+        (result, node)
+    }
+
+    #[tokio::test]
+    async fn test_settle_accepted_invoice() {
+        let (result, node) = settle_in_state(InvoiceState::Accepted).await;
+        assert_eq!(result, Ok(SettleOutcome::Settled));
+        assert_eq!(node.settle_calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_settle_invoice_already_settled() {
+        let (result, node) = settle_in_state(InvoiceState::Settled).await;
+        assert_eq!(result, Ok(SettleOutcome::AlreadySettled));
+        assert_eq!(node.settle_calls, 0);
+    }
+
+    #[tokio::test]
+    async fn test_settle_invoice_canceled() {
+        let (result, node) = settle_in_state(InvoiceState::Canceled).await;
+        assert_eq!(result, Ok(SettleOutcome::Canceled));
+        assert_eq!(node.settle_calls, 0);
+    }
+
+    #[tokio::test]
+    async fn test_settle_invoice_not_paid() {
+        let (result, node) = settle_in_state(InvoiceState::Open).await;
+        assert!(result.is_err());
+        assert_eq!(node.settle_calls, 0);
+    }
+}
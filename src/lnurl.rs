@@ -1,6 +1,25 @@
+use crate::cli::settings::Settings;
 use crate::error::MostroError;
-use anyhow::{Context, Result};
+use crate::lightning::invoice::decode_invoice;
+use anyhow::{Context, Error, Result};
 use serde_json::Value;
+use std::time::Duration;
+
+/// Used when `lnurl_timeout_seconds` is not set
+const DEFAULT_LNURL_TIMEOUT_SECS: u64 = 10;
+
+/// Time a lightning address server has to answer, a slow or malicious one
+/// must not hang the payment to the buyer
+fn lnurl_timeout() -> Duration {
+    match Settings::get_mostro().lnurl_timeout_seconds {
+        0 => Duration::from_secs(DEFAULT_LNURL_TIMEOUT_SECS),
+        secs => Duration::from_secs(secs as u64),
+    }
+}
+
+fn lnurl_client(timeout: Duration) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder().timeout(timeout).build()?)
+}
 
 pub async fn ln_exists(address: &str) -> Result<(), MostroError> {
     let (user, domain) = match address.split_once('@') {
@@ -9,7 +28,10 @@ pub async fn ln_exists(address: &str) -> Result<(), MostroError> {
     };
 
     let url = format!("https://{domain}/.well-known/lnurlp/{user}");
-    let res = reqwest::get(url)
+    let res = lnurl_client(lnurl_timeout())
+        .map_err(|_| MostroError::NoAPIResponse)?
+        .get(url)
+        .send()
         .await
         .map_err(|_| MostroError::NoAPIResponse)?;
     let status = res.status();
@@ -31,10 +53,32 @@ pub async fn resolv_ln_address(address: &str, amount: u64) -> Result<String> {
         Some((user, domain)) => (user, domain),
         None => return Ok("".to_string()),
     };
-    let amount_msat = amount * 1000;
 
     let url = format!("https://{domain}/.well-known/lnurlp/{user}");
-    let res = reqwest::get(url)
+    resolv_lnurl(&url, amount, lnurl_timeout()).await
+}
+
+/// Check the payment request returned by a LNURL endpoint is a bolt11 invoice
+/// for `amount_msat`, a misbehaving endpoint could return anything
+fn check_lnurl_invoice(pr: &str, amount_msat: u64) -> Result<()> {
+    let invoice = decode_invoice(pr)
+        .map_err(|e| Error::msg(format!("LNURL endpoint returned an invalid invoice: {e}")))?;
+    if invoice.amount_milli_satoshis() != Some(amount_msat) {
+        return Err(Error::msg(
+            "LNURL endpoint returned an invoice with wrong amount",
+        ));
+    }
+
+    Ok(())
+}
+
+async fn resolv_lnurl(url: &str, amount: u64, timeout: Duration) -> Result<String> {
+    let amount_msat = amount * 1000;
+    let client = lnurl_client(timeout)?;
+
+    let res = client
+        .get(url)
+        .send()
         .await
         .context("Something went wrong with API request, try again!")?;
     let status = res.status();
@@ -52,7 +96,9 @@ pub async fn resolv_ln_address(address: &str, amount: u64) -> Result<String> {
         }
         let callback = body["callback"].as_str().unwrap_or("");
         let callback = format!("{callback}?amount={amount_msat}");
-        let res = reqwest::get(callback)
+        let res = client
+            .get(callback)
+            .send()
             .await
             .context("Something went wrong with API request, try again!")?;
         let status = res.status();
@@ -60,6 +106,7 @@ pub async fn resolv_ln_address(address: &str, amount: u64) -> Result<String> {
             let body = res.text().await?;
             let body: Value = serde_json::from_str(&body)?;
             let pr = body["pr"].as_str().unwrap_or("");
+            check_lnurl_invoice(pr, amount_msat)?;
 
             return Ok(pr.to_string());
         }
@@ -68,3 +115,84 @@ pub async fn resolv_ln_address(address: &str, amount: u64) -> Result<String> {
         Ok("".to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::{header, mock_server};
+    use crate::http::{request_line, Response};
+
+    const INVOICE_50K_SATS: &str = "lnbcrt500u1p3lzwdzpp5t9kgwgwd07y2lrwdscdnkqu4scrcgpm5pt9uwx0rxn5rxawlxlvqdqqcqzpgxqyz5vqsp5a6k7syfxeg8jy63rteywwjla5rrg2pvhedx8ajr2ltm4seydhsqq9qyyssq0n2uwlumsx4d0mtjm8tp7jw3y4da6p6z9gyyjac0d9xugf72lhh4snxpugek6n83geafue9ndgrhuhzk98xcecu2t3z56ut35mkammsqscqp0n";
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Start a LNURL server answering the callback with `callback_body`,
+    /// returns the lnurlp url
+    async fn mock_lnurl_server(callback_body: String) -> String {
+        mock_slow_lnurl_server(callback_body, Duration::ZERO).await
+    }
+
+    /// Like `mock_lnurl_server` but waiting `delay` before answering the callback
+    async fn mock_slow_lnurl_server(callback_body: String, delay: Duration) -> String {
+        let addr = mock_server(move |request| {
+            let callback_body = callback_body.clone();
+            async move {
+                if request_line(&request).1.starts_with("/callback") {
+                    tokio::time::sleep(delay).await;
+                    return Response::json(200, callback_body);
+                }
+                let host = header(&request, "host").unwrap_or_default();
+                Response::json(
+                    200,
+                    format!(
+                        r#"{{"tag":"payRequest","minSendable":1000,"maxSendable":100000000,"callback":"http://{host}/callback"}}"#
+                    ),
+                )
+            }
+        })
+        .await;
+
+        format!("http://{addr}/.well-known/lnurlp/user")
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_returns_invoice() {
+        let url = mock_lnurl_server(format!(r#"{{"pr":"{INVOICE_50K_SATS}"}}"#)).await;
+        assert_eq!(
+            resolv_lnurl(&url, 50_000, TIMEOUT).await.unwrap(),
+            INVOICE_50K_SATS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_returns_invalid_payload() {
+        for body in [
+            r#"{"pr":"not a bolt11 invoice"}"#.to_string(),
+            r#"{"pr":""}"#.to_string(),
+            r#"{"status":"ERROR"}"#.to_string(),
+        ] {
+            let url = mock_lnurl_server(body).await;
+            assert!(resolv_lnurl(&url, 50_000, TIMEOUT).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_returns_invoice_with_wrong_amount() {
+        let url = mock_lnurl_server(format!(r#"{{"pr":"{INVOICE_50K_SATS}"}}"#)).await;
+        assert!(resolv_lnurl(&url, 10_000, TIMEOUT).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lnurl_server_too_slow() {
+        let url = mock_slow_lnurl_server(
+            format!(r#"{{"pr":"{INVOICE_50K_SATS}"}}"#),
+            Duration::from_secs(5),
+        )
+        .await;
+        let started = std::time::Instant::now();
+        assert!(resolv_lnurl(&url, 50_000, Duration::from_millis(200))
+            .await
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
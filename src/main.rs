@@ -4,33 +4,47 @@ pub mod cli;
 pub mod db;
 pub mod error;
 pub mod flow;
+pub mod http;
 pub mod lightning;
 pub mod lnurl;
 pub mod messages;
-pub mod models;
+pub mod metrics;
+pub mod nip05;
 pub mod nip33;
+pub mod pow;
+#[cfg(feature = "rest-api")]
+pub mod rest_api;
 pub mod scheduler;
+pub mod self_test;
+pub mod shutdown;
 pub mod util;
+pub mod webhook;
 
+use crate::app::release::reconcile_buyer_payments;
 use crate::app::run;
-use crate::cli::settings::{init_global_settings, Settings};
-use crate::cli::settings_init;
+#[cfg(unix)]
+use crate::cli::settings::reload_settings_on_signal;
+use crate::cli::settings::{init_global_settings, LogFormat, Settings};
+use crate::cli::{settings_init, Cli};
 use crate::lightning::LnStatus;
 use anyhow::Result;
+use clap::Parser;
 use db::find_held_invoices;
-use lightning::LndConnector;
+use lightning::backend::connect_backend;
+use lightning::reconcile::{cancel_orphaned_invoices, find_orphaned_invoices};
 use nostr_sdk::prelude::*;
 use scheduler::start_scheduler;
 use std::env;
 use std::process::exit;
 use std::sync::Arc;
 use std::sync::OnceLock;
+use std::sync::RwLock;
 use tokio::sync::Mutex;
 use tracing::{error, info};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
-use util::{get_nostr_client, invoice_subscribe};
+use util::{get_nostr_client, invoice_subscribe, reconcile_settled_orders};
 
-static MOSTRO_CONFIG: OnceLock<Settings> = OnceLock::new();
+static MOSTRO_CONFIG: OnceLock<RwLock<Arc<Settings>>> = OnceLock::new();
 static NOSTR_CLIENT: OnceLock<Client> = OnceLock::new();
 static LN_STATUS: OnceLock<LnStatus> = OnceLock::new();
 
@@ -44,23 +58,58 @@ async fn main() -> Result<()> {
         env::set_var("RUST_LOG", "none,mostro=info");
     }
 
-    // Tracing using RUST_LOG
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
-
     let rate_list: Arc<Mutex<Vec<Event>>> = Arc::new(Mutex::new(vec![]));
 
     // Init path from cli
-    let config_path = settings_init()?;
+    let cli = Cli::parse();
+    let config_path = settings_init(&cli)?;
 
     // Create config global var
-    init_global_settings(Settings::new(config_path)?);
+    init_global_settings(Settings::new(config_path.clone())?);
+
+    // Tracing using RUST_LOG, on the format set in settings
+    let json_logs = Settings::get_mostro().log_format == LogFormat::Json;
+    tracing_subscriber::registry()
+        .with((!json_logs).then(fmt::layer))
+        .with(json_logs.then(|| fmt::layer().json()))
+        .with(EnvFilter::from_default_env())
+        .init();
+
+    // Validate Mostro keys before any trade is processed
+    if let Err(e) = util::init_keys() {
+        error!("{e} - closing Mostro!");
+        exit(1);
+    }
+
+    // Reload settings without restarting on SIGHUP
+    #[cfg(unix)]
+    tokio::spawn(reload_settings_on_signal(config_path));
 
     // Connect to database
     let pool = db::connect().await?;
 
+    // Dry-run trade to catch misconfiguration before going live
+    self_test::startup_self_test(&pool, &Settings::get_mostro()).await?;
+
+    // Operator requested orphaned hold invoices
+    if cli.orphaned_invoices {
+        let mut ln_client = connect_backend().await?;
+        let active_hashes = db::find_active_order_hashes(&pool).await?;
+        let orphaned = find_orphaned_invoices(ln_client.as_mut(), &active_hashes).await?;
+        for invoice in orphaned.iter() {
+            println!(
+                "Hash: {} - Amount: {} sats - State: {:?}",
+                invoice.hash, invoice.amount, invoice.state
+            );
+        }
+        println!("{} orphaned hold invoices found", orphaned.len());
+        if cli.cancel {
+            let canceled = cancel_orphaned_invoices(ln_client.as_mut(), &orphaned).await;
+            println!("{canceled} orphaned hold invoices canceled");
+        }
+        return Ok(());
+    }
+
     // Connect to relays
     // from now unwrap is safe - oncelock inited
     if NOSTR_CLIENT.set(util::connect_nostr().await?).is_err() {
@@ -68,10 +117,10 @@ async fn main() -> Result<()> {
     };
 
     let my_keys = util::get_keys()?;
-    let subscription = Filter::new()
-        .pubkey(my_keys.public_key())
-        .kind(Kind::GiftWrap)
-        .limit(0);
+    let subscription = util::subscription_filter(
+        my_keys.public_key(),
+        &Settings::get_nostr().subscription_kinds,
+    );
 
     let client = match get_nostr_client() {
         Ok(client) => client,
@@ -85,13 +134,22 @@ async fn main() -> Result<()> {
     // Client subscription
     client.subscribe(vec![subscription], None).await?;
 
-    let mut ln_client = LndConnector::new().await?;
+    let mut ln_client = connect_backend().await?;
     let ln_status = ln_client.get_node_info().await?;
-    let ln_status = LnStatus::from_get_info_response(ln_status);
     if LN_STATUS.set(ln_status).is_err() {
         panic!("No connection to LND node - shutting down Mostro!");
     };
 
+    // Recover orders settled on the node but not recorded on database
+    if let Err(e) = reconcile_settled_orders(&pool, ln_client.as_mut(), &my_keys).await {
+        error!("Error reconciling settled orders: {e}");
+    }
+
+    // Finish orders whose buyer payment ended while mostro was down
+    if let Err(e) = reconcile_buyer_payments(&pool).await {
+        error!("Error reconciling buyer payments: {e}");
+    }
+
     if let Ok(held_invoices) = find_held_invoices(&pool).await {
         for invoice in held_invoices.iter() {
             if let Some(hash) = &invoice.hash {
@@ -106,7 +164,40 @@ async fn main() -> Result<()> {
     // Start scheduler for tasks
     start_scheduler(rate_list.clone()).await;
 
-    run(my_keys, client, &mut ln_client, pool, rate_list.clone()).await
+    // Order status notifications for integrators
+    if let Err(e) = webhook::start_webhook() {
+        error!("Error starting webhook notifications: {e}");
+    }
+
+    // Node health metrics and read-only REST API for operators
+    if let Err(e) = http::start_http(pool.clone()).await {
+        error!("Error starting HTTP listener: {e}");
+    }
+
+    // Stop gracefully on SIGINT or SIGTERM
+    tokio::spawn(shutdown::trigger_on_signal());
+
+    run(
+        my_keys,
+        client,
+        ln_client.as_mut(),
+        pool.clone(),
+        rate_list.clone(),
+        shutdown::subscribe(),
+    )
+    .await?;
+
+    // Let the payments in flight finish before exiting
+    if !shutdown::wait_in_flight(std::time::Duration::from_secs(30)).await {
+        error!("Shutting down with payments still in flight");
+    }
+    pool.close().await;
+    if let Err(e) = client.disconnect().await {
+        error!("Error disconnecting from relays: {e}");
+    }
+    info!("Mostro stopped");
+
+    Ok(())
 }
 
 #[cfg(test)]
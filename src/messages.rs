@@ -1,11 +1,221 @@
+use crate::cli::settings::Settings;
 use anyhow::Result;
+use chrono::DateTime;
+use mostro_core::order::Order;
+
+/// Format a sats amount with thousands separators, e.g. `100,000 sats`
+pub fn format_sats(amount: i64) -> String {
+    let digits = amount.unsigned_abs().to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            formatted.push(',');
+        }
+        formatted.push(c);
+    }
+    let sign = if amount < 0 { "-" } else { "" };
+    format!("{sign}{formatted} sats")
+}
+
+/// Format the amount of a trade, with both sats and fiat amounts
+/// e.g. `100,000 sats (USD 50)`
+pub fn format_trade_amount(amount: i64, fiat_code: &str, fiat_amount: &str) -> String {
+    format!("{} ({fiat_code} {fiat_amount})", format_sats(amount))
+}
+
+/// Format the amount of a trade for messages to users, with the sats amount
+/// only if `show_sats`, e.g. `100,000 sats (USD 50)` or `USD 50`
+pub fn display_amount(show_sats: bool, amount: i64, fiat_code: &str, fiat_amount: &str) -> String {
+    if show_sats {
+        format_trade_amount(amount, fiat_code, fiat_amount)
+    } else {
+        format!("{fiat_code} {fiat_amount}")
+    }
+}
+
+/// Format the amount of `order` for messages to users
+pub fn order_amount(order: &Order) -> String {
+    display_amount(
+        Settings::get_mostro().display_sats_amount,
+        order.amount,
+        &order.fiat_code,
+        &order.fiat_amount.to_string(),
+    )
+}
+
+/// Effective price of a trade in fiat per bitcoin, premium included
+pub fn effective_price(amount: i64, fiat_amount: i64) -> Option<f64> {
+    (amount > 0).then(|| fiat_amount as f64 * 1E8 / amount as f64)
+}
+
+/// Format the premium and effective price of a trade
+/// e.g. `Premium 2% - Price USD 51,000/BTC`
+pub fn format_premium_details(premium: i64, price: f64, fiat_code: &str) -> String {
+    let price = format_sats(price.round() as i64).replace(" sats", "");
+    format!("Premium {premium}% - Price {fiat_code} {price}/BTC")
+}
 
 pub fn hold_invoice_description(
     order_id: &str,
+    amount: i64,
     fiat_code: &str,
     fiat_amount: &str,
+    premium_details: Option<String>,
 ) -> Result<String> {
+    let mut trade = if Settings::get_mostro().display_sats_amount {
+        format!(
+            "SELL {}",
+            format_trade_amount(amount, fiat_code, fiat_amount)
+        )
+    } else {
+        format!("SELL BTC for {fiat_code} {fiat_amount}")
+    };
+    if let Some(details) = premium_details {
+        trade = format!("{trade} - {details}");
+    }
     Ok(format!(
-        "Escrow amount Order #{order_id}: SELL BTC for {fiat_code} {fiat_amount} - It WILL FREEZE IN WALLET. It will release once you release. It will return if buyer does not confirm the payment"
+        "Escrow amount Order #{order_id}: {trade} - It WILL FREEZE IN WALLET. It will release once you release. It will return if buyer does not confirm the payment"
     ))
 }
+
+/// Append the operator support contact to a message, if set
+pub fn with_operator_contact(text: &str, contact: &str) -> String {
+    match contact.trim() {
+        "" => text.to_string(),
+        contact => format!("{text} - {contact}"),
+    }
+}
+
+/// Message sent to the parties once a dispute is opened
+pub fn dispute_opened_message(amount: &str, contact: &str) -> String {
+    with_operator_contact(
+        &format!(
+            "Dispute opened for the trade of {amount}, a solver will take it and contact you soon"
+        ),
+        contact,
+    )
+}
+
+/// Format a unix timestamp as a UTC date and time, e.g. `2023-11-14 22:13:20 UTC`
+pub fn format_timestamp(timestamp: i64) -> String {
+    match DateTime::from_timestamp(timestamp, 0) {
+        Some(date) => date.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// Message sent to the buyer when the payment of an order failed
+pub fn payment_failed_message(amount: &str, next_retry: i64, contact: &str) -> String {
+    with_operator_contact(
+        &format!(
+            "Payment of {amount} failed, next retry at {}",
+            format_timestamp(next_retry)
+        ),
+        contact,
+    )
+}
+
+/// Message sent to the buyer once the seller is asked to pay the hold invoice
+/// of a taken order
+pub fn waiting_seller_to_pay_message(premium_details: &str) -> String {
+    format!("Order taken, waiting for the seller to pay - {premium_details}")
+}
+
+/// Message sent to the maker when a taker is interested in the order
+pub fn order_interest_message(amount: &str) -> String {
+    format!("A taker is interested in your order of {amount}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_sats() {
+        assert_eq!(format_sats(0), "0 sats");
+        assert_eq!(format_sats(999), "999 sats");
+        assert_eq!(format_sats(1_000), "1,000 sats");
+        assert_eq!(format_sats(100_000), "100,000 sats");
+        assert_eq!(format_sats(1_234_567), "1,234,567 sats");
+        assert_eq!(format_sats(-5_000), "-5,000 sats");
+    }
+
+    #[test]
+    fn test_format_trade_amount() {
+        let amount = format_trade_amount(150_000, "USD", "100");
+        assert_eq!(amount, "150,000 sats (USD 100)");
+        assert!(amount.contains("150,000 sats"));
+        assert!(amount.contains("USD 100"));
+    }
+
+    #[test]
+    fn test_premium_details() {
+        // 100 USD for 200,000 sats
+        let price = effective_price(200_000, 100).unwrap();
+        assert_eq!(price, 50_000.0);
+        assert_eq!(
+            format_premium_details(2, price, "USD"),
+            "Premium 2% - Price USD 50,000/BTC"
+        );
+        assert_eq!(effective_price(0, 100), None);
+    }
+
+    #[test]
+    fn test_premium_details_in_take_confirmation() {
+        let price = effective_price(200_000, 100).unwrap();
+        let message = waiting_seller_to_pay_message(&format_premium_details(2, price, "USD"));
+        assert!(message.contains("Premium 2%"));
+        assert!(message.contains("Price USD 50,000/BTC"));
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13:20 UTC");
+        // Out of the range of dates
+        assert_eq!(format_timestamp(i64::MAX), i64::MAX.to_string());
+    }
+
+    #[test]
+    fn test_display_amount() {
+        assert_eq!(
+            display_amount(true, 150_000, "USD", "100"),
+            "150,000 sats (USD 100)"
+        );
+        assert_eq!(display_amount(false, 150_000, "USD", "100"), "USD 100");
+    }
+
+    #[test]
+    fn test_amounts_in_messages() {
+        let amount = display_amount(true, 150_000, "USD", "100");
+        for message in [
+            dispute_opened_message(&amount, ""),
+            payment_failed_message(&amount, 1_700_000_000, ""),
+            order_interest_message(&amount),
+        ] {
+            assert!(message.contains("150,000 sats"));
+            assert!(message.contains("USD 100"));
+        }
+    }
+
+    #[test]
+    fn test_operator_contact_in_messages() {
+        let contact = "Support: support@example.com";
+        assert!(dispute_opened_message("USD 100", contact).ends_with(contact));
+        let message = payment_failed_message("USD 100", 1_700_000_000, contact);
+        assert!(message.contains("2023-11-14 22:13:20 UTC"));
+        assert!(message.ends_with(contact));
+    }
+
+    #[test]
+    fn test_messages_without_operator_contact() {
+        assert_eq!(
+            payment_failed_message("USD 100", 1_700_000_000, ""),
+            "Payment of USD 100 failed, next retry at 2023-11-14 22:13:20 UTC"
+        );
+        assert_eq!(
+            dispute_opened_message("USD 100", " "),
+            "Dispute opened for the trade of USD 100, a solver will take it and contact you soon"
+        );
+    }
+}
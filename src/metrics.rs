@@ -0,0 +1,237 @@
+//! Node health metrics exposed at `/metrics` in Prometheus text format,
+//! served by the HTTP listener when `metrics_enabled` is set
+
+use crate::db::count_orders_by_status;
+use crate::http::{request_line, Response};
+use crate::util::{relay_publish_stats, RelayPublishStats};
+
+use mostro_core::order::Status;
+use sqlx::SqlitePool;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::error;
+
+/// Counters of the node events since start
+pub enum Counter {
+    OrdersCreated,
+    OrdersSettled,
+    PaymentsSucceeded,
+    PaymentsFailed,
+    DisputesOpened,
+}
+
+static ORDERS_CREATED: AtomicU64 = AtomicU64::new(0);
+static ORDERS_SETTLED: AtomicU64 = AtomicU64::new(0);
+static PAYMENTS_SUCCEEDED: AtomicU64 = AtomicU64::new(0);
+static PAYMENTS_FAILED: AtomicU64 = AtomicU64::new(0);
+static DISPUTES_OPENED: AtomicU64 = AtomicU64::new(0);
+
+impl Counter {
+    fn value(&self) -> &'static AtomicU64 {
+        match self {
+            Counter::OrdersCreated => &ORDERS_CREATED,
+            Counter::OrdersSettled => &ORDERS_SETTLED,
+            Counter::PaymentsSucceeded => &PAYMENTS_SUCCEEDED,
+            Counter::PaymentsFailed => &PAYMENTS_FAILED,
+            Counter::DisputesOpened => &DISPUTES_OPENED,
+        }
+    }
+}
+
+pub fn increment(counter: Counter) {
+    counter.value().fetch_add(1, Ordering::Relaxed);
+}
+
+/// Gauges read from database when metrics are requested
+#[derive(Debug, Default)]
+struct Gauges {
+    active_orders: usize,
+    pending_hold_invoices: usize,
+}
+
+impl Gauges {
+    /// Gauges from the count of orders by status and of those with a hold invoice
+    fn from_counts(counts: &[(String, i64, i64)]) -> Self {
+        let mut gauges = Gauges::default();
+        for (status, orders, with_hash) in counts {
+            match Status::from_str(status) {
+                Ok(
+                    Status::Pending
+                    | Status::WaitingBuyerInvoice
+                    | Status::WaitingPayment
+                    | Status::SettledHoldInvoice,
+                ) => gauges.active_orders += *orders as usize,
+                Ok(Status::Active | Status::FiatSent | Status::Dispute) => {
+                    gauges.active_orders += *orders as usize;
+                    gauges.pending_hold_invoices += *with_hash as usize;
+                }
+                _ => {}
+            }
+        }
+        gauges
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl ToString) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {}", value.to_string());
+}
+
+/// Render the metrics in Prometheus text format
+fn render_metrics(gauges: &Gauges, relays: &[(String, RelayPublishStats)]) -> String {
+    let mut out = String::new();
+    for (name, help, counter) in [
+        (
+            "mostro_orders_created_total",
+            "Orders created",
+            Counter::OrdersCreated,
+        ),
+        (
+            "mostro_orders_settled_total",
+            "Orders with the seller hold invoice settled",
+            Counter::OrdersSettled,
+        ),
+        (
+            "mostro_payments_succeeded_total",
+            "Payments to buyers succeeded",
+            Counter::PaymentsSucceeded,
+        ),
+        (
+            "mostro_payments_failed_total",
+            "Payments to buyers failed",
+            Counter::PaymentsFailed,
+        ),
+        (
+            "mostro_disputes_opened_total",
+            "Disputes opened",
+            Counter::DisputesOpened,
+        ),
+    ] {
+        let value = counter.value().load(Ordering::Relaxed);
+        write_metric(&mut out, name, "counter", help, value);
+    }
+    write_metric(
+        &mut out,
+        "mostro_active_orders",
+        "gauge",
+        "Orders not finished yet",
+        gauges.active_orders,
+    );
+    write_metric(
+        &mut out,
+        "mostro_pending_hold_invoices",
+        "gauge",
+        "Hold invoices paid by sellers and not settled nor canceled yet",
+        gauges.pending_hold_invoices,
+    );
+    let name = "mostro_relay_publish_total";
+    let _ = writeln!(out, "# HELP {name} Status events published to each relay");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for (relay, stats) in relays {
+        for (result, value) in [("succeeded", stats.succeeded), ("failed", stats.failed)] {
+            let _ = writeln!(
+                out,
+                "{name}{{relay=\"{relay}\",result=\"{result}\"}} {value}"
+            );
+        }
+    }
+
+    out
+}
+
+/// Serve the metrics, the gauges are counted when requested
+pub async fn handle_request(request: &str, pool: &SqlitePool) -> Response {
+    let (method, _) = request_line(request);
+    if method != "GET" {
+        return Response::json(405, r#"{"error":"method not allowed"}"#.to_string());
+    }
+    let gauges = match count_orders_by_status(pool).await {
+        Ok(counts) => Gauges::from_counts(&counts),
+        Err(e) => {
+            error!("Metrics: {e}");
+            Gauges::default()
+        }
+    };
+    Response {
+        status: 200,
+        content_type: "text/plain; version=0.0.4",
+        body: render_metrics(&gauges, &relay_publish_stats()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric_value(metrics: &str, name: &str) -> u64 {
+        metrics
+            .lines()
+            .find_map(|l| l.strip_prefix(&format!("{name} ")))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_counters_rendered() {
+        let before = render_metrics(&Gauges::default(), &[]);
+        increment(Counter::DisputesOpened);
+        increment(Counter::PaymentsFailed);
+        let after = render_metrics(&Gauges::default(), &[]);
+        for name in [
+            "mostro_disputes_opened_total",
+            "mostro_payments_failed_total",
+        ] {
+            assert!(metric_value(&after, name) > metric_value(&before, name));
+        }
+        assert!(after.contains("# TYPE mostro_orders_created_total counter"));
+    }
+
+    #[test]
+    fn test_gauges_rendered() {
+        let metrics = render_metrics(
+            &Gauges {
+                active_orders: 7,
+                pending_hold_invoices: 3,
+            },
+            &[],
+        );
+        assert!(metrics.contains("# TYPE mostro_active_orders gauge"));
+        assert_eq!(metric_value(&metrics, "mostro_active_orders"), 7);
+        assert_eq!(metric_value(&metrics, "mostro_pending_hold_invoices"), 3);
+    }
+
+    #[test]
+    fn test_gauges_from_counts() {
+        let counts = [
+            (Status::Pending.to_string(), 4, 0),
+            (Status::Active.to_string(), 3, 2),
+            (Status::Dispute.to_string(), 1, 1),
+            (Status::Success.to_string(), 10, 10),
+        ];
+        let gauges = Gauges::from_counts(&counts);
+        assert_eq!(gauges.active_orders, 8);
+        assert_eq!(gauges.pending_hold_invoices, 3);
+    }
+
+    #[test]
+    fn test_relay_publish_rendered() {
+        let relays = [(
+            "wss://relay.example".to_string(),
+            RelayPublishStats {
+                succeeded: 4,
+                failed: 1,
+            },
+        )];
+        let metrics = render_metrics(&Gauges::default(), &relays);
+        assert!(metrics.contains("# TYPE mostro_relay_publish_total counter"));
+        assert!(metrics.contains(
+            "mostro_relay_publish_total{relay=\"wss://relay.example\",result=\"succeeded\"} 4"
+        ));
+        assert!(metrics.contains(
+            "mostro_relay_publish_total{relay=\"wss://relay.example\",result=\"failed\"} 1"
+        ));
+    }
+}
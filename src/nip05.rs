@@ -0,0 +1,288 @@
+//! NIP-05 verification of takers, makers can require the taker identity to
+//! resolve to a NIP-05 identifier as a light sybil deterrent
+
+use crate::cli::settings::Settings;
+use anyhow::Result;
+use nostr_sdk::PublicKey;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Time a NIP-05 server has to answer
+const NIP05_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds a failed verification is cached, the taker can fix the
+/// identifier and retry without waiting for `nip05_cache_seconds`
+const NIP05_FAILED_CACHE_SECONDS: i64 = 60;
+
+/// Verification results by pubkey and identifier with the time they expire
+static NIP05_CACHE: Lazy<Mutex<HashMap<(PublicKey, String), (bool, i64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Url of the `nostr.json` document of a `name@domain` identifier and the name
+fn nip05_url(identifier: &str) -> Option<(String, String)> {
+    let (name, domain) = identifier.split_once('@')?;
+    if name.is_empty() || domain.is_empty() || domain.contains(['/', ':', '@', '?', '#']) {
+        return None;
+    }
+    let name = name.to_lowercase();
+
+    Some((
+        format!("https://{domain}/.well-known/nostr.json?name={name}"),
+        name,
+    ))
+}
+
+/// Check an address can be reached from the internet, the NIP-05 server
+/// can't be used to reach the Mostro host or its private network
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(&IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    // Link local, fe80::/10
+                    || ip.segments()[0] & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolve the domain of a NIP-05 identifier to a public address, `None` if
+/// it doesn't resolve or any of its addresses is not public
+async fn resolve_public_addr(domain: &str) -> Option<SocketAddr> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, 443)).await.ok()?.collect();
+    if addrs.iter().any(|addr| !is_public_ip(&addr.ip())) {
+        return None;
+    }
+
+    addrs.into_iter().next()
+}
+
+/// Client to request a `nostr.json` document, redirects are not followed as
+/// NIP-05 requires. With `pinned` the domain is only connected to the
+/// address it was checked to resolve to
+fn nip05_client(
+    timeout: Duration,
+    pinned: Option<(&str, SocketAddr)>,
+) -> reqwest::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some((domain, addr)) = pinned {
+        builder = builder.resolve(domain, addr);
+    }
+    builder.build()
+}
+
+/// Check the `nostr.json` document at `url` maps `name` to `pubkey`
+async fn verify_nip05_at(
+    client: &reqwest::Client,
+    url: &str,
+    name: &str,
+    pubkey: &PublicKey,
+) -> Result<bool> {
+    let res = client.get(url).send().await?;
+    if !res.status().is_success() {
+        return Ok(false);
+    }
+    let body: Value = serde_json::from_str(&res.text().await?)?;
+
+    Ok(body["names"][name].as_str() == Some(pubkey.to_hex().as_str()))
+}
+
+fn cached_nip05(pubkey: &PublicKey, identifier: &str, now: i64) -> Option<bool> {
+    let cache = NIP05_CACHE.lock().unwrap();
+    match cache.get(&(*pubkey, identifier.to_string())) {
+        Some((verified, expires_at)) if *expires_at > now => Some(*verified),
+        _ => None,
+    }
+}
+
+fn cache_nip05(pubkey: &PublicKey, identifier: &str, verified: bool, now: i64, ttl: i64) {
+    let mut cache = NIP05_CACHE.lock().unwrap();
+    // Expired results are dropped so the cache doesn't grow forever
+    cache.retain(|_, (_, expires_at)| *expires_at > now);
+    cache.insert((*pubkey, identifier.to_string()), (verified, now + ttl));
+}
+
+/// Seconds a verification result is cached, failures only for a short time
+fn nip05_cache_ttl(verified: bool, cache_seconds: i64) -> i64 {
+    if verified {
+        cache_seconds
+    } else {
+        cache_seconds.min(NIP05_FAILED_CACHE_SECONDS)
+    }
+}
+
+/// Verify `identifier` resolves to `pubkey`, verified identifiers are cached
+/// for `nip05_cache_seconds`. Unreachable servers and identifiers of
+/// private hosts count as not verified and are not cached
+pub async fn verify_nip05(pubkey: &PublicKey, identifier: &str, now: i64) -> bool {
+    if let Some(verified) = cached_nip05(pubkey, identifier, now) {
+        return verified;
+    }
+    let Some((url, name)) = nip05_url(identifier) else {
+        return false;
+    };
+    let Some((_, domain)) = identifier.split_once('@') else {
+        return false;
+    };
+    let Some(addr) = resolve_public_addr(domain).await else {
+        tracing::info!("NIP-05 {identifier} not verified: domain not public");
+        return false;
+    };
+    let verified = match nip05_client(NIP05_TIMEOUT, Some((domain, addr))) {
+        Ok(client) => verify_nip05_at(&client, &url, &name, pubkey).await,
+        Err(e) => Err(e.into()),
+    };
+    let verified = match verified {
+        Ok(verified) => verified,
+        Err(e) => {
+            tracing::info!("NIP-05 {identifier} not verified: {e}");
+            return false;
+        }
+    };
+    let ttl = nip05_cache_ttl(verified, Settings::get_mostro().nip05_cache_seconds as i64);
+    cache_nip05(pubkey, identifier, verified, now, ttl);
+
+    verified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::mock_server;
+    use crate::http::Response;
+    use nostr_sdk::Keys;
+
+    /// Start a NIP-05 server answering with `body` after `delay`, returns the
+    /// `nostr.json` url
+    async fn mock_nip05_server(body: String, delay: Duration) -> String {
+        let addr = mock_server(move |_| {
+            let body = body.clone();
+            async move {
+                tokio::time::sleep(delay).await;
+                Response::json(200, body)
+            }
+        })
+        .await;
+
+        format!("http://{addr}/.well-known/nostr.json?name=alice")
+    }
+
+    #[test]
+    fn test_nip05_url() {
+        assert_eq!(
+            nip05_url("Alice@example.com"),
+            Some((
+                "https://example.com/.well-known/nostr.json?name=alice".to_string(),
+                "alice".to_string()
+            ))
+        );
+        assert_eq!(nip05_url("example.com"), None);
+        assert_eq!(nip05_url("@example.com"), None);
+        assert_eq!(nip05_url("alice@example.com/path"), None);
+        assert_eq!(nip05_url("alice@localhost:8080"), None);
+        assert_eq!(nip05_url("alice@bob@example.com"), None);
+    }
+
+    #[test]
+    fn test_private_hosts_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(&ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public_ip(&ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loopback_domain_not_resolved() {
+        assert_eq!(resolve_public_addr("localhost").await, None);
+        assert_eq!(resolve_public_addr("127.0.0.1").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_verify_nip05() {
+        let keys = Keys::generate();
+        let body = format!(
+            r#"{{"names":{{"alice":"{}"}}}}"#,
+            keys.public_key().to_hex()
+        );
+        let url = mock_nip05_server(body, Duration::ZERO).await;
+        let client = nip05_client(NIP05_TIMEOUT, None).unwrap();
+        assert!(verify_nip05_at(&client, &url, "alice", &keys.public_key())
+            .await
+            .unwrap());
+        // Someone else's identifier
+        let stranger = Keys::generate().public_key();
+        assert!(!verify_nip05_at(&client, &url, "alice", &stranger)
+            .await
+            .unwrap());
+        assert!(!verify_nip05_at(&client, &url, "bob", &keys.public_key())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_verify_nip05_server_too_slow() {
+        let keys = Keys::generate();
+        let body = format!(
+            r#"{{"names":{{"alice":"{}"}}}}"#,
+            keys.public_key().to_hex()
+        );
+        let url = mock_nip05_server(body, Duration::from_secs(5)).await;
+        let client = nip05_client(Duration::from_millis(200), None).unwrap();
+        assert!(verify_nip05_at(&client, &url, "alice", &keys.public_key())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_nip05_cache_expires() {
+        let pubkey = Keys::generate().public_key();
+        cache_nip05(&pubkey, "alice@example.com", true, 0, 1_000);
+        assert_eq!(cached_nip05(&pubkey, "alice@example.com", 999), Some(true));
+        assert_eq!(cached_nip05(&pubkey, "bob@example.com", 999), None);
+        // Resolved again once expired
+        assert_eq!(cached_nip05(&pubkey, "alice@example.com", 1_000), None);
+    }
+
+    #[test]
+    fn test_failed_nip05_cached_briefly() {
+        assert_eq!(nip05_cache_ttl(true, 3_600), 3_600);
+        assert_eq!(nip05_cache_ttl(false, 3_600), NIP05_FAILED_CACHE_SECONDS);
+        assert_eq!(nip05_cache_ttl(false, 10), 10);
+    }
+}
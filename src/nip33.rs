@@ -1,12 +1,19 @@
+use crate::cli::settings::Delegation;
 use crate::lightning::LnStatus;
+use crate::messages::effective_price;
 use crate::Settings;
 use chrono::Duration;
 use mostro_core::order::{Order, Status};
 use mostro_core::rating::Rating;
+use mostro_core::user::User;
 use mostro_core::NOSTR_REPLACEABLE_EVENT_KIND;
 use nostr::event::builder::Error;
+use nostr::hashes::{sha256, Hash};
+use nostr::secp256k1::schnorr::Signature;
+use nostr::secp256k1::{Message, Secp256k1, XOnlyPublicKey};
 use nostr_sdk::prelude::*;
 use std::borrow::Cow;
+use std::str::FromStr;
 use std::vec;
 
 /// Creates a new mostro nip33 event
@@ -27,16 +34,102 @@ pub fn new_event(
     identifier: String,
     extra_tags: Tags,
 ) -> Result<Event, Error> {
-    let mut tags: Vec<Tag> = Vec::with_capacity(1 + extra_tags.len());
+    let delegation = Settings::get_nostr().delegation;
+    build_event(keys, content, identifier, extra_tags, delegation.as_ref())
+}
+
+/// Creates a new mostro nip33 event, with a NIP-26 delegation tag if
+/// `delegation` is valid for the event
+fn build_event(
+    keys: &Keys,
+    content: &str,
+    identifier: String,
+    extra_tags: Tags,
+    delegation: Option<&Delegation>,
+) -> Result<Event, Error> {
+    let created_at = Timestamp::now();
+    let mut tags: Vec<Tag> = Vec::with_capacity(2 + extra_tags.len());
     tags.push(Tag::identifier(identifier));
     tags.extend(extra_tags);
+    if let Some(delegation) = delegation {
+        match create_delegation_tag(
+            delegation,
+            &keys.public_key(),
+            NOSTR_REPLACEABLE_EVENT_KIND,
+            created_at.as_u64(),
+        ) {
+            Some(tag) => tags.push(tag),
+            None => tracing::warn!("Delegation not valid for the event, not added"),
+        }
+    }
     let tags = Tags::new(tags);
 
     EventBuilder::new(Kind::Custom(NOSTR_REPLACEABLE_EVENT_KIND), content)
         .tags(tags)
+        .custom_created_at(created_at)
         .sign_with_keys(keys)
 }
 
+/// Check the NIP-26 conditions, only `kind` and `created_at` ones are supported
+fn delegation_conditions_met(conditions: &str, kind: u16, created_at: u64) -> bool {
+    conditions.split('&').all(|condition| {
+        if let Some(k) = condition.strip_prefix("kind=") {
+            k.parse::<u16>() == Ok(kind)
+        } else if let Some(t) = condition.strip_prefix("created_at<") {
+            t.parse::<u64>().is_ok_and(|t| created_at < t)
+        } else if let Some(t) = condition.strip_prefix("created_at>") {
+            t.parse::<u64>().is_ok_and(|t| created_at > t)
+        } else {
+            false
+        }
+    })
+}
+
+/// Check the delegator signed the delegation of `delegatee` with the conditions
+fn verify_delegation(delegation: &Delegation, delegatee: &PublicKey) -> bool {
+    let (Ok(delegator), Ok(token)) = (
+        XOnlyPublicKey::from_str(&delegation.delegator_pubkey),
+        Signature::from_str(&delegation.token),
+    ) else {
+        return false;
+    };
+    let delegation_string = format!(
+        "nostr:delegation:{}:{}",
+        delegatee.to_hex(),
+        delegation.conditions
+    );
+    let hash = sha256::Hash::hash(delegation_string.as_bytes());
+    let message = Message::from_digest(hash.to_byte_array());
+
+    Secp256k1::verification_only()
+        .verify_schnorr(&token, &message, &delegator)
+        .is_ok()
+}
+
+/// NIP-26 delegation tag, only if the delegation is valid for an event
+/// of `kind` created at `created_at` by `delegatee`
+fn create_delegation_tag(
+    delegation: &Delegation,
+    delegatee: &PublicKey,
+    kind: u16,
+    created_at: u64,
+) -> Option<Tag> {
+    if !delegation_conditions_met(&delegation.conditions, kind, created_at)
+        || !verify_delegation(delegation, delegatee)
+    {
+        return None;
+    }
+
+    Some(Tag::custom(
+        TagKind::Custom(Cow::Borrowed("delegation")),
+        vec![
+            delegation.delegator_pubkey.clone(),
+            delegation.conditions.clone(),
+            delegation.token.clone(),
+        ],
+    ))
+}
+
 fn create_rating_string(rating: Option<Rating>) -> String {
     if let Some(rating) = rating {
         if let Ok(rating_json) = rating.as_json() {
@@ -64,6 +157,16 @@ fn create_fiat_amt_array(order: &Order) -> Vec<String> {
     }
 }
 
+/// Fee tag of the order, only if the fee is disclosed in public events
+fn create_fee_tag(order: &Order, publish_fee: bool) -> Option<Tag> {
+    publish_fee.then(|| {
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("fee")),
+            vec![order.fee.to_string()],
+        )
+    })
+}
+
 /// Transform an order fields to tags
 ///
 /// # Arguments
@@ -71,7 +174,7 @@ fn create_fiat_amt_array(order: &Order) -> Vec<String> {
 /// * `order` - The order to transform
 ///
 pub fn order_to_tags(order: &Order, reputation: Option<Rating>) -> Tags {
-    let tags: Vec<Tag> = vec![
+    let mut tags: Vec<Tag> = vec![
         Tag::custom(
             TagKind::Custom(Cow::Borrowed("k")),
             vec![order.kind.to_string()],
@@ -125,10 +228,57 @@ pub fn order_to_tags(order: &Order, reputation: Option<Rating>) -> Tags {
             vec!["order".to_string()],
         ),
     ];
+    let mostro_settings = Settings::get_mostro();
+    tags.extend(create_fee_tag(order, mostro_settings.publish_fee));
+    // Effective price once the sats amount is known, premium included
+    if mostro_settings.show_premium_details {
+        if let Some(price) = effective_price(order.amount, order.fiat_amount) {
+            tags.push(Tag::custom(
+                TagKind::Custom(Cow::Borrowed("price")),
+                vec![format!("{price:.2}")],
+            ));
+        }
+    }
 
     Tags::new(tags)
 }
 
+/// Transform a solver fields to the tags of its reputation event, the role
+/// tag lets clients recognize the solvers authorized by this Mostro
+///
+/// # Arguments
+///
+/// * `user` - The solver
+///
+pub fn solver_to_tags(user: &User) -> Tags {
+    let reputation = Rating::new(
+        user.total_reviews as u64,
+        user.total_rating,
+        user.last_rating as u8,
+        user.min_rating as u8,
+        user.max_rating as u8,
+    );
+
+    Tags::new(vec![
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("role")),
+            vec!["solver".to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("rating")),
+            vec![create_rating_string(Some(reputation))],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("y")),
+            vec!["mostro".to_string()],
+        ),
+        Tag::custom(
+            TagKind::Custom(Cow::Borrowed("z")),
+            vec!["rating".to_string()],
+        ),
+    ])
+}
+
 /// Transform mostro info fields to tags
 ///
 /// # Arguments
@@ -169,7 +319,7 @@ pub fn info_to_tags(ln_status: &LnStatus) -> Tags {
         ),
         Tag::custom(
             TagKind::Custom(Cow::Borrowed("pow")),
-            vec![mostro_settings.pow.to_string()],
+            vec![crate::pow::required_pow(mostro_settings.pow).to_string()],
         ),
         Tag::custom(
             TagKind::Custom(Cow::Borrowed("hold_invoice_expiration_window")),
@@ -223,3 +373,127 @@ pub fn info_to_tags(ln_status: &LnStatus) -> Tags {
 
     tags
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order_event(publish_fee: bool) -> Event {
+        let order = Order {
+            fee: 300,
+            ..Default::default()
+        };
+        let tags = Tags::new(create_fee_tag(&order, publish_fee).into_iter().collect());
+        build_event(&Keys::generate(), "", order.id.to_string(), tags, None).unwrap()
+    }
+
+    fn fee_tag(event: &Event) -> Option<Vec<String>> {
+        event
+            .tags
+            .iter()
+            .find(|t| t.kind() == TagKind::Custom(Cow::Borrowed("fee")))
+            .map(|t| t.clone().to_vec())
+    }
+
+    #[test]
+    fn test_order_event_with_fee_disclosed() {
+        let event = order_event(true);
+        assert_eq!(
+            fee_tag(&event),
+            Some(vec!["fee".to_string(), "300".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_order_event_without_fee_disclosed() {
+        let event = order_event(false);
+        assert_eq!(fee_tag(&event), None);
+    }
+
+    #[test]
+    fn test_solver_reputation_event() {
+        let keys = Keys::generate();
+        let solver = Keys::generate().public_key().to_hex();
+        let user = User::new(solver.clone(), 0, 1, 0, 0, 0);
+        let event = build_event(&keys, "", solver.clone(), solver_to_tags(&user), None).unwrap();
+
+        assert_eq!(event.kind, Kind::Custom(NOSTR_REPLACEABLE_EVENT_KIND));
+        assert_eq!(event.tags.identifier(), Some(solver.as_str()));
+        let role = event
+            .tags
+            .iter()
+            .find(|t| t.kind() == TagKind::Custom(Cow::Borrowed("role")))
+            .map(|t| t.clone().to_vec());
+        assert_eq!(role, Some(vec!["role".to_string(), "solver".to_string()]));
+    }
+
+    fn delegation(delegator: &Keys, delegatee: &Keys, conditions: &str) -> Delegation {
+        let delegation_string = format!(
+            "nostr:delegation:{}:{}",
+            delegatee.public_key().to_hex(),
+            conditions
+        );
+        let hash = sha256::Hash::hash(delegation_string.as_bytes());
+        let token = delegator.sign_schnorr(&Message::from_digest(hash.to_byte_array()));
+        Delegation {
+            delegator_pubkey: delegator.public_key().to_hex(),
+            conditions: conditions.to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    fn delegation_tag(event: &Event) -> Option<Vec<String>> {
+        event
+            .tags
+            .iter()
+            .find(|t| t.kind() == TagKind::Custom(Cow::Borrowed("delegation")))
+            .map(|t| t.clone().to_vec())
+    }
+
+    #[test]
+    fn test_event_with_delegation() {
+        let delegator = Keys::generate();
+        let keys = Keys::generate();
+        let conditions = format!("kind={NOSTR_REPLACEABLE_EVENT_KIND}&created_at>1700000000");
+        let delegation = delegation(&delegator, &keys, &conditions);
+        let event = build_event(
+            &keys,
+            "",
+            "id".to_string(),
+            Tags::new(vec![]),
+            Some(&delegation),
+        )
+        .unwrap();
+
+        let tag = delegation_tag(&event).unwrap();
+        assert_eq!(tag[1], delegator.public_key().to_hex());
+        assert_eq!(tag[2], conditions);
+        assert!(verify_delegation(&delegation, &event.pubkey));
+        assert!(event.verify().is_ok());
+    }
+
+    #[test]
+    fn test_event_without_valid_delegation() {
+        let delegator = Keys::generate();
+        let keys = Keys::generate();
+        // Delegation to other key
+        let other = delegation(&delegator, &Keys::generate(), "kind=38383");
+        let event =
+            build_event(&keys, "", "id".to_string(), Tags::new(vec![]), Some(&other)).unwrap();
+        assert_eq!(delegation_tag(&event), None);
+        // Delegation expired
+        let expired = delegation(&delegator, &keys, "kind=38383&created_at<1700000000");
+        let event = build_event(
+            &keys,
+            "",
+            "id".to_string(),
+            Tags::new(vec![]),
+            Some(&expired),
+        )
+        .unwrap();
+        assert_eq!(delegation_tag(&event), None);
+        // Not configured
+        let event = build_event(&keys, "", "id".to_string(), Tags::new(vec![]), None).unwrap();
+        assert_eq!(delegation_tag(&event), None);
+    }
+}
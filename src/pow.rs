@@ -0,0 +1,131 @@
+//! Adaptive proof of work, the POW required to incoming events rises while
+//! the event rate is over a threshold and relaxes when traffic subsides
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use tokio::sync::Notify;
+use tracing::info;
+
+/// POW raised by the adaptive POW over the configured one, published in the
+/// info event so clients know the difficulty their events must meet
+static RAISED_POW: AtomicU8 = AtomicU8::new(0);
+static POW_CHANGED: Notify = Notify::const_new();
+
+/// Record the POW required now, the info event is published again if it changed
+pub fn set_required_pow(pow: u8) {
+    if RAISED_POW.swap(pow, Ordering::SeqCst) != pow {
+        POW_CHANGED.notify_one();
+    }
+}
+
+/// POW required now, never under the configured `pow`
+pub fn required_pow(pow: u8) -> u8 {
+    RAISED_POW.load(Ordering::SeqCst).max(pow)
+}
+
+/// Wait until the required POW changes
+pub async fn pow_changed() {
+    POW_CHANGED.notified().await
+}
+
+/// POW required to incoming events adjusted once per window of `window`
+/// seconds between `floor` and `ceiling`, a window with more than
+/// `threshold` events adds a bit, one with less than half of them removes one
+#[derive(Debug)]
+pub struct AdaptivePow {
+    floor: u8,
+    ceiling: u8,
+    threshold: usize,
+    window: i64,
+    required: u8,
+    window_start: i64,
+    events: usize,
+}
+
+impl AdaptivePow {
+    pub fn new(floor: u8, ceiling: u8, threshold: usize, window: i64, now: i64) -> Self {
+        Self {
+            floor,
+            ceiling: ceiling.max(floor),
+            threshold,
+            window: window.max(1),
+            required: floor,
+            window_start: now,
+            events: 0,
+        }
+    }
+
+    /// Record an event received at `now` and get the POW it must meet
+    pub fn record(&mut self, now: i64) -> u8 {
+        let elapsed = (now - self.window_start) / self.window;
+        if elapsed > 0 {
+            let previous = self.required;
+            if self.events > self.threshold {
+                self.required = self.required.saturating_add(1).min(self.ceiling);
+            } else if self.events * 2 < self.threshold {
+                self.required = self.required.saturating_sub(1).max(self.floor);
+            }
+            // Windows without any event relax it too
+            let quiet = u8::try_from(elapsed - 1).unwrap_or(u8::MAX);
+            self.required = self.required.saturating_sub(quiet).max(self.floor);
+            if self.required != previous {
+                info!(
+                    "Required POW changed from {previous} to {} after {} events",
+                    self.required, self.events
+                );
+            }
+            self.window_start += elapsed * self.window;
+            self.events = 0;
+        }
+        self.events += 1;
+
+        self.required
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send `events` events spread in the window starting at `start`
+    fn burst(pow: &mut AdaptivePow, start: i64, events: usize) -> u8 {
+        (0..events).fold(0, |_, i| {
+            pow.record(start + (i as i64 * 60) / events as i64)
+        })
+    }
+
+    #[test]
+    fn test_pow_rises_under_load_then_falls() {
+        // 100 events per minute, between 0 and 3 bits
+        let mut pow = AdaptivePow::new(0, 3, 100, 60, 0);
+        burst(&mut pow, 0, 500);
+        assert_eq!(pow.record(60), 1);
+        burst(&mut pow, 60, 500);
+        burst(&mut pow, 120, 500);
+        burst(&mut pow, 180, 500);
+        // Never over the ceiling
+        assert_eq!(burst(&mut pow, 240, 500), 3);
+
+        // Traffic subsides
+        burst(&mut pow, 300, 10);
+        assert_eq!(pow.record(360), 2);
+        // Long quiet period back to the floor
+        assert_eq!(pow.record(3600), 0);
+    }
+
+    #[test]
+    fn test_pow_steady_under_threshold() {
+        let mut pow = AdaptivePow::new(5, 10, 100, 60, 0);
+        for window in 0..10 {
+            assert_eq!(burst(&mut pow, window * 60, 80), 5);
+        }
+    }
+
+    #[test]
+    fn test_published_pow_never_under_configured() {
+        set_required_pow(12);
+        assert_eq!(required_pow(5), 12);
+        assert_eq!(required_pow(15), 15);
+        set_required_pow(0);
+        assert_eq!(required_pow(5), 5);
+    }
+}
@@ -0,0 +1,422 @@
+//! Read-only HTTP API to inspect active orders, the order history, open
+//! disputes and orders held for review, built with the `rest-api` feature
+//! and served by the HTTP listener when `rest_api_enabled` is set
+
+use crate::db::{
+    find_active_orders_page, find_open_disputes, find_quarantined_orders, list_orders, OrderFilter,
+    OrderSort,
+};
+use crate::http::{request_line, Response};
+
+use mostro_core::dispute::Dispute;
+use mostro_core::order::{Order, Status};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use sqlx_crud::Crud;
+use std::str::FromStr;
+use tracing::error;
+use uuid::Uuid;
+
+/// Order as served by the API, trade secrets like the preimage, the hash,
+/// the buyer invoice or the identity keys of the parties are never exposed
+#[derive(Debug, Serialize)]
+struct PublicOrder {
+    id: Uuid,
+    kind: String,
+    event_id: String,
+    status: String,
+    amount: i64,
+    min_amount: Option<i64>,
+    max_amount: Option<i64>,
+    fiat_code: String,
+    fiat_amount: i64,
+    payment_method: String,
+    premium: i64,
+    price_from_api: bool,
+    fee: i64,
+    range_parent_id: Option<Uuid>,
+    created_at: i64,
+    taken_at: i64,
+    expires_at: i64,
+}
+
+impl From<&Order> for PublicOrder {
+    fn from(order: &Order) -> Self {
+        PublicOrder {
+            id: order.id,
+            kind: order.kind.clone(),
+            event_id: order.event_id.clone(),
+            status: order.status.clone(),
+            amount: order.amount,
+            min_amount: order.min_amount,
+            max_amount: order.max_amount,
+            fiat_code: order.fiat_code.clone(),
+            fiat_amount: order.fiat_amount,
+            payment_method: order.payment_method.clone(),
+            premium: order.premium,
+            price_from_api: order.price_from_api,
+            fee: order.fee,
+            range_parent_id: order.range_parent_id,
+            created_at: order.created_at,
+            taken_at: order.taken_at,
+            expires_at: order.expires_at,
+        }
+    }
+}
+
+/// Dispute as served by the API, without the tokens the parties use to
+/// recognize the solver
+#[derive(Debug, Serialize)]
+struct PublicDispute {
+    id: Uuid,
+    order_id: Uuid,
+    status: String,
+    order_previous_status: String,
+    solver_pubkey: Option<String>,
+    created_at: i64,
+    taken_at: i64,
+}
+
+impl From<&Dispute> for PublicDispute {
+    fn from(dispute: &Dispute) -> Self {
+        PublicDispute {
+            id: dispute.id,
+            order_id: dispute.order_id,
+            status: dispute.status.clone(),
+            order_previous_status: dispute.order_previous_status.clone(),
+            solver_pubkey: dispute.solver_pubkey.clone(),
+            created_at: dispute.created_at,
+            taken_at: dispute.taken_at,
+        }
+    }
+}
+
+/// Page of the order history with the total of orders matching the filter
+#[derive(Debug, Serialize)]
+struct HistoryPage {
+    orders: Vec<PublicOrder>,
+    total: i64,
+}
+
+/// Page size requested, never bigger than `max_page_size`
+fn limited_page_size(page_size: Option<u32>, max_page_size: u32) -> u32 {
+    match page_size {
+        Some(size) if size > 0 => size.min(max_page_size),
+        _ => max_page_size,
+    }
+}
+
+/// Page of orders requested with `sort`, `page` and `page_size` query parameters
+#[derive(Debug, Default, PartialEq, Eq)]
+struct OrdersQuery {
+    sort: OrderSort,
+    page: u32,
+    page_size: Option<u32>,
+}
+
+impl OrdersQuery {
+    /// Parse the query parameters, unknown parameters and values are ignored
+    fn parse(query: &str) -> Self {
+        let mut orders_query = OrdersQuery::default();
+        for (name, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            match (name, value) {
+                ("sort", "amount") => orders_query.sort = OrderSort::Amount,
+                ("sort", "premium") => orders_query.sort = OrderSort::Premium,
+                ("sort", "recent") => orders_query.sort = OrderSort::Recent,
+                ("page", page) => orders_query.page = page.parse().unwrap_or_default(),
+                ("page_size", size) => orders_query.page_size = size.parse().ok(),
+                _ => {}
+            }
+        }
+        orders_query
+    }
+
+    /// Page size requested, never bigger than `max_page_size`
+    fn page_size(&self, max_page_size: u32) -> u32 {
+        limited_page_size(self.page_size, max_page_size)
+    }
+}
+
+/// Page of the order history requested with `status`, `fiat_code`, `from`,
+/// `to` and `pubkey` filters and `page` and `page_size` query parameters
+#[derive(Debug, Default, PartialEq)]
+struct HistoryQuery {
+    filter: OrderFilter,
+    page: u32,
+    page_size: Option<u32>,
+}
+
+impl HistoryQuery {
+    /// Parse the query parameters, unknown parameters and values are ignored
+    fn parse(query: &str) -> Self {
+        let mut history_query = HistoryQuery::default();
+        let filter = &mut history_query.filter;
+        for (name, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            match (name, value) {
+                ("status", status) => filter.status = Status::from_str(status).ok(),
+                ("fiat_code", code) => filter.fiat_code = Some(code.to_string()),
+                ("from", from) => filter.from = from.parse().ok(),
+                ("to", to) => filter.to = to.parse().ok(),
+                ("pubkey", pubkey) => filter.pubkey = Some(pubkey.to_string()),
+                ("page", page) => history_query.page = page.parse().unwrap_or_default(),
+                ("page_size", size) => history_query.page_size = size.parse().ok(),
+                _ => {}
+            }
+        }
+        history_query
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Orders(OrdersQuery),
+    History(HistoryQuery),
+    Order(Uuid),
+    Disputes,
+    Quarantine,
+    NotFound,
+    MethodNotAllowed,
+}
+
+/// Route of a request, only GET requests are allowed as the API never changes state
+fn parse_route(method: &str, path: &str) -> Route {
+    if method != "GET" {
+        return Route::MethodNotAllowed;
+    }
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    match path.trim_end_matches('/').split('/').collect::<Vec<_>>()[..] {
+        ["", "orders"] => Route::Orders(OrdersQuery::parse(query)),
+        ["", "orders", id] => match Uuid::parse_str(id) {
+            Ok(id) => Route::Order(id),
+            Err(_) => Route::NotFound,
+        },
+        ["", "history"] => Route::History(HistoryQuery::parse(query)),
+        ["", "disputes"] => Route::Disputes,
+        ["", "quarantine"] => Route::Quarantine,
+        _ => Route::NotFound,
+    }
+}
+
+/// Check the request has the bearer token, requests are rejected without a token set
+fn is_authorized(request: &str, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    request.lines().skip(1).any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("authorization")
+                && value.trim() == format!("Bearer {token}")
+        })
+    })
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response {
+    match serde_json::to_string(value) {
+        Ok(body) => Response::json(200, body),
+        Err(e) => {
+            error!("REST API: {e}");
+            Response::json(500, r#"{"error":"internal error"}"#.to_string())
+        }
+    }
+}
+
+/// Serve a request, only with the bearer token set on settings
+pub async fn handle_request(
+    request: &str,
+    pool: &SqlitePool,
+    token: &str,
+    max_page_size: u32,
+) -> Response {
+    let (method, path) = request_line(request);
+
+    if !is_authorized(request, token) {
+        return Response::json(401, r#"{"error":"unauthorized"}"#.to_string());
+    }
+    match parse_route(method, path) {
+        Route::Orders(query) => match find_active_orders_page(
+            pool,
+            query.sort,
+            query.page,
+            query.page_size(max_page_size),
+        )
+        .await
+        {
+            Ok(orders) => json_response(&orders.iter().map(PublicOrder::from).collect::<Vec<_>>()),
+            Err(e) => {
+                error!("REST API: {e}");
+                Response::json(500, r#"{"error":"internal error"}"#.to_string())
+            }
+        },
+        Route::History(query) => {
+            let page_size = limited_page_size(query.page_size, max_page_size);
+            let offset = query.page.saturating_mul(page_size);
+            match list_orders(pool, &query.filter, page_size, offset).await {
+                Ok(page) => json_response(&HistoryPage {
+                    orders: page.orders.iter().map(PublicOrder::from).collect(),
+                    total: page.total,
+                }),
+                Err(e) => {
+                    error!("REST API: {e}");
+                    Response::json(500, r#"{"error":"internal error"}"#.to_string())
+                }
+            }
+        }
+        Route::Order(id) => match Order::by_id(pool, id).await {
+            Ok(Some(order)) => json_response(&PublicOrder::from(&order)),
+            Ok(None) => Response::json(404, r#"{"error":"not found"}"#.to_string()),
+            Err(e) => {
+                error!("REST API: {e}");
+                Response::json(500, r#"{"error":"internal error"}"#.to_string())
+            }
+        },
+        Route::Disputes => match find_open_disputes(pool).await {
+            Ok(disputes) => {
+                json_response(&disputes.iter().map(PublicDispute::from).collect::<Vec<_>>())
+            }
+            Err(e) => {
+                error!("REST API: {e}");
+                Response::json(500, r#"{"error":"internal error"}"#.to_string())
+            }
+        },
+        Route::Quarantine => match find_quarantined_orders(pool).await {
+            Ok(orders) => json_response(&orders),
+            Err(e) => {
+                error!("REST API: {e}");
+                Response::json(500, r#"{"error":"internal error"}"#.to_string())
+            }
+        },
+        Route::NotFound => Response::json(404, r#"{"error":"not found"}"#.to_string()),
+        Route::MethodNotAllowed => {
+            Response::json(405, r#"{"error":"method not allowed"}"#.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            parse_route("GET", "/orders"),
+            Route::Orders(OrdersQuery::default())
+        );
+        assert_eq!(
+            parse_route("GET", "/orders/"),
+            Route::Orders(OrdersQuery::default())
+        );
+        assert_eq!(
+            parse_route("GET", &format!("/orders/{id}")),
+            Route::Order(id)
+        );
+        assert_eq!(parse_route("GET", "/orders/not-an-id"), Route::NotFound);
+        assert_eq!(parse_route("GET", "/disputes?page=1"), Route::Disputes);
+        assert_eq!(parse_route("GET", "/quarantine"), Route::Quarantine);
+        assert_eq!(parse_route("GET", "/users"), Route::NotFound);
+    }
+
+    #[test]
+    fn test_read_only_routes() {
+        for method in ["POST", "PUT", "PATCH", "DELETE"] {
+            assert_eq!(parse_route(method, "/orders"), Route::MethodNotAllowed);
+        }
+    }
+
+    #[test]
+    fn test_orders_query() {
+        assert_eq!(
+            parse_route("GET", "/orders?sort=premium&page=2&page_size=20"),
+            Route::Orders(OrdersQuery {
+                sort: OrderSort::Premium,
+                page: 2,
+                page_size: Some(20),
+            })
+        );
+        // Wrong values are ignored
+        let query = OrdersQuery::parse("sort=price&page=-1&page_size=x");
+        assert_eq!(query, OrdersQuery::default());
+    }
+
+    #[test]
+    fn test_history_query() {
+        assert_eq!(
+            parse_route(
+                "GET",
+                "/history?status=success&fiat_code=USD&from=100&to=200&pubkey=abc&page=1&page_size=5"
+            ),
+            Route::History(HistoryQuery {
+                filter: OrderFilter {
+                    status: Some(Status::Success),
+                    fiat_code: Some("USD".to_string()),
+                    from: Some(100),
+                    to: Some(200),
+                    pubkey: Some("abc".to_string()),
+                },
+                page: 1,
+                page_size: Some(5),
+            })
+        );
+        // Wrong values are ignored
+        let query = HistoryQuery::parse("status=lost&from=yesterday");
+        assert_eq!(query, HistoryQuery::default());
+    }
+
+    #[test]
+    fn test_page_size_limited() {
+        let query = OrdersQuery::parse("page_size=20");
+        assert_eq!(query.page_size(50), 20);
+        let query = OrdersQuery::parse("page_size=500");
+        assert_eq!(query.page_size(50), 50);
+        let query = OrdersQuery::parse("page_size=0");
+        assert_eq!(query.page_size(50), 50);
+        assert_eq!(OrdersQuery::default().page_size(50), 50);
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let request =
+            "GET /orders HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\n\r\n";
+        assert!(is_authorized(request, "secret"));
+        assert!(!is_authorized(request, "other"));
+        let request = "GET /orders HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert!(!is_authorized(request, "secret"));
+        // No token set on config
+        assert!(!is_authorized(request, ""));
+    }
+
+    #[test]
+    fn test_public_order_hides_secrets() {
+        let order = Order {
+            id: Uuid::new_v4(),
+            preimage: Some("preimage".to_string()),
+            hash: Some("hash".to_string()),
+            buyer_invoice: Some("lnbc1invoice".to_string()),
+            master_buyer_pubkey: Some("buyer-identity".to_string()),
+            master_seller_pubkey: Some("seller-identity".to_string()),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&PublicOrder::from(&order)).unwrap();
+        for secret in [
+            "preimage",
+            "hash",
+            "lnbc1invoice",
+            "buyer-identity",
+            "seller-identity",
+        ] {
+            assert!(!json.contains(secret), "{secret} exposed");
+        }
+        assert!(json.contains(&order.id.to_string()));
+    }
+
+    #[test]
+    fn test_public_dispute_hides_tokens() {
+        let mut dispute = Dispute::new(Uuid::new_v4());
+        dispute.buyer_token = Some(1234);
+        dispute.seller_token = Some(5678);
+        let json = serde_json::to_string(&PublicDispute::from(&dispute)).unwrap();
+        assert!(!json.contains("token"));
+        assert!(!json.contains("1234"));
+    }
+}
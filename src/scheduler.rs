@@ -1,36 +1,305 @@
+use crate::app::admin_settle::settle_after_cooldown;
+use crate::app::dispute_escalation::escalate_stuck_disputes;
 use crate::app::release::do_payment;
 use crate::bitcoin_price::BitcoinPriceManager;
 use crate::cli::settings::Settings;
 use crate::db::*;
-use crate::lightning::LndConnector;
+use crate::lightning::backend::connect_backend;
+use crate::lightning::{is_lnd_available, set_lnd_available};
+use crate::shutdown;
 use crate::util;
 use crate::util::get_nostr_client;
 use crate::LN_STATUS;
 
 use chrono::{TimeDelta, Utc};
-use mostro_core::order::{Kind, Status};
+use mostro_core::message::{Action, Payload};
+use mostro_core::order::{Kind, Order, Status};
 use nostr_sdk::EventBuilder;
-use nostr_sdk::{Event, Kind as NostrKind, Tag};
+use nostr_sdk::{Event, Kind as NostrKind, PublicKey, Tag};
 use sqlx_crud::Crud;
+use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tracing::{error, info};
-use util::{get_keys, get_nostr_relays, update_order_event};
+use util::{get_keys, get_nostr_relays, send_new_order_msg, update_order_event};
 
 pub async fn start_scheduler(rate_list: Arc<Mutex<Vec<Event>>>) {
     info!("Creating scheduler");
 
-    job_expire_pending_older_orders().await;
+    job_expire_older_orders().await;
     job_update_rate_events(rate_list).await;
     let _ = job_cancel_orders().await;
     job_retry_failed_payments().await;
     job_info_event_send().await;
     job_relay_list().await;
     job_update_bitcoin_prices().await;
+    job_check_lnd_status().await;
+    job_purge_dispute_evidence().await;
+    job_close_resolved_disputes().await;
+    job_purge_processed_events().await;
+    job_backup_database().await;
+    job_escalate_stuck_disputes().await;
+    job_remind_hold_invoices().await;
+    job_settle_after_cooldown().await;
 
     info!("Scheduler Started");
 }
 
+async fn job_check_lnd_status() {
+    tokio::spawn(async move {
+        loop {
+            let interval = Settings::get_ln().lnd_health_check_interval as u64;
+            let available = match connect_backend().await {
+                Ok(mut ln_client) => ln_client.get_node_info().await.is_ok(),
+                Err(_) => false,
+            };
+            if available != is_lnd_available() {
+                if available {
+                    info!("LND is available again");
+                } else {
+                    error!("LND is not available, rejecting actions needing it");
+                }
+                set_lnd_available(available);
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(interval.max(1))).await;
+        }
+    });
+}
+
+/// Remind sellers to pay their hold invoice before it expires, once it lapses
+/// `job_cancel_orders` cancels it and moves the order back
+async fn job_remind_hold_invoices() {
+    let pool = match connect().await {
+        Ok(p) => p,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let before = Settings::get_mostro().hold_invoice_reminder_seconds as i64;
+            let now = Utc::now().timestamp();
+            if before > 0 {
+                if let Ok(reminders) = find_hold_invoice_reminders(&pool, now, before).await {
+                    for reminder in reminders {
+                        if let Err(e) = remind_hold_invoice(&pool, reminder, now).await {
+                            error!("Error reminding the seller to pay: {e}");
+                        }
+                    }
+                }
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+        }
+    });
+}
+
+/// Send the seller the hold invoice again
+async fn remind_hold_invoice(
+    pool: &sqlx::SqlitePool,
+    reminder: HoldInvoiceReminder,
+    now: i64,
+) -> anyhow::Result<()> {
+    if !mark_hold_invoice_reminded(pool, reminder.order_id, now).await? {
+        return Ok(());
+    }
+    let Some(order) = Order::by_id(pool, reminder.order_id).await? else {
+        return Ok(());
+    };
+    let Some(seller_pubkey) = order.seller_pubkey.as_ref() else {
+        return Ok(());
+    };
+    let seller_pubkey = PublicKey::from_str(seller_pubkey)?;
+    info!(
+        "Order Id {}: hold invoice expires in {} seconds, reminding the seller",
+        order.id,
+        reminder.expires_at - now
+    );
+    let mut new_order = order.as_new_order();
+    new_order.status = Some(Status::WaitingPayment);
+    send_new_order_msg(
+        None,
+        Some(order.id),
+        Action::PayInvoice,
+        Some(Payload::PaymentRequest(
+            Some(new_order),
+            reminder.payment_request,
+            None,
+        )),
+        &seller_pubkey,
+        order.trade_index_seller,
+    )
+    .await;
+
+    Ok(())
+}
+
+/// Settle the disputed orders whose admin settlement cooldown ended, the
+/// settlements are stored so the ones due during a restart are done on start
+async fn job_settle_after_cooldown() {
+    let pool = match connect().await {
+        Ok(p) => p,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        let mut shutdown = shutdown::subscribe();
+        loop {
+            match find_due_settlements(&pool, Utc::now().timestamp()).await {
+                Ok(settlements) if !settlements.is_empty() => {
+                    match (get_keys(), connect_backend().await) {
+                        (Ok(keys), Ok(mut ln_client)) => {
+                            for settlement in settlements {
+                                // No new settlements once the shutdown starts
+                                if shutdown::is_shutting_down() {
+                                    break;
+                                }
+                                let _in_flight = shutdown::InFlight::start();
+                                let order_id = settlement.order_id;
+                                if let Err(e) = settle_after_cooldown(
+                                    &pool,
+                                    &keys,
+                                    ln_client.as_mut(),
+                                    settlement,
+                                )
+                                .await
+                                {
+                                    error!("Order Id {order_id}: settlement not done: {e}");
+                                }
+                            }
+                        }
+                        (Err(e), _) | (_, Err(e)) => error!("{e}"),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => error!("Error finding due settlements: {e}"),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(10)) => {}
+                _ = shutdown::wait(&mut shutdown) => break,
+            }
+        }
+    });
+}
+
+async fn job_escalate_stuck_disputes() {
+    let pool = match connect().await {
+        Ok(p) => p,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            match escalate_stuck_disputes(&pool, Utc::now().timestamp()).await {
+                Ok(0) => {}
+                Ok(escalated) => info!("Escalated {escalated} stuck disputes to the admin"),
+                Err(e) => error!("Error escalating stuck disputes: {e}"),
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(600)).await;
+        }
+    });
+}
+
+async fn job_purge_dispute_evidence() {
+    let pool = match connect().await {
+        Ok(p) => p,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let retention_days = Settings::get_mostro().dispute_evidence_retention_days as i64;
+            if retention_days > 0 {
+                let before = Utc::now().timestamp() - retention_days * 86400;
+                match purge_resolved_dispute_evidence(&pool, before).await {
+                    Ok(0) => {}
+                    Ok(purged) => info!("Purged evidence of {purged} resolved disputes"),
+                    Err(e) => error!("Error purging dispute evidence: {e}"),
+                }
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
+        }
+    });
+}
+
+async fn job_close_resolved_disputes() {
+    let pool = match connect().await {
+        Ok(p) => p,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let delay = Settings::get_mostro().dispute_close_delay_seconds as i64;
+            if delay > 0 {
+                match close_resolved_disputes(&pool, Utc::now().timestamp(), delay).await {
+                    Ok(0) => {}
+                    Ok(closed) => info!("Closed and archived {closed} resolved disputes"),
+                    Err(e) => error!("Error closing resolved disputes: {e}"),
+                }
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
+        }
+    });
+}
+
+async fn job_purge_processed_events() {
+    let pool = match connect().await {
+        Ok(p) => p,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        loop {
+            // Kept for a day, far longer than the max age of any event
+            let before = Utc::now().timestamp() - 86400;
+            if let Err(e) = purge_processed_events(&pool, before).await {
+                error!("Error purging processed events: {e}");
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
+        }
+    });
+}
+
+async fn job_backup_database() {
+    let db_settings = Settings::get_db();
+    if db_settings.backup_interval_seconds == 0 {
+        return;
+    }
+    let pool = match connect().await {
+        Ok(p) => p,
+        Err(e) => return error!("{e}"),
+    };
+
+    tokio::spawn(async move {
+        let dir = std::path::PathBuf::from(&db_settings.backup_dir);
+        let mut shutdown = shutdown::subscribe();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(
+                    db_settings.backup_interval_seconds as u64,
+                )) => {}
+                _ = shutdown::wait(&mut shutdown) => break,
+            }
+            match backup_database(&pool, &dir, Utc::now().timestamp()).await {
+                Ok((path, size)) => {
+                    info!("Database backup {} written, {size} bytes", path.display())
+                }
+                Err(e) => {
+                    error!("Error backing up the database: {e}");
+                    continue;
+                }
+            }
+            let keep = db_settings.backup_keep.max(1) as usize;
+            let rotate_dir = dir.clone();
+            match tokio::task::spawn_blocking(move || rotate_backups(&rotate_dir, keep)).await {
+                Ok(Ok(0)) => {}
+                Ok(Ok(removed)) => info!("Removed {removed} old database backups"),
+                Ok(Err(e)) => error!("Error removing old database backups: {e}"),
+                Err(e) => error!("Error removing old database backups: {e}"),
+            }
+        }
+    });
+}
+
 async fn job_relay_list() {
     let mostro_keys = match get_keys() {
         Ok(keys) => keys,
@@ -59,7 +328,10 @@ async fn job_relay_list() {
                     }
                 }
             }
-            tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(interval)) => {}
+                _ = shutdown::wait(&mut shutdown) => break,
+            }
         }
     });
 }
@@ -87,7 +359,11 @@ async fn job_info_event_send() {
                 let _ = client.send_event(info_ev).await;
             }
 
-            tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
+            // Clients learn a raised POW without waiting for the next interval
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(interval)) => {}
+                _ = crate::pow::pow_changed() => {}
+            }
         }
     });
 }
@@ -103,22 +379,54 @@ async fn job_retry_failed_payments() {
     };
 
     tokio::spawn(async move {
+        let mut shutdown = shutdown::subscribe();
         loop {
             info!(
                 "I run async every {} minutes - checking for failed lighting payment",
                 interval
             );
 
-            if let Ok(payment_failed_list) = crate::db::find_failed_payment(&pool).await {
+            // Keys read every round to follow a reload of the settings
+            let keys = match get_keys() {
+                Ok(keys) => Some(keys),
+                Err(e) => {
+                    error!("{e}");
+                    None
+                }
+            };
+            if let (Some(keys), Ok(payment_failed_list)) =
+                (keys, crate::db::find_failed_payment(&pool).await)
+            {
                 for payment_failed in payment_failed_list.into_iter() {
-                    if payment_failed.payment_attempts < retries_number {
-                        if let Err(e) = do_payment(payment_failed.clone(), None).await {
+                    if payment_failed.payment_attempts < retries_number
+                        && is_payment_retry_due(&pool, payment_failed.id, Utc::now().timestamp())
+                            .await
+                            .unwrap_or(true)
+                    {
+                        // No new payments once the shutdown starts
+                        if shutdown::is_shutting_down() {
+                            break;
+                        }
+                        // Skipped if the buyer sent a new invoice meanwhile
+                        match claim_failed_payment(&pool, payment_failed.id, None).await {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(e) => {
+                                error!("{e}");
+                                continue;
+                            }
+                        }
+                        if let Err(e) = do_payment(payment_failed.clone(), None, &keys, &pool).await
+                        {
                             error!("{e}");
                         }
                     }
                 }
             }
-            tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(interval)) => {}
+                _ = shutdown::wait(&mut shutdown) => break,
+            }
         }
     });
 }
@@ -180,7 +488,7 @@ async fn job_cancel_orders() -> anyhow::Result<()> {
         Err(e) => return Err(anyhow::Error::msg(e.to_string())),
     };
 
-    let mut ln_client = LndConnector::new().await?;
+    let mut ln_client = connect_backend().await?;
     let mostro_settings = Settings::get_mostro();
     let exp_seconds = mostro_settings.expiration_seconds;
 
@@ -290,7 +598,7 @@ async fn job_cancel_orders() -> anyhow::Result<()> {
                             );
                         }
                         if let Ok(order_updated) =
-                            update_order_event(&keys, new_status, &order).await
+                            update_order_event(&keys, new_status, &order, &pool).await
                         {
                             let _ = order_updated.update(&pool).await;
                         }
@@ -312,7 +620,7 @@ async fn job_cancel_orders() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn job_expire_pending_older_orders() {
+async fn job_expire_older_orders() {
     let pool = match connect().await {
         Ok(p) => p,
         Err(e) => return error!("{e}"),
@@ -324,32 +632,72 @@ async fn job_expire_pending_older_orders() {
 
     tokio::spawn(async move {
         loop {
-            info!("Check older orders and mark them Expired - check is done every minute");
+            let interval = Settings::get_mostro().expiration_scan_interval;
+            info!(
+                "Check older orders and mark them Expired - check is done every {interval} seconds"
+            );
             if let Ok(older_orders_list) = crate::db::find_order_by_date(&pool).await {
                 for order in older_orders_list.iter() {
-                    println!("Uid {} - created at {}", order.id, order.created_at);
+                    info!(
+                        "Order Id {} expired - created at {}",
+                        order.id, order.created_at
+                    );
+                    // Taken orders could have a hold invoice, funds are returned to seller
+                    if let Some(hash) = order.hash.as_ref() {
+                        match connect_backend().await {
+                            Ok(mut ln_client) => {
+                                if let Err(e) = ln_client.cancel_hold_invoice(hash).await {
+                                    error!("Order Id {}: {e}", order.id);
+                                }
+                            }
+                            Err(e) => error!("Order Id {}: {e}", order.id),
+                        }
+                    }
                     // We update the order id with the new event_id
                     if let Ok(order_updated) =
-                        crate::util::update_order_event(&keys, Status::Expired, order).await
+                        crate::util::update_order_event(&keys, Status::Expired, order, &pool).await
                     {
                         let _ = order_updated.update(&pool).await;
+                        notify_order_expired(&order_updated).await;
                     }
                 }
             }
             let now = Utc::now();
-            if let Some(next_tick) =
-                now.checked_add_signed(TimeDelta::try_minutes(1).expect("Wrong minutes value"))
-            {
+            if let Some(next_tick) = now.checked_add_signed(
+                TimeDelta::try_seconds(interval as i64).expect("Wrong seconds value"),
+            ) {
                 info!(
                     "Next tick for removal of older orders is {}",
                     next_tick.format("%a %b %e %T %Y")
                 );
             }
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+            tokio::time::sleep(tokio::time::Duration::from_secs(interval.max(1))).await;
         }
     });
 }
 
+/// Let the parties of an expired order know it can't be taken nor continued
+async fn notify_order_expired(order: &Order) {
+    let mut small_order = order.as_new_order();
+    small_order.status = Some(Status::Expired);
+    for pubkey in [&order.buyer_pubkey, &order.seller_pubkey]
+        .into_iter()
+        .flatten()
+    {
+        if let Ok(pubkey) = PublicKey::from_str(pubkey) {
+            send_new_order_msg(
+                None,
+                Some(order.id),
+                Action::Canceled,
+                Some(Payload::Order(small_order.clone())),
+                &pubkey,
+                None,
+            )
+            .await;
+        }
+    }
+}
+
 async fn job_update_bitcoin_prices() {
     tokio::spawn(async {
         loop {
@@ -0,0 +1,227 @@
+//! Startup self-test running a complete trade in dry-run, the order goes
+//! through every status of a trade inside a database transaction that is
+//! rolled back, nothing is published to relays nor paid.
+
+use crate::app::release::check_release_policy;
+use crate::cli::settings::Mostro;
+use crate::util::{check_order_takeable, fee_for};
+
+use anyhow::{Error, Result};
+use mostro_core::order::{Kind, Order, Status};
+use nostr_sdk::Timestamp;
+use sqlx::SqlitePool;
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// Statuses an order goes through in a trade without incidents
+const TRADE_FLOW: [Status; 6] = [
+    Status::Pending,
+    Status::WaitingPayment,
+    Status::Active,
+    Status::FiatSent,
+    Status::SettledHoldInvoice,
+    Status::Success,
+];
+
+/// Check the trade settings make possible to create and complete an order
+pub fn check_trade_settings(settings: &Mostro) -> Result<()> {
+    if !(0.0..1.0).contains(&settings.fee) {
+        return Err(Error::msg(format!("Wrong fee {}", settings.fee)));
+    }
+    if settings.min_payment_amount > settings.max_order_amount {
+        return Err(Error::msg(format!(
+            "Min payment amount {} over max order amount {}",
+            settings.min_payment_amount, settings.max_order_amount
+        )));
+    }
+    if settings.expiration_seconds == 0 {
+        return Err(Error::msg("Pending orders expire right away"));
+    }
+
+    Ok(())
+}
+
+/// Move the dry-run order from `from` to `to` status
+async fn transition(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    order_id: Uuid,
+    from: &Status,
+    to: &Status,
+) -> Result<()> {
+    let rows_affected = sqlx::query("UPDATE orders SET status = ?1 WHERE id = ?2 AND status = ?3")
+        .bind(to.to_string())
+        .bind(order_id)
+        .bind(from.to_string())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if rows_affected != 1 {
+        return Err(Error::msg(format!("Order can't move from {from} to {to}")));
+    }
+
+    Ok(())
+}
+
+/// Run a complete order, take, fiat sent and release cycle in dry-run
+pub async fn run_self_test(pool: &SqlitePool, mostro_settings: &Mostro) -> Result<()> {
+    check_trade_settings(mostro_settings)?;
+
+    let now = Timestamp::now().as_u64() as i64;
+    let amount = mostro_settings.min_payment_amount as i64;
+    let order = Order {
+        id: Uuid::new_v4(),
+        kind: Kind::Sell.to_string(),
+        status: Status::Pending.to_string(),
+        amount,
+        fee: fee_for(mostro_settings, amount),
+        fiat_code: "USD".to_string(),
+        fiat_amount: 1,
+        payment_method: "self-test".to_string(),
+        created_at: now,
+        expires_at: now + mostro_settings.expiration_seconds as i64,
+        ..Default::default()
+    };
+    if order.fee > order.amount {
+        return Err(Error::msg(format!("Fee {} over amount", order.fee)));
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"
+          INSERT INTO orders (id, kind, event_id, status, premium, payment_method, amount,
+            fee, fiat_code, fiat_amount, created_at, expires_at)
+          VALUES (?1, ?2, '', ?3, 0, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        "#,
+    )
+    .bind(order.id)
+    .bind(&order.kind)
+    .bind(&order.status)
+    .bind(&order.payment_method)
+    .bind(order.amount)
+    .bind(order.fee)
+    .bind(&order.fiat_code)
+    .bind(order.fiat_amount)
+    .bind(order.created_at)
+    .bind(order.expires_at)
+    .execute(&mut *tx)
+    .await?;
+
+    check_order_takeable(
+        &order,
+        now,
+        mostro_settings.max_take_order_age_seconds as i64,
+    )
+    .map_err(|e| Error::msg(format!("Order can't be taken: {e:?}")))?;
+
+    for step in TRADE_FLOW.windows(2) {
+        let (from, to) = (&step[0], &step[1]);
+        // Seller can release once all the release policies are met
+        if matches!(to, Status::SettledHoldInvoice) {
+            let released = Order {
+                status: from.to_string(),
+                ..order.clone()
+            };
+            check_release_policy(&released, &mostro_settings.release_policies, i64::MAX)
+                .map_err(|e| Error::msg(format!("Order can't be released: {e:?}")))?;
+        }
+        transition(&mut tx, order.id, from, to).await?;
+        info!("Self-test: order moved from {from} to {to}");
+    }
+
+    // Nothing of the dry-run is kept
+    tx.rollback().await?;
+
+    Ok(())
+}
+
+/// Run the startup self-test if enabled, logging a clear pass or fail
+pub async fn startup_self_test(pool: &SqlitePool, mostro_settings: &Mostro) -> Result<()> {
+    if !mostro_settings.startup_self_test {
+        return Ok(());
+    }
+    match run_self_test(pool, mostro_settings).await {
+        Ok(()) => {
+            info!("Self-test PASSED: trade flow completed in dry-run");
+            Ok(())
+        }
+        Err(e) => {
+            error!("Self-test FAILED: {e}");
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::settings::Settings;
+    use crate::db::connect_test_db;
+    use std::env::set_var;
+    use std::path::PathBuf;
+
+    fn template_settings() -> Mostro {
+        set_var("RUN_MODE", ".tpl");
+        Settings::new(PathBuf::from("./")).unwrap().mostro
+    }
+
+    #[tokio::test]
+    async fn test_self_test_passes_on_healthy_config() {
+        let (pool, _db) = connect_test_db().await;
+        run_self_test(&pool, &template_settings()).await.unwrap();
+
+        // Dry-run order is not kept
+        let orders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM orders")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(orders, 0);
+    }
+
+    #[test]
+    fn test_self_test_fails_on_broken_config() {
+        let healthy = Mostro {
+            fee: 0.006,
+            max_order_amount: 1_000_000,
+            min_payment_amount: 100,
+            expiration_seconds: 900,
+            ..Default::default()
+        };
+        assert!(check_trade_settings(&healthy).is_ok());
+
+        let wrong_fee = Mostro {
+            fee: 1.5,
+            ..healthy.clone()
+        };
+        assert!(check_trade_settings(&wrong_fee).is_err());
+
+        let wrong_amounts = Mostro {
+            min_payment_amount: 2_000_000,
+            ..healthy.clone()
+        };
+        assert!(check_trade_settings(&wrong_amounts).is_err());
+
+        let no_expiration = Mostro {
+            expiration_seconds: 0,
+            ..healthy
+        };
+        assert!(check_trade_settings(&no_expiration).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_startup_self_test_fails_on_broken_config() {
+        let (pool, _db) = connect_test_db().await;
+        let broken = Mostro {
+            startup_self_test: true,
+            fee: 1.5,
+            ..template_settings()
+        };
+        assert!(startup_self_test(&pool, &broken).await.is_err());
+
+        // Disabled, a broken config doesn't stop the startup
+        let disabled = Mostro {
+            startup_self_test: false,
+            ..broken
+        };
+        assert!(startup_self_test(&pool, &disabled).await.is_ok());
+    }
+}
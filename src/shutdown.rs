@@ -0,0 +1,107 @@
+//! Graceful shutdown, on SIGINT or SIGTERM the event loop stops taking new
+//! events, the background jobs stop and the payments in flight are waited for
+
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tracing::info;
+
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static IN_FLIGHT_DONE: Notify = Notify::const_new();
+
+/// Receiver changing once the shutdown starts
+pub fn subscribe() -> watch::Receiver<bool> {
+    SHUTDOWN.subscribe()
+}
+
+/// Start the shutdown
+pub fn trigger() {
+    SHUTDOWN.send_replace(true);
+}
+
+pub fn is_shutting_down() -> bool {
+    *SHUTDOWN.borrow()
+}
+
+/// Wait for the shutdown to start, returns at once if it already started
+pub async fn wait(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|shutting_down| *shutting_down).await;
+}
+
+/// Wait for SIGINT or SIGTERM and start the shutdown
+pub async fn trigger_on_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+
+    info!("Shutdown signal received");
+    trigger();
+}
+
+/// A task the shutdown waits for, e.g. a payment to the buyer, done on drop
+pub struct InFlight(());
+
+impl InFlight {
+    pub fn start() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) == 1 {
+            IN_FLIGHT_DONE.notify_waiters();
+        }
+    }
+}
+
+/// Wait for the tasks in flight up to `timeout`, returns false on timeout
+pub async fn wait_in_flight(timeout: Duration) -> bool {
+    tokio::time::timeout(timeout, async {
+        loop {
+            let done = IN_FLIGHT_DONE.notified();
+            if IN_FLIGHT.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            done.await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_tasks_in_flight() {
+        let task = InFlight::start();
+        assert!(!wait_in_flight(Duration::from_millis(10)).await);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(task);
+        });
+        assert!(wait_in_flight(Duration::from_secs(1)).await);
+    }
+}
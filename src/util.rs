@@ -1,141 +1,120 @@
 use crate::app::rate_user::get_user_reputation;
-use crate::bitcoin_price::BitcoinPriceManager;
-use crate::cli::settings::Settings;
+use crate::bitcoin_price::{get_btc_price, sats_for_fiat, BitcoinPriceManager};
+use crate::cli::settings::{Mostro, PowTier, Settings};
 use crate::db;
+use crate::db::{pubkeys_match, OrderOptions};
 use crate::error::MostroError;
 use crate::flow;
-use crate::lightning;
-use crate::lightning::LndConnector;
+use crate::lightning::backend::{connect_backend, LightningBackend};
+use crate::lightning::reconcile::{settle_checked, SettleOutcome, SETTLE_RETRY_DELAY};
 use crate::messages;
-use crate::models::Yadio;
 use crate::nip33::{new_event, order_to_tags};
 use crate::NOSTR_CLIENT;
 
-use anyhow::{Context, Error, Result};
+use anyhow::{Error, Result};
 use chrono::Duration;
 use mostro_core::message::CantDoReason;
 use mostro_core::message::{Action, Message, Payload};
 use mostro_core::order::{Kind as OrderKind, Order, SmallOrder, Status};
 use nostr::nips::nip59::UnwrappedGift;
 use nostr_sdk::prelude::*;
+use once_cell::sync::Lazy;
 use sqlx::SqlitePool;
 use sqlx_crud::Crud;
 use std::fmt::Write;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::thread;
+use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc::channel;
 use tokio::sync::Mutex;
 // use fedimint_tonic_lnd::Client;
 use fedimint_tonic_lnd::lnrpc::invoice::InvoiceState;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 use uuid::Uuid;
 
-pub type FiatNames = std::collections::HashMap<String, String>;
-const MAX_RETRY: u16 = 4;
-
-pub async fn retries_yadio_request(
-    req_string: &str,
-    fiat_code: &str,
-) -> Result<(Option<reqwest::Response>, bool)> {
-    // Get Fiat list and check if currency exchange is available
-    let api_req_string = "https://api.yadio.io/currencies".to_string();
-    let fiat_list_check = reqwest::get(api_req_string)
-        .await?
-        .json::<FiatNames>()
-        .await?
-        .contains_key(fiat_code);
-
-    // Exit with error - no currency
-    if !fiat_list_check {
-        return Ok((None, fiat_list_check));
-    }
-
-    let res = reqwest::get(req_string)
-        .await
-        .context("Something went wrong with API request, try again!")?;
-
-    Ok((Some(res), fiat_list_check))
-}
-
 pub fn get_bitcoin_price(fiat_code: &str) -> Result<f64> {
     BitcoinPriceManager::get_price(fiat_code)
         .ok_or_else(|| anyhow::anyhow!("Failed to get Bitcoin price"))
 }
 
-/// Request market quote from Yadio to have sats amount at actual market price
+/// Request market quote from the price source to have sats amount at actual market price,
+/// fails if the price source is not available
 pub async fn get_market_quote(
     fiat_amount: &i64,
     fiat_code: &str,
     premium: i64,
 ) -> Result<i64, MostroError> {
-    // Add here check for market price
-    let req_string = format!(
-        "https://api.yadio.io/convert/{}/{}/BTC",
-        fiat_amount, fiat_code
-    );
-    info!("Requesting API price: {}", req_string);
+    let price = get_btc_price(&BitcoinPriceManager, fiat_code).await?;
 
-    let mut req = (None, false);
-    let mut no_answer_api = false;
+    Ok(sats_for_fiat(*fiat_amount, price, premium))
+}
 
-    // Retry for 4 times
-    for retries_num in 1..=MAX_RETRY {
-        match retries_yadio_request(&req_string, fiat_code).await {
-            Ok(response) => {
-                req = response;
-                break;
-            }
-            Err(_e) => {
-                if retries_num == MAX_RETRY {
-                    no_answer_api = true;
-                }
-                println!(
-                    "API price request failed retrying - {} tentatives left.",
-                    (MAX_RETRY - retries_num)
-                );
-                thread::sleep(std::time::Duration::from_secs(2));
-            }
-        };
-    }
+pub fn get_fee(amount: i64) -> i64 {
+    fee_for(&Settings::get_mostro(), amount)
+}
 
-    // Case no answers from Yadio
-    if no_answer_api {
-        return Err(MostroError::NoAPIResponse);
-    }
+/// Fee of each party for `amount` with the given settings
+pub fn fee_for(mostro_settings: &Mostro, amount: i64) -> i64 {
+    let fee = calculate_fee(
+        amount,
+        mostro_settings.fee,
+        mostro_settings.fee_flat_sats as i64,
+    );
+    cap_fee(fee, amount, mostro_settings.max_fee_percent)
+}
 
-    // No currency present
-    if !req.1 {
-        return Err(MostroError::NoCurrency);
-    }
+/// Identities on the `fee_free_pubkeys` whitelist don't pay fees
+pub fn is_fee_free(pubkey: &str) -> bool {
+    is_whitelisted(&Settings::get_mostro().fee_free_pubkeys, pubkey)
+}
 
-    if req.0.is_none() {
-        return Err(MostroError::MalformedAPIRes);
-    }
+/// Orders made by a whitelisted identity don't pay fees
+pub fn is_order_fee_free(order: &Order) -> bool {
+    is_order_maker_whitelisted(&Settings::get_mostro().fee_free_pubkeys, order)
+}
+
+/// Check `pubkey` is on `whitelist`, keys are matched in hex or bech32
+fn is_whitelisted(whitelist: &[String], pubkey: &str) -> bool {
+    whitelist.iter().any(|free| pubkeys_match(free, pubkey))
+}
 
-    let quote = if let Some(q) = req.0 {
-        q.json::<Yadio>().await?
+fn is_order_maker_whitelisted(whitelist: &[String], order: &Order) -> bool {
+    let maker = if order.kind == OrderKind::Buy.to_string() {
+        order.master_buyer_pubkey.as_deref()
     } else {
-        return Err(MostroError::MalformedAPIRes);
+        order.master_seller_pubkey.as_deref()
     };
+    maker.is_some_and(|maker| is_whitelisted(whitelist, maker))
+}
 
-    let mut sats = quote.result * 100_000_000_f64;
+/// Calculate the fee each party pays, the mostro fee and the flat fee split
+/// between buyer and seller, a party never pays more than half the amount
+pub fn calculate_fee(amount: i64, fee: f64, flat_fee: i64) -> i64 {
+    // We calculate the bot fee
+    let split_fee = (fee * amount as f64 + flat_fee as f64) / 2.0;
+    (split_fee.round() as i64).min(amount / 2)
+}
 
-    // Added premium value to have correct sats value
-    if premium != 0 {
-        sats += (premium as f64) / 100_f64 * sats;
+/// Clamp the fee each party pays so the total fee never exceeds
+/// `max_fee_percent` of the order amount, a cap of 0 disables it
+pub fn cap_fee(fee: i64, amount: i64, max_fee_percent: f64) -> i64 {
+    if max_fee_percent <= 0.0 {
+        return fee;
     }
-
-    Ok(sats as i64)
+    let max_split_fee = (max_fee_percent / 100.0 * amount as f64 / 2.0).floor() as i64;
+    fee.min(max_split_fee)
 }
 
-pub fn get_fee(amount: i64) -> i64 {
-    let mostro_settings = Settings::get_mostro();
-    // We calculate the bot fee
-    let split_fee = (mostro_settings.fee * amount as f64) / 2.0;
-    split_fee.round() as i64
+/// Get the minimum POW required for an order of `amount` sats,
+/// the highest tier reached by the amount wins but never below `base_pow`
+pub fn get_required_pow(base_pow: u8, tiers: &[PowTier], amount: u64) -> u8 {
+    tiers
+        .iter()
+        .filter(|tier| amount >= tier.min_amount)
+        .map(|tier| tier.pow)
+        .fold(base_pow, u8::max)
 }
 
 pub fn get_expiration_date(expire: Option<i64>) -> i64 {
@@ -166,6 +145,7 @@ pub async fn publish_order(
     trade_pubkey: PublicKey,
     request_id: Option<u64>,
     trade_index: Option<i64>,
+    options: OrderOptions,
 ) -> Result<()> {
     // Prepare a new default order
     let new_order_db = match prepare_new_order(
@@ -187,6 +167,32 @@ pub async fn publish_order(
     let mut order = new_order_db.clone().create(pool).await?;
     let order_id = order.id;
     info!("New order saved Id: {}", order_id);
+    db::set_order_options(pool, &order, &options).await?;
+    let mut small_order = new_order_db.as_new_order();
+    small_order.id = Some(order_id);
+
+    // Suspicious orders wait for the operator review before being published
+    if let Some(reason) = options.quarantine {
+        // The maker gets the order ack once the order is approved
+        warn!("ALERT: Order Id {order_id} quarantined for review: {reason}");
+        return Ok(());
+    }
+
+    // Unlisted orders are only shared by the maker, takers use the order id
+    if options.unlisted {
+        info!("Order Id {order_id} is unlisted, not published");
+        send_new_order_msg(
+            request_id,
+            Some(order_id),
+            Action::NewOrder,
+            Some(Payload::Order(small_order)),
+            &trade_pubkey,
+            trade_index,
+        )
+        .await;
+        return Ok(());
+    }
+
     // Get user reputation
     let reputation = get_user_reputation(&initiator_pubkey.to_string(), keys).await?;
     // We transform the order fields to tags to use in the event
@@ -199,27 +205,19 @@ pub async fn publish_order(
     // We update the order with the new event_id
     order.event_id = event_id;
     order.update(pool).await?;
-    let mut order = new_order_db.as_new_order();
-    order.id = Some(order_id);
 
     // Send message as ack with small order
     send_new_order_msg(
         request_id,
         Some(order_id),
         Action::NewOrder,
-        Some(Payload::Order(order)),
+        Some(Payload::Order(small_order)),
         &trade_pubkey,
         trade_index,
     )
     .await;
 
-    NOSTR_CLIENT
-        .get()
-        .unwrap()
-        .send_event(event)
-        .await
-        .map(|_s| ())
-        .map_err(|err| err.into())
+    publish_status_event(event).await
 }
 
 async fn prepare_new_order(
@@ -230,7 +228,7 @@ async fn prepare_new_order(
     trade_pubkey: PublicKey,
 ) -> Option<Order> {
     let mut fee = 0;
-    if new_order.amount > 0 {
+    if new_order.amount > 0 && !is_fee_free(&identity_pubkey.to_string()) {
         fee = get_fee(new_order.amount);
     }
 
@@ -328,16 +326,37 @@ pub async fn send_dm(
     Ok(())
 }
 
+/// Mostro keys with the private key they were parsed from, parsed again
+/// when the settings are reloaded with another key
+type KeysCache = RwLock<Option<(String, Keys)>>;
+
+static MOSTRO_KEYS: KeysCache = RwLock::new(None);
+
+/// Parse the Mostro keys from the private key set on settings
+pub fn parse_keys(nsec_privkey: &str) -> Result<Keys> {
+    Keys::parse(nsec_privkey)
+        .map_err(|e| Error::msg(format!("Failed to parse nostr private key: {e}")))
+}
+
+/// Load the Mostro keys once at startup, so a wrong key stops Mostro
+/// before any trade instead of failing in the middle of a payout
+pub fn init_keys() -> Result<Keys> {
+    get_keys()
+}
+
 pub fn get_keys() -> Result<Keys> {
-    let nostr_settings = Settings::get_nostr();
-    // nostr private key
-    match Keys::parse(&nostr_settings.nsec_privkey) {
-        Ok(my_keys) => Ok(my_keys),
-        Err(e) => {
-            tracing::error!("Failed to parse nostr private key: {}", e);
-            std::process::exit(1);
+    cached_keys(&MOSTRO_KEYS, &Settings::get_nostr().nsec_privkey)
+}
+
+fn cached_keys(cache: &KeysCache, nsec_privkey: &str) -> Result<Keys> {
+    if let Some((cached, keys)) = cache.read().unwrap().as_ref() {
+        if cached == nsec_privkey {
+            return Ok(keys.clone());
         }
     }
+    let keys = parse_keys(nsec_privkey)?;
+    *cache.write().unwrap() = Some((nsec_privkey.to_string(), keys.clone()));
+    Ok(keys)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -371,12 +390,32 @@ pub async fn update_user_rating_event(
     order.update(pool).await?;
 
     // Add event message to global list
-    rate_list.lock().await.push(event);
+    queue_rate_event(&mut *rate_list.lock().await, event);
 
     Ok(())
 }
 
-pub async fn update_order_event(keys: &Keys, status: Status, order: &Order) -> Result<Order> {
+/// Rating events waiting to be published, the oldest are dropped beyond it
+const MAX_RATE_EVENTS: usize = 1000;
+
+/// Queue a rating event to be published, it replaces the queued event of the
+/// same user as only the latest one is kept by relays
+pub fn queue_rate_event(rate_list: &mut Vec<Event>, event: Event) {
+    let user = event.tags.identifier().map(str::to_string);
+    rate_list.retain(|queued| queued.tags.identifier().map(str::to_string) != user);
+    rate_list.push(event);
+    if rate_list.len() > MAX_RATE_EVENTS {
+        let excess = rate_list.len() - MAX_RATE_EVENTS;
+        rate_list.drain(..excess);
+    }
+}
+
+pub async fn update_order_event(
+    keys: &Keys,
+    status: Status,
+    order: &Order,
+    pool: &SqlitePool,
+) -> Result<Order> {
     let mut order_updated = order.clone();
     // update order.status with new status
     order_updated.status = status.to_string();
@@ -394,11 +433,27 @@ pub async fn update_order_event(keys: &Keys, status: Status, order: &Order) -> R
         order_id,
         status.to_string()
     );
+    // Status changes already recorded in the database were notified then
+    if order.status != order_updated.status {
+        crate::webhook::notify_status_change(
+            order.id,
+            &order.status,
+            &order_updated.status,
+            Timestamp::now().as_u64() as i64,
+        );
+    }
 
-    if let Ok(client) = get_nostr_client() {
-        if client.send_event(event).await.is_err() {
-            tracing::warn!("order id : {} is expired", order_updated.id)
-        }
+    // Unlisted orders are never published
+    let unlisted = db::is_order_unlisted(pool, order.id).await.unwrap_or(false);
+    if unlisted {
+        return Ok(order_updated);
+    }
+
+    if let Err(e) = publish_status_event(event).await {
+        warn!(
+            "Order Id {}: status event not published: {e}",
+            order_updated.id
+        )
     }
 
     println!(
@@ -409,6 +464,107 @@ pub async fn update_order_event(keys: &Keys, status: Status, order: &Order) -> R
     Ok(order_updated)
 }
 
+/// Makers ask for an unlisted order adding an `unlisted` tag to the rumor
+pub fn is_unlisted_request(event: &UnwrappedGift) -> bool {
+    event
+        .rumor
+        .tags
+        .iter()
+        .any(|tag| tag.as_slice().first().map(|t| t.as_str()) == Some("unlisted"))
+}
+
+/// Makers can require POW from takers adding a `take_pow` tag to the rumor
+pub fn get_take_pow_request(event: &UnwrappedGift) -> Option<u8> {
+    event
+        .rumor
+        .tags
+        .iter()
+        .find_map(|tag| match tag.as_slice() {
+            [name, pow, ..] if name == "take_pow" => pow.parse::<u8>().ok(),
+            _ => None,
+        })
+}
+
+/// Makers can require takers with a verified NIP-05 adding a `requires_nip05`
+/// tag to the rumor
+pub fn is_nip05_required_request(event: &UnwrappedGift) -> bool {
+    event
+        .rumor
+        .tags
+        .iter()
+        .any(|tag| tag.as_slice().first().map(|t| t.as_str()) == Some("requires_nip05"))
+}
+
+/// Takers send their NIP-05 identifier in a `nip05` tag of the rumor
+pub fn get_nip05_identifier(event: &UnwrappedGift) -> Option<String> {
+    event
+        .rumor
+        .tags
+        .iter()
+        .find_map(|tag| match tag.as_slice() {
+            [name, identifier, ..] if name == "nip05" => Some(identifier.to_string()),
+            _ => None,
+        })
+}
+
+/// Check the taker meets the NIP-05 requirement of the order, if it has one,
+/// the identifier must resolve to the taker identity pubkey
+pub async fn meets_nip05_requirement(
+    pool: &SqlitePool,
+    order_id: Uuid,
+    event: &UnwrappedGift,
+) -> Result<bool> {
+    if !db::is_nip05_order(pool, order_id).await? {
+        return Ok(true);
+    }
+    let Some(identifier) = get_nip05_identifier(event) else {
+        return Ok(false);
+    };
+
+    Ok(
+        crate::nip05::verify_nip05(&event.sender, &identifier, Timestamp::now().as_u64() as i64)
+            .await,
+    )
+}
+
+/// Find orders whose hold invoice was settled on the node but not recorded on
+/// database, they are moved to settled state and the buyer payment is retried
+pub async fn reconcile_settled_orders(
+    pool: &SqlitePool,
+    ln_client: &mut dyn LightningBackend,
+    my_keys: &Keys,
+) -> Result<()> {
+    for order in db::find_unsettled_orders(pool).await? {
+        let Some(hash) = order.hash.as_ref() else {
+            continue;
+        };
+        match ln_client.lookup_invoice_state(hash).await {
+            Ok(InvoiceState::Settled) => {
+                info!(
+                    "Order Id {}: hold invoice settled but not recorded, recovering",
+                    order.id
+                );
+                let mut order =
+                    update_order_event(my_keys, Status::SettledHoldInvoice, &order, pool).await?;
+                // Payment to buyer is done by the failed payments job
+                order.failed_payment = true;
+                order.update(pool).await?;
+            }
+            Ok(_) => {}
+            Err(e) => error!("Order Id {}: invoice lookup failed: {e}", order.id),
+        }
+    }
+
+    Ok(())
+}
+
+/// Filter of the Mostro subscription, gift wraps and the extra kinds
+/// set on settings addressed to `pubkey`
+pub fn subscription_filter(pubkey: PublicKey, extra_kinds: &[u16]) -> Filter {
+    let kinds = std::iter::once(Kind::GiftWrap).chain(extra_kinds.iter().map(|k| Kind::from(*k)));
+    Filter::new().pubkey(pubkey).kinds(kinds).limit(0)
+}
+
 pub async fn connect_nostr() -> Result<Client> {
     let nostr_settings = Settings::get_nostr();
 
@@ -433,7 +589,28 @@ pub async fn connect_nostr() -> Result<Client> {
     Ok(client)
 }
 
+/// Premium and effective price of the order shown to the counterpart, if enabled
+pub fn get_premium_details(order: &Order) -> Option<String> {
+    if !Settings::get_mostro().show_premium_details {
+        return None;
+    }
+
+    premium_details(order)
+}
+
+/// Premium and effective price of the order, once its sats amount is known
+fn premium_details(order: &Order) -> Option<String> {
+    let price = messages::effective_price(order.amount, order.fiat_amount)?;
+
+    Some(messages::format_premium_details(
+        order.premium,
+        price,
+        &order.fiat_code,
+    ))
+}
+
 pub async fn show_hold_invoice(
+    pool: &SqlitePool,
     my_keys: &Keys,
     payment_request: Option<String>,
     buyer_pubkey: &PublicKey,
@@ -441,17 +618,19 @@ pub async fn show_hold_invoice(
     mut order: Order,
     request_id: Option<u64>,
 ) -> anyhow::Result<()> {
-    let mut ln_client = lightning::LndConnector::new().await?;
+    let mut ln_client = connect_backend().await?;
     // Add fee of seller to hold invoice
     let new_amount = order.amount + order.fee;
 
     // Now we generate the hold invoice that seller should pay
-    let (invoice_response, preimage, hash) = ln_client
+    let (hold_invoice, preimage, hash) = ln_client
         .create_hold_invoice(
             &messages::hold_invoice_description(
                 &order.id.to_string(),
+                new_amount,
                 &order.fiat_code,
                 &order.fiat_amount.to_string(),
+                get_premium_details(&order),
             )?,
             new_amount,
         )
@@ -468,9 +647,15 @@ pub async fn show_hold_invoice(
     order.seller_pubkey = Some(seller_pubkey.to_string());
 
     // We need to publish a new event with the new status
-    let pool = db::connect().await?;
-    let order_updated = update_order_event(my_keys, Status::WaitingPayment, &order).await?;
-    order_updated.update(&pool).await?;
+    let order_updated = update_order_event(my_keys, Status::WaitingPayment, &order, pool).await?;
+    order_updated.update(pool).await?;
+    // Kept to remind the seller before it expires
+    match crate::lightning::invoice::expires_at(&hold_invoice) {
+        Ok(expires_at) => {
+            db::add_hold_invoice(pool, order.id, &hold_invoice, expires_at as i64).await?
+        }
+        Err(e) => error!("Order Id {}: hold invoice expiry unknown: {e}", order.id),
+    }
 
     let mut new_order = order.as_new_order();
     new_order.status = Some(Status::WaitingPayment);
@@ -479,21 +664,19 @@ pub async fn show_hold_invoice(
         request_id,
         Some(order.id),
         Action::PayInvoice,
-        Some(Payload::PaymentRequest(
-            Some(new_order),
-            invoice_response.payment_request,
-            None,
-        )),
+        Some(Payload::PaymentRequest(Some(new_order), hold_invoice, None)),
         seller_pubkey,
         order.trade_index_seller,
     )
     .await;
     // We send a message to buyer to know that seller was requested to pay the invoice
+    let premium_details = get_premium_details(&order)
+        .map(|details| Payload::TextMessage(messages::waiting_seller_to_pay_message(&details)));
     send_new_order_msg(
         request_id,
         Some(order.id),
         Action::WaitingSellerToPay,
-        None,
+        premium_details,
         buyer_pubkey,
         order.trade_index_buyer,
     )
@@ -506,7 +689,7 @@ pub async fn show_hold_invoice(
 
 // Create function to reuse in case of resubscription
 pub async fn invoice_subscribe(hash: Vec<u8>, request_id: Option<u64>) -> anyhow::Result<()> {
-    let mut ln_client_invoices = lightning::LndConnector::new().await?;
+    let mut ln_client_invoices = connect_backend().await?;
     let (tx, mut rx) = channel(100);
 
     let invoice_task = {
@@ -554,10 +737,15 @@ pub async fn get_market_amount_and_fee(
     fiat_amount: i64,
     fiat_code: &str,
     premium: i64,
+    fee_free: bool,
 ) -> Result<(i64, i64)> {
     // Update amount order
     let new_sats_amount = get_market_quote(&fiat_amount, fiat_code, premium).await?;
-    let fee = get_fee(new_sats_amount);
+    let fee = if fee_free {
+        0
+    } else {
+        get_fee(new_sats_amount)
+    };
 
     Ok((new_sats_amount, fee))
 }
@@ -606,45 +794,110 @@ pub async fn set_waiting_invoice_status(
     Ok(order.amount)
 }
 
-/// Settle a seller hold invoice
+/// Settle a seller hold invoice, an invoice settled out of band lets the
+/// payout go on while a canceled one is returned for the caller to cancel
+/// the order
 #[allow(clippy::too_many_arguments)]
 pub async fn settle_seller_hold_invoice(
-    event: &UnwrappedGift,
-    ln_client: &mut LndConnector,
+    sender: &PublicKey,
+    ln_client: &mut dyn LightningBackend,
     action: Action,
     is_admin: bool,
     order: &Order,
     request_id: Option<u64>,
-) -> Result<()> {
+) -> Result<SettleOutcome> {
     // Check if the pubkey is right
     if !is_admin
-        && event.rumor.pubkey.to_string() != *order.seller_pubkey.as_ref().unwrap().to_string()
+        && !order
+            .seller_pubkey
+            .as_deref()
+            .is_some_and(|seller| pubkeys_match(seller, &sender.to_hex()))
     {
         send_cant_do_msg(
             request_id,
             Some(order.id),
             Some(CantDoReason::InvalidPubkey),
-            &event.rumor.pubkey,
+            sender,
         )
         .await;
         return Err(Error::msg("Not allowed"));
     }
 
-    // Settling the hold invoice
+    // Settling the hold invoice, the order status is only updated by
+    // the caller once the settlement is confirmed
     if let Some(preimage) = order.preimage.as_ref() {
-        ln_client.settle_hold_invoice(preimage).await?;
-        info!("{action}: Order Id {}: hold invoice settled", order.id);
+        let attempts = Settings::get_ln().settle_attempts;
+        let outcome = match settle_checked(
+            ln_client,
+            preimage,
+            order.hash.as_deref(),
+            attempts,
+            SETTLE_RETRY_DELAY,
+        )
+        .await
+        {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                error!(
+                    "{action}: Order Id {}: hold invoice not settled: {e}",
+                    order.id
+                );
+                return Err(e.into());
+            }
+        };
+        match outcome {
+            SettleOutcome::Settled => {
+                info!("{action}: Order Id {}: hold invoice settled", order.id)
+            }
+            SettleOutcome::AlreadySettled => warn!(
+                "{action}: Order Id {}: hold invoice already settled out of band",
+                order.id
+            ),
+            SettleOutcome::Canceled => error!(
+                "{action}: Order Id {}: hold invoice canceled out of band, nothing to pay",
+                order.id
+            ),
+        }
+        Ok(outcome)
     } else {
         send_cant_do_msg(
             request_id,
             Some(order.id),
             Some(CantDoReason::InvalidInvoice),
-            &event.rumor.pubkey,
+            sender,
         )
         .await;
-        return Err(Error::msg("No preimage"));
+        Err(Error::msg("No preimage"))
     }
-    Ok(())
+}
+
+/// Cancel an order whose hold invoice was canceled out of band, the seller
+/// already got the funds back so both parties are told the order is canceled
+pub async fn cancel_order_without_funds(
+    pool: &SqlitePool,
+    my_keys: &Keys,
+    order: &Order,
+) -> Result<Order> {
+    let order = update_order_event(my_keys, Status::Canceled, order, pool)
+        .await?
+        .update(pool)
+        .await?;
+    for pubkey in [order.buyer_pubkey.as_ref(), order.seller_pubkey.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        send_new_order_msg(
+            None,
+            Some(order.id),
+            Action::Canceled,
+            None,
+            &PublicKey::from_str(pubkey)?,
+            None,
+        )
+        .await;
+    }
+
+    Ok(order)
 }
 
 pub fn bytes_to_string(bytes: &[u8]) -> String {
@@ -654,6 +907,48 @@ pub fn bytes_to_string(bytes: &[u8]) -> String {
     })
 }
 
+/// Check if locking `amount` sats more exceeds the `cap` of funds locked
+/// across active orders, a zero cap means no limit
+pub fn exceeds_locked_funds_cap(locked: i64, amount: i64, cap: i64) -> bool {
+    cap > 0 && locked.saturating_add(amount) > cap
+}
+
+/// Check if an order of `amount` sats fits in the configured locked funds cap,
+/// `order_id` is the order being taken so its current amount is not counted twice
+pub async fn is_within_locked_funds_cap(
+    pool: &SqlitePool,
+    order_id: Option<Uuid>,
+    amount: i64,
+) -> Result<bool> {
+    let cap = Settings::get_mostro().max_locked_funds as i64;
+    if cap == 0 {
+        return Ok(true);
+    }
+    let locked = db::get_locked_funds(pool, order_id).await?;
+
+    Ok(!exceeds_locked_funds_cap(locked, amount, cap))
+}
+
+/// Strip control characters of a received text message and reject
+/// it if longer than `max_length` characters, 0 for no limit
+pub fn sanitize_text_message(text: &str, max_length: usize) -> Result<String, CantDoReason> {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    let text = text.trim().to_string();
+    if max_length > 0 && text.chars().count() > max_length {
+        return Err(CantDoReason::InvalidParameters);
+    }
+
+    Ok(text)
+}
+
+/// Get the id required by an action, a missing id is answered
+/// with a CantDo message instead of failing the handler
+pub fn get_required_id(msg: &Message) -> Result<Uuid, CantDoReason> {
+    msg.get_inner_message_kind()
+        .id
+        .ok_or(CantDoReason::InvalidParameters)
+}
+
 pub async fn send_cant_do_msg(
     request_id: Option<u64>,
     order_id: Option<Uuid>,
@@ -663,8 +958,12 @@ pub async fn send_cant_do_msg(
     // Send message to event creator
     let message = Message::cant_do(order_id, request_id, Some(Payload::CantDo(reason)));
     if let Ok(message) = message.as_json() {
-        let sender_keys = crate::util::get_keys().unwrap();
-        let _ = send_dm(destination_key, sender_keys, message, None).await;
+        match crate::util::get_keys() {
+            Ok(sender_keys) => {
+                let _ = send_dm(destination_key, sender_keys, message, None).await;
+            }
+            Err(e) => error!("Message to {destination_key} not sent: {e}"),
+        }
     }
 }
 
@@ -679,11 +978,203 @@ pub async fn send_new_order_msg(
     // Send message to event creator
     let message = Message::new_order(order_id, request_id, trade_index, action, payload);
     if let Ok(message) = message.as_json() {
-        let sender_keys = crate::util::get_keys().unwrap();
-        let _ = send_dm(destination_key, sender_keys, message, None).await;
+        match crate::util::get_keys() {
+            Ok(sender_keys) => {
+                let _ = send_dm(destination_key, sender_keys, message, None).await;
+            }
+            Err(e) => error!("Message to {destination_key} not sent: {e}"),
+        }
+    }
+}
+
+/// Attempts to publish an event to a relay that failed it
+const RELAY_RETRY_ATTEMPTS: u32 = 5;
+
+/// Publishes to each relay since start
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RelayPublishStats {
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+static RELAY_PUBLISH_STATS: Lazy<std::sync::Mutex<HashMap<String, RelayPublishStats>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// Record the relays that accepted and failed a publish
+pub fn record_relay_publish(succeeded: &[String], failed: &[String]) {
+    let mut stats = RELAY_PUBLISH_STATS.lock().unwrap();
+    for relay in succeeded {
+        stats.entry(relay.clone()).or_default().succeeded += 1;
+    }
+    for relay in failed {
+        stats.entry(relay.clone()).or_default().failed += 1;
     }
 }
 
+/// Publish stats of every relay published to, sorted by relay
+pub fn relay_publish_stats() -> Vec<(String, RelayPublishStats)> {
+    let mut stats: Vec<_> = RELAY_PUBLISH_STATS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(relay, stats)| (relay.clone(), *stats))
+        .collect();
+    stats.sort_by(|a, b| a.0.cmp(&b.0));
+    stats
+}
+
+/// Delay before the `attempt` retry to publish to a failed relay, doubled
+/// on each attempt up to 5 minutes
+pub fn relay_retry_delay(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_secs(2u64.saturating_pow(attempt).min(300))
+}
+
+/// Publish an order or dispute status event to all the relays, successful if
+/// at least one relay accepts it, the relays failing it are retried with backoff
+/// so clients subscribed to any of them see the status change
+pub async fn publish_status_event(event: Event) -> Result<()> {
+    let client = get_nostr_client()?;
+    let output = client.send_event(event.clone()).await?;
+    let succeeded: Vec<String> = output.success.iter().map(|url| url.to_string()).collect();
+    let failed: Vec<String> = output.failed.keys().map(|url| url.to_string()).collect();
+    record_relay_publish(&succeeded, &failed);
+
+    if !failed.is_empty() {
+        tokio::spawn(retry_relay_publish(event, failed));
+    }
+    if succeeded.is_empty() {
+        return Err(Error::msg("Event not accepted by any relay"));
+    }
+
+    Ok(())
+}
+
+/// Retry to publish an event to the relays that failed it
+async fn retry_relay_publish(event: Event, mut relays: Vec<String>) {
+    for attempt in 1..=RELAY_RETRY_ATTEMPTS {
+        tokio::time::sleep(relay_retry_delay(attempt)).await;
+        let Ok(client) = get_nostr_client() else {
+            return;
+        };
+        let mut failed = vec![];
+        for relay in relays {
+            match client.send_event_to([relay.as_str()], event.clone()).await {
+                Ok(output) if !output.success.is_empty() => {
+                    record_relay_publish(&[relay], &[]);
+                }
+                _ => {
+                    record_relay_publish(&[], std::slice::from_ref(&relay));
+                    failed.push(relay);
+                }
+            }
+        }
+        if failed.is_empty() {
+            return;
+        }
+        relays = failed;
+    }
+    error!(
+        "Event {} not published to relays {:?} after {RELAY_RETRY_ATTEMPTS} retries",
+        event.id, relays
+    );
+}
+
+/// Keeps the recent events of each user to limit how many of them a user
+/// can do within a time window, e.g. orders created or disputes opened
+#[derive(Debug, Default)]
+pub struct SlidingWindowLimiter {
+    events: HashMap<String, VecDeque<i64>>,
+}
+
+impl SlidingWindowLimiter {
+    /// Record an event of `user` at `now` if it is within the limit of `max`
+    /// events every `window` seconds (0 means no limit), returns false if over
+    /// the limit
+    pub fn try_record(&mut self, user: &str, now: i64, window: i64, max: usize) -> bool {
+        if max == 0 {
+            return true;
+        }
+        // Forget events out of the window and users without any left
+        self.events.retain(|_, events| {
+            while events.front().is_some_and(|at| *at <= now - window) {
+                events.pop_front();
+            }
+            !events.is_empty()
+        });
+        let events = self.events.entry(user.to_string()).or_default();
+        if events.len() >= max {
+            return false;
+        }
+        events.push_back(now);
+        true
+    }
+}
+
+/// Orders with a hold invoice settlement being handled
+static ORDERS_BEING_SETTLED: Lazy<std::sync::Mutex<HashSet<Uuid>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashSet::new()));
+
+/// Per-order lock held while the hold invoice of an order is settled, an
+/// admin settle and a seller release of the same order never run together
+pub struct OrderSettleLock(Uuid);
+
+impl OrderSettleLock {
+    /// Lock the order, None if other settlement of the same order holds the lock
+    pub fn acquire(order_id: Uuid) -> Option<Self> {
+        ORDERS_BEING_SETTLED
+            .lock()
+            .unwrap()
+            .insert(order_id)
+            .then_some(Self(order_id))
+    }
+}
+
+impl Drop for OrderSettleLock {
+    fn drop(&mut self) {
+        ORDERS_BEING_SETTLED.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Lock an order to settle its hold invoice and get it as stored once locked,
+/// None if other settlement holds the lock or the order can't be settled
+/// anymore, e.g. it was settled while waiting for the lock
+pub async fn lock_order_settlement(
+    pool: &SqlitePool,
+    order_id: Uuid,
+) -> Result<Option<(OrderSettleLock, Order)>> {
+    let Some(lock) = OrderSettleLock::acquire(order_id) else {
+        return Ok(None);
+    };
+    let Some(order) = Order::by_id(pool, order_id).await? else {
+        return Ok(None);
+    };
+    if !matches!(
+        Status::from_str(&order.status),
+        Ok(Status::Active | Status::FiatSent | Status::Dispute)
+    ) {
+        return Ok(None);
+    }
+
+    Ok(Some((lock, order)))
+}
+
+/// Check that an order can still be taken at `now`, it must be pending, not expired
+/// and not older than `max_age` seconds (0 means no limit)
+pub fn check_order_takeable(order: &Order, now: i64, max_age: i64) -> Result<(), CantDoReason> {
+    // A take may arrive right after the order was canceled or disputed
+    match Status::from_str(&order.status) {
+        Ok(Status::Pending) => {}
+        Ok(Status::Canceled | Status::CooperativelyCanceled | Status::CanceledByAdmin) => {
+            return Err(CantDoReason::OrderAlreadyCanceled)
+        }
+        _ => return Err(CantDoReason::NotAllowedByStatus),
+    }
+    if order.expires_at <= now || (max_age > 0 && order.created_at + max_age <= now) {
+        return Err(CantDoReason::InvalidOrderStatus);
+    }
+    Ok(())
+}
+
 pub fn get_fiat_amount_requested(order: &Order, msg: &Message) -> Option<i64> {
     // Check if order is range and get amount request after checking boundaries
     // set order fiat amount to the value requested preparing for hold invoice
@@ -724,9 +1215,14 @@ pub async fn get_nostr_relays() -> Option<HashMap<RelayUrl, Relay>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cli::settings::replace_settings;
+    use crate::MOSTRO_CONFIG;
     use mostro_core::message::{Message, MessageKind};
     use mostro_core::order::Order;
-    use std::sync::Once;
+    use std::env::set_var;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    use std::sync::{Once, RwLock};
     use uuid::uuid;
     // Setup function to initialize common settings or data before tests
     static INIT: Once = Once::new();
@@ -737,6 +1233,133 @@ mod tests {
         });
     }
 
+    fn init_settings_test() {
+        let test_path = PathBuf::from("./");
+        set_var("RUN_MODE", ".tpl");
+        MOSTRO_CONFIG.get_or_init(|| RwLock::new(Arc::new(Settings::new(test_path).unwrap())));
+    }
+
+    #[test]
+    fn test_premium_details_of_taken_order() {
+        let order = Order {
+            amount: 200_000,
+            fiat_code: "USD".to_string(),
+            fiat_amount: 100,
+            premium: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            premium_details(&order).unwrap(),
+            "Premium 2% - Price USD 50,000/BTC"
+        );
+        // Market price orders not taken yet have no sats amount
+        let order = Order { amount: 0, ..order };
+        assert!(premium_details(&order).is_none());
+    }
+
+    #[test]
+    fn test_cached_keys_follow_reload() {
+        let cache = RwLock::new(None);
+        let first = Keys::generate();
+        let second = Keys::generate();
+
+        let nsec = first.secret_key().to_bech32().unwrap();
+        assert_eq!(
+            cached_keys(&cache, &nsec).unwrap().public_key(),
+            first.public_key()
+        );
+        // Settings reloaded with another key
+        let nsec = second.secret_key().to_bech32().unwrap();
+        assert_eq!(
+            cached_keys(&cache, &nsec).unwrap().public_key(),
+            second.public_key()
+        );
+        // A broken key fails on read, the old keys are not used in its place
+        assert!(cached_keys(&cache, "nsec1wrong").is_err());
+    }
+
+    #[test]
+    fn test_reload_settings_changes_fee() {
+        set_var("RUN_MODE", ".tpl");
+        let mut settings = Settings::new(PathBuf::from("./")).unwrap();
+        settings.mostro.fee = 0.0;
+        // A private copy of the settings, other tests keep reading the global ones
+        let config = RwLock::new(Arc::new(settings.clone()));
+        assert_eq!(fee_for(&config.read().unwrap().mostro, 100_000), 0);
+
+        // Fee is changed without restarting
+        settings.mostro.fee = 0.006;
+        replace_settings(&config, settings);
+        assert_eq!(fee_for(&config.read().unwrap().mostro, 100_000), 300);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_settlements_pay_once() {
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let order = Order {
+            id: Uuid::new_v4(),
+            status: Status::Dispute.to_string(),
+            ..Default::default()
+        };
+        let order = order.create(&pool).await.unwrap();
+
+        // Admin settle and seller release of the same order at once
+        async fn settle(pool: &SqlitePool, order_id: Uuid, payouts: &AtomicUsize) {
+            let Some((_lock, order)) = lock_order_settlement(pool, order_id).await.unwrap() else {
+                return;
+            };
+            tokio::task::yield_now().await;
+            if db::set_order_settled(pool, order.id).await.unwrap() {
+                payouts.fetch_add(1, AtomicOrdering::SeqCst);
+            }
+        }
+        let payouts = AtomicUsize::new(0);
+        tokio::join!(
+            settle(&pool, order.id, &payouts),
+            settle(&pool, order.id, &payouts)
+        );
+        assert_eq!(payouts.load(AtomicOrdering::SeqCst), 1);
+
+        // A late settlement finds the order already settled
+        assert!(lock_order_settlement(&pool, order.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_relay_publish_stats() {
+        let relays = [
+            "wss://up.example".to_string(),
+            "wss://down.example".to_string(),
+        ];
+        record_relay_publish(&relays[..1], &relays[1..]);
+        record_relay_publish(&relays, &[]);
+        let stats = relay_publish_stats();
+        let of = |relay: &str| stats.iter().find(|(r, _)| r == relay).unwrap().1;
+        assert_eq!(
+            of("wss://up.example"),
+            RelayPublishStats {
+                succeeded: 2,
+                failed: 0
+            }
+        );
+        assert_eq!(
+            of("wss://down.example"),
+            RelayPublishStats {
+                succeeded: 1,
+                failed: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_relay_retry_backoff() {
+        assert_eq!(relay_retry_delay(1).as_secs(), 2);
+        assert_eq!(relay_retry_delay(3).as_secs(), 8);
+        assert_eq!(relay_retry_delay(20).as_secs(), 300);
+    }
+
     #[test]
     fn test_bytes_to_string() {
         initialize();
@@ -748,6 +1371,7 @@ mod tests {
     #[tokio::test]
     async fn test_get_market_quote() {
         initialize();
+        init_settings_test();
         // Mock the get_market_quote function's external API call
         let fiat_amount = 1000; // $1000
         let fiat_code = "USD";
@@ -806,6 +1430,425 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_unlisted_order_request() {
+        let keys = Keys::generate();
+        let rumor = |tags: Vec<Tag>| UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: EventBuilder::text_note("")
+                .tags(tags)
+                .build(keys.public_key()),
+        };
+        let unlisted = rumor(vec![Tag::custom(
+            TagKind::Custom("unlisted".into()),
+            Vec::<String>::new(),
+        )]);
+        // Unlisted orders are not published
+        assert!(is_unlisted_request(&unlisted));
+        assert!(!is_unlisted_request(&rumor(vec![])));
+    }
+
+    #[test]
+    fn test_rate_events_queue() {
+        init_settings_test();
+        let keys = Keys::generate();
+        let rating = |user: &str, last_rating: &str| {
+            new_event(
+                &keys,
+                "",
+                user.to_string(),
+                Tags::new(vec![Tag::custom(
+                    TagKind::Custom("last_rating".into()),
+                    [last_rating],
+                )]),
+            )
+            .unwrap()
+        };
+        let mut rate_list = vec![];
+        queue_rate_event(&mut rate_list, rating("alice", "3"));
+        queue_rate_event(&mut rate_list, rating("bob", "4"));
+        // Only the latest rating event of a user is kept
+        let latest = rating("alice", "5");
+        queue_rate_event(&mut rate_list, latest.clone());
+        assert_eq!(rate_list.len(), 2);
+        assert_eq!(rate_list[1], latest);
+
+        // The list is bounded
+        for i in 0..MAX_RATE_EVENTS + 10 {
+            queue_rate_event(&mut rate_list, rating(&i.to_string(), "5"));
+        }
+        assert_eq!(rate_list.len(), MAX_RATE_EVENTS);
+        assert_eq!(
+            rate_list[MAX_RATE_EVENTS - 1].tags.identifier(),
+            Some((MAX_RATE_EVENTS + 9).to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn test_nip05_requests() {
+        let keys = Keys::generate();
+        let rumor = |tags: Vec<Tag>| UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: EventBuilder::text_note("")
+                .tags(tags)
+                .build(keys.public_key()),
+        };
+        let maker = rumor(vec![Tag::custom(
+            TagKind::Custom("requires_nip05".into()),
+            Vec::<String>::new(),
+        )]);
+        assert!(is_nip05_required_request(&maker));
+        assert!(!is_nip05_required_request(&rumor(vec![])));
+
+        let taker = rumor(vec![Tag::custom(
+            TagKind::Custom("nip05".into()),
+            ["alice@example.com"],
+        )]);
+        assert_eq!(
+            get_nip05_identifier(&taker).as_deref(),
+            Some("alice@example.com")
+        );
+        assert_eq!(get_nip05_identifier(&rumor(vec![])), None);
+    }
+
+    #[tokio::test]
+    async fn test_orders_without_nip05_requirement_take_anyone() {
+        let (pool, _db) = crate::db::connect_test_db().await;
+        let keys = Keys::generate();
+        let taker = UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: EventBuilder::text_note("").build(keys.public_key()),
+        };
+        let order_id = Uuid::new_v4();
+        assert!(meets_nip05_requirement(&pool, order_id, &taker)
+            .await
+            .unwrap());
+        // Takers without an identifier can't take orders requiring one
+        db::add_nip05_order(&pool, order_id).await.unwrap();
+        assert!(!meets_nip05_requirement(&pool, order_id, &taker)
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn test_take_pow_request() {
+        let keys = Keys::generate();
+        let rumor = |tags: Vec<Tag>| UnwrappedGift {
+            sender: keys.public_key(),
+            rumor: EventBuilder::text_note("")
+                .tags(tags)
+                .build(keys.public_key()),
+        };
+        let event = rumor(vec![Tag::custom(
+            TagKind::Custom("take_pow".into()),
+            vec!["12"],
+        )]);
+        assert_eq!(get_take_pow_request(&event), Some(12));
+        let event = rumor(vec![Tag::custom(
+            TagKind::Custom("take_pow".into()),
+            vec!["a lot"],
+        )]);
+        assert_eq!(get_take_pow_request(&event), None);
+        assert_eq!(get_take_pow_request(&rumor(vec![])), None);
+    }
+
+    #[test]
+    fn test_sanitize_text_message() {
+        initialize();
+        assert_eq!(
+            sanitize_text_message("npub1abc\u{0}\u{1b}[0m\n", 100),
+            Ok("npub1abc[0m".to_string())
+        );
+        assert_eq!(sanitize_text_message("hello", 5), Ok("hello".to_string()));
+        // Over length
+        assert_eq!(
+            sanitize_text_message("hello!", 5),
+            Err(CantDoReason::InvalidParameters)
+        );
+        // Control characters don't count in the length
+        assert_eq!(
+            sanitize_text_message("hel\u{7}lo", 5),
+            Ok("hello".to_string())
+        );
+        assert!(sanitize_text_message(&"a".repeat(5000), 0).is_ok());
+    }
+
+    #[test]
+    fn test_locked_funds_cap() {
+        initialize();
+        // At the cap is allowed
+        assert!(!exceeds_locked_funds_cap(900_000, 100_000, 1_000_000));
+        // Over the cap is rejected
+        assert!(exceeds_locked_funds_cap(900_000, 100_001, 1_000_000));
+        // No cap
+        assert!(!exceeds_locked_funds_cap(900_000, 100_001, 0));
+    }
+
+    #[test]
+    fn test_get_required_id() {
+        initialize();
+        let order_id = Uuid::new_v4();
+        // Actions requiring an order id sent without it are rejected, not panicking
+        for action in [
+            Action::Release,
+            Action::Cancel,
+            Action::FiatSent,
+            Action::Dispute,
+            Action::AddInvoice,
+            Action::TakeBuy,
+            Action::TakeSell,
+            Action::RateUser,
+            Action::AdminCancel,
+            Action::AdminSettle,
+            Action::AdminTakeDispute,
+        ] {
+            let msg = Message::new_order(None, Some(1), None, action.clone(), None);
+            assert_eq!(get_required_id(&msg), Err(CantDoReason::InvalidParameters));
+            let msg = Message::new_order(Some(order_id), Some(1), None, action, None);
+            assert_eq!(get_required_id(&msg), Ok(order_id));
+        }
+    }
+
+    #[test]
+    fn test_subscription_filter_kinds() {
+        let pubkey = Keys::generate().public_key();
+        let filter = subscription_filter(pubkey, &[]);
+        let kinds = filter.kinds.unwrap();
+        assert_eq!(kinds.len(), 1);
+        assert!(kinds.contains(&Kind::GiftWrap));
+
+        let filter = subscription_filter(pubkey, &[38383]);
+        let kinds = filter.kinds.unwrap();
+        assert_eq!(kinds.len(), 2);
+        assert!(kinds.contains(&Kind::GiftWrap));
+        assert!(kinds.contains(&Kind::from(38383)));
+        assert!(!kinds.contains(&Kind::TextNote));
+    }
+
+    #[test]
+    fn test_key_load_failure() {
+        assert!(parse_keys("nsec1wrongkey").is_err());
+        assert!(parse_keys("").is_err());
+        let keys = Keys::generate();
+        let nsec = keys.secret_key().to_bech32().unwrap();
+        assert_eq!(parse_keys(&nsec).unwrap().public_key(), keys.public_key());
+    }
+
+    #[test]
+    fn test_calculate_fee() {
+        initialize();
+        assert_eq!(calculate_fee(100_000, 0.006, 0), 300);
+        assert_eq!(calculate_fee(100_000, 0.0, 0), 0);
+    }
+
+    #[test]
+    fn test_fee_rounding_small_amounts() {
+        // 0.6% of 100 sats is 0.3 sats each party
+        assert_eq!(calculate_fee(100, 0.006, 0), 0);
+        // 0.6% of 250 sats is 0.75 sats each party
+        assert_eq!(calculate_fee(250, 0.006, 0), 1);
+        // Flat fee split between the parties, halves round up
+        assert_eq!(calculate_fee(1_000, 0.0, 5), 3);
+        assert_eq!(calculate_fee(1_000, 0.006, 10), 8);
+        // A party never pays more than half the amount
+        assert_eq!(calculate_fee(10, 0.006, 100), 5);
+        assert_eq!(calculate_fee(1, 0.006, 100), 0);
+    }
+
+    #[test]
+    fn test_fee_free_orders() {
+        let maker = Keys::generate().public_key();
+        let taker = Keys::generate().public_key().to_hex();
+        // Whitelisted as npub, stored in the order as hex
+        let whitelist = vec![maker.to_bech32().unwrap()];
+        let maker = maker.to_hex();
+
+        let order = Order {
+            kind: OrderKind::Sell.to_string(),
+            master_seller_pubkey: Some(maker.clone()),
+            master_buyer_pubkey: Some(taker.clone()),
+            ..Default::default()
+        };
+        assert!(is_order_maker_whitelisted(&whitelist, &order));
+        // Only the maker's whitelisting counts
+        let order = Order {
+            kind: OrderKind::Buy.to_string(),
+            master_buyer_pubkey: Some(taker),
+            master_seller_pubkey: Some(maker),
+            ..Default::default()
+        };
+        assert!(!is_order_maker_whitelisted(&whitelist, &order));
+    }
+
+    #[test]
+    fn test_fee_clamped_to_cap() {
+        // 1% total fee on 100k sats is 500 sats each party, capped at 0.5%
+        let fee = calculate_fee(100_000, 0.01, 0);
+        assert_eq!(fee, 500);
+        assert_eq!(cap_fee(fee, 100_000, 0.5), 250);
+    }
+
+    #[test]
+    fn test_fee_under_cap_unchanged() {
+        let fee = calculate_fee(100_000, 0.006, 0);
+        assert_eq!(cap_fee(fee, 100_000, 1.0), 300);
+        // No cap set
+        assert_eq!(cap_fee(fee, 100_000, 0.0), 300);
+    }
+
+    #[test]
+    fn test_get_required_pow() {
+        initialize();
+        let tiers = vec![
+            PowTier {
+                min_amount: 100_000,
+                pow: 8,
+            },
+            PowTier {
+                min_amount: 500_000,
+                pow: 16,
+            },
+        ];
+        // Small orders only need the base POW
+        assert_eq!(get_required_pow(2, &tiers, 1_000), 2);
+        assert_eq!(get_required_pow(2, &tiers, 99_999), 2);
+        // Large orders need the POW of the highest reached tier
+        assert_eq!(get_required_pow(2, &tiers, 100_000), 8);
+        assert_eq!(get_required_pow(2, &tiers, 499_999), 8);
+        assert_eq!(get_required_pow(2, &tiers, 1_000_000), 16);
+        // Tiers never lower the base POW
+        assert_eq!(get_required_pow(20, &tiers, 1_000_000), 20);
+        // No tiers configured
+        assert_eq!(get_required_pow(4, &[], 1_000_000), 4);
+    }
+
+    #[test]
+    fn test_take_pending_order() {
+        initialize();
+        let now = 1_700_000_000;
+        let order = Order {
+            status: Status::Pending.to_string(),
+            created_at: now - 60,
+            expires_at: now + 3600,
+            ..Default::default()
+        };
+        assert!(check_order_takeable(&order, now, 0).is_ok());
+        assert!(check_order_takeable(&order, now, 3600).is_ok());
+    }
+
+    #[test]
+    fn test_take_already_taken_order() {
+        initialize();
+        let now = 1_700_000_000;
+        let order = Order {
+            status: Status::WaitingPayment.to_string(),
+            created_at: now - 60,
+            expires_at: now + 3600,
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_order_takeable(&order, now, 0),
+            Err(CantDoReason::NotAllowedByStatus)
+        ));
+    }
+
+    #[test]
+    fn test_take_disputed_order() {
+        initialize();
+        let now = 1_700_000_000;
+        let order = Order {
+            status: Status::Dispute.to_string(),
+            created_at: now - 60,
+            expires_at: now + 3600,
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_order_takeable(&order, now, 0),
+            Err(CantDoReason::NotAllowedByStatus)
+        ));
+    }
+
+    #[test]
+    fn test_take_canceled_order() {
+        initialize();
+        let now = 1_700_000_000;
+        for status in [
+            Status::Canceled,
+            Status::CooperativelyCanceled,
+            Status::CanceledByAdmin,
+        ] {
+            let order = Order {
+                status: status.to_string(),
+                created_at: now - 60,
+                expires_at: now + 3600,
+                ..Default::default()
+            };
+            assert!(matches!(
+                check_order_takeable(&order, now, 0),
+                Err(CantDoReason::OrderAlreadyCanceled)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_take_expired_order() {
+        initialize();
+        let now = 1_700_000_000;
+        let order = Order {
+            status: Status::Pending.to_string(),
+            created_at: now - 7200,
+            expires_at: now - 60,
+            ..Default::default()
+        };
+        assert!(matches!(
+            check_order_takeable(&order, now, 0),
+            Err(CantDoReason::InvalidOrderStatus)
+        ));
+        // Order too old to be taken
+        let order = Order {
+            expires_at: now + 3600,
+            ..order
+        };
+        assert!(matches!(
+            check_order_takeable(&order, now, 3600),
+            Err(CantDoReason::InvalidOrderStatus)
+        ));
+    }
+
+    #[test]
+    fn test_sliding_window_limiter() {
+        let mut limiter = SlidingWindowLimiter::default();
+        // 2 events every 60 seconds
+        assert!(limiter.try_record("user", 0, 60, 2));
+        assert!(limiter.try_record("user", 10, 60, 2));
+        assert!(!limiter.try_record("user", 20, 60, 2));
+        // Other users have their own limit
+        assert!(limiter.try_record("other", 20, 60, 2));
+        // The first event is out of the window, rejected attempts are not recorded
+        assert!(limiter.try_record("user", 60, 60, 2));
+        assert!(!limiter.try_record("user", 65, 60, 2));
+    }
+
+    #[test]
+    fn test_sliding_window_no_limit() {
+        let mut limiter = SlidingWindowLimiter::default();
+        for now in 0..100 {
+            assert!(limiter.try_record("user", now, 60, 0));
+        }
+        assert!(limiter.events.is_empty());
+    }
+
+    #[test]
+    fn test_sliding_window_forgets_idle_users() {
+        let mut limiter = SlidingWindowLimiter::default();
+        assert!(limiter.try_record("user", 0, 60, 2));
+        assert!(limiter.try_record("other", 30, 60, 2));
+        // Users without events in the window are dropped
+        assert!(limiter.try_record("other", 70, 60, 2));
+        assert_eq!(limiter.events.len(), 1);
+        assert!(!limiter.events.contains_key("user"));
+    }
+
     #[tokio::test]
     async fn test_get_fiat_amount_requested() {
         initialize();
@@ -826,4 +1869,28 @@ mod tests {
         let amount = get_fiat_amount_requested(&order, &message);
         assert_eq!(amount, Some(1000));
     }
+
+    #[test]
+    fn test_take_fixed_sats_order() {
+        initialize();
+        let now = 1_700_000_000;
+        // Sats locked at creation, fiat amount is only a reference
+        let order = Order {
+            amount: 100_000,
+            fiat_amount: 50,
+            status: Status::Pending.to_string(),
+            created_at: now - 60,
+            expires_at: now + 3600,
+            ..Default::default()
+        };
+        assert!(check_order_takeable(&order, now, 0).is_ok());
+        let message = Message::Order(MessageKind::new(
+            Some(order.id),
+            Some(1),
+            Some(1),
+            Action::TakeSell,
+            None,
+        ));
+        assert_eq!(get_fiat_amount_requested(&order, &message), Some(50));
+    }
 }
@@ -0,0 +1,252 @@
+//! Webhook notifications of order status changes for integrators that prefer
+//! push notifications to subscribing to Nostr
+
+use crate::cli::settings::Settings;
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use serde::Serialize;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Notifications waiting to be delivered, once full new ones are dropped so a
+/// slow endpoint never blocks the event loop
+const WEBHOOK_QUEUE_SIZE: usize = 1000;
+/// Delivery attempts of each notification
+const WEBHOOK_ATTEMPTS: u32 = 5;
+/// Time the endpoint has to answer each attempt
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Header with the hex HMAC-SHA256 of the body keyed with `webhook_secret`,
+/// not sent without a secret
+pub const SIGNATURE_HEADER: &str = "X-Mostro-Signature";
+
+pub(crate) static WEBHOOK_QUEUE: OnceLock<mpsc::Sender<StatusChange>> = OnceLock::new();
+
+/// Payload posted to the webhook
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatusChange {
+    pub order_id: Uuid,
+    pub old_status: String,
+    pub new_status: String,
+    pub timestamp: i64,
+}
+
+/// Hex HMAC-SHA256 of `body` keyed with `secret`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body);
+
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+/// Delay before the attempt after `attempt`, doubling each time
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    base * 2u32.saturating_pow(attempt)
+}
+
+/// Post `change` to `url` until it gets a 2xx answer, returns false if all
+/// the attempts failed
+async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    change: &StatusChange,
+    base_delay: Duration,
+) -> bool {
+    let body = match serde_json::to_vec(change) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Error serializing webhook payload: {e}");
+            return false;
+        }
+    };
+    let signature = (!secret.is_empty()).then(|| sign(secret, &body));
+    for attempt in 0..WEBHOOK_ATTEMPTS {
+        let mut req = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            req = req.header(SIGNATURE_HEADER, signature);
+        }
+        let res = req.send().await;
+        match res {
+            Ok(res) if res.status().is_success() => return true,
+            Ok(res) => warn!(
+                "Order Id {}: webhook answered {}, attempt {}",
+                change.order_id,
+                res.status(),
+                attempt + 1
+            ),
+            Err(e) => warn!(
+                "Order Id {}: webhook failed: {e}, attempt {}",
+                change.order_id,
+                attempt + 1
+            ),
+        }
+        if attempt + 1 < WEBHOOK_ATTEMPTS {
+            tokio::time::sleep(retry_delay(base_delay, attempt)).await;
+        }
+    }
+
+    false
+}
+
+/// Start the dispatcher posting the queued notifications in order
+fn spawn_dispatcher(
+    url: String,
+    secret: String,
+    base_delay: Duration,
+) -> anyhow::Result<mpsc::Sender<StatusChange>> {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+    let (tx, mut rx) = mpsc::channel::<StatusChange>(WEBHOOK_QUEUE_SIZE);
+    tokio::spawn(async move {
+        while let Some(change) = rx.recv().await {
+            if !deliver(&client, &url, &secret, &change, base_delay).await {
+                error!(
+                    "Order Id {}: webhook not delivered, {} -> {}",
+                    change.order_id, change.old_status, change.new_status
+                );
+            }
+        }
+    });
+
+    Ok(tx)
+}
+
+/// Start the webhook dispatcher if `webhook_url` is set
+pub fn start_webhook() -> anyhow::Result<()> {
+    let mostro_settings = Settings::get_mostro();
+    if mostro_settings.webhook_url.is_empty() {
+        return Ok(());
+    }
+    let tx = spawn_dispatcher(
+        mostro_settings.webhook_url.clone(),
+        mostro_settings.webhook_secret.clone(),
+        Duration::from_secs(1),
+    )?;
+    let _ = WEBHOOK_QUEUE.set(tx);
+    info!("Webhook notifications enabled");
+
+    Ok(())
+}
+
+fn enqueue(queue: &mpsc::Sender<StatusChange>, change: StatusChange) {
+    match queue.try_send(change) {
+        Ok(()) => {}
+        Err(TrySendError::Full(change)) => warn!(
+            "Order Id {}: webhook queue full, notification dropped",
+            change.order_id
+        ),
+        Err(TrySendError::Closed(_)) => {}
+    }
+}
+
+/// Queue a notification of an order status change, does nothing if the
+/// webhook is disabled
+pub fn notify_status_change(order_id: Uuid, old_status: &str, new_status: &str, timestamp: i64) {
+    if let Some(queue) = WEBHOOK_QUEUE.get() {
+        enqueue(
+            queue,
+            StatusChange {
+                order_id,
+                old_status: old_status.to_string(),
+                new_status: new_status.to_string(),
+                timestamp,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::tests::mock_server;
+    use crate::http::Response;
+    use std::sync::{Arc, Mutex};
+
+    fn change() -> StatusChange {
+        StatusChange {
+            order_id: Uuid::new_v4(),
+            old_status: "active".to_string(),
+            new_status: "fiat-sent".to_string(),
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    /// Start an endpoint answering 500 to the first `failures` requests and
+    /// 200 afterwards, returns its url and the requests received
+    async fn mock_endpoint(failures: usize) -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        let addr = mock_server(move |request| {
+            let count = {
+                let mut received = received.lock().unwrap();
+                received.push(request);
+                received.len()
+            };
+            let status = if count > failures { 200 } else { 500 };
+            async move { Response::json(status, String::new()) }
+        })
+        .await;
+
+        (format!("http://{addr}/hook"), requests)
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries_until_success() {
+        let (url, requests) = mock_endpoint(2).await;
+        let change = change();
+        let client = reqwest::Client::new();
+        assert!(deliver(&client, &url, "secret", &change, Duration::from_millis(1)).await);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let body = serde_json::to_vec(&change).unwrap();
+        let signature = sign("secret", &body);
+        let request = requests[2].to_lowercase();
+        assert!(request.contains(&format!("{}: {signature}", SIGNATURE_HEADER.to_lowercase())));
+        assert!(requests[2].contains(r#""new_status":"fiat-sent""#));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_unsigned_without_secret() {
+        let (url, requests) = mock_endpoint(0).await;
+        let client = reqwest::Client::new();
+        assert!(deliver(&client, &url, "", &change(), Duration::from_millis(1)).await);
+
+        let requests = requests.lock().unwrap();
+        let request = requests[0].to_lowercase();
+        assert!(!request.contains(&SIGNATURE_HEADER.to_lowercase()));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_gives_up() {
+        let (url, requests) = mock_endpoint(usize::MAX).await;
+        let client = reqwest::Client::new();
+        assert!(!deliver(&client, &url, "secret", &change(), Duration::from_millis(1)).await);
+        assert_eq!(requests.lock().unwrap().len(), WEBHOOK_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_notifications() {
+        let (tx, mut rx) = mpsc::channel(1);
+        enqueue(&tx, change());
+        // Doesn't wait for room in the queue
+        enqueue(&tx, change());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+}